@@ -0,0 +1,5 @@
+fn main() {
+    // gRPC stubs are generated only with `grpc` feature, which needs `protoc` installed
+    #[cfg(feature = "grpc")]
+    tonic_build::compile_protos("proto/bevy_rl.proto").unwrap();
+}
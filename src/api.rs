@@ -6,214 +6,2749 @@
 //!
 //! Sergei Surovsev <ssurovsev@gmail.com>
 
-use crossbeam_channel::*;
-
-use gotham::helpers::http::response::create_response;
+use gotham::handler::HandlerFuture;
+use gotham::helpers::http::response::{create_empty_response, create_response};
 use gotham::middleware::state::StateMiddleware;
-use gotham::pipeline::{single_middleware, single_pipeline};
+use gotham::pipeline::{new_pipeline, single_pipeline};
 use gotham::prelude::StaticResponseExtender;
 use gotham::router::builder::*;
 use gotham::router::Router;
 use gotham::state::StateData;
-use gotham::state::{FromState, State};
-use hyper::{Body, Response, StatusCode};
+use gotham::state::{client_addr, FromState, State};
+use hyper::header::{ACCEPT, CONNECTION, SEC_WEBSOCKET_ACCEPT, SEC_WEBSOCKET_KEY, UPGRADE};
+use hyper::upgrade::{OnUpgrade, Upgraded};
+use hyper::{Body, HeaderMap, Response, StatusCode, Uri};
+use tokio_tungstenite::tungstenite::handshake::derive_accept_key;
+use tokio_tungstenite::tungstenite::protocol::{Message, Role};
+use tokio_tungstenite::WebSocketStream;
+
+#[cfg(feature = "tls")]
+use futures::TryFutureExt;
+use futures::{FutureExt, SinkExt, StreamExt};
 
+use crossbeam_channel::{bounded, RecvTimeoutError, Sender};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
+use std::collections::HashMap;
 use std::io::Cursor;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
 
-use crate::{state, AIGymSettings};
+use crate::metrics::MetricsMiddleware;
+use crate::security::{CorsMiddleware, SigningMiddleware, TokenMiddleware};
+use crate::snapshot::{SnapshotError, SnapshotRequest};
+use crate::{preprocess, state, AIGymSettings};
 
-/// A reprsentation of agent's state (reward, terminated) in terms of bevy_rl
+/// A reprsentation of agent's state (reward, terminated, truncated) in terms of bevy_rl
 /// That's not the same as the state of the environment
 #[derive(Serialize, Deserialize)]
 pub(crate) struct AgentState {
-    reward: f32,
-    is_terminated: bool,
+    pub(crate) reward: f32,
+    pub(crate) is_terminated: bool,
+    pub(crate) is_truncated: bool,
+    pub(crate) info: serde_json::Value,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) vector_observation: Option<Vec<f32>>,
 }
 
 /// This is used for deserializing agent's action from the request body
 #[derive(Serialize, Deserialize, Debug)]
 pub(crate) struct AgentAction {
-    action: Option<String>,
+    pub(crate) action: Option<String>,
 }
 
 /// `GothamState` is a wrapper around `AIGymState` that is used by Gotham middleware
 /// It's holds a state of the environment and settings
 #[derive(Clone, StateData)]
 pub(crate) struct GothamState<
-    T: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe,
+    T: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::de::DeserializeOwned,
     P: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::Serialize,
 > {
     pub(crate) inner: state::AIGymState<T, P>,
     pub(crate) settings: AIGymSettings,
+    pub(crate) step_batcher: Arc<StepBatcher<P>>,
+}
+
+/// Collects `step` calls which arrive within `AIGymSettings.step_batch_window` into one batch.
+/// The first call of a batch waits for the window to pass and talks to the engine for all of them
+pub(crate) struct StepBatcher<P> {
+    #[allow(clippy::type_complexity)]
+    pending: Mutex<
+        Vec<(
+            Vec<Option<String>>,
+            Sender<Result<Vec<state::AgentTransition<P>>, ApiError>>,
+        )>,
+    >,
+    engine: Mutex<()>,
+}
+
+impl<P> Default for StepBatcher<P> {
+    fn default() -> Self {
+        Self {
+            pending: Mutex::new(Vec::new()),
+            engine: Mutex::new(()),
+        }
+    }
 }
 
 /// Describes REST API routes
 pub(crate) fn router<
-    T: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe,
+    T: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::de::DeserializeOwned,
     P: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::Serialize,
 >(
     state: GothamState<T, P>,
 ) -> Router {
-    let middleware = StateMiddleware::new(state);
-    let pipeline = single_middleware(middleware);
+    let signing_middleware = SigningMiddleware::new(state.settings.signing_key.clone());
+    let token_middleware = TokenMiddleware::new(state.settings.api_token.clone());
+    let cors_middleware = CorsMiddleware::new(state.settings.cors_origins.clone());
+    let is_cors_enabled = !state.settings.cors_origins.is_empty();
+    let metrics_middleware = MetricsMiddleware::new(state.inner.lock().unwrap().metrics.clone());
+    let pipeline = new_pipeline()
+        .add(StateMiddleware::new(state))
+        .add(metrics_middleware)
+        .add(signing_middleware)
+        .add(cors_middleware)
+        .add(token_middleware)
+        .build();
 
     let (chain, pipelines) = single_pipeline(pipeline);
 
     build_router(chain, pipelines, |route| {
+        route.get("/").to(dashboard);
+        route.get("/status").to(status::<T, P>);
         route
             .get("/visual_observations")
+            .with_query_string_extractor::<ImageFormatQueryString>()
             .to(visual_observations::<T, P>);
+        route
+            .get("/visual_observations/raw")
+            .to(raw_visual_observations::<T, P>);
+        #[cfg(feature = "shared-memory")]
+        route
+            .get("/visual_observations/shm")
+            .to(shared_visual_observations::<T, P>);
+        route
+            .get("/visual_observations/:agent_id:[0-9]+")
+            .with_path_extractor::<AgentPathExtractor>()
+            .with_query_string_extractor::<ImageFormatQueryString>()
+            .to(agent_visual_observation::<T, P>);
+        route
+            .get("/logging_observations")
+            .with_query_string_extractor::<ImageFormatQueryString>()
+            .to(logging_observations::<T, P>);
+        route
+            .get("/stacked_observations")
+            .to(stacked_observations::<T, P>);
+        route
+            .get("/depth_observations")
+            .to(depth_observations::<T, P>);
         route
             .get("/step")
             .with_query_string_extractor::<StepQueryString>()
             .to(step::<T, P>);
-        route.get("/reset").to(reset::<T, P>);
-        route.get("/state").to(env_state::<T, P>);
+        route
+            .get("/reset")
+            .with_query_string_extractor::<ResetQueryString>()
+            .to(reset::<T, P>);
+        route
+            .get("/reset_agents")
+            .with_query_string_extractor::<ResetAgentsQueryString>()
+            .to(reset_agents::<T, P>);
+        route.get("/save_state").to(save_state::<T, P>);
+        route
+            .get("/load_state")
+            .with_query_string_extractor::<LoadStateQueryString>()
+            .to(load_state::<T, P>);
+        route.post("/set_state").to(set_state::<T, P>);
+        route.post("/pause").to(pause::<T, P>);
+        route.post("/resume").to(resume::<T, P>);
+        route
+            .post("/advance")
+            .with_query_string_extractor::<AdvanceQueryString>()
+            .to(advance::<T, P>);
+        route
+            .get("/state")
+            .with_query_string_extractor::<PayloadFormatQueryString>()
+            .to(env_state::<T, P>);
+        route.get("/state/bin").to(env_state_bin::<T, P>);
+        route.get("/ws").to(ws::<T, P>);
+        route
+            .get("/stream")
+            .with_query_string_extractor::<StreamQueryString>()
+            .to(stream::<T, P>);
+        route.get("/leaderboard").to(leaderboard::<T, P>);
+        route.get("/health").to(health::<T, P>);
+        route.get("/healthz").to(healthz::<T, P>);
+        route.get("/metrics").to(metrics::<T, P>);
+        route.get("/readyz").to(readyz::<T, P>);
+        route.get("/spec").to(spec::<T, P>);
+        route.get("/openapi.json").to(openapi::<T, P>);
+        route.get("/spaces").to(spaces::<T, P>);
+        route.get("/info").to(info::<T, P>);
+        route
+            .get("/policy/load")
+            .with_query_string_extractor::<LoadPolicyQueryString>()
+            .to(load_policy::<T, P>);
+        route.get("/episodes").to(episodes::<T, P>);
+        route.get("/curriculum").to(curriculum::<T, P>);
+        route.put("/curriculum").to(put_curriculum::<T, P>);
+        route.get("/lidar").to(lidar::<T, P>);
+        route
+            .get("/vec/step")
+            .with_query_string_extractor::<StepQueryString>()
+            .to(vec_step::<T, P>);
+        route
+            .get("/vec/reset")
+            .with_query_string_extractor::<ResetQueryString>()
+            .to(vec_reset::<T, P>);
+        route
+            .get("/debug/contact_sheet")
+            .with_query_string_extractor::<ContactSheetQueryString>()
+            .to(contact_sheet::<T, P>);
+        route.get("/debug/obs_stats").to(obs_stats::<T, P>);
+        route
+            .get("/custom/:name")
+            .with_path_extractor::<CustomEndpointPathExtractor>()
+            .to(custom_endpoint::<T, P>);
+
+        // Router answers `405 Method Not Allowed` to methods without a route, so
+        // preflight requests need their own routes to reach `CorsMiddleware`
+        if is_cors_enabled {
+            for path in PREFLIGHT_PATHS {
+                route.options(path).to(preflight);
+            }
+        }
     })
 }
 
-/// Return rendered visual observations as a single PNG image
-fn visual_observations<
-    T: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe,
+/// Routes browsers may send preflight requests to, same as in `router`.
+/// Shared memory is left out, it's only readable on the same host
+const PREFLIGHT_PATHS: &[&str] = &[
+    "/status",
+    "/visual_observations",
+    "/visual_observations/raw",
+    "/visual_observations/:agent_id:[0-9]+",
+    "/logging_observations",
+    "/stacked_observations",
+    "/depth_observations",
+    "/step",
+    "/reset",
+    "/reset_agents",
+    "/save_state",
+    "/load_state",
+    "/set_state",
+    "/pause",
+    "/resume",
+    "/advance",
+    "/state",
+    "/state/bin",
+    "/stream",
+    "/leaderboard",
+    "/health",
+    "/healthz",
+    "/metrics",
+    "/readyz",
+    "/spec",
+    "/openapi.json",
+    "/spaces",
+    "/info",
+    "/policy/load",
+    "/curriculum",
+    "/episodes",
+    "/lidar",
+    "/vec/step",
+    "/vec/reset",
+    "/debug/contact_sheet",
+    "/debug/obs_stats",
+    "/custom/:name",
+];
+
+/// Preflight request from a disallowed origin, `CorsMiddleware` answers allowed ones
+fn preflight(state: State) -> (State, Response<Body>) {
+    let response = create_empty_response(&state, StatusCode::NO_CONTENT);
+    (state, response)
+}
+
+/// Bind the first port of `ports` on 127.0.0.1 which is available, synchronously so that
+/// startup fails loudly if all of them are taken. The listener is handed to the server as is,
+/// another process can't take the port in between
+pub(crate) fn bind_listener(ports: std::ops::Range<u16>) -> std::io::Result<std::net::TcpListener> {
+    let mut last_error = std::io::Error::new(std::io::ErrorKind::InvalidInput, "empty port range");
+    for port in ports {
+        match std::net::TcpListener::bind(("127.0.0.1", port)) {
+            Ok(listener) => {
+                listener.set_nonblocking(true)?;
+                return Ok(listener);
+            }
+            Err(e) => {
+                bevy::log::warn!("bevy_rl: port {port} is not available: {e}");
+                last_error = e;
+            }
+        }
+    }
+    Err(last_error)
+}
+
+/// Serve REST API on a bound listener in a separate thread with its own async runtime,
+/// over TLS if `tls_config` is given
+pub(crate) fn serve(
+    listener: std::net::TcpListener,
+    router: Router,
+    #[cfg(feature = "tls")] tls_config: Option<rustls::ServerConfig>,
+) {
+    std::thread::spawn(move || {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+
+        runtime.block_on(async move {
+            let listener = match tokio::net::TcpListener::from_std(listener) {
+                Ok(listener) => listener,
+                Err(e) => {
+                    bevy::log::error!("bevy_rl: REST API stopped: {e}");
+                    return;
+                }
+            };
+            #[cfg(feature = "tls")]
+            if let Some(tls_config) = tls_config {
+                let acceptor = tokio_rustls::TlsAcceptor::from(Arc::new(tls_config));
+                return gotham::bind_server(listener, router, move |socket| {
+                    acceptor.accept(socket).map_err(|e| {
+                        bevy::log::debug!("bevy_rl: TLS handshake failed: {e}");
+                    })
+                })
+                .await;
+            }
+            gotham::bind_server(listener, router, |socket| futures::future::ok(socket)).await
+        });
+    });
+}
+
+/// Page of the built-in dashboard, it polls `/status` and `/visual_observations`
+const DASHBOARD: &str = include_str!("dashboard.html");
+
+/// Serve the built-in dashboard for monitoring the environment from a browser
+fn dashboard(state: State) -> (State, Response<Body>) {
+    let response = create_response(&state, StatusCode::OK, mime::TEXT_HTML_UTF_8, DASHBOARD);
+    (state, response)
+}
+
+/// `status` API endpoint: state of the simulation loop and of every learning agent, for the dashboard
+fn status<
+    T: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::de::DeserializeOwned,
     P: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::Serialize,
 >(
     state: State,
+) -> (State, String) {
+    let state_: &GothamState<T, P> = GothamState::borrow_from(&state);
+    let status = {
+        let ai_gym_state = state_.inner.lock().unwrap();
+        let agents: Vec<serde_json::Value> = state_
+            .settings
+            .learning_agents()
+            .into_iter()
+            .map(|agent_index| {
+                let episode_stats = &ai_gym_state.episode_stats[agent_index];
+                json!({
+                    "agent": agent_index,
+                    "reward": ai_gym_state.rewards[agent_index],
+                    "terminated": ai_gym_state.terminations[agent_index],
+                    "truncated": ai_gym_state.truncations[agent_index],
+                    "episode_return": episode_stats.episode_return,
+                    "episodes": episode_stats.episodes,
+                    "mean_return": episode_stats.mean_return,
+                })
+            })
+            .collect();
+
+        json!({
+            "env_name": state_.settings.env_name,
+            "simulation_state": ai_gym_state
+                .simulation_state
+                .as_ref()
+                .map(|simulation_state| format!("{simulation_state:?}")),
+            "paused": ai_gym_state.debug_paused,
+            "frames": ai_gym_state.frames,
+            "episode_steps": ai_gym_state.episode_steps,
+            "trainer_connected": ai_gym_state
+                .last_request_at
+                .is_some_and(|t| t.elapsed() < crate::overlay::CONNECTION_TIMEOUT),
+            "agents": agents,
+        })
+    };
+
+    (state, status.to_string())
+}
+
+/// Return rendered visual observations side by side as a single image, PNG unless
+/// another `format` is requested
+fn visual_observations<
+    T: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::de::DeserializeOwned,
+    P: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::Serialize,
+>(
+    mut state: State,
 ) -> (State, Response<Body>) {
-    let screens: Vec<image::RgbaImage>;
-    let settings: AIGymSettings;
-    {
-        let state_: &GothamState<T, P> = GothamState::borrow_from(&state);
-        let state__ = state_.inner.lock().unwrap();
-        screens = state__.visual_observations.clone();
-        settings = state_.settings.clone();
+    let query_param = ImageFormatQueryString::take_from(&mut state);
+    let state_: &GothamState<T, P> = GothamState::borrow_from(&state);
+    let observations = state_.inner.lock().unwrap().observations.clone();
+    if let Some(response) = unavailable_response(&state, &observations) {
+        return (state, response);
     }
 
-    let mut bytes: Vec<u8> = Vec::new();
-    let mut all_agents_image =
-        image::RgbaImage::new(settings.width * settings.num_agents, settings.height);
+    let mut response = match query_param.encoding() {
+        Ok(ImageEncoding::Raw) => {
+            raw_frames_response(&state, &state_.without_npcs(observations.visual.clone()))
+        }
+        Ok(encoding) => {
+            let (content_type, bytes) = encoded_tiles(state_, &observations, encoding);
+            create_response::<Vec<u8>>(&state, StatusCode::OK, content_type, bytes)
+        }
+        Err(e) => e.response(&state),
+    };
+    set_observation_step(&mut response, &observations);
 
-    for (agent_index, screen) in screens.iter().enumerate() {
-        let image = screen.clone();
+    (state, response)
+}
 
-        image::imageops::overlay(
-            &mut all_agents_image,
-            &image,
-            ((agent_index as u32) * settings.width) as i64,
-            0,
-        );
+/// Return visual observations of all agents as concatenated raw pixels, without encoding.
+/// Resolution, number of agents, channels and `uint8`/`uint16` dtype are given in headers
+fn raw_visual_observations<
+    T: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::de::DeserializeOwned,
+    P: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::Serialize,
+>(
+    state: State,
+) -> (State, Response<Body>) {
+    let state_: &GothamState<T, P> = GothamState::borrow_from(&state);
+    let observations = state_.inner.lock().unwrap().observations.clone();
+    if let Some(response) = unavailable_response(&state, &observations) {
+        return (state, response);
     }
 
-    all_agents_image
-        .write_to(&mut Cursor::new(&mut bytes), image::ImageFormat::Png)
-        .unwrap();
+    let mut response =
+        raw_frames_response(&state, &state_.without_npcs(observations.visual.clone()));
+    set_observation_step(&mut response, &observations);
+    (state, response)
+}
 
-    let response = create_response::<Vec<u8>>(&state, StatusCode::OK, mime::IMAGE_PNG, bytes);
+/// Return the shared-memory region visual observations are written to and the last written step,
+/// trainers on the same host read frames from there without serialization
+#[cfg(feature = "shared-memory")]
+fn shared_visual_observations<
+    T: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::de::DeserializeOwned,
+    P: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::Serialize,
+>(
+    state: State,
+) -> (State, Response<Body>) {
+    let state_: &GothamState<T, P> = GothamState::borrow_from(&state);
+    let step = state_.inner.lock().unwrap().shared_memory_step;
 
+    let response = match &state_.settings.shared_memory {
+        Some(name) => create_response(
+            &state,
+            StatusCode::OK,
+            mime::APPLICATION_JSON,
+            json!({"name": name, "path": crate::shm::region_path(name), "step": step}).to_string(),
+        ),
+        None => ApiError::NotFound(
+            "Shared memory is not enabled, set AIGymSettings.shared_memory".to_string(),
+        )
+        .response(&state),
+    };
     (state, response)
 }
 
-/// Describe the query string for the step request
+/// Visual observations of learning agents tiled side by side and encoded, once per step
+fn encoded_tiles<
+    T: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::de::DeserializeOwned,
+    P: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::Serialize,
+>(
+    gotham_state: &GothamState<T, P>,
+    observations: &state::StepObservations,
+    encoding: ImageEncoding,
+) -> (mime::Mime, Vec<u8>) {
+    observations.encoded(&format!("tiled {encoding:?}"), |observations| {
+        let screens = gotham_state.without_npcs(observations.visual.clone());
+        let (width, height) = gotham_state.settings.observation_size();
+        let image = tile_observations(&screens, width, height, screens.len() as u32);
+        encode_image(&image, encoding)
+    })
+}
+
+/// Observations of the step couldn't be read back from GPU, the error is reported at `/health`
+fn unavailable_response(
+    state: &State,
+    observations: &state::StepObservations,
+) -> Option<Response<Body>> {
+    observations.unavailable.then(|| {
+        let mut response = ApiError::Unavailable(
+            "Observations of this step are unavailable, GPU readback failed".to_string(),
+        )
+        .response(state);
+        set_observation_step(&mut response, observations);
+        response
+    })
+}
+
+/// Tell which control step observations of the response were captured at
+fn set_observation_step(response: &mut Response<Body>, observations: &state::StepObservations) {
+    response
+        .headers_mut()
+        .insert("x-observation-step", observations.step.into());
+}
+
+/// Concatenated raw pixels of frames of the same size and color type,
+/// with `x-num-agents`, `x-height`, `x-width`, `x-channels` and `x-dtype` headers
+fn raw_frames_response(state: &State, frames: &[image::DynamicImage]) -> Response<Body> {
+    let (width, height, color) = frames
+        .first()
+        .map_or((0, 0, image::ColorType::Rgba8), |frame| {
+            (frame.width(), frame.height(), frame.color())
+        });
+    let dtype = if color.bytes_per_pixel() / color.channel_count() == 2 {
+        "uint16"
+    } else {
+        "uint8"
+    };
+    let bytes: Vec<u8> = frames
+        .iter()
+        .flat_map(|frame| frame.as_bytes())
+        .copied()
+        .collect();
+
+    let mut response =
+        create_response::<Vec<u8>>(state, StatusCode::OK, mime::APPLICATION_OCTET_STREAM, bytes);
+    for (name, value) in [
+        ("x-width", width.to_string()),
+        ("x-height", height.to_string()),
+        ("x-num-agents", frames.len().to_string()),
+        ("x-channels", color.channel_count().to_string()),
+        ("x-dtype", dtype.to_string()),
+    ] {
+        response.headers_mut().insert(name, value.parse().unwrap());
+    }
+    response
+}
+
+/// Describe the path of the per-agent observation request
 #[derive(Deserialize, StateData, StaticResponseExtender)]
-struct StepQueryString {
-    payload: String,
+struct AgentPathExtractor {
+    agent_id: usize,
 }
 
-/// `step` API endpoint to take an action and return the next `AgentState`
-fn step<
-    T: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe,
+/// Describe the query string of visual observation requests: `format` is `png` (default),
+/// `jpeg`, `webp` (lossless) or `raw`, `quality` of JPEG is 1-100
+#[derive(Deserialize, StateData, StaticResponseExtender)]
+struct ImageFormatQueryString {
+    format: Option<String>,
+    quality: Option<u8>,
+}
+
+/// How images of visual observations are encoded in responses
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum ImageEncoding {
+    Png,
+    Jpeg { quality: u8 },
+    WebP,
+    Raw,
+}
+
+impl ImageFormatQueryString {
+    fn encoding(&self) -> Result<ImageEncoding, ApiError> {
+        match self.format.as_deref().unwrap_or("png") {
+            "png" => Ok(ImageEncoding::Png),
+            "jpeg" | "jpg" => Ok(ImageEncoding::Jpeg {
+                quality: self.quality.unwrap_or(80).clamp(1, 100),
+            }),
+            "webp" => Ok(ImageEncoding::WebP),
+            "raw" => Ok(ImageEncoding::Raw),
+            format => Err(ApiError::BadRequest(format!(
+                "Unknown format {format}, expected png, jpeg, webp or raw"
+            ))),
+        }
+    }
+}
+
+/// Return the visual observation of one agent, so distributed workers fetch only their own frame.
+/// `agent_id` counts learning agents like results of other endpoints, NPCs aren't served
+fn agent_visual_observation<
+    T: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::de::DeserializeOwned,
     P: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::Serialize,
 >(
     mut state: State,
-) -> (State, String) {
-    let query_param = StepQueryString::take_from(&mut state);
+) -> (State, Response<Body>) {
+    let path = AgentPathExtractor::take_from(&mut state);
+    let query_param = ImageFormatQueryString::take_from(&mut state);
 
-    let err = serde_json::from_str::<Vec<AgentAction>>(&query_param.payload).err();
-    if let Some(message) = err {
-        return (state, message.to_string());
+    let state_ = GothamState::<T, P>::borrow_from(&state);
+    let Some(agent_index) = state_
+        .settings
+        .learning_agents()
+        .get(path.agent_id)
+        .copied()
+    else {
+        return error_response(state, ApiError::NotFound("No such agent".to_string()));
+    };
+    let observations = state_.inner.lock().unwrap().observations.clone();
+    if let Some(response) = unavailable_response(&state, &observations) {
+        return (state, response);
     }
-    let agent_actions: Vec<AgentAction> = serde_json::from_str(&query_param.payload).unwrap();
+    let Some(frame) = observations.visual.get(agent_index) else {
+        return error_response(state, ApiError::NotFound("No such agent".to_string()));
+    };
 
-    let state_: &GothamState<T, P> = GothamState::borrow_from(&state);
-    let step_request_tx: Sender<Vec<Option<String>>>;
-    let setp_result_rx: Receiver<Vec<bool>>;
+    let mut response = match query_param.encoding() {
+        Ok(ImageEncoding::Raw) => raw_frames_response(&state, &[frame.clone()]),
+        Ok(encoding) => {
+            let (content_type, bytes) = observations
+                .encoded(&format!("agent {} {encoding:?}", path.agent_id), |_| {
+                    encode_image(frame, encoding)
+                });
+            create_response::<Vec<u8>>(&state, StatusCode::OK, content_type, bytes)
+        }
+        Err(e) => e.response(&state),
+    };
+    set_observation_step(&mut response, &observations);
 
-    if agent_actions.len() != state_.settings.num_agents as usize {
-        return (state, "Invalid number of actions".to_string());
-    }
+    (state, response)
+}
 
-    {
-        let ai_gym_state = state_.inner.lock().unwrap();
-        step_request_tx = ai_gym_state.step_request_tx.clone();
-        setp_result_rx = ai_gym_state.step_result_rx.clone();
-    }
+/// Response with an image encoded as requested, `Raw` is sent as PNG here
+fn image_response(
+    state: &State,
+    image: &image::DynamicImage,
+    encoding: ImageEncoding,
+) -> Response<Body> {
+    let (content_type, bytes) = encode_image(image, encoding);
+    create_response::<Vec<u8>>(state, StatusCode::OK, content_type, bytes)
+}
 
-    let actions = agent_actions
-        .iter()
-        .map(|agent_action| agent_action.action.clone())
-        .collect();
+/// Encode an image and tell its content type, `Raw` is encoded as PNG
+fn encode_image(image: &image::DynamicImage, encoding: ImageEncoding) -> (mime::Mime, Vec<u8>) {
+    let mut bytes: Vec<u8> = Vec::new();
+    let content_type = match encoding {
+        ImageEncoding::Png | ImageEncoding::Raw => {
+            image
+                .write_to(&mut Cursor::new(&mut bytes), image::ImageFormat::Png)
+                .unwrap();
+            mime::IMAGE_PNG
+        }
+        // JPEG has no alpha channel and 16-bit depth, frames are converted to RGB8
+        ImageEncoding::Jpeg { quality } => {
+            let encoder = image::codecs::jpeg::JpegEncoder::new_with_quality(&mut bytes, quality);
+            match image {
+                image::DynamicImage::ImageLuma8(_) => image.write_with_encoder(encoder),
+                _ => image::DynamicImage::ImageRgb8(image.to_rgb8()).write_with_encoder(encoder),
+            }
+            .unwrap();
+            mime::IMAGE_JPEG
+        }
+        // The encoder is lossless and 8-bit only
+        ImageEncoding::WebP => {
+            let encoder = image::codecs::webp::WebPEncoder::new_lossless(&mut bytes);
+            match image {
+                image::DynamicImage::ImageLuma8(_) | image::DynamicImage::ImageRgb8(_) => {
+                    image.write_with_encoder(encoder)
+                }
+                _ => image::DynamicImage::ImageRgba8(image.to_rgba8()).write_with_encoder(encoder),
+            }
+            .unwrap();
+            "image/webp".parse().unwrap()
+        }
+    };
 
-    step_request_tx.send(actions).unwrap();
-    setp_result_rx.recv().unwrap();
+    (content_type, bytes)
+}
 
-    let mut agent_states: Vec<AgentState> = Vec::new();
-    {
-        let ai_gym_state = state_.inner.lock().unwrap();
-        for i in 0..ai_gym_state.rewards.len() {
-            agent_states.push(AgentState {
-                reward: ai_gym_state.rewards[i],
-                is_terminated: ai_gym_state.terminations[i],
-            });
+/// Describe the query string of the stream request
+#[derive(Deserialize, StateData, StaticResponseExtender)]
+struct StreamQueryString {
+    fps: Option<f32>,
+    quality: Option<u8>,
+}
+
+/// Serve the tiled view of all agents as an endless MJPEG stream (`multipart/x-mixed-replace`),
+/// which browsers play in an `<img>` tag. Every client gets a task on the API runtime sending
+/// frames until it disconnects
+fn stream<
+    T: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::de::DeserializeOwned,
+    P: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::Serialize,
+>(
+    mut state: State,
+) -> (State, Response<Body>) {
+    let query_param = StreamQueryString::take_from(&mut state);
+    let interval =
+        std::time::Duration::from_secs_f32(1.0 / query_param.fps.unwrap_or(10.0).clamp(0.1, 60.0));
+    let encoding = ImageEncoding::Jpeg {
+        quality: query_param.quality.unwrap_or(80).clamp(1, 100),
+    };
+
+    let gotham_state = GothamState::<T, P>::borrow_from(&state).clone();
+    let (mut sender, body) = Body::channel();
+    tokio::spawn(async move {
+        let mut ticks = tokio::time::interval(interval);
+        ticks.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            ticks.tick().await;
+            // Fails once the client is gone, also while no frames are available
+            if futures::future::poll_fn(|cx| sender.poll_ready(cx))
+                .await
+                .is_err()
+            {
+                break;
+            }
+
+            // Encoding is CPU-bound, keep it off the async workers
+            let gotham_state = gotham_state.clone();
+            let jpeg = tokio::task::spawn_blocking(move || {
+                let observations = {
+                    let ai_gym_state = gotham_state.inner.lock().unwrap();
+                    if ai_gym_state.observations.unavailable {
+                        return None;
+                    }
+                    ai_gym_state.observations.clone()
+                };
+                Some(encoded_tiles(&gotham_state, &observations, encoding).1)
+            })
+            .await;
+            let jpeg = match jpeg {
+                Ok(Some(jpeg)) => jpeg,
+                Ok(None) => continue,
+                Err(_) => break,
+            };
+
+            let mut part = format!(
+                "--{STREAM_BOUNDARY}\r\nContent-Type: image/jpeg\r\nContent-Length: {}\r\n\r\n",
+                jpeg.len()
+            )
+            .into_bytes();
+            part.extend(jpeg);
+            part.extend(b"\r\n");
+
+            if sender.send_data(part.into()).await.is_err() {
+                break;
+            }
         }
+    });
+
+    let response = Response::builder()
+        .status(StatusCode::OK)
+        .header(
+            hyper::header::CONTENT_TYPE,
+            format!("multipart/x-mixed-replace; boundary={STREAM_BOUNDARY}"),
+        )
+        .header(hyper::header::CACHE_CONTROL, "no-cache")
+        .body(body)
+        .unwrap();
+
+    (state, response)
+}
+
+/// Separates frames of `/stream`
+const STREAM_BOUNDARY: &str = "bevy_rl_frame";
+
+/// Return the latest full-resolution logging frames as a single image, PNG unless
+/// another `format` is requested
+fn logging_observations<
+    T: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::de::DeserializeOwned,
+    P: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::Serialize,
+>(
+    mut state: State,
+) -> (State, Response<Body>) {
+    let query_param = ImageFormatQueryString::take_from(&mut state);
+    let screens: Vec<image::DynamicImage>;
+    let settings: AIGymSettings;
+    {
+        let state_: &GothamState<T, P> = GothamState::borrow_from(&state);
+        let state__ = state_.inner.lock().unwrap();
+        screens = state__
+            .logging_observations
+            .iter()
+            .cloned()
+            .map(image::DynamicImage::ImageRgba8)
+            .collect();
+        settings = state_.settings.clone();
     }
 
-    (state, json!(agent_states).to_string())
+    let response = match query_param.encoding() {
+        Ok(ImageEncoding::Raw) => raw_frames_response(&state, &screens),
+        Ok(encoding) => {
+            let image = tile_observations(
+                &screens,
+                settings.width,
+                settings.height,
+                settings.total_agents(),
+            );
+            image_response(&state, &image, encoding)
+        }
+        Err(e) => e.response(&state),
+    };
+
+    (state, response)
 }
 
-/// `reset` API endpoint to reset the environment
-fn reset<
-    T: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe,
+/// Return stacked visual observations of all agents as a raw tensor of
+/// `[agents, frames, height, width, channels]` shape, given in `x-shape` header.
+/// Values are `u8` (`u16` for float texture formats), or `f32` after `Normalize`, see `x-dtype` header
+fn stacked_observations<
+    T: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::de::DeserializeOwned,
     P: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::Serialize,
 >(
     state: State,
-) -> (State, String) {
-    let reset_request_channel_tx: Sender<bool>;
-    let reset_result_channel_rx: Receiver<bool>;
+) -> (State, Response<Body>) {
+    let stacks: Vec<Vec<image::DynamicImage>>;
+    let settings: AIGymSettings;
     {
         let state_: &GothamState<T, P> = GothamState::borrow_from(&state);
-        let ai_gym_state = state_.inner.lock().unwrap();
-        reset_request_channel_tx = ai_gym_state.reset_request_tx.clone();
-        reset_result_channel_rx = ai_gym_state.reset_result_rx.clone();
+        let state__ = state_.inner.lock().unwrap();
+        stacks = state_.without_npcs(
+            (0..state__.visual_observations.len())
+                .map(|agent_index| state__.stacked_observations(agent_index))
+                .collect(),
+        );
+        settings = state_.settings.clone();
     }
 
-    reset_request_channel_tx.send(true).unwrap();
-    reset_result_channel_rx.recv().unwrap();
+    let pipeline = preprocess::pipeline(&settings);
+    let (width, height) = settings.observation_size();
+    let channels = stacks
+        .first()
+        .and_then(|stack| stack.first())
+        .map(|frame| frame.color().channel_count())
+        .unwrap_or(4);
+    let is_16_bit = stacks
+        .first()
+        .and_then(|stack| stack.first())
+        .is_some_and(|frame| frame.color().bytes_per_pixel() / frame.color().channel_count() == 2);
+    let mut bytes: Vec<u8> = stacks
+        .iter()
+        .flatten()
+        .flat_map(|frame| frame.as_bytes().to_vec())
+        .collect();
+
+    let dtype = if let Some((mean, std)) = preprocess::normalization(&pipeline) {
+        let values: Vec<f32> = if is_16_bit {
+            bytes
+                .chunks_exact(2)
+                .map(|value| u16::from_ne_bytes([value[0], value[1]]) as f32 / u16::MAX as f32)
+                .collect()
+        } else {
+            bytes
+                .iter()
+                .map(|&value| value as f32 / u8::MAX as f32)
+                .collect()
+        };
+        bytes = values
+            .into_iter()
+            .flat_map(|value| ((value - mean) / std).to_ne_bytes())
+            .collect();
+        "float32"
+    } else if is_16_bit {
+        "uint16"
+    } else {
+        "uint8"
+    };
+
+    let mut response = create_response::<Vec<u8>>(
+        &state,
+        StatusCode::OK,
+        mime::APPLICATION_OCTET_STREAM,
+        bytes,
+    );
+    let shape = format!(
+        "{},{},{},{},{}",
+        stacks.len(),
+        preprocess::stack_frames(&pipeline),
+        height,
+        width,
+        channels
+    );
+    response
+        .headers_mut()
+        .insert("x-shape", shape.parse().unwrap());
+    response
+        .headers_mut()
+        .insert("x-dtype", dtype.parse().unwrap());
 
+    (state, response)
+}
+
+/// Return agents' depth maps side by side as a single 16-bit grayscale PNG image
+fn depth_observations<
+    T: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::de::DeserializeOwned,
+    P: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::Serialize,
+>(
+    state: State,
+) -> (State, Response<Body>) {
     let state_: &GothamState<T, P> = GothamState::borrow_from(&state);
-    let mut agent_states: Vec<AgentState> = Vec::new();
-    {
-        let ai_gym_state = state_.inner.lock().unwrap();
-        for i in 0..ai_gym_state.rewards.len() {
-            agent_states.push(AgentState {
-                reward: ai_gym_state.rewards[i],
-                is_terminated: ai_gym_state.terminations[i],
-            });
+    let observations = state_.inner.lock().unwrap().observations.clone();
+    if let Some(response) = unavailable_response(&state, &observations) {
+        return (state, response);
+    }
+    let (content_type, bytes) = observations.encoded("depth", |observations| {
+        (
+            mime::IMAGE_PNG,
+            encode_depth_maps(
+                &state_.without_npcs(observations.depth.clone()),
+                &state_.settings,
+            ),
+        )
+    });
+
+    let mut response = create_response::<Vec<u8>>(&state, StatusCode::OK, content_type, bytes);
+    set_observation_step(&mut response, &observations);
+    (state, response)
+}
+
+/// Depth maps side by side as a 16-bit grayscale PNG image
+fn encode_depth_maps(
+    depth_maps: &[crate::render::DepthImage],
+    settings: &AIGymSettings,
+) -> Vec<u8> {
+    let mut all_agents_image = image::ImageBuffer::<image::Luma<u16>, Vec<u16>>::new(
+        settings.width * depth_maps.len() as u32,
+        settings.height,
+    );
+    for (agent_index, depth_map) in depth_maps.iter().enumerate() {
+        for (x, y, depth) in depth_map.enumerate_pixels() {
+            let value = (depth.0[0].clamp(0.0, 1.0) * u16::MAX as f32) as u16;
+            all_agents_image.put_pixel(
+                agent_index as u32 * settings.width + x,
+                y,
+                image::Luma([value]),
+            );
         }
     }
 
-    (state, json!(agent_states).to_string())
+    let mut bytes: Vec<u8> = Vec::new();
+    all_agents_image
+        .write_to(&mut Cursor::new(&mut bytes), image::ImageFormat::Png)
+        .unwrap();
+    bytes
+}
+
+/// Describe the query string for the contact sheet request
+#[derive(Deserialize, StateData, StaticResponseExtender)]
+struct ContactSheetQueryString {
+    agent: usize,
+    last: Option<usize>,
 }
 
-/// `env_state` API endpoint to get the environment state
-fn env_state<
-    T: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe,
+/// Return the last policy frames of an agent tiled in a grid as a single PNG image,
+/// oldest frame first. Frames are kept only when `AIGymSettings.frame_history` is set.
+/// `agent` counts learning agents like `/visual_observations/{agent_id}`
+fn contact_sheet<
+    T: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::de::DeserializeOwned,
     P: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::Serialize,
 >(
-    state: State,
-) -> (State, String) {
-    let state_: &GothamState<T, P> = GothamState::borrow_from(&state);
-    let env_state = state_.inner.lock().unwrap().environment_state.clone();
+    mut state: State,
+) -> (State, Response<Body>) {
+    let query_param = ContactSheetQueryString::take_from(&mut state);
+
+    let frames: Vec<image::DynamicImage>;
+    let settings: AIGymSettings;
+    {
+        let state_: &GothamState<T, P> = GothamState::borrow_from(&state);
+        let state__ = state_.inner.lock().unwrap();
+        settings = state_.settings.clone();
+
+        let history = state_
+            .settings
+            .learning_agents()
+            .get(query_param.agent)
+            .and_then(|&agent_index| state__.frame_history.get(agent_index));
+        let Some(history) = history else {
+            drop(state__);
+            return error_response(
+                state,
+                ApiError::BadRequest("Invalid agent index".to_string()),
+            );
+        };
+
+        let last = query_param.last.unwrap_or(history.len()).min(history.len());
+        frames = history.iter().skip(history.len() - last).cloned().collect();
+    }
+
+    let (width, height) = settings.observation_size();
+    let bytes = tile_grid(&frames, width, height);
+    let response = create_response::<Vec<u8>>(&state, StatusCode::OK, mime::IMAGE_PNG, bytes);
+
+    (state, response)
+}
+
+/// Put frames in a square-ish grid row by row and encode them as PNG
+pub(crate) fn tile_grid(frames: &[image::DynamicImage], width: u32, height: u32) -> Vec<u8> {
+    let columns = (frames.len() as f32).sqrt().ceil().max(1.0) as u32;
+    let rows = (frames.len() as u32).div_ceil(columns).max(1);
+
+    let positions = (0..frames.len() as u32).map(|frame_index| {
+        (
+            (frame_index % columns) * width,
+            (frame_index / columns) * height,
+        )
+    });
+
+    let mut bytes: Vec<u8> = Vec::new();
+    tile_images(frames, positions, width * columns, height * rows)
+        .write_to(&mut Cursor::new(&mut bytes), image::ImageFormat::Png)
+        .unwrap();
+
+    bytes
+}
+
+/// Put agents' frames side by side
+fn tile_observations(
+    screens: &[image::DynamicImage],
+    width: u32,
+    height: u32,
+    num_agents: u32,
+) -> image::DynamicImage {
+    let positions = (0..screens.len() as u32).map(|agent_index| (agent_index * width, 0));
+
+    tile_images(screens, positions, width * num_agents, height)
+}
+
+/// Copy images to given positions of a canvas.
+/// The canvas keeps color type of the images, so grayscale observations stay grayscale
+fn tile_images(
+    images: &[image::DynamicImage],
+    positions: impl Iterator<Item = (u32, u32)>,
+    width: u32,
+    height: u32,
+) -> image::DynamicImage {
+    let color = images
+        .first()
+        .map(|image| image.color())
+        .unwrap_or(image::ColorType::Rgba8);
+
+    let mut canvas = image::DynamicImage::new(width, height, color);
+
+    for (image, (x, y)) in images.iter().zip(positions) {
+        image::imageops::replace(&mut canvas, image, x as i64, y as i64);
+    }
+
+    canvas
+}
+
+/// Describe the query string for the step request
+#[derive(Deserialize, StateData, StaticResponseExtender)]
+struct StepQueryString {
+    payload: String,
+    format: Option<String>,
+}
+
+impl<
+        T: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::de::DeserializeOwned,
+        P: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::Serialize,
+    > GothamState<T, P>
+{
+    /// Send serialized actions to the engine and wait for agents' transitions.
+    /// Shared by HTTP and WebSocket transports
+    pub(crate) fn step(
+        &self,
+        client: &str,
+        agent_actions: Vec<AgentAction>,
+    ) -> Result<Vec<state::AgentTransition<P>>, ApiError> {
+        // Actions of agents driven by the trainer. Actions of all learning agents are accepted
+        // too, ones of local agents are replaced by their local actions then
+        let api_agents = self.settings.api_agents();
+        let learning_agents = self.settings.learning_agents();
+        let agent_indices = if agent_actions.len() == api_agents.len() {
+            api_agents
+        } else if agent_actions.len() == learning_agents.len() {
+            learning_agents
+        } else {
+            return Err(ApiError::BadRequest(
+                "Invalid number of actions".to_string(),
+            ));
+        };
+        self.check_actions(&agent_actions)?;
+
+        // NPCs get no actions from the trainer
+        let mut actions = vec![None; self.settings.total_agents() as usize];
+        for (&agent_index, agent_action) in agent_indices.iter().zip(agent_actions) {
+            actions[agent_index] = agent_action.action;
+        }
+
+        Ok(self.without_npcs(self.step_agents(client, actions)?))
+    }
+
+    /// Reject actions which don't deserialize into the environment's action type when
+    /// `AIGymSettings.reject_invalid_actions` is set
+    fn check_actions(&self, agent_actions: &[AgentAction]) -> Result<(), ApiError> {
+        if !self.settings.reject_invalid_actions {
+            return Ok(());
+        }
+        for (i, agent_action) in agent_actions.iter().enumerate() {
+            if let Some(action) = &agent_action.action {
+                if let Err(e) = serde_json::from_str::<T>(action) {
+                    return Err(ApiError::BadRequest(format!(
+                        "Invalid action of agent {i}: {e}"
+                    )));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Step with actions of all agents, NPCs included
+    fn step_agents(
+        &self,
+        client: &str,
+        actions: Vec<Option<String>>,
+    ) -> Result<Vec<state::AgentTransition<P>>, ApiError> {
+        {
+            let mut ai_gym_state = self.inner.lock().unwrap();
+            ai_gym_state.record_api_request(client);
+            if let Some(competition) = &self.settings.competition {
+                ai_gym_state
+                    .leaderboard
+                    .check_step(competition, client)
+                    .map_err(ApiError::Conflict)?;
+            }
+        }
+
+        let requested_at = std::time::Instant::now();
+        let transitions = self.request_step(actions)?;
+        let step_latency = requested_at.elapsed().as_secs_f64();
+        self.inner
+            .lock()
+            .unwrap()
+            .metrics
+            .lock()
+            .unwrap()
+            .step_latency
+            .observe(step_latency);
+
+        if self.settings.competition.is_some() {
+            // Score learning agents only, NPCs are driven by the environment
+            let rewards = self.without_npcs(transitions.iter().map(|t| t.reward).collect());
+            let step_reward = rewards.iter().sum::<f32>() / rewards.len().max(1) as f32;
+            let mut ai_gym_state = self.inner.lock().unwrap();
+            ai_gym_state.leaderboard.record_step(client, step_reward);
+        }
 
-    (state, json!(env_state).to_string())
+        Ok(transitions)
+    }
+
+    /// Ask the engine to reset the environment and wait until it's done
+    pub(crate) fn reset(
+        &self,
+        client: &str,
+        request: state::ResetRequest,
+    ) -> Result<Vec<AgentState>, ApiError> {
+        Ok(self.without_npcs(self.reset_all(client, request)?))
+    }
+
+    /// Reset and return states of all agents, NPCs included
+    fn reset_all(
+        &self,
+        client: &str,
+        mut request: state::ResetRequest,
+    ) -> Result<Vec<AgentState>, ApiError> {
+        if let Some(env_ids) = &request.env_ids {
+            if env_ids
+                .iter()
+                .any(|&env_id| env_id >= self.settings.env_count() as usize)
+            {
+                return Err(ApiError::BadRequest(
+                    "Invalid environment index".to_string(),
+                ));
+            }
+        }
+
+        {
+            let mut ai_gym_state = self.inner.lock().unwrap();
+            ai_gym_state.record_api_request(client);
+            if let Some(competition) = &self.settings.competition {
+                // Clients can't choose seeds in competition mode
+                request.seed = ai_gym_state
+                    .leaderboard
+                    .start_episode(competition, client)
+                    .map_err(ApiError::Conflict)?;
+            }
+        }
+
+        self.wait(self.inner.submit_reset(request), "reset")?;
+
+        Ok(self.agent_states())
+    }
+
+    /// Ask the engine to reset given agents and wait until it's done. Agents are counted
+    /// among learning agents like transitions, NPCs can't be reset by the trainer
+    pub(crate) fn reset_agents(
+        &self,
+        client: &str,
+        agent_indices: Vec<usize>,
+    ) -> Result<Vec<AgentState>, ApiError> {
+        self.check_not_competition("reset_agents")?;
+        let learning_agents = self.settings.learning_agents();
+        let agent_indices = agent_indices
+            .iter()
+            .map(|&i| learning_agents.get(i).copied())
+            .collect::<Option<Vec<usize>>>()
+            .ok_or_else(|| ApiError::BadRequest("Invalid agent index".to_string()))?;
+
+        self.inner.lock().unwrap().record_api_request(client);
+        self.wait(
+            self.inner.submit_reset_agents(agent_indices),
+            "reset_agents",
+        )?;
+
+        Ok(self.without_npcs(self.agent_states()))
+    }
+
+    /// Requests changing the environment outside of steps and resets would let clients skip
+    /// the step budget and episode limit, they're refused in competition mode
+    fn check_not_competition(&self, operation: &str) -> Result<(), ApiError> {
+        if self.settings.competition.is_some() {
+            return Err(ApiError::Conflict(format!(
+                "{operation} is disabled in competition mode"
+            )));
+        }
+
+        Ok(())
+    }
+
+    /// Ask the engine to snapshot the world, returns the snapshot id and number of entities
+    pub(crate) fn save_state(&self, client: &str) -> Result<(u64, usize), ApiError> {
+        self.inner.lock().unwrap().record_api_request(client);
+        self.wait(
+            self.inner.submit_snapshot(SnapshotRequest::Save),
+            "save_state",
+        )?
+        .map_err(snapshot_error)
+    }
+
+    /// Ask the engine to restore a snapshot and wait until it's done
+    pub(crate) fn load_state(&self, client: &str, id: u64) -> Result<Vec<AgentState>, ApiError> {
+        self.check_not_competition("load_state")?;
+        self.inner.lock().unwrap().record_api_request(client);
+        self.wait(
+            self.inner.submit_snapshot(SnapshotRequest::Load(id)),
+            "load_state",
+        )?
+        .map_err(snapshot_error)?;
+
+        Ok(self.without_npcs(self.agent_states()))
+    }
+
+    /// Ask the engine to pause (`true`) or resume the simulation for debugging
+    pub(crate) fn debug_pause(
+        &self,
+        client: &str,
+        pause: bool,
+    ) -> Result<serde_json::Value, ApiError> {
+        if pause {
+            self.check_not_competition("pause")?;
+        }
+        self.inner.lock().unwrap().record_api_request(client);
+        let simulation_state = self.wait(
+            self.inner.submit_debug_pause(pause),
+            if pause { "pause" } else { "resume" },
+        )?;
+
+        Ok(json!({
+            "paused": pause,
+            "simulation_state": format!("{simulation_state:?}"),
+        }))
+    }
+
+    /// Ask the engine to run frames without a control step and wait until they're done
+    pub(crate) fn advance(
+        &self,
+        client: &str,
+        frames: u32,
+        noop: bool,
+    ) -> Result<Vec<AgentState>, ApiError> {
+        self.check_not_competition("advance")?;
+        self.inner.lock().unwrap().record_api_request(client);
+        self.wait(
+            self.inner
+                .submit_advance(state::AdvanceRequest { frames, noop }),
+            "advance",
+        )?;
+
+        Ok(self.without_npcs(self.agent_states()))
+    }
+
+    /// Ask the engine to fire `EventSetState` and wait until it's handled
+    pub(crate) fn set_state(
+        &self,
+        client: &str,
+        payload: serde_json::Value,
+    ) -> Result<Vec<AgentState>, ApiError> {
+        self.check_not_competition("set_state")?;
+        {
+            let mut ai_gym_state = self.inner.lock().unwrap();
+            if !ai_gym_state.set_state_enabled {
+                return Err(ApiError::NotFound(
+                    "Setting state isn't supported, add SetStatePlugin".to_string(),
+                ));
+            }
+            ai_gym_state.record_api_request(client);
+        }
+
+        self.wait(self.inner.submit_set_state(payload), "set_state")?
+            .map_err(|e| ApiError::BadRequest(format!("Invalid state: {e}")))?;
+
+        Ok(self.without_npcs(self.agent_states()))
+    }
+
+    /// Pass actions to the engine, batched with concurrent calls if `step_batch_window` is set
+    fn request_step(
+        &self,
+        actions: Vec<Option<String>>,
+    ) -> Result<Vec<state::AgentTransition<P>>, ApiError> {
+        let Some(window) = self.settings.step_batch_window else {
+            let mut transitions = self.wait(self.inner.submit_step_batch(vec![actions]), "step")?;
+            return transitions
+                .pop()
+                .ok_or_else(|| ApiError::Internal("Engine returned no transitions".to_string()));
+        };
+
+        let (result_tx, result_rx) = bounded(1);
+        let is_first = {
+            let mut pending = self.step_batcher.pending.lock().unwrap();
+            pending.push((actions, result_tx));
+            pending.len() == 1
+        };
+
+        if is_first {
+            std::thread::sleep(std::time::Duration::from_secs_f32(window));
+
+            // Previous batch may still be in the engine, calls arriving meanwhile join this one
+            let _engine = self.step_batcher.engine.lock().unwrap();
+            let (batch, result_txs): (Vec<_>, Vec<_>) =
+                std::mem::take(&mut *self.step_batcher.pending.lock().unwrap())
+                    .into_iter()
+                    .unzip();
+
+            let batch_size = batch.len();
+            let result = self
+                .wait(self.inner.submit_step_batch(batch), "step")
+                .and_then(|batch_transitions| {
+                    if batch_transitions.len() == batch_size {
+                        Ok(batch_transitions)
+                    } else {
+                        Err(ApiError::Internal(format!(
+                            "Engine returned transitions of {} steps for a batch of {batch_size}",
+                            batch_transitions.len()
+                        )))
+                    }
+                });
+
+            // Callers that gave up have dropped their receivers
+            match result {
+                Ok(batch_transitions) => {
+                    for (result_tx, transitions) in result_txs.into_iter().zip(batch_transitions) {
+                        let _ = result_tx.send(Ok(transitions));
+                    }
+                }
+                Err(e) => {
+                    for result_tx in result_txs {
+                        let _ = result_tx.send(Err(e.clone()));
+                    }
+                }
+            }
+        }
+
+        result_rx
+            .recv()
+            .unwrap_or_else(|_| Err(ApiError::Unavailable("Step batch was dropped".to_string())))
+    }
+
+    /// Wait for the engine's reply to a `request` for up to `AIGymSettings.request_timeout`.
+    /// A request the engine hasn't taken by then is cancelled, so a retry isn't applied twice
+    fn wait<R>(&self, reply: state::PendingReply<R>, request: &str) -> Result<R, ApiError> {
+        let result = match self.settings.request_timeout {
+            Some(timeout) => reply.recv_timeout(std::time::Duration::from_secs_f32(timeout)),
+            None => reply.recv().map_err(|_| RecvTimeoutError::Disconnected),
+        };
+
+        result.map_err(|e| match e {
+            RecvTimeoutError::Timeout => {
+                let cancelled = reply.cancel();
+                let ai_gym_state = self.inner.lock().unwrap();
+                ApiError::Timeout {
+                    message: format!(
+                        "Engine didn't process {request} request in {} seconds",
+                        self.settings.request_timeout.unwrap_or_default()
+                    ),
+                    diagnostics: json!({
+                        "simulation_state": ai_gym_state
+                            .simulation_state
+                            .as_ref()
+                            .map(|simulation_state| format!("{simulation_state:?}")),
+                        "frames": ai_gym_state.frames,
+                        "seconds_since_frame": ai_gym_state.seconds_since_frame(),
+                        "cancelled": cancelled,
+                    }),
+                }
+            }
+            RecvTimeoutError::Disconnected => {
+                ApiError::Unavailable("Engine has stopped".to_string())
+            }
+        })
+    }
+
+    /// Step all environment copies at once, actions and transitions are grouped by copy
+    pub(crate) fn vec_step(
+        &self,
+        client: &str,
+        env_actions: Vec<Vec<AgentAction>>,
+    ) -> Result<Vec<Vec<state::AgentTransition<P>>>, ApiError> {
+        if env_actions.len() != self.settings.env_count() as usize
+            || env_actions
+                .iter()
+                .any(|actions| actions.len() != self.settings.num_agents as usize)
+        {
+            return Err(ApiError::BadRequest(
+                "Invalid number of actions".to_string(),
+            ));
+        }
+        for actions in env_actions.iter() {
+            self.check_actions(actions)?;
+        }
+
+        // Copies are addressed as a whole, NPCs included
+        let actions = env_actions
+            .into_iter()
+            .flatten()
+            .map(|agent_action| agent_action.action)
+            .collect();
+        let transitions = self.step_agents(client, actions)?;
+
+        Ok(self.group_by_env(transitions))
+    }
+
+    /// Reset given environment copies (all of them if not set), agents' states are grouped by copy
+    pub(crate) fn vec_reset(
+        &self,
+        client: &str,
+        request: state::ResetRequest,
+    ) -> Result<Vec<Vec<AgentState>>, ApiError> {
+        let agent_states = self.reset_all(client, request)?;
+
+        Ok(self.group_by_env(agent_states))
+    }
+
+    /// Split per-agent values into per-copy lists
+    fn group_by_env<V>(&self, values: Vec<V>) -> Vec<Vec<V>> {
+        let num_agents = self.settings.num_agents.max(1) as usize;
+        let mut values = values.into_iter();
+        (0..self.settings.env_count())
+            .map(|_| values.by_ref().take(num_agents).collect())
+            .collect()
+    }
+
+    /// Environment state encoded with the user codec if it's set, in `format` otherwise
+    pub(crate) fn encoded_env_state(&self, format: PayloadFormat) -> (mime::Mime, Vec<u8>) {
+        let ai_gym_state = self.inner.lock().unwrap();
+        let env_state = ai_gym_state.environment_state.as_ref();
+
+        match (&ai_gym_state.state_codec, env_state) {
+            (Some(codec), Some(env_state)) => (codec.content_type.clone(), codec.encode(env_state)),
+            _ => format.encode(&env_state),
+        }
+    }
+
+    /// Per-agent records as sent to the client, shaped by `response_fields`
+    pub(crate) fn payload<V: Serialize>(&self, records: &V) -> serde_json::Value {
+        self.settings.response_fields.shape(json!(records))
+    }
+
+    /// Drop values of `npc_agents`, the trainer only sees learning agents
+    pub(crate) fn without_npcs<V>(&self, values: Vec<V>) -> Vec<V> {
+        if self.settings.npc_agents.is_empty() {
+            return values;
+        }
+
+        values
+            .into_iter()
+            .enumerate()
+            .filter(|(agent_index, _)| !self.settings.npc_agents.contains(agent_index))
+            .map(|(_, value)| value)
+            .collect()
+    }
+
+    /// Current (reward, terminated, truncated) of every agent
+    fn agent_states(&self) -> Vec<AgentState> {
+        let mut agent_states: Vec<AgentState> = Vec::new();
+        {
+            let ai_gym_state = self.inner.lock().unwrap();
+            for i in 0..ai_gym_state.rewards.len() {
+                agent_states.push(AgentState {
+                    reward: ai_gym_state.rewards[i],
+                    is_terminated: ai_gym_state.terminations[i],
+                    is_truncated: ai_gym_state.truncations[i],
+                    info: ai_gym_state.infos[i].clone(),
+                    vector_observation: ai_gym_state.vector_observations[i].clone(),
+                });
+            }
+        }
+
+        agent_states
+    }
+}
+
+/// Identify the client by `X-Client-Id` header, falling back to its address. In competition
+/// mode the header isn't trusted, clients are identified by their registered key or address
+pub(crate) fn client_id(state: &State, settings: &AIGymSettings) -> String {
+    let headers = HeaderMap::borrow_from(state);
+    let header = |name: &str| headers.get(name).and_then(|value| value.to_str().ok());
+    let addr = client_addr(state).map(|addr| addr.ip());
+    if let Some(competition) = &settings.competition {
+        return competition.identify(header("x-client-key"), addr);
+    }
+
+    header("x-client-id")
+        .map(str::to_string)
+        .or_else(|| addr.map(|addr| addr.to_string()))
+        .unwrap_or_default()
+}
+
+/// `step` API endpoint to take an action and return the next `AgentTransition` for every agent
+fn step<
+    T: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::de::DeserializeOwned,
+    P: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::Serialize,
+>(
+    mut state: State,
+) -> (State, Response<Body>) {
+    let query_param = StepQueryString::take_from(&mut state);
+    let format = PayloadFormat::negotiate(&state, query_param.format.as_deref());
+
+    let agent_actions: Vec<AgentAction> = match serde_json::from_str(&query_param.payload) {
+        Ok(agent_actions) => agent_actions,
+        Err(e) => return error_response(state, ApiError::BadRequest(e.to_string())),
+    };
+
+    let state_: &GothamState<T, P> = GothamState::borrow_from(&state);
+    match state_.step(&client_id(&state, &state_.settings), agent_actions) {
+        Ok(transitions) => {
+            let response = format.response(&state, &state_.payload(&transitions));
+            (state, response)
+        }
+        Err(e) => error_response(state, e),
+    }
+}
+
+/// Describe the query string for the reset request, `options` is a JSON string.
+/// `envs` is a JSON list of environment copies to reset, it's read by `vec/reset` only
+#[derive(Deserialize, StateData, StaticResponseExtender)]
+struct ResetQueryString {
+    seed: Option<u64>,
+    options: Option<String>,
+    metadata: Option<String>,
+    format: Option<String>,
+    envs: Option<String>,
+}
+
+/// Parse seed, options and metadata of a reset request, shared by `reset` and `vec/reset`
+fn parse_reset_query(query_param: &ResetQueryString) -> Result<state::ResetRequest, ApiError> {
+    let options = query_param
+        .options
+        .as_deref()
+        .map(serde_json::from_str)
+        .transpose()
+        .map_err(|e| ApiError::BadRequest(format!("Invalid options: {e}")))?;
+    let metadata = query_param
+        .metadata
+        .as_deref()
+        .map(serde_json::from_str)
+        .transpose()
+        .map_err(|e| ApiError::BadRequest(format!("Invalid metadata: {e}")))?
+        .unwrap_or_default();
+
+    Ok(state::ResetRequest {
+        seed: query_param.seed,
+        options,
+        metadata,
+        ..Default::default()
+    })
+}
+
+/// `reset` API endpoint to reset the environment
+fn reset<
+    T: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::de::DeserializeOwned,
+    P: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::Serialize,
+>(
+    mut state: State,
+) -> (State, Response<Body>) {
+    let query_param = ResetQueryString::take_from(&mut state);
+    let format = PayloadFormat::negotiate(&state, query_param.format.as_deref());
+
+    let request = match parse_reset_query(&query_param) {
+        Ok(request) => request,
+        Err(e) => return error_response(state, e),
+    };
+
+    let state_: &GothamState<T, P> = GothamState::borrow_from(&state);
+    match state_.reset(&client_id(&state, &state_.settings), request) {
+        Ok(agent_states) => {
+            let response = format.response(&state, &state_.payload(&agent_states));
+            (state, response)
+        }
+        Err(e) => error_response(state, e),
+    }
+}
+
+/// Error of an API request, sent as `{"error": message, "code": code}` with a matching status,
+/// so clients can't mistake it for a result
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum ApiError {
+    /// Malformed or invalid parameters, `400 Bad Request`
+    BadRequest(String),
+    /// Request without a valid `AIGymSettings.api_token`, `401 Unauthorized`
+    Unauthorized(String),
+    /// Nothing to serve at the requested path, `404 Not Found`
+    NotFound(String),
+    /// The request conflicts with the state of the environment (competition limits), `409 Conflict`
+    Conflict(String),
+    /// The server failed to produce the result, `500 Internal Server Error`
+    Internal(String),
+    /// The result can't be served right now, `503 Service Unavailable`
+    Unavailable(String),
+    /// The engine didn't process the request within `AIGymSettings.request_timeout`,
+    /// `504 Gateway Timeout` with the state of the simulation loop
+    Timeout {
+        message: String,
+        diagnostics: serde_json::Value,
+    },
+}
+
+impl ApiError {
+    pub(crate) fn status(&self) -> StatusCode {
+        match self {
+            Self::BadRequest(_) => StatusCode::BAD_REQUEST,
+            Self::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            Self::NotFound(_) => StatusCode::NOT_FOUND,
+            Self::Conflict(_) => StatusCode::CONFLICT,
+            Self::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            Self::Unavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+            Self::Timeout { .. } => StatusCode::GATEWAY_TIMEOUT,
+        }
+    }
+
+    /// Machine-readable kind of the error
+    pub(crate) fn code(&self) -> &'static str {
+        match self {
+            Self::BadRequest(_) => "bad_request",
+            Self::Unauthorized(_) => "unauthorized",
+            Self::NotFound(_) => "not_found",
+            Self::Conflict(_) => "conflict",
+            Self::Internal(_) => "internal",
+            Self::Unavailable(_) => "unavailable",
+            Self::Timeout { .. } => "timeout",
+        }
+    }
+
+    pub(crate) fn message(&self) -> &str {
+        match self {
+            Self::BadRequest(message)
+            | Self::Unauthorized(message)
+            | Self::NotFound(message)
+            | Self::Conflict(message)
+            | Self::Internal(message)
+            | Self::Unavailable(message)
+            | Self::Timeout { message, .. } => message,
+        }
+    }
+
+    /// JSON body of the error, also sent over WebSocket
+    pub(crate) fn body(&self) -> serde_json::Value {
+        let mut body = json!({"error": self.message(), "code": self.code()});
+        if let Self::Timeout { diagnostics, .. } = self {
+            body["diagnostics"] = diagnostics.clone();
+        }
+        body
+    }
+
+    pub(crate) fn response(&self, state: &State) -> Response<Body> {
+        create_response(
+            state,
+            self.status(),
+            mime::APPLICATION_JSON,
+            self.body().to_string(),
+        )
+    }
+}
+
+impl std::fmt::Display for ApiError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.message())
+    }
+}
+
+fn error_response(state: State, error: ApiError) -> (State, Response<Body>) {
+    let response = error.response(&state);
+    (state, response)
+}
+
+/// Describe the query string of structured endpoints: `format` is `json` (default) or `msgpack`
+#[derive(Deserialize, StateData, StaticResponseExtender)]
+struct PayloadFormatQueryString {
+    format: Option<String>,
+}
+
+/// Wire format of `/state`, `/step` and `/reset` responses
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum PayloadFormat {
+    Json,
+    MsgPack,
+}
+
+impl PayloadFormat {
+    /// Chosen by `format` query parameter, or by `Accept` header if it's not given
+    fn negotiate(state: &State, format: Option<&str>) -> Self {
+        let is_msgpack = match format {
+            Some(format) => format == "msgpack",
+            None => HeaderMap::borrow_from(state)
+                .get(ACCEPT)
+                .and_then(|accept| accept.to_str().ok())
+                .is_some_and(|accept| accept.contains("msgpack")),
+        };
+        if is_msgpack {
+            Self::MsgPack
+        } else {
+            Self::Json
+        }
+    }
+
+    /// Encode a value and tell its content type. MessagePack keeps field names,
+    /// so payloads decode to the same structure as JSON
+    pub(crate) fn encode<V: Serialize>(self, value: &V) -> (mime::Mime, Vec<u8>) {
+        match self {
+            Self::Json => (
+                mime::APPLICATION_JSON,
+                json!(value).to_string().into_bytes(),
+            ),
+            Self::MsgPack => (
+                "application/msgpack".parse().unwrap(),
+                rmp_serde::to_vec_named(value).unwrap(),
+            ),
+        }
+    }
+
+    fn response<V: Serialize>(self, state: &State, value: &V) -> Response<Body> {
+        let (content_type, bytes) = self.encode(value);
+        create_response::<Vec<u8>>(state, StatusCode::OK, content_type, bytes)
+    }
+}
+
+/// Describe the query string for the partial reset request, `agents` is a JSON list of indices
+#[derive(Deserialize, StateData, StaticResponseExtender)]
+struct ResetAgentsQueryString {
+    agents: String,
+}
+
+/// `reset_agents` API endpoint to reset only some of the agents
+fn reset_agents<
+    T: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::de::DeserializeOwned,
+    P: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::Serialize,
+>(
+    mut state: State,
+) -> (State, Response<Body>) {
+    let query_param = ResetAgentsQueryString::take_from(&mut state);
+
+    let agent_indices: Vec<usize> = match serde_json::from_str(&query_param.agents) {
+        Ok(agent_indices) => agent_indices,
+        Err(e) => return error_response(state, ApiError::BadRequest(e.to_string())),
+    };
+
+    let state_: &GothamState<T, P> = GothamState::borrow_from(&state);
+    match state_.reset_agents(&client_id(&state, &state_.settings), agent_indices) {
+        Ok(agent_states) => {
+            let response = PayloadFormat::Json.response(&state, &state_.payload(&agent_states));
+            (state, response)
+        }
+        Err(e) => error_response(state, e),
+    }
+}
+
+fn snapshot_error(e: SnapshotError) -> ApiError {
+    match e {
+        SnapshotError::NotFound(id) => ApiError::NotFound(format!("Snapshot {id} not found")),
+        SnapshotError::Scene(e) => ApiError::Internal(format!("Can't restore snapshot: {e}")),
+    }
+}
+
+/// `save_state` API endpoint to snapshot entities marked `Snapshotted` and the episode state
+fn save_state<
+    T: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::de::DeserializeOwned,
+    P: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::Serialize,
+>(
+    state: State,
+) -> (State, Response<Body>) {
+    let state_: &GothamState<T, P> = GothamState::borrow_from(&state);
+    match state_.save_state(&client_id(&state, &state_.settings)) {
+        Ok((id, entities)) => {
+            let response = PayloadFormat::Json.response(
+                &state,
+                &json!({
+                    "id": id,
+                    "entities": entities,
+                }),
+            );
+            (state, response)
+        }
+        Err(e) => error_response(state, e),
+    }
+}
+
+/// Describe the query string for restoring a snapshot
+#[derive(Deserialize, StateData, StaticResponseExtender)]
+struct LoadStateQueryString {
+    id: u64,
+}
+
+/// `load_state` API endpoint to restore a snapshot saved with `save_state`
+fn load_state<
+    T: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::de::DeserializeOwned,
+    P: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::Serialize,
+>(
+    mut state: State,
+) -> (State, Response<Body>) {
+    let query_param = LoadStateQueryString::take_from(&mut state);
+
+    let state_: &GothamState<T, P> = GothamState::borrow_from(&state);
+    match state_.load_state(&client_id(&state, &state_.settings), query_param.id) {
+        Ok(agent_states) => {
+            let response = PayloadFormat::Json.response(&state, &state_.payload(&agent_states));
+            (state, response)
+        }
+        Err(e) => error_response(state, e),
+    }
+}
+
+/// `spec` API endpoint to describe observations served to the policy and their preprocessing
+fn spec<
+    T: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::de::DeserializeOwned,
+    P: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::Serialize,
+>(
+    state: State,
+) -> (State, String) {
+    let state_: &GothamState<T, P> = GothamState::borrow_from(&state);
+    let spec = preprocess::spec(&state_.settings);
+
+    (state, spec.to_string())
+}
+
+/// `info` API endpoint to identify the environment for orchestrators managing fleets of them
+fn info<
+    T: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::de::DeserializeOwned,
+    P: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::Serialize,
+>(
+    state: State,
+) -> (State, String) {
+    let state_: &GothamState<T, P> = GothamState::borrow_from(&state);
+    let settings = &state_.settings;
+    let frames = state_.inner.lock().unwrap().frames;
+    let info = json!({
+        "env_name": settings.env_name,
+        "version": env!("CARGO_PKG_VERSION"),
+        "num_agents": settings.num_agents,
+        "num_envs": settings.env_count(),
+        "learning_agents": settings.learning_agents().len(),
+        "api_agents": settings.api_agents().len(),
+        "resolution": {"width": settings.width, "height": settings.height},
+        "observation_size": settings.observation_size(),
+        "pause_interval": settings.pause_interval,
+        "step_mode": settings.step_mode,
+        "frames": frames,
+    });
+
+    (state, info.to_string())
+}
+
+/// `spaces` API endpoint to describe action and observation spaces of an agent
+fn spaces<
+    T: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::de::DeserializeOwned,
+    P: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::Serialize,
+>(
+    state: State,
+) -> (State, String) {
+    let state_: &GothamState<T, P> = GothamState::borrow_from(&state);
+    let (action_space, observation_space) = {
+        let ai_gym_state = state_.inner.lock().unwrap();
+        (
+            ai_gym_state.action_space.clone(),
+            ai_gym_state.observation_space.clone(),
+        )
+    };
+    let spaces = json!({
+        "action_space": action_space,
+        "observation_space": observation_space
+            .or_else(|| crate::spaces::visual_observation_space(&state_.settings)),
+        "num_agents": state_.settings.learning_agents().len(),
+    });
+
+    (state, spaces.to_string())
+}
+
+/// `openapi.json` API endpoint to describe all routes and schemas of the environment
+fn openapi<
+    T: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::de::DeserializeOwned,
+    P: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::Serialize,
+>(
+    state: State,
+) -> (State, Response<Body>) {
+    let state_: &GothamState<T, P> = GothamState::borrow_from(&state);
+    let document = {
+        let ai_gym_state = state_.inner.lock().unwrap();
+        crate::codegen::openapi(
+            ai_gym_state.action_schema.as_ref(),
+            ai_gym_state.state_schema.as_ref(),
+        )
+    };
+
+    let response = create_response(
+        &state,
+        StatusCode::OK,
+        mime::APPLICATION_JSON,
+        document.to_string(),
+    );
+    (state, response)
+}
+
+/// Describe the query string for the policy swap request, `path` is a checkpoint file
+/// relative to `AIGymSettings.policy_dir` and `slot` the policy to swap, the default one if not set
+#[derive(Deserialize, StateData, StaticResponseExtender)]
+struct LoadPolicyQueryString {
+    path: String,
+    slot: Option<String>,
+}
+
+/// `policy/load` API endpoint to swap the in-process policy at the start of the next episode
+fn load_policy<
+    T: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::de::DeserializeOwned,
+    P: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::Serialize,
+>(
+    mut state: State,
+) -> (State, Response<Body>) {
+    let query_param = LoadPolicyQueryString::take_from(&mut state);
+    let state_: &GothamState<T, P> = GothamState::borrow_from(&state);
+    let path = match policy_path(state_.settings.policy_dir.as_deref(), &query_param.path) {
+        Ok(path) => path,
+        Err(e) => return error_response(state, e),
+    };
+
+    let mut ai_gym_state = state_.inner.lock().unwrap();
+    // A later request for the same slot replaces the pending one
+    ai_gym_state
+        .pending_policies
+        .retain(|(slot, _)| *slot != query_param.slot);
+    ai_gym_state
+        .pending_policies
+        .push((query_param.slot.clone(), path.clone()));
+    let current = match &query_param.slot {
+        Some(slot) => ai_gym_state.policy_checkpoints.get(slot).cloned(),
+        None => ai_gym_state.policy_checkpoint.clone(),
+    };
+    drop(ai_gym_state);
+
+    let response = PayloadFormat::Json.response(
+        &state,
+        &json!({"slot": query_param.slot, "current": current, "pending": path}),
+    );
+    (state, response)
+}
+
+/// Resolve a checkpoint path of `/policy/load` inside `policy_dir`. Absolute paths and `..`
+/// are rejected, so clients can't probe files elsewhere
+fn policy_path(policy_dir: Option<&str>, path: &str) -> Result<String, ApiError> {
+    let Some(policy_dir) = policy_dir else {
+        return Err(ApiError::NotFound(
+            "Loading policies is disabled, set AIGymSettings.policy_dir".to_string(),
+        ));
+    };
+
+    let relative = std::path::Path::new(path);
+    let is_contained = relative
+        .components()
+        .all(|component| matches!(component, std::path::Component::Normal(_)));
+    if path.is_empty() || !is_contained {
+        return Err(ApiError::BadRequest(
+            "Policy path must be relative to the policy directory".to_string(),
+        ));
+    }
+
+    let path = std::path::Path::new(policy_dir).join(relative);
+    if !path.is_file() {
+        return Err(ApiError::NotFound(
+            "Policy checkpoint not found".to_string(),
+        ));
+    }
+    Ok(path.to_string_lossy().into_owned())
+}
+
+/// JSON body of `PUT` and `POST` requests
+async fn json_body(state: &mut State) -> Result<serde_json::Value, ApiError> {
+    let body = hyper::body::to_bytes(Body::take_from(state))
+        .await
+        .map_err(|e| ApiError::BadRequest(format!("Can't read body: {e}")))?;
+    serde_json::from_slice(&body)
+        .map_err(|e| ApiError::BadRequest(format!("Invalid JSON body: {e}")))
+}
+
+/// `curriculum` API endpoint to get curriculum parameters in effect and ones waiting for the next episode
+fn curriculum<
+    T: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::de::DeserializeOwned,
+    P: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::Serialize,
+>(
+    state: State,
+) -> (State, String) {
+    let state_: &GothamState<T, P> = GothamState::borrow_from(&state);
+    let curriculum = {
+        let ai_gym_state = state_.inner.lock().unwrap();
+        json!({
+            "current": ai_gym_state.curriculum,
+            "pending": ai_gym_state.pending_curriculum,
+        })
+    };
+
+    (state, curriculum.to_string())
+}
+
+/// `PUT curriculum` API endpoint to set curriculum parameters from the JSON body,
+/// applied at the start of the next episode
+fn put_curriculum<
+    T: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::de::DeserializeOwned,
+    P: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::Serialize,
+>(
+    mut state: State,
+) -> Pin<Box<HandlerFuture>> {
+    async move {
+        let parameters = match json_body(&mut state).await {
+            Ok(parameters) => parameters,
+            Err(e) => return Ok(error_response(state, e)),
+        };
+
+        let state_: &GothamState<T, P> = GothamState::borrow_from(&state);
+        if let Err(e) = state_.check_not_competition("curriculum") {
+            return Ok(error_response(state, e));
+        }
+        let client = client_id(&state, &state_.settings);
+        let curriculum = {
+            let mut ai_gym_state = state_.inner.lock().unwrap();
+            ai_gym_state.record_api_request(&client);
+            ai_gym_state.pending_curriculum = Some(parameters);
+            json!({
+                "current": ai_gym_state.curriculum,
+                "pending": ai_gym_state.pending_curriculum,
+            })
+        };
+
+        let response = PayloadFormat::Json.response(&state, &curriculum);
+        Ok((state, response))
+    }
+    .boxed()
+}
+
+/// `pause` API endpoint to freeze the simulation for inspection, steps and resets wait until `resume`
+fn pause<
+    T: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::de::DeserializeOwned,
+    P: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::Serialize,
+>(
+    state: State,
+) -> (State, Response<Body>) {
+    debug_pause::<T, P>(state, true)
+}
+
+/// `resume` API endpoint to continue the simulation paused with `pause`
+fn resume<
+    T: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::de::DeserializeOwned,
+    P: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::Serialize,
+>(
+    state: State,
+) -> (State, Response<Body>) {
+    debug_pause::<T, P>(state, false)
+}
+
+fn debug_pause<
+    T: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::de::DeserializeOwned,
+    P: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::Serialize,
+>(
+    state: State,
+    pause: bool,
+) -> (State, Response<Body>) {
+    let state_: &GothamState<T, P> = GothamState::borrow_from(&state);
+    match state_.debug_pause(&client_id(&state, &state_.settings), pause) {
+        Ok(result) => {
+            let response = PayloadFormat::Json.response(&state, &result);
+            (state, response)
+        }
+        Err(e) => error_response(state, e),
+    }
+}
+
+/// Describe the query string for running frames: their number and whether to apply no actions
+#[derive(Deserialize, StateData, StaticResponseExtender)]
+struct AdvanceQueryString {
+    frames: u32,
+    noop: Option<bool>,
+}
+
+/// `advance` API endpoint to run frames with the previous actions without a learning step,
+/// e.g. for warm-up periods and cutscenes
+fn advance<
+    T: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::de::DeserializeOwned,
+    P: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::Serialize,
+>(
+    mut state: State,
+) -> (State, Response<Body>) {
+    let query_param = AdvanceQueryString::take_from(&mut state);
+
+    let state_: &GothamState<T, P> = GothamState::borrow_from(&state);
+    match state_.advance(
+        &client_id(&state, &state_.settings),
+        query_param.frames,
+        query_param.noop.unwrap_or(false),
+    ) {
+        Ok(agent_states) => {
+            let response = PayloadFormat::Json.response(&state, &state_.payload(&agent_states));
+            (state, response)
+        }
+        Err(e) => error_response(state, e),
+    }
+}
+
+/// `set_state` API endpoint to put the environment into the configuration given in the body
+fn set_state<
+    T: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::de::DeserializeOwned,
+    P: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::Serialize,
+>(
+    mut state: State,
+) -> Pin<Box<HandlerFuture>> {
+    async move {
+        let payload = match json_body(&mut state).await {
+            Ok(payload) => payload,
+            Err(e) => return Ok(error_response(state, e)),
+        };
+
+        let state_: &GothamState<T, P> = GothamState::borrow_from(&state);
+        let response = match state_.set_state(&client_id(&state, &state_.settings), payload) {
+            Ok(agent_states) => {
+                PayloadFormat::Json.response(&state, &state_.payload(&agent_states))
+            }
+            Err(e) => return Ok(error_response(state, e)),
+        };
+        Ok((state, response))
+    }
+    .boxed()
+}
+
+/// `episodes` API endpoint to get per-agent episode statistics
+fn episodes<
+    T: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::de::DeserializeOwned,
+    P: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::Serialize,
+>(
+    state: State,
+) -> (State, String) {
+    let state_: &GothamState<T, P> = GothamState::borrow_from(&state);
+    let episode_stats = state_.inner.lock().unwrap().episode_stats.clone();
+
+    (state, json!(episode_stats).to_string())
+}
+
+/// `health` API endpoint to check whether the simulation keeps up with the control interval
+fn health<
+    T: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::de::DeserializeOwned,
+    P: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::Serialize,
+>(
+    state: State,
+) -> (State, String) {
+    let state_: &GothamState<T, P> = GothamState::borrow_from(&state);
+    let (frame_rate_health, readback_health, trainer_latency) = {
+        let ai_gym_state = state_.inner.lock().unwrap();
+        (
+            ai_gym_state.frame_rate_health.clone(),
+            ai_gym_state.readback_health.clone(),
+            ai_gym_state.trainer_latency,
+        )
+    };
+    let status = if frame_rate_health.low_frame_rate || readback_health.error.is_some() {
+        "degraded"
+    } else {
+        "ok"
+    };
+
+    (
+        state,
+        json!({
+            "status": status,
+            "frame_rate": frame_rate_health,
+            "readback": readback_health,
+            "trainer_latency": trainer_latency,
+        })
+        .to_string(),
+    )
+}
+
+/// `metrics` API endpoint: counters and histograms in Prometheus text format
+fn metrics<
+    T: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::de::DeserializeOwned,
+    P: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::Serialize,
+>(
+    state: State,
+) -> (State, Response<Body>) {
+    let state_: &GothamState<T, P> = GothamState::borrow_from(&state);
+    let text = {
+        let ai_gym_state = state_.inner.lock().unwrap();
+        let metrics = ai_gym_state.metrics.lock().unwrap();
+        metrics.render(ai_gym_state.frames, ai_gym_state.seconds_since_frame())
+    };
+
+    let content_type: mime::Mime = "text/plain; version=0.0.4".parse().unwrap();
+    let response = create_response(&state, StatusCode::OK, content_type, text);
+    (state, response)
+}
+
+/// JSON response with `200 OK` if `ok`, `503 Service Unavailable` otherwise, for probes
+/// of cluster schedulers which only look at the status code
+fn probe_response(state: State, ok: bool, body: serde_json::Value) -> (State, Response<Body>) {
+    let status = if ok {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    let response = create_response(&state, status, mime::APPLICATION_JSON, body.to_string());
+    (state, response)
+}
+
+/// `healthz` API endpoint for liveness probes: fails once the simulation loop hasn't run a frame
+/// for `AIGymSettings.liveness_timeout` seconds, so a wedged environment gets restarted
+fn healthz<
+    T: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::de::DeserializeOwned,
+    P: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::Serialize,
+>(
+    state: State,
+) -> (State, Response<Body>) {
+    let state_: &GothamState<T, P> = GothamState::borrow_from(&state);
+    let (responsive, body) = {
+        let ai_gym_state = state_.inner.lock().unwrap();
+        let responsive = ai_gym_state.is_responsive();
+        (
+            responsive,
+            json!({
+                "status": if responsive { "ok" } else { "unresponsive" },
+                "frames": ai_gym_state.frames,
+                "seconds_since_frame": ai_gym_state.seconds_since_frame(),
+            }),
+        )
+    };
+
+    probe_response(state, responsive, body)
+}
+
+/// `readyz` API endpoint for readiness probes: the environment has left
+/// `SimulationState::Initializing`, render targets are allocated and the simulation loop is responsive
+fn readyz<
+    T: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::de::DeserializeOwned,
+    P: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::Serialize,
+>(
+    state: State,
+) -> (State, Response<Body>) {
+    let state_: &GothamState<T, P> = GothamState::borrow_from(&state);
+    let (ready, body) = {
+        let ai_gym_state = state_.inner.lock().unwrap();
+        let initialized = ai_gym_state.initialized;
+        let render_targets = ai_gym_state.has_render_targets();
+        let responsive = ai_gym_state.is_responsive();
+        let ready = initialized && render_targets && responsive;
+        (
+            ready,
+            json!({
+                "ready": ready,
+                "initialized": initialized,
+                "render_targets": render_targets,
+                "responsive": responsive,
+                "seconds_since_frame": ai_gym_state.seconds_since_frame(),
+            }),
+        )
+    };
+
+    probe_response(state, ready, body)
+}
+
+/// `lidar` API endpoint to get distances measured by agents' `LidarSensor`s
+fn lidar<
+    T: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::de::DeserializeOwned,
+    P: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::Serialize,
+>(
+    state: State,
+) -> (State, String) {
+    let state_: &GothamState<T, P> = GothamState::borrow_from(&state);
+    let lidar_observations = state_.inner.lock().unwrap().lidar_observations.clone();
+
+    (state, json!(lidar_observations).to_string())
+}
+
+/// `vec/step` API endpoint to step all environment copies, `payload` is a JSON list
+/// of action lists, one per copy
+fn vec_step<
+    T: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::de::DeserializeOwned,
+    P: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::Serialize,
+>(
+    mut state: State,
+) -> (State, Response<Body>) {
+    let query_param = StepQueryString::take_from(&mut state);
+
+    let env_actions: Vec<Vec<AgentAction>> = match serde_json::from_str(&query_param.payload) {
+        Ok(env_actions) => env_actions,
+        Err(e) => return error_response(state, ApiError::BadRequest(e.to_string())),
+    };
+
+    let state_: &GothamState<T, P> = GothamState::borrow_from(&state);
+    match state_.vec_step(&client_id(&state, &state_.settings), env_actions) {
+        Ok(transitions) => {
+            let response = PayloadFormat::Json.response(&state, &state_.payload(&transitions));
+            (state, response)
+        }
+        Err(e) => error_response(state, e),
+    }
+}
+
+/// `vec/reset` API endpoint to reset some or all environment copies
+fn vec_reset<
+    T: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::de::DeserializeOwned,
+    P: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::Serialize,
+>(
+    mut state: State,
+) -> (State, Response<Body>) {
+    let query_param = ResetQueryString::take_from(&mut state);
+
+    let mut request = match parse_reset_query(&query_param) {
+        Ok(request) => request,
+        Err(e) => return error_response(state, e),
+    };
+    request.env_ids = match query_param
+        .envs
+        .as_deref()
+        .map(serde_json::from_str)
+        .transpose()
+    {
+        Ok(env_ids) => env_ids,
+        Err(e) => return error_response(state, ApiError::BadRequest(e.to_string())),
+    };
+
+    let state_: &GothamState<T, P> = GothamState::borrow_from(&state);
+    match state_.vec_reset(&client_id(&state, &state_.settings), request) {
+        Ok(agent_states) => {
+            let response = PayloadFormat::Json.response(&state, &state_.payload(&agent_states));
+            (state, response)
+        }
+        Err(e) => error_response(state, e),
+    }
+}
+
+/// `leaderboard` API endpoint to get clients' scores in competition mode
+fn leaderboard<
+    T: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::de::DeserializeOwned,
+    P: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::Serialize,
+>(
+    state: State,
+) -> (State, String) {
+    let state_: &GothamState<T, P> = GothamState::borrow_from(&state);
+    let scores = state_.inner.lock().unwrap().leaderboard.scores();
+
+    (state, json!(scores).to_string())
+}
+
+/// A command sent by the client over WebSocket connection
+#[derive(Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+enum WsCommand {
+    Step {
+        actions: Vec<AgentAction>,
+    },
+    Reset {
+        seed: Option<u64>,
+        options: Option<serde_json::Value>,
+        #[serde(default)]
+        metadata: HashMap<String, serde_json::Value>,
+    },
+    ResetAgents {
+        agents: Vec<usize>,
+    },
+    State,
+}
+
+/// `ws` API endpoint upgrades the connection to WebSocket.
+/// The client keeps a single connection and sends JSON commands in text or binary frames,
+/// e.g. `{"command": "step", "actions": [{"action": "..."}]}`,
+/// results are sent back in frames of the same kind.
+fn ws<
+    T: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::de::DeserializeOwned,
+    P: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::Serialize,
+>(
+    mut state: State,
+) -> (State, Response<Body>) {
+    let headers = HeaderMap::take_from(&mut state);
+    let on_upgrade = OnUpgrade::try_take_from(&mut state);
+
+    let is_upgrade_requested = headers
+        .get(UPGRADE)
+        .is_some_and(|value| value.as_bytes().eq_ignore_ascii_case(b"websocket"));
+    let (Some(on_upgrade), Some(key), true) = (
+        on_upgrade,
+        headers.get(SEC_WEBSOCKET_KEY),
+        is_upgrade_requested,
+    ) else {
+        return error_response(
+            state,
+            ApiError::BadRequest("Expected WebSocket upgrade request".to_string()),
+        );
+    };
+
+    let response = Response::builder()
+        .status(StatusCode::SWITCHING_PROTOCOLS)
+        .header(UPGRADE, "websocket")
+        .header(CONNECTION, "upgrade")
+        .header(SEC_WEBSOCKET_ACCEPT, derive_accept_key(key.as_bytes()))
+        .body(Body::empty())
+        .unwrap();
+
+    let gotham_state = GothamState::<T, P>::borrow_from(&state).clone();
+    let client = client_id(&state, &gotham_state.settings);
+    tokio::spawn(async move {
+        if let Ok(upgraded) = on_upgrade.await {
+            let stream = WebSocketStream::from_raw_socket(upgraded, Role::Server, None).await;
+            ws_connection(gotham_state, client, stream).await;
+        }
+    });
+
+    (state, response)
+}
+
+/// Serve WebSocket commands until the client disconnects
+async fn ws_connection<
+    T: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::de::DeserializeOwned,
+    P: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::Serialize,
+>(
+    gotham_state: GothamState<T, P>,
+    client: String,
+    mut stream: WebSocketStream<Upgraded>,
+) {
+    while let Some(Ok(message)) = stream.next().await {
+        let (payload, is_binary) = match message {
+            Message::Text(text) => (text.into_bytes(), false),
+            Message::Binary(bytes) => (bytes, true),
+            Message::Close(_) => break,
+            _ => continue,
+        };
+
+        // Engine calls block on crossbeam channels, keep them off the async workers
+        let gotham_state = gotham_state.clone();
+        let client = client.clone();
+        let Ok(response) =
+            tokio::task::spawn_blocking(move || ws_command(&gotham_state, &client, &payload)).await
+        else {
+            break;
+        };
+
+        let message = match response {
+            WsReply::Encoded(bytes) => Message::Binary(bytes),
+            WsReply::Json(text) if is_binary => Message::Binary(text.into_bytes()),
+            WsReply::Json(text) => Message::Text(text),
+        };
+        if stream.send(message).await.is_err() {
+            break;
+        }
+    }
+}
+
+/// Result of a WebSocket command
+enum WsReply {
+    /// Sent in a frame of the same kind as the command
+    Json(String),
+    /// Encoded with the state codec, always sent in a binary frame
+    Encoded(Vec<u8>),
+}
+
+/// Execute a single WebSocket command and serialize its result
+fn ws_command<
+    T: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::de::DeserializeOwned,
+    P: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::Serialize,
+>(
+    gotham_state: &GothamState<T, P>,
+    client: &str,
+    payload: &[u8],
+) -> WsReply {
+    let command = match serde_json::from_slice::<WsCommand>(payload) {
+        Ok(command) => command,
+        Err(e) => return WsReply::Json(ApiError::BadRequest(e.to_string()).body().to_string()),
+    };
+
+    let response = match command {
+        WsCommand::Step { actions } => match gotham_state.step(client, actions) {
+            Ok(transitions) => gotham_state.payload(&transitions).to_string(),
+            Err(e) => e.body().to_string(),
+        },
+        WsCommand::Reset {
+            seed,
+            options,
+            metadata,
+        } => {
+            let request = state::ResetRequest {
+                seed,
+                options,
+                metadata,
+                ..Default::default()
+            };
+            match gotham_state.reset(client, request) {
+                Ok(agent_states) => gotham_state.payload(&agent_states).to_string(),
+                Err(e) => e.body().to_string(),
+            }
+        }
+        WsCommand::ResetAgents { agents } => match gotham_state.reset_agents(client, agents) {
+            Ok(agent_states) => gotham_state.payload(&agent_states).to_string(),
+            Err(e) => e.body().to_string(),
+        },
+        WsCommand::State => {
+            let (content_type, bytes) = gotham_state.encoded_env_state(PayloadFormat::Json);
+            if content_type != mime::APPLICATION_JSON {
+                return WsReply::Encoded(bytes);
+            }
+            String::from_utf8_lossy(&bytes).into_owned()
+        }
+    };
+
+    WsReply::Json(response)
+}
+
+/// `env_state` API endpoint to get the environment state
+fn env_state<
+    T: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::de::DeserializeOwned,
+    P: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::Serialize,
+>(
+    mut state: State,
+) -> (State, Response<Body>) {
+    let query_param = PayloadFormatQueryString::take_from(&mut state);
+    let format = PayloadFormat::negotiate(&state, query_param.format.as_deref());
+
+    let state_: &GothamState<T, P> = GothamState::borrow_from(&state);
+    let (content_type, bytes) = state_.encoded_env_state(format);
+    let response = create_response::<Vec<u8>>(&state, StatusCode::OK, content_type, bytes);
+
+    (state, response)
+}
+
+/// `state/bin` API endpoint to return environment state serialized with bincode, for Rust
+/// clients polling state at high frequency. It's `Option<State>`, `None` until the state is set
+fn env_state_bin<
+    T: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::de::DeserializeOwned,
+    P: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::Serialize,
+>(
+    state: State,
+) -> (State, Response<Body>) {
+    let state_: &GothamState<T, P> = GothamState::borrow_from(&state);
+    let bytes = {
+        let ai_gym_state = state_.inner.lock().unwrap();
+        bincode::serialize(&ai_gym_state.environment_state)
+    };
+
+    let response = match bytes {
+        Ok(bytes) => create_response::<Vec<u8>>(
+            &state,
+            StatusCode::OK,
+            mime::APPLICATION_OCTET_STREAM,
+            bytes,
+        ),
+        Err(e) => ApiError::Internal(e.to_string()).response(&state),
+    };
+    (state, response)
+}
+
+/// Summary statistics of a set of values
+#[derive(Serialize)]
+struct ValueStats {
+    min: f32,
+    max: f32,
+    mean: f32,
+    std: f32,
+}
+
+impl ValueStats {
+    fn from_values(values: impl Iterator<Item = f32>) -> Option<Self> {
+        let (mut min, mut max, mut sum, mut sum_sq, mut count) =
+            (f32::MAX, f32::MIN, 0.0f64, 0.0f64, 0u64);
+        for value in values {
+            min = min.min(value);
+            max = max.max(value);
+            sum += value as f64;
+            sum_sq += (value as f64) * (value as f64);
+            count += 1;
+        }
+
+        if count == 0 {
+            return None;
+        }
+
+        let mean = sum / count as f64;
+        let variance = (sum_sq / count as f64 - mean * mean).max(0.0);
+        Some(Self {
+            min,
+            max,
+            mean: mean as f32,
+            std: variance.sqrt() as f32,
+        })
+    }
+}
+
+/// `obs_stats` API endpoint: min/max/mean/std of every channel of learning agents' visual
+/// observations of the captured step and of their vector observations. Helps to catch
+/// all-black frames, saturation or unnormalized inputs
+fn obs_stats<
+    T: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::de::DeserializeOwned,
+    P: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::Serialize,
+>(
+    state: State,
+) -> (State, String) {
+    let state_: &GothamState<T, P> = GothamState::borrow_from(&state);
+    let (observations, vector_observations) = {
+        let state__ = state_.inner.lock().unwrap();
+        (
+            state__.observations.clone(),
+            state__.vector_observations.clone(),
+        )
+    };
+
+    let visual: Vec<serde_json::Value> = observations
+        .visual
+        .iter()
+        .map(|screen| {
+            let names: &[&str] = match screen.color().channel_count() {
+                1 => &["l"],
+                3 => &["r", "g", "b"],
+                _ => &["r", "g", "b", "a"],
+            };
+            // Normalized to [0, 1] whatever the sample type, luma is copied to every color channel
+            let pixels = screen.to_rgba32f();
+            let channels: serde_json::Map<String, serde_json::Value> = names
+                .iter()
+                .enumerate()
+                .map(|(channel, name)| {
+                    let stats =
+                        ValueStats::from_values(pixels.pixels().map(|pixel| pixel.0[channel]));
+                    (name.to_string(), json!(stats))
+                })
+                .collect();
+            serde_json::Value::Object(channels)
+        })
+        .collect();
+    let visual = state_.without_npcs(visual);
+
+    // `null` for agents without a vector observation
+    let vector: Vec<Option<ValueStats>> = vector_observations
+        .iter()
+        .map(|observation| {
+            observation
+                .as_ref()
+                .and_then(|values| ValueStats::from_values(values.iter().copied()))
+        })
+        .collect();
+    let vector = state_.without_npcs(vector);
+
+    (
+        state,
+        json!({"step": observations.step, "visual": visual, "vector": vector}).to_string(),
+    )
+}
+
+/// Describe the path of requests to endpoints registered with `add_endpoint`
+#[derive(Deserialize, StateData, StaticResponseExtender)]
+struct CustomEndpointPathExtractor {
+    name: String,
+}
+
+/// Decode `key=value` pairs of a URL query string, later duplicates win
+fn query_params(query: Option<&str>) -> HashMap<String, String> {
+    form_urlencoded::parse(query.unwrap_or_default().as_bytes())
+        .into_owned()
+        .collect()
+}
+
+/// `custom/{name}` API endpoint to serve routes registered by the environment with `add_endpoint`
+fn custom_endpoint<
+    T: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::de::DeserializeOwned,
+    P: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::Serialize,
+>(
+    mut state: State,
+) -> (State, Response<Body>) {
+    let path = CustomEndpointPathExtractor::take_from(&mut state);
+    let params = query_params(Uri::borrow_from(&state).query());
+
+    let result = {
+        let state_: &GothamState<T, P> = GothamState::borrow_from(&state);
+        let ai_gym_state = state_.inner.lock().unwrap();
+        ai_gym_state
+            .custom_endpoints
+            .get(&path.name)
+            .cloned()
+            .map(|endpoint| endpoint.respond(&ai_gym_state, &params))
+    };
+
+    let response = match result {
+        Some(Ok(body)) => create_response(
+            &state,
+            StatusCode::OK,
+            mime::APPLICATION_JSON,
+            body.to_string(),
+        ),
+        Some(Err(message)) => ApiError::BadRequest(message).response(&state),
+        None => {
+            ApiError::NotFound(format!("No endpoint {} registered", path.name)).response(&state)
+        }
+    };
+    (state, response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn query_params_are_decoded() {
+        let params = query_params(Some("name=a+b%2Fc&flag&empty=&id=1&id=2&bad=%zz%4"));
+        assert_eq!(params["name"], "a b/c");
+        assert_eq!(params["flag"], "");
+        assert_eq!(params["empty"], "");
+        assert_eq!(params["id"], "2");
+        assert_eq!(params["bad"], "%zz%4");
+        assert_eq!(params.len(), 5);
+
+        assert!(query_params(None).is_empty());
+        assert!(query_params(Some("")).is_empty());
+    }
+
+    #[test]
+    fn policy_paths_stay_in_policy_dir() {
+        let policy_dir =
+            std::env::temp_dir().join(format!("bevy_rl_policies_{}", std::process::id()));
+        std::fs::create_dir_all(policy_dir.join("opponents")).unwrap();
+        std::fs::write(policy_dir.join("opponents/v1.onnx"), b"").unwrap();
+        let policy_dir = policy_dir.to_str().unwrap();
+
+        assert!(matches!(
+            policy_path(None, "opponents/v1.onnx"),
+            Err(ApiError::NotFound(_))
+        ));
+        for path in ["", "/etc/passwd", "../v1.onnx", "opponents/../../v1.onnx"] {
+            assert!(matches!(
+                policy_path(Some(policy_dir), path),
+                Err(ApiError::BadRequest(_))
+            ));
+        }
+        assert!(matches!(
+            policy_path(Some(policy_dir), "opponents/v2.onnx"),
+            Err(ApiError::NotFound(_))
+        ));
+        assert!(policy_path(Some(policy_dir), "opponents/v1.onnx")
+            .unwrap()
+            .ends_with("v1.onnx"));
+
+        std::fs::remove_dir_all(policy_dir).unwrap();
+    }
 }
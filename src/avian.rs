@@ -0,0 +1,102 @@
+//! Physics stepping with avian (`avian` feature).
+//! Avian runs in Bevy's fixed timestep schedule by default, which ticks a varying number of times
+//! per control step depending on frame rate. Build avian into the `AvianStep` schedule instead
+//! and `AvianStepPlugin` runs it exactly once per control interval (`frame_skip` times per control
+//! step) with a fixed `dt`, split into `substeps`. Physics time is paused on `EventPause`.
+
+use avian3d::prelude::*;
+use bevy::ecs::schedule::ScheduleLabel;
+use bevy::prelude::*;
+
+use crate::{EventAdvanceFrame, EventControl, EventPause};
+
+/// Schedule to build avian into with `PhysicsPlugins::new(AvianStep)`
+#[derive(ScheduleLabel, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct AvianStep;
+
+/// Steps avian once per control interval
+pub struct AvianStepPlugin {
+    /// Simulated time of a physics step, usually the control interval
+    pub dt: f32,
+    /// Avian substeps of a physics step
+    pub substeps: u32,
+}
+
+impl AvianStepPlugin {
+    pub fn new(dt: f32) -> Self {
+        Self { dt, substeps: 6 }
+    }
+
+    /// Number of substeps of each physics step, 6 by default as in avian
+    pub fn with_substeps(mut self, substeps: u32) -> Self {
+        self.substeps = substeps.max(1);
+        self
+    }
+}
+
+impl Plugin for AvianStepPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(SubstepCount(self.substeps))
+            .insert_resource(AvianStepSettings {
+                dt: std::time::Duration::from_secs_f32(self.dt),
+            })
+            .init_resource::<PendingPhysicsSteps>()
+            .add_systems(
+                Update,
+                (count_physics_steps, pause_physics, run_physics_steps).chain(),
+            );
+    }
+}
+
+#[derive(Resource)]
+struct AvianStepSettings {
+    dt: std::time::Duration,
+}
+
+/// Control intervals whose physics step hasn't run yet
+#[derive(Resource, Default)]
+struct PendingPhysicsSteps(u32);
+
+/// One step per control interval, and per frame of `/advance`
+fn count_physics_steps(
+    mut control_events: EventReader<EventControl>,
+    mut advance_frame_events: EventReader<EventAdvanceFrame>,
+    mut pending_steps: ResMut<PendingPhysicsSteps>,
+) {
+    pending_steps.0 += control_events.read().count() as u32;
+    pending_steps.0 += advance_frame_events.read().count() as u32;
+}
+
+fn pause_physics(
+    mut pause_events: EventReader<EventPause>,
+    mut physics_time: ResMut<Time<Physics>>,
+) {
+    if pause_events.read().count() > 0 {
+        physics_time.pause();
+    }
+}
+
+/// Run `AvianStep` for pending steps with `Time` advancing by `dt`, physics time is paused again
+/// afterwards so that nothing else steps it
+fn run_physics_steps(world: &mut World) {
+    let steps = std::mem::take(&mut world.resource_mut::<PendingPhysicsSteps>().0);
+    if steps == 0 {
+        return;
+    }
+
+    let dt = world.resource::<AvianStepSettings>().dt;
+    let mut step_time = Time::<()>::default();
+    step_time.advance_by(dt);
+    let time = std::mem::replace(&mut *world.resource_mut::<Time>(), step_time);
+
+    world.resource_mut::<Time<Physics>>().unpause();
+    for _ in 0..steps {
+        if world.try_run_schedule(AvianStep).is_err() {
+            warn!("bevy_rl: AvianStep schedule is missing, build avian with PhysicsPlugins::new(AvianStep)");
+            break;
+        }
+    }
+    world.resource_mut::<Time<Physics>>().pause();
+
+    *world.resource_mut::<Time>() = time;
+}
@@ -0,0 +1,805 @@
+//! Client code generation.
+//! Emits minimal typed REST clients (Python dataclasses with `requests`, TypeScript with `fetch`)
+//! from the list of API endpoints, so teams in mixed stacks don't hand-write them.
+//! The same list is described as an OpenAPI document at `/openapi.json` for other generators.
+//! Write them from a build script or a small binary of your environment:
+//!
+//! ```ignore
+//! std::fs::write("client/bevy_rl_client.py", bevy_rl::codegen::python_client())?;
+//! std::fs::write("client/bevyRlClient.ts", bevy_rl::codegen::typescript_client())?;
+//! ```
+
+use serde_json::json;
+
+/// Kind of value an endpoint responds with
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Response {
+    /// Environment-specific JSON
+    Json,
+    /// List of `AgentTransition`
+    Transitions,
+    /// List of `AgentTransition` lists, one per environment copy
+    VecTransitions,
+    /// List of `AgentState`
+    AgentStates,
+    /// List of `AgentState` lists, one per environment copy
+    VecAgentStates,
+    /// PNG image
+    Png,
+    /// Raw bytes, shape given in `x-shape` header
+    Raw,
+}
+
+/// HTTP method of an endpoint
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Method {
+    Get,
+    Put,
+    Post,
+}
+
+impl Method {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Method::Get => "GET",
+            Method::Put => "PUT",
+            Method::Post => "POST",
+        }
+    }
+}
+
+/// Query string parameter of an endpoint
+#[derive(Clone, Copy, Debug)]
+pub struct Param {
+    pub name: &'static str,
+    pub optional: bool,
+    /// Sent as a JSON string
+    pub json: bool,
+    /// Sent as is, typed as a string in clients
+    pub text: bool,
+    /// Substituted for `{name}` in the endpoint path instead of the query string
+    pub in_path: bool,
+}
+
+/// REST API endpoint
+#[derive(Clone, Copy, Debug)]
+pub struct Endpoint {
+    pub name: &'static str,
+    pub method: Method,
+    pub path: &'static str,
+    pub params: &'static [Param],
+    /// Requests carry a JSON body
+    pub body: bool,
+    pub response: Response,
+}
+
+const fn param(name: &'static str, optional: bool, json: bool) -> Param {
+    Param {
+        name,
+        optional,
+        json,
+        text: false,
+        in_path: false,
+    }
+}
+
+const fn text_param(name: &'static str, optional: bool) -> Param {
+    Param {
+        name,
+        optional,
+        json: false,
+        text: true,
+        in_path: false,
+    }
+}
+
+const fn path_param(name: &'static str) -> Param {
+    Param {
+        name,
+        optional: false,
+        json: false,
+        text: false,
+        in_path: true,
+    }
+}
+
+/// Endpoints served by the REST API (WebSocket and `/stream` excluded)
+pub const ENDPOINTS: &[Endpoint] = &[
+    Endpoint {
+        name: "visual_observations",
+        method: Method::Get,
+        path: "/visual_observations",
+        params: &[text_param("format", true), param("quality", true, false)],
+        body: false,
+        response: Response::Png,
+    },
+    Endpoint {
+        name: "agent_visual_observation",
+        method: Method::Get,
+        path: "/visual_observations/{agent_id}",
+        params: &[
+            path_param("agent_id"),
+            text_param("format", true),
+            param("quality", true, false),
+        ],
+        body: false,
+        response: Response::Raw,
+    },
+    Endpoint {
+        name: "logging_observations",
+        method: Method::Get,
+        path: "/logging_observations",
+        params: &[text_param("format", true), param("quality", true, false)],
+        body: false,
+        response: Response::Png,
+    },
+    Endpoint {
+        name: "raw_visual_observations",
+        method: Method::Get,
+        path: "/visual_observations/raw",
+        params: &[],
+        body: false,
+        response: Response::Raw,
+    },
+    Endpoint {
+        name: "shared_visual_observations",
+        method: Method::Get,
+        path: "/visual_observations/shm",
+        params: &[],
+        body: false,
+        response: Response::Json,
+    },
+    Endpoint {
+        name: "stacked_observations",
+        method: Method::Get,
+        path: "/stacked_observations",
+        params: &[],
+        body: false,
+        response: Response::Raw,
+    },
+    Endpoint {
+        name: "depth_observations",
+        method: Method::Get,
+        path: "/depth_observations",
+        params: &[],
+        body: false,
+        response: Response::Png,
+    },
+    Endpoint {
+        name: "state",
+        method: Method::Get,
+        path: "/state",
+        params: &[],
+        body: false,
+        response: Response::Json,
+    },
+    Endpoint {
+        name: "state_bin",
+        method: Method::Get,
+        path: "/state/bin",
+        params: &[],
+        body: false,
+        response: Response::Raw,
+    },
+    Endpoint {
+        name: "step",
+        method: Method::Get,
+        path: "/step",
+        params: &[param("payload", false, true)],
+        body: false,
+        response: Response::Transitions,
+    },
+    Endpoint {
+        name: "reset",
+        method: Method::Get,
+        path: "/reset",
+        params: &[param("seed", true, false), param("options", true, true)],
+        body: false,
+        response: Response::AgentStates,
+    },
+    Endpoint {
+        name: "reset_agents",
+        method: Method::Get,
+        path: "/reset_agents",
+        params: &[param("agents", false, true)],
+        body: false,
+        response: Response::AgentStates,
+    },
+    Endpoint {
+        name: "save_state",
+        method: Method::Get,
+        path: "/save_state",
+        params: &[],
+        body: false,
+        response: Response::Json,
+    },
+    Endpoint {
+        name: "load_state",
+        method: Method::Get,
+        path: "/load_state",
+        params: &[param("id", false, false)],
+        body: false,
+        response: Response::AgentStates,
+    },
+    Endpoint {
+        name: "set_state",
+        method: Method::Post,
+        path: "/set_state",
+        params: &[],
+        body: true,
+        response: Response::AgentStates,
+    },
+    Endpoint {
+        name: "pause",
+        method: Method::Post,
+        path: "/pause",
+        params: &[],
+        body: false,
+        response: Response::Json,
+    },
+    Endpoint {
+        name: "resume",
+        method: Method::Post,
+        path: "/resume",
+        params: &[],
+        body: false,
+        response: Response::Json,
+    },
+    Endpoint {
+        name: "advance",
+        method: Method::Post,
+        path: "/advance",
+        params: &[param("frames", false, false), param("noop", true, true)],
+        body: false,
+        response: Response::AgentStates,
+    },
+    Endpoint {
+        name: "vec_step",
+        method: Method::Get,
+        path: "/vec/step",
+        params: &[param("payload", false, true)],
+        body: false,
+        response: Response::VecTransitions,
+    },
+    Endpoint {
+        name: "vec_reset",
+        method: Method::Get,
+        path: "/vec/reset",
+        params: &[
+            param("envs", true, true),
+            param("seed", true, false),
+            param("options", true, true),
+        ],
+        body: false,
+        response: Response::VecAgentStates,
+    },
+    Endpoint {
+        name: "leaderboard",
+        method: Method::Get,
+        path: "/leaderboard",
+        params: &[],
+        body: false,
+        response: Response::Json,
+    },
+    Endpoint {
+        name: "health",
+        method: Method::Get,
+        path: "/health",
+        params: &[],
+        body: false,
+        response: Response::Json,
+    },
+    Endpoint {
+        name: "healthz",
+        method: Method::Get,
+        path: "/healthz",
+        params: &[],
+        body: false,
+        response: Response::Json,
+    },
+    Endpoint {
+        name: "readyz",
+        method: Method::Get,
+        path: "/readyz",
+        params: &[],
+        body: false,
+        response: Response::Json,
+    },
+    Endpoint {
+        name: "spec",
+        method: Method::Get,
+        path: "/spec",
+        params: &[],
+        body: false,
+        response: Response::Json,
+    },
+    Endpoint {
+        name: "info",
+        method: Method::Get,
+        path: "/info",
+        params: &[],
+        body: false,
+        response: Response::Json,
+    },
+    Endpoint {
+        name: "status",
+        method: Method::Get,
+        path: "/status",
+        params: &[],
+        body: false,
+        response: Response::Json,
+    },
+    Endpoint {
+        name: "spaces",
+        method: Method::Get,
+        path: "/spaces",
+        params: &[],
+        body: false,
+        response: Response::Json,
+    },
+    Endpoint {
+        name: "openapi",
+        method: Method::Get,
+        path: "/openapi.json",
+        params: &[],
+        body: false,
+        response: Response::Json,
+    },
+    Endpoint {
+        name: "load_policy",
+        method: Method::Get,
+        path: "/policy/load",
+        params: &[text_param("path", false), text_param("slot", true)],
+        body: false,
+        response: Response::Json,
+    },
+    Endpoint {
+        name: "curriculum",
+        method: Method::Get,
+        path: "/curriculum",
+        params: &[],
+        body: false,
+        response: Response::Json,
+    },
+    Endpoint {
+        name: "put_curriculum",
+        method: Method::Put,
+        path: "/curriculum",
+        params: &[],
+        body: true,
+        response: Response::Json,
+    },
+    Endpoint {
+        name: "episodes",
+        method: Method::Get,
+        path: "/episodes",
+        params: &[],
+        body: false,
+        response: Response::Json,
+    },
+    Endpoint {
+        name: "lidar",
+        method: Method::Get,
+        path: "/lidar",
+        params: &[],
+        body: false,
+        response: Response::Json,
+    },
+    Endpoint {
+        name: "contact_sheet",
+        method: Method::Get,
+        path: "/debug/contact_sheet",
+        params: &[param("agent", false, false), param("last", true, false)],
+        body: false,
+        response: Response::Png,
+    },
+    Endpoint {
+        name: "obs_stats",
+        method: Method::Get,
+        path: "/debug/obs_stats",
+        params: &[],
+        body: false,
+        response: Response::Json,
+    },
+];
+
+/// Python client: dataclasses for transitions and agent states and a `requests`-based client
+pub fn python_client() -> String {
+    let mut code = String::from(
+        r#"# Generated by bevy_rl::codegen, do not edit
+import json
+from dataclasses import dataclass, field
+from typing import Any, List, Optional
+
+import requests
+
+
+@dataclass
+class AgentTransition:
+    observation: Any
+    reward: float
+    terminated: bool
+    truncated: bool
+    info: dict = field(default_factory=dict)
+    elapsed: float = 0.0
+    discount: Optional[float] = None
+    terminal_observation: Any = None
+    tasks: dict = field(default_factory=dict)
+    vector_observation: Optional[List[float]] = None
+
+
+@dataclass
+class AgentState:
+    reward: float
+    is_terminated: bool
+    is_truncated: bool
+    info: dict = field(default_factory=dict)
+    vector_observation: Optional[List[float]] = None
+
+
+class BevyRlClient:
+    def __init__(self, url: str = "http://127.0.0.1:7878", session: Optional[requests.Session] = None, token: Optional[str] = None):
+        self.url = url.rstrip("/")
+        self.session = session or requests.Session()
+        if token is not None:
+            self.session.headers["Authorization"] = f"Bearer {token}"
+
+    def _request(self, method: str, path: str, params: dict, body: Any = None) -> requests.Response:
+        params = {k: v for k, v in params.items() if v is not None}
+        response = self.session.request(method, self.url + path, params=params, json=body)
+        response.raise_for_status()
+        return response
+"#,
+    );
+
+    for endpoint in ENDPOINTS {
+        let args: Vec<String> = endpoint
+            .params
+            .iter()
+            .map(|p| {
+                if p.optional {
+                    format!("{}: Any = None", p.name)
+                } else {
+                    format!("{}: Any", p.name)
+                }
+            })
+            .collect();
+        let params: Vec<String> = endpoint
+            .params
+            .iter()
+            .filter(|p| !p.in_path)
+            .map(|p| {
+                let value = if p.json && p.optional {
+                    format!("None if {0} is None else json.dumps({0})", p.name)
+                } else if p.json {
+                    format!("json.dumps({})", p.name)
+                } else {
+                    p.name.to_string()
+                };
+                format!("\"{}\": {}", p.name, value)
+            })
+            .collect();
+        let (return_type, conversion) = match endpoint.response {
+            Response::Json => ("Any", "response.json()"),
+            Response::Png => ("bytes", "response.content"),
+            Response::Raw => ("bytes", "response.content"),
+            Response::Transitions => (
+                "List[AgentTransition]",
+                "[AgentTransition(**t) for t in response.json()]",
+            ),
+            Response::VecTransitions => (
+                "List[List[AgentTransition]]",
+                "[[AgentTransition(**t) for t in env] for env in response.json()]",
+            ),
+            Response::AgentStates => (
+                "List[AgentState]",
+                "[AgentState(**s) for s in response.json()]",
+            ),
+            Response::VecAgentStates => (
+                "List[List[AgentState]]",
+                "[[AgentState(**s) for s in env] for env in response.json()]",
+            ),
+        };
+
+        // JSON body goes first, it's what the request is about
+        let body = if endpoint.body { "body" } else { "None" };
+        let signature = std::iter::once("self".to_string())
+            .chain(endpoint.body.then(|| "body: Any".to_string()))
+            .chain(args)
+            .collect::<Vec<_>>()
+            .join(", ");
+        // Path parameters are filled in by an f-string
+        let path = if endpoint.params.iter().any(|p| p.in_path) {
+            format!("f\"{}\"", endpoint.path)
+        } else {
+            format!("\"{}\"", endpoint.path)
+        };
+        code.push_str(&format!(
+            "\n    def {}({}) -> {}:\n        response = self._request(\"{}\", {}, {{{}}}, {})\n        return {}\n",
+            endpoint.name,
+            signature,
+            return_type,
+            endpoint.method.as_str(),
+            path,
+            params.join(", "),
+            body,
+            conversion,
+        ));
+    }
+
+    code
+}
+
+/// TypeScript client: interfaces for transitions and agent states and a `fetch`-based client
+pub fn typescript_client() -> String {
+    let mut code = String::from(
+        r#"// Generated by bevy_rl::codegen, do not edit
+
+export interface AgentTransition<O = unknown> {
+  observation: O | null;
+  reward: number;
+  terminated: boolean;
+  truncated: boolean;
+  info: Record<string, unknown>;
+  elapsed: number;
+  discount?: number;
+  terminal_observation?: O;
+  tasks?: Record<string, { reward: number; terminated: boolean }>;
+  vector_observation?: number[];
+}
+
+export interface AgentState {
+  reward: number;
+  is_terminated: boolean;
+  is_truncated: boolean;
+  info: Record<string, unknown>;
+  vector_observation?: number[];
+}
+
+export class BevyRlClient {
+  constructor(private url: string = "http://127.0.0.1:7878", private token?: string) {}
+
+  private async request(method: string, path: string, params: Record<string, unknown>, body?: unknown): Promise<Response> {
+    const query = new URLSearchParams();
+    for (const [key, value] of Object.entries(params)) {
+      if (value !== undefined && value !== null) {
+        query.set(key, String(value));
+      }
+    }
+    const headers: Record<string, string> = this.token ? { Authorization: `Bearer ${this.token}` } : {};
+    if (body !== undefined) {
+      headers["Content-Type"] = "application/json";
+    }
+    const response = await fetch(`${this.url}${path}?${query}`, {
+      method,
+      headers,
+      body: body === undefined ? undefined : JSON.stringify(body),
+    });
+    if (!response.ok) {
+      throw new Error(`${path}: ${response.status} ${await response.text()}`);
+    }
+    return response;
+  }
+"#,
+    );
+
+    for endpoint in ENDPOINTS {
+        let body_arg = endpoint.body.then(|| "body: unknown".to_string());
+        let args: Vec<String> = body_arg
+            .into_iter()
+            .chain(endpoint.params.iter().map(|p| {
+                let optional = if p.optional { "?" } else { "" };
+                let kind = if p.json {
+                    "unknown"
+                } else if p.text {
+                    "string"
+                } else {
+                    "number"
+                };
+                format!("{}{}: {}", to_camel_case(p.name), optional, kind)
+            }))
+            .collect();
+        let params: Vec<String> = endpoint
+            .params
+            .iter()
+            .filter(|p| !p.in_path)
+            .map(|p| {
+                let name = to_camel_case(p.name);
+                let value = if p.json && p.optional {
+                    format!("{name} === undefined ? undefined : JSON.stringify({name})")
+                } else if p.json {
+                    format!("JSON.stringify({name})")
+                } else {
+                    name
+                };
+                format!("{}: {}", p.name, value)
+            })
+            .collect();
+        let (return_type, conversion) = match endpoint.response {
+            Response::Json => ("unknown", "response.json()"),
+            Response::Png => ("Blob", "response.blob()"),
+            Response::Raw => ("ArrayBuffer", "response.arrayBuffer()"),
+            Response::Transitions => ("AgentTransition[]", "response.json()"),
+            Response::VecTransitions => ("AgentTransition[][]", "response.json()"),
+            Response::AgentStates => ("AgentState[]", "response.json()"),
+            Response::VecAgentStates => ("AgentState[][]", "response.json()"),
+        };
+
+        // Path parameters are filled in by a template literal
+        let path = endpoint.params.iter().filter(|p| p.in_path).fold(
+            endpoint.path.to_string(),
+            |path, p| {
+                path.replace(
+                    &format!("{{{}}}", p.name),
+                    &format!("${{{}}}", to_camel_case(p.name)),
+                )
+            },
+        );
+        code.push_str(&format!(
+            "\n  async {}({}): Promise<{}> {{\n    const response = await this.request(\"{}\", `{}`, {{ {} }}{});\n    return {};\n  }}\n",
+            to_camel_case(endpoint.name),
+            args.join(", "),
+            return_type,
+            endpoint.method.as_str(),
+            path,
+            params.join(", "),
+            if endpoint.body { ", body" } else { "" },
+            conversion,
+        ));
+    }
+
+    code.push_str("}\n");
+    code
+}
+
+/// OpenAPI 3 document of `ENDPOINTS`, served at `/openapi.json`. `action` and `state` are
+/// JSON schemas of the environment's action and state types, if they are registered
+pub fn openapi(
+    action: Option<&serde_json::Value>,
+    state: Option<&serde_json::Value>,
+) -> serde_json::Value {
+    let mut paths = serde_json::Map::new();
+    for endpoint in ENDPOINTS {
+        let parameters: Vec<serde_json::Value> = endpoint
+            .params
+            .iter()
+            .map(|p| {
+                let schema = if p.name == "payload" {
+                    json!({"$ref": "#/components/schemas/StepPayload"})
+                } else if p.json {
+                    json!({})
+                } else if p.text {
+                    json!({"type": "string"})
+                } else {
+                    json!({"type": "number"})
+                };
+                let location = if p.in_path { "path" } else { "query" };
+                // JSON parameters are sent as serialized strings
+                if p.json {
+                    json!({
+                        "name": p.name,
+                        "in": location,
+                        "required": !p.optional,
+                        "content": {"application/json": {"schema": schema}},
+                    })
+                } else {
+                    json!({
+                        "name": p.name,
+                        "in": location,
+                        "required": !p.optional,
+                        "schema": schema,
+                    })
+                }
+            })
+            .collect();
+
+        let schema = |name: &str| json!({"$ref": format!("#/components/schemas/{name}")});
+        let list = |item: serde_json::Value| json!({"type": "array", "items": item});
+        let content = match endpoint.response {
+            Response::Json if endpoint.name == "state" => {
+                json!({"application/json": {"schema": schema("State")}})
+            }
+            Response::Json => json!({"application/json": {"schema": {}}}),
+            Response::Png => {
+                json!({"image/png": {"schema": {"type": "string", "format": "binary"}}})
+            }
+            Response::Raw => json!({
+                "application/octet-stream": {"schema": {"type": "string", "format": "binary"}}
+            }),
+            Response::Transitions => {
+                json!({"application/json": {"schema": list(schema("AgentTransition"))}})
+            }
+            Response::VecTransitions => {
+                json!({"application/json": {"schema": list(list(schema("AgentTransition")))}})
+            }
+            Response::AgentStates => {
+                json!({"application/json": {"schema": list(schema("AgentState"))}})
+            }
+            Response::VecAgentStates => {
+                json!({"application/json": {"schema": list(list(schema("AgentState")))}})
+            }
+        };
+
+        let mut operation = json!({
+            "operationId": to_camel_case(endpoint.name),
+            "parameters": parameters,
+            "responses": {"200": {"description": "OK", "content": content}},
+        });
+        if endpoint.body {
+            operation["requestBody"] =
+                json!({"required": true, "content": {"application/json": {"schema": {}}}});
+        }
+        // Routes with several methods share a path item
+        let path_item = paths
+            .entry(endpoint.path.to_string())
+            .or_insert_with(|| json!({}));
+        path_item[endpoint.method.as_str().to_lowercase()] = operation;
+    }
+
+    let task_outcome = json!({
+        "type": "object",
+        "properties": {"reward": {"type": "number"}, "terminated": {"type": "boolean"}},
+    });
+    json!({
+        "openapi": "3.0.3",
+        "info": {"title": "bevy_rl", "version": env!("CARGO_PKG_VERSION")},
+        "paths": paths,
+        "components": {
+            "schemas": {
+                "Action": action.cloned().unwrap_or_else(|| json!({})),
+                "State": state.cloned().unwrap_or_else(|| json!({})),
+                "AgentAction": {
+                    "type": "object",
+                    "properties": {
+                        "action": {
+                            "type": "string",
+                            "nullable": true,
+                            "description": "Action serialized as JSON, see the Action schema",
+                        },
+                    },
+                },
+                "StepPayload": {"type": "array", "items": {"$ref": "#/components/schemas/AgentAction"}},
+                "AgentTransition": {
+                    "type": "object",
+                    "required": ["reward", "terminated", "truncated", "info", "elapsed"],
+                    "properties": {
+                        "observation": {"allOf": [{"$ref": "#/components/schemas/State"}], "nullable": true},
+                        "reward": {"type": "number"},
+                        "terminated": {"type": "boolean"},
+                        "truncated": {"type": "boolean"},
+                        "info": {"type": "object"},
+                        "elapsed": {"type": "number"},
+                        "discount": {"type": "number"},
+                        "terminal_observation": {"$ref": "#/components/schemas/State"},
+                        "tasks": {"type": "object", "additionalProperties": task_outcome},
+                        "vector_observation": {"type": "array", "items": {"type": "number"}},
+                    },
+                },
+                "AgentState": {
+                    "type": "object",
+                    "required": ["reward", "is_terminated", "is_truncated", "info"],
+                    "properties": {
+                        "reward": {"type": "number"},
+                        "is_terminated": {"type": "boolean"},
+                        "is_truncated": {"type": "boolean"},
+                        "info": {"type": "object"},
+                        "vector_observation": {"type": "array", "items": {"type": "number"}},
+                    },
+                },
+            },
+        },
+    })
+}
+
+pub(crate) fn to_camel_case(name: &str) -> String {
+    let mut parts = name.split('_');
+    let mut camel_case = parts.next().unwrap_or_default().to_string();
+    for part in parts {
+        let mut chars = part.chars();
+        if let Some(first) = chars.next() {
+            camel_case.extend(first.to_uppercase());
+            camel_case.push_str(chars.as_str());
+        }
+    }
+    camel_case
+}
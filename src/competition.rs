@@ -0,0 +1,214 @@
+//! Competition mode turns an environment into a self-hosted benchmark server.
+//! Clients are identified by a registered key sent in `X-Client-Key` header (remote address is
+//! used otherwise), each client gets a step budget and an episode limit, episodes are seeded
+//! with hidden seeds and scores are reported at `/leaderboard`.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+
+use serde::Serialize;
+
+/// Competition mode settings
+#[derive(Clone, Debug, Default)]
+pub struct CompetitionSettings {
+    /// Maximum number of steps a client can take
+    pub step_budget: Option<u64>,
+    /// Maximum number of episodes a client can start
+    pub max_episodes: Option<u64>,
+    /// Seeds for `AIGymRng` used for consecutive episodes of every client, never revealed to clients
+    pub seeds: Vec<u64>,
+    /// Names of clients on the leaderboard by their keys. Ids sent by clients aren't trusted,
+    /// a new one would give a fresh step budget
+    pub client_keys: HashMap<String, String>,
+}
+
+impl CompetitionSettings {
+    /// Name of the client with a registered `key`, its address otherwise
+    pub(crate) fn identify(&self, key: Option<&str>, addr: Option<IpAddr>) -> String {
+        key.and_then(|key| self.client_keys.get(key))
+            .cloned()
+            .or_else(|| addr.map(|addr| addr.to_string()))
+            .unwrap_or_default()
+    }
+}
+
+/// Client's entry in the leaderboard
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct ClientScore {
+    pub client: String,
+    pub steps: u64,
+    pub episodes: u64,
+    pub mean_return: f32,
+    pub best_return: Option<f32>,
+}
+
+#[derive(Clone, Debug, Default)]
+struct ClientRecord {
+    steps: u64,
+    episodes: u64,
+    finished_returns: Vec<f32>,
+    episode_return: f32,
+    episode_steps: u64,
+}
+
+impl ClientRecord {
+    fn finish_episode(&mut self) {
+        if self.episode_steps > 0 {
+            self.finished_returns.push(self.episode_return);
+        }
+        self.episode_return = 0.0;
+        self.episode_steps = 0;
+    }
+
+    /// Returns of finished episodes and the one in progress
+    fn returns(&self) -> Vec<f32> {
+        let mut returns = self.finished_returns.clone();
+        if self.episode_steps > 0 {
+            returns.push(self.episode_return);
+        }
+        returns
+    }
+}
+
+/// Per-client bookkeeping of competition mode
+#[derive(Clone, Debug, Default)]
+pub struct Leaderboard {
+    clients: HashMap<String, ClientRecord>,
+}
+
+impl Leaderboard {
+    /// Check whether the client can take another step
+    pub(crate) fn check_step(
+        &self,
+        settings: &CompetitionSettings,
+        client: &str,
+    ) -> Result<(), String> {
+        let steps = self.clients.get(client).map_or(0, |r| r.steps);
+        match settings.step_budget {
+            Some(step_budget) if steps >= step_budget => Err("Step budget exhausted".to_string()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Record client's step, `step_reward` is mean of agents' rewards of the step
+    pub(crate) fn record_step(&mut self, client: &str, step_reward: f32) {
+        let record = self.clients.entry(client.to_string()).or_default();
+        record.steps += 1;
+        record.episode_steps += 1;
+        record.episode_return += step_reward;
+    }
+
+    /// Start a new episode for the client, returns hidden seed for it. Every client gets seeds
+    /// in the same order, regardless of other clients' resets
+    pub(crate) fn start_episode(
+        &mut self,
+        settings: &CompetitionSettings,
+        client: &str,
+    ) -> Result<Option<u64>, String> {
+        let record = self.clients.entry(client.to_string()).or_default();
+        if let Some(max_episodes) = settings.max_episodes {
+            if record.episodes >= max_episodes {
+                return Err("Episode limit reached".to_string());
+            }
+        }
+
+        record.finish_episode();
+        let seed = if settings.seeds.is_empty() {
+            None
+        } else {
+            Some(settings.seeds[(record.episodes % settings.seeds.len() as u64) as usize])
+        };
+        record.episodes += 1;
+
+        Ok(seed)
+    }
+
+    /// Clients' scores sorted by mean return, best first
+    pub fn scores(&self) -> Vec<ClientScore> {
+        let mut scores: Vec<ClientScore> = self
+            .clients
+            .iter()
+            .map(|(client, record)| {
+                let returns = record.returns();
+                let mean_return = if returns.is_empty() {
+                    0.0
+                } else {
+                    returns.iter().sum::<f32>() / returns.len() as f32
+                };
+                ClientScore {
+                    client: client.clone(),
+                    steps: record.steps,
+                    episodes: record.episodes,
+                    mean_return,
+                    best_return: returns.into_iter().reduce(f32::max),
+                }
+            })
+            .collect();
+
+        scores.sort_by(|a, b| b.mean_return.total_cmp(&a.mean_return));
+        scores
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scores_are_sorted_by_mean_return() {
+        let settings = CompetitionSettings::default();
+        let mut leaderboard = Leaderboard::default();
+
+        leaderboard.start_episode(&settings, "a").unwrap();
+        leaderboard.record_step("a", 1.0);
+        leaderboard.record_step("a", 1.0);
+        leaderboard.start_episode(&settings, "a").unwrap();
+        leaderboard.record_step("a", 4.0);
+
+        leaderboard.start_episode(&settings, "b").unwrap();
+        leaderboard.record_step("b", 1.0);
+        // Reset without steps doesn't count as an episode with zero return
+        leaderboard.start_episode(&settings, "b").unwrap();
+
+        let scores = leaderboard.scores();
+        assert_eq!(scores.len(), 2);
+
+        assert_eq!(scores[0].client, "a");
+        assert_eq!(scores[0].steps, 3);
+        assert_eq!(scores[0].episodes, 2);
+        assert_eq!(scores[0].mean_return, 3.0);
+        assert_eq!(scores[0].best_return, Some(4.0));
+
+        assert_eq!(scores[1].client, "b");
+        assert_eq!(scores[1].episodes, 2);
+        assert_eq!(scores[1].mean_return, 1.0);
+        assert_eq!(scores[1].best_return, Some(1.0));
+    }
+
+    #[test]
+    fn clients_get_the_same_seeds() {
+        let settings = CompetitionSettings {
+            seeds: vec![1, 2, 3],
+            ..Default::default()
+        };
+        let mut leaderboard = Leaderboard::default();
+
+        assert_eq!(leaderboard.start_episode(&settings, "a"), Ok(Some(1)));
+        assert_eq!(leaderboard.start_episode(&settings, "b"), Ok(Some(1)));
+        assert_eq!(leaderboard.start_episode(&settings, "a"), Ok(Some(2)));
+        assert_eq!(leaderboard.start_episode(&settings, "b"), Ok(Some(2)));
+    }
+
+    #[test]
+    fn clients_are_identified_by_registered_keys() {
+        let settings = CompetitionSettings {
+            client_keys: HashMap::from([("secret".to_string(), "team-a".to_string())]),
+            ..Default::default()
+        };
+        let addr = Some(IpAddr::from([10, 0, 0, 1]));
+
+        assert_eq!(settings.identify(Some("secret"), addr), "team-a");
+        assert_eq!(settings.identify(Some("guess"), addr), "10.0.0.1");
+        assert_eq!(settings.identify(None, addr), "10.0.0.1");
+    }
+}
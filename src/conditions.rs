@@ -0,0 +1,27 @@
+//! Run conditions for environment systems.
+//! They follow the plugin's own transition timing, so systems gated with them
+//! see the same frames as bevy_rl does.
+
+use bevy::prelude::*;
+
+use crate::{state::AIGymState, EventControl, SimulationState};
+
+/// Simulation is running and collecting experience, i.e. not paused for control
+pub fn run_if_collecting(simulation_state: Option<Res<State<SimulationState>>>) -> bool {
+    simulation_state.is_some_and(|state| *state.get() == SimulationState::Running)
+}
+
+/// At least one agent is neither terminated nor truncated
+pub fn run_if_episode_active<
+    T: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::de::DeserializeOwned,
+    P: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::Serialize,
+>(
+    ai_gym_state: Res<AIGymState<T, P>>,
+) -> bool {
+    !ai_gym_state.lock().unwrap().is_episode_done()
+}
+
+/// Actions of a control step (from the trainer, local input or a missed deadline) arrived this frame
+pub fn run_every_control_step(mut control_event_reader: EventReader<EventControl>) -> bool {
+    control_event_reader.read().count() > 0
+}
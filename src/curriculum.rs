@@ -0,0 +1,158 @@
+//! Built-in curriculum engine.
+//! Stages are unlocked one after another at episode boundaries, once their condition holds.
+//! Unlocking a stage updates `AIGymStateInner.parameters` and fires `EventConfigure`
+//! so the environment can rebuild itself with new parameters on reset.
+//! Trainers running their own curriculum push parameters with `PUT /curriculum` instead,
+//! they are kept in `CurriculumState` and announced with `EventCurriculumUpdate`.
+
+use std::collections::{HashMap, VecDeque};
+
+use bevy::prelude::*;
+
+use crate::{state, EventConfigure};
+
+/// Curriculum parameters pushed by the trainer with `PUT /curriculum`, updated at episode
+/// boundaries. The payload is the JSON body as is, read it as your own type with `get`
+#[derive(Resource, Clone, Debug, Default)]
+pub struct CurriculumState {
+    pub payload: Option<serde_json::Value>,
+    /// Number of updates so far
+    pub version: u64,
+}
+
+impl CurriculumState {
+    /// Payload deserialized as `C`, `None` if there is none or it doesn't match
+    pub fn get<C: serde::de::DeserializeOwned>(&self) -> Option<C> {
+        self.payload
+            .clone()
+            .and_then(|payload| serde_json::from_value(payload).ok())
+    }
+}
+
+/// Condition to unlock a curriculum stage
+#[derive(Clone, Debug)]
+pub enum CurriculumCondition {
+    /// Number of finished episodes is at least given value
+    EpisodeCount(u64),
+    /// Moving average of episode return (mean over agents) exceeds given value
+    MeanReturnAbove(f32),
+}
+
+/// A curriculum stage: parameters applied once `condition` holds
+#[derive(Clone, Debug)]
+pub struct CurriculumStage {
+    pub condition: CurriculumCondition,
+    pub parameters: HashMap<String, f32>,
+}
+
+/// Curriculum schedule. Insert it as resource to enable curriculum
+#[derive(Resource, Clone, Debug)]
+pub struct Curriculum {
+    pub stages: Vec<CurriculumStage>,
+    /// Number of episodes in moving average of return
+    pub window: usize,
+
+    current_stage: usize,
+    episodes: u64,
+    returns: VecDeque<f32>,
+}
+
+impl Curriculum {
+    pub fn new(stages: Vec<CurriculumStage>, window: usize) -> Self {
+        Self {
+            stages,
+            window: window.max(1),
+            current_stage: 0,
+            episodes: 0,
+            returns: VecDeque::new(),
+        }
+    }
+
+    /// Number of stages unlocked so far
+    pub fn current_stage(&self) -> usize {
+        self.current_stage
+    }
+
+    /// Number of finished episodes
+    pub fn episodes(&self) -> u64 {
+        self.episodes
+    }
+
+    /// Moving average of episode return
+    pub fn mean_return(&self) -> Option<f32> {
+        if self.returns.is_empty() {
+            return None;
+        }
+        Some(self.returns.iter().sum::<f32>() / self.returns.len() as f32)
+    }
+
+    /// Record a finished episode and unlock stages which conditions hold.
+    /// Returns parameters of unlocked stages merged in order
+    pub fn record_episode(&mut self, episode_return: f32) -> Option<HashMap<String, f32>> {
+        self.episodes += 1;
+        self.returns.push_back(episode_return);
+        while self.returns.len() > self.window {
+            self.returns.pop_front();
+        }
+
+        let mut parameters: Option<HashMap<String, f32>> = None;
+        while let Some(stage) = self.stages.get(self.current_stage) {
+            let unlocked = match stage.condition {
+                CurriculumCondition::EpisodeCount(count) => self.episodes >= count,
+                CurriculumCondition::MeanReturnAbove(value) => {
+                    self.returns.len() >= self.window
+                        && self.mean_return().is_some_and(|r| r > value)
+                }
+            };
+            if !unlocked {
+                break;
+            }
+
+            parameters
+                .get_or_insert_with(HashMap::new)
+                .extend(stage.parameters.clone());
+            self.current_stage += 1;
+        }
+
+        parameters
+    }
+}
+
+/// Advance curriculum when a reset is requested.
+/// Runs before `EventReset` is fired so that episode statistics still hold the finished episode
+pub(crate) fn update_curriculum<
+    T: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::de::DeserializeOwned,
+    P: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::Serialize,
+>(
+    ai_gym_state: Res<state::AIGymState<T, P>>,
+    mut curriculum: ResMut<Curriculum>,
+    mut configure_event_writer: EventWriter<EventConfigure>,
+) {
+    let mut ai_gym_state = ai_gym_state.lock().unwrap();
+    if !ai_gym_state.is_reset_request() {
+        return;
+    }
+
+    // Resets without steps (the initial one, repeated resets) don't finish an episode
+    let learning_agents = ai_gym_state.settings.learning_agents();
+    if learning_agents
+        .iter()
+        .all(|&agent_index| ai_gym_state.episode_stats[agent_index].episode_length == 0)
+    {
+        return;
+    }
+
+    // Mean return of learning agents, `rewards` only hold the last step
+    let episode_return = learning_agents
+        .iter()
+        .map(|&agent_index| ai_gym_state.episode_stats[agent_index].episode_return)
+        .sum::<f32>()
+        / learning_agents.len().max(1) as f32;
+
+    if let Some(parameters) = curriculum.record_episode(episode_return) {
+        for (name, value) in parameters {
+            ai_gym_state.set_parameter(&name, value);
+        }
+        configure_event_writer.send(EventConfigure(ai_gym_state.parameters.clone()));
+    }
+}
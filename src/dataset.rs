@@ -0,0 +1,238 @@
+//! Offline RL datasets in D4RL layout.
+//! Steps of learning agents are recorded with `ai_gym_state.record_dataset(encode_action)`
+//! and exported with `Dataset::write_hdf5` (`hdf5` feature) into `observations`, `actions`,
+//! `rewards`, `terminals` and `timeouts` arrays, one row per agent step, which offline RL
+//! libraries load directly.
+
+use std::sync::Arc;
+
+/// Step of an agent: observation the action was taken on and what followed it
+#[derive(Clone, Debug)]
+pub struct DatasetStep {
+    /// Stacked visual observations, `Dataset.frame_shape` bytes
+    pub visual_observation: Vec<u8>,
+    pub vector_observation: Option<Vec<f32>>,
+    pub action: Vec<f32>,
+    pub reward: f32,
+    pub terminal: bool,
+    /// Episode was truncated after the step
+    pub timeout: bool,
+}
+
+/// Recorded steps of all learning agents in order
+#[derive(Clone, Debug, Default)]
+pub struct Dataset {
+    /// `[frames, height, width, channels]` of visual observations, empty without them
+    pub frame_shape: Vec<usize>,
+    pub steps: Vec<DatasetStep>,
+}
+
+impl Dataset {
+    pub fn len(&self) -> usize {
+        self.steps.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.steps.is_empty()
+    }
+
+    /// Write D4RL arrays to an HDF5 file:
+    ///
+    /// | Key                        | Type      | Shape                                      |
+    /// | -------------------------- | --------- | ------------------------------------------ |
+    /// | `observations`             | `uint8`   | `[N, frames, height, width, channels]`     |
+    /// | `actions`                  | `float32` | `[N, action_size]`                         |
+    /// | `rewards`                  | `float32` | `[N]`                                      |
+    /// | `terminals`, `timeouts`    | `bool`    | `[N]`                                      |
+    /// | `infos/vector_observations`| `float32` | `[N, observation_size]`                    |
+    ///
+    /// Without visual observations `observations` holds vector observations instead
+    #[cfg(feature = "hdf5")]
+    pub fn write_hdf5(&self, path: impl AsRef<std::path::Path>) -> hdf5::Result<()> {
+        let n = self.steps.len();
+        let action_size = self.steps.first().map_or(0, |step| step.action.len());
+        if self
+            .steps
+            .iter()
+            .any(|step| step.action.len() != action_size)
+        {
+            return Err("actions of all steps must have the same size".into());
+        }
+
+        let vector_size = self
+            .steps
+            .first()
+            .and_then(|step| step.vector_observation.as_ref())
+            .map(Vec::len);
+        let vector_observations: Option<Vec<f32>> = match vector_size {
+            Some(size) => {
+                let values: Option<Vec<Vec<f32>>> = self
+                    .steps
+                    .iter()
+                    .map(|step| {
+                        step.vector_observation
+                            .clone()
+                            .filter(|observation| observation.len() == size)
+                    })
+                    .collect();
+                Some(
+                    values
+                        .ok_or("vector observations of all steps must have the same size")?
+                        .concat(),
+                )
+            }
+            None => None,
+        };
+
+        let file = hdf5::File::create(path)?;
+        if let [frames, height, width, channels] = self.frame_shape[..] {
+            let frame_size = frames * height * width * channels;
+            if self
+                .steps
+                .iter()
+                .any(|step| step.visual_observation.len() != frame_size)
+            {
+                return Err("visual observations don't match frame_shape".into());
+            }
+            let observations: Vec<u8> = self
+                .steps
+                .iter()
+                .flat_map(|step| step.visual_observation.iter().copied())
+                .collect();
+            file.new_dataset::<u8>()
+                .shape((n, frames, height, width, channels))
+                .create("observations")?
+                .write_raw(&observations)?;
+
+            if let (Some(size), Some(values)) = (vector_size, &vector_observations) {
+                file.create_group("infos")?
+                    .new_dataset::<f32>()
+                    .shape((n, size))
+                    .create("vector_observations")?
+                    .write_raw(values)?;
+            }
+        } else {
+            let (size, values) = vector_size
+                .zip(vector_observations)
+                .ok_or("steps have neither visual nor vector observations")?;
+            file.new_dataset::<f32>()
+                .shape((n, size))
+                .create("observations")?
+                .write_raw(&values)?;
+        }
+
+        let actions: Vec<f32> = self
+            .steps
+            .iter()
+            .flat_map(|step| step.action.iter().copied())
+            .collect();
+        file.new_dataset::<f32>()
+            .shape((n, action_size))
+            .create("actions")?
+            .write_raw(&actions)?;
+
+        let rewards: Vec<f32> = self.steps.iter().map(|step| step.reward).collect();
+        let terminals: Vec<bool> = self.steps.iter().map(|step| step.terminal).collect();
+        let timeouts: Vec<bool> = self.steps.iter().map(|step| step.timeout).collect();
+        file.new_dataset::<f32>()
+            .shape(n)
+            .create("rewards")?
+            .write_raw(&rewards)?;
+        file.new_dataset::<bool>()
+            .shape(n)
+            .create("terminals")?
+            .write_raw(&terminals)?;
+        file.new_dataset::<bool>()
+            .shape(n)
+            .create("timeouts")?
+            .write_raw(&timeouts)?;
+
+        file.close()
+    }
+}
+
+/// Observation and action of an agent at the start of a step, waiting for its outcome
+#[derive(Clone)]
+struct PendingStep {
+    visual_observation: Vec<u8>,
+    vector_observation: Option<Vec<f32>>,
+    action: Vec<f32>,
+}
+
+/// Records steps of agents into a `Dataset` while the environment runs
+#[derive(Clone)]
+pub(crate) struct DatasetRecorder<A> {
+    encode_action: Arc<dyn Fn(&A) -> Vec<f32> + Send + Sync>,
+    pending: Vec<Option<PendingStep>>,
+    pub(crate) dataset: Dataset,
+}
+
+impl<A> DatasetRecorder<A> {
+    pub(crate) fn new(encode_action: impl Fn(&A) -> Vec<f32> + Send + Sync + 'static) -> Self {
+        Self {
+            encode_action: Arc::new(encode_action),
+            pending: Vec::new(),
+            dataset: Dataset::default(),
+        }
+    }
+
+    /// Keep the observation an agent acts on. `frames` are its stacked visual observations
+    pub(crate) fn begin_step(
+        &mut self,
+        agent_index: usize,
+        frames: &[image::DynamicImage],
+        vector_observation: Option<Vec<f32>>,
+        action: &A,
+    ) {
+        // 16-bit frames are stored as 8-bit ones of the same channels
+        let frames: Vec<image::DynamicImage> = frames
+            .iter()
+            .map(|frame| match frame.color().channel_count() {
+                1 => frame.to_luma8().into(),
+                3 => frame.to_rgb8().into(),
+                _ => frame.to_rgba8().into(),
+            })
+            .collect();
+        if let Some(frame) = frames.first() {
+            self.dataset.frame_shape = vec![
+                frames.len(),
+                frame.height() as usize,
+                frame.width() as usize,
+                frame.color().channel_count() as usize,
+            ];
+        }
+
+        if self.pending.len() <= agent_index {
+            self.pending.resize(agent_index + 1, None);
+        }
+        self.pending[agent_index] = Some(PendingStep {
+            visual_observation: frames
+                .iter()
+                .flat_map(|frame| frame.as_bytes().to_vec())
+                .collect(),
+            vector_observation,
+            action: (self.encode_action)(action),
+        });
+    }
+
+    /// Record the step of an agent begun with `begin_step` once its transition is known
+    pub(crate) fn finish_step(
+        &mut self,
+        agent_index: usize,
+        reward: f32,
+        terminal: bool,
+        timeout: bool,
+    ) {
+        let Some(pending) = self.pending.get_mut(agent_index).and_then(Option::take) else {
+            return;
+        };
+        self.dataset.steps.push(DatasetStep {
+            visual_observation: pending.visual_observation,
+            vector_observation: pending.vector_observation,
+            action: pending.action,
+            reward,
+            terminal,
+            timeout,
+        });
+    }
+}
@@ -0,0 +1,271 @@
+//! gRPC control interface, enabled with `grpc` cargo feature.
+//! It exposes the same operations as REST API (see `proto/bevy_rl.proto`) and shares
+//! the channel-based synchronization with it, so both can be used at the same time.
+
+use std::net::SocketAddr;
+use std::thread;
+
+use serde_json::json;
+use tonic::{Request, Response, Status};
+
+use crate::api::{AgentAction, AgentState, ApiError, GothamState};
+use crate::state::ResetRequest;
+use crate::AIGymSettings;
+
+pub mod proto {
+    tonic::include_proto!("bevy_rl");
+}
+
+use proto::environment_server::{Environment, EnvironmentServer};
+
+struct EnvironmentService<
+    T: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::de::DeserializeOwned,
+    P: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::Serialize,
+> {
+    gotham_state: GothamState<T, P>,
+}
+
+#[tonic::async_trait]
+impl<
+        T: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::de::DeserializeOwned,
+        P: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::Serialize,
+    > Environment for EnvironmentService<T, P>
+{
+    async fn step(
+        &self,
+        request: Request<proto::StepRequest>,
+    ) -> Result<Response<proto::StepResponse>, Status> {
+        let client = client_id(&request, &self.gotham_state.settings);
+        let actions = request
+            .into_inner()
+            .actions
+            .into_iter()
+            .map(|a| AgentAction { action: a.action })
+            .collect();
+
+        // Engine calls block on crossbeam channels, keep them off the async workers
+        let gotham_state = self.gotham_state.clone();
+        let transitions = tokio::task::spawn_blocking(move || gotham_state.step(&client, actions))
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?
+            .map_err(Status::from)?;
+
+        let transitions = transitions
+            .into_iter()
+            .map(|t| proto::AgentTransition {
+                observation_json: json!(t.observation).to_string(),
+                reward: t.reward,
+                terminated: t.terminated,
+                truncated: t.truncated,
+                info_json: t.info.to_string(),
+                elapsed: t.elapsed,
+                discount: t.discount,
+                tasks_json: json!(t.tasks).to_string(),
+                vector_observation: t.vector_observation.unwrap_or_default(),
+            })
+            .collect();
+
+        Ok(Response::new(proto::StepResponse { transitions }))
+    }
+
+    async fn reset(
+        &self,
+        request: Request<proto::ResetRequest>,
+    ) -> Result<Response<proto::ResetResponse>, Status> {
+        let client = client_id(&request, &self.gotham_state.settings);
+        let request = request.into_inner();
+        let options = request
+            .options_json
+            .as_deref()
+            .map(serde_json::from_str)
+            .transpose()
+            .map_err(|e| Status::invalid_argument(e.to_string()))?;
+        let metadata = request
+            .metadata_json
+            .as_deref()
+            .map(serde_json::from_str)
+            .transpose()
+            .map_err(|e| Status::invalid_argument(e.to_string()))?
+            .unwrap_or_default();
+        let request = ResetRequest {
+            seed: request.seed,
+            options,
+            metadata,
+            ..Default::default()
+        };
+
+        let gotham_state = self.gotham_state.clone();
+        let agent_states =
+            tokio::task::spawn_blocking(move || gotham_state.reset(&client, request))
+                .await
+                .map_err(|e| Status::internal(e.to_string()))?
+                .map_err(Status::from)?;
+
+        let agents = agent_states.into_iter().map(agent_state_proto).collect();
+
+        Ok(Response::new(proto::ResetResponse { agents }))
+    }
+
+    async fn reset_agents(
+        &self,
+        request: Request<proto::ResetAgentsRequest>,
+    ) -> Result<Response<proto::ResetResponse>, Status> {
+        let client = client_id(&request, &self.gotham_state.settings);
+        let agent_indices = request
+            .into_inner()
+            .agents
+            .into_iter()
+            .map(|i| i as usize)
+            .collect();
+
+        let gotham_state = self.gotham_state.clone();
+        let agent_states =
+            tokio::task::spawn_blocking(move || gotham_state.reset_agents(&client, agent_indices))
+                .await
+                .map_err(|e| Status::internal(e.to_string()))?
+                .map_err(Status::from)?;
+
+        Ok(Response::new(proto::ResetResponse {
+            agents: agent_states.into_iter().map(agent_state_proto).collect(),
+        }))
+    }
+
+    async fn get_state(
+        &self,
+        _request: Request<proto::GetStateRequest>,
+    ) -> Result<Response<proto::GetStateResponse>, Status> {
+        let env_state = self
+            .gotham_state
+            .inner
+            .lock()
+            .unwrap()
+            .environment_state
+            .clone();
+
+        Ok(Response::new(proto::GetStateResponse {
+            state_json: json!(env_state).to_string(),
+        }))
+    }
+
+    async fn get_visual_observations(
+        &self,
+        _request: Request<proto::GetVisualObservationsRequest>,
+    ) -> Result<Response<proto::GetVisualObservationsResponse>, Status> {
+        let observations = self.gotham_state.inner.lock().unwrap().observations.clone();
+        if observations.unavailable {
+            return Err(Status::unavailable(
+                "Observations of this step are unavailable, GPU readback failed",
+            ));
+        }
+        let screens = observations.visual.clone();
+
+        let observations = screens
+            .into_iter()
+            .map(|screen| proto::VisualObservation {
+                width: screen.width(),
+                height: screen.height(),
+                rgba: screen.into_rgba8().into_raw(),
+            })
+            .collect();
+
+        Ok(Response::new(proto::GetVisualObservationsResponse {
+            observations,
+        }))
+    }
+}
+
+impl From<ApiError> for Status {
+    fn from(error: ApiError) -> Self {
+        match error {
+            ApiError::BadRequest(message) => Status::invalid_argument(message),
+            ApiError::Unauthorized(message) => Status::unauthenticated(message),
+            ApiError::NotFound(message) => Status::not_found(message),
+            ApiError::Conflict(message) => Status::failed_precondition(message),
+            ApiError::Internal(message) => Status::internal(message),
+            ApiError::Unavailable(message) => Status::unavailable(message),
+            ApiError::Timeout { message, .. } => Status::deadline_exceeded(message),
+        }
+    }
+}
+
+fn agent_state_proto(agent_state: AgentState) -> proto::AgentState {
+    proto::AgentState {
+        reward: agent_state.reward,
+        is_terminated: agent_state.is_terminated,
+        is_truncated: agent_state.is_truncated,
+        info_json: agent_state.info.to_string(),
+        vector_observation: agent_state.vector_observation.unwrap_or_default(),
+    }
+}
+
+/// Identify the client by `x-client-id` metadata, falling back to its address. In competition
+/// mode the metadata isn't trusted, clients are identified by their registered key or address
+fn client_id<R>(request: &Request<R>, settings: &AIGymSettings) -> String {
+    let metadata = |name: &str| {
+        request
+            .metadata()
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+    };
+    let addr = request.remote_addr().map(|addr| addr.ip());
+    if let Some(competition) = &settings.competition {
+        return competition.identify(metadata("x-client-key"), addr);
+    }
+
+    metadata("x-client-id")
+        .map(str::to_string)
+        .or_else(|| addr.map(|addr| addr.to_string()))
+        .unwrap_or_default()
+}
+
+/// Bind gRPC server's address synchronously, so that startup fails loudly if it's taken
+pub(crate) fn bind_listener(address: SocketAddr) -> std::io::Result<std::net::TcpListener> {
+    let listener = std::net::TcpListener::bind(address)?;
+    listener.set_nonblocking(true)?;
+    Ok(listener)
+}
+
+/// Serve gRPC on a bound listener in a separate thread with its own async runtime
+pub(crate) fn serve<
+    T: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::de::DeserializeOwned,
+    P: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::Serialize,
+>(
+    listener: std::net::TcpListener,
+    gotham_state: GothamState<T, P>,
+) {
+    thread::spawn(move || {
+        let runtime = match tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+        {
+            Ok(runtime) => runtime,
+            Err(e) => {
+                bevy::log::error!("bevy_rl: gRPC server not started: {e}");
+                return;
+            }
+        };
+
+        runtime.block_on(async move {
+            let incoming = tokio::net::TcpListener::from_std(listener)
+                .map_err(|e| e.into())
+                .and_then(|listener| {
+                    tonic::transport::server::TcpIncoming::from_listener(listener, true, None)
+                });
+            let incoming = match incoming {
+                Ok(incoming) => incoming,
+                Err(e) => {
+                    bevy::log::error!("bevy_rl: gRPC server stopped: {e}");
+                    return;
+                }
+            };
+
+            if let Err(e) = tonic::transport::Server::builder()
+                .add_service(EnvironmentServer::new(EnvironmentService { gotham_state }))
+                .serve_with_incoming(incoming)
+                .await
+            {
+                bevy::log::error!("bevy_rl: gRPC server stopped: {e}");
+            }
+        });
+    });
+}
@@ -0,0 +1,81 @@
+//! In-process gym interface.
+//! Policies trained in the same process as Bevy (e.g. with candle or tch) can drive the environment
+//! through `GymHandle` without going through REST API. It uses the same channels as REST API,
+//! so calls block until the engine processes them and must be made outside of the engine thread.
+
+use serde::Serialize;
+
+use crate::state::{AIGymState, AgentTransition, ResetRequest};
+
+/// Gym interface of an environment
+pub trait GymEnv {
+    type Action;
+    type Observation;
+
+    /// Apply actions (`None` to skip an agent) and return transitions of the next control step
+    fn step(&self, actions: Vec<Option<Self::Action>>) -> Vec<AgentTransition<Self::Observation>>;
+
+    /// Reset the environment (optionally with a seed and options) and return agents' initial transitions
+    fn reset(&self, request: ResetRequest) -> Vec<AgentTransition<Self::Observation>>;
+
+    /// Current environment state
+    fn observations(&self) -> Option<Self::Observation>;
+
+    /// Current visual observations of agents
+    fn visual_observations(&self) -> Vec<image::DynamicImage>;
+}
+
+/// `GymEnv` implementation backed by `AIGymState`. Calls panic once the engine has stopped,
+/// use `AIGymState::request_step` and `request_reset` directly to handle that
+#[derive(Clone)]
+pub struct GymHandle<
+    A: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::de::DeserializeOwned,
+    B: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe,
+>(pub AIGymState<A, B>);
+
+impl<
+        A: 'static
+            + Send
+            + Sync
+            + Clone
+            + std::panic::RefUnwindSafe
+            + serde::de::DeserializeOwned
+            + Serialize,
+        B: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe,
+    > GymEnv for GymHandle<A, B>
+{
+    type Action = A;
+    type Observation = B;
+
+    fn step(&self, actions: Vec<Option<A>>) -> Vec<AgentTransition<B>> {
+        // Actions travel the same way as REST API ones, so environment gets them in both
+        // `EventControl` and `EventControlTyped`
+        let actions = actions
+            .iter()
+            .map(|action| {
+                action
+                    .as_ref()
+                    .map(|action| serde_json::to_string(action).unwrap())
+            })
+            .collect();
+
+        self.0
+            .request_step(actions)
+            .expect("bevy_rl: engine has stopped")
+    }
+
+    fn reset(&self, request: ResetRequest) -> Vec<AgentTransition<B>> {
+        self.0
+            .request_reset(request)
+            .expect("bevy_rl: engine has stopped");
+        self.0.lock().unwrap().transitions()
+    }
+
+    fn observations(&self) -> Option<B> {
+        self.0.lock().unwrap().environment_state.clone()
+    }
+
+    fn visual_observations(&self) -> Vec<image::DynamicImage> {
+        self.0.lock().unwrap().visual_observations.clone()
+    }
+}
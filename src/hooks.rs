@@ -0,0 +1,141 @@
+//! Callback alternative to handling bevy_rl events.
+//! Implement `EnvHooks` for your environment and add `EnvHooksPlugin`: it reads `EventReset`,
+//! `EventControlTyped` and `EventPause`, calls the hooks, and does the bookkeeping environments
+//! otherwise write by hand: switching `SimulationState` back to `Running`, publishing the state
+//! with `set_env_state`, adding rewards and resetting bevy_rl state with `reset`, which closes
+//! episodes and answers reset requests.
+
+use std::sync::Mutex;
+
+use bevy::ecs::event::EventCursor;
+use bevy::prelude::*;
+
+use crate::{state, BevyRlSet, EventControlTyped, EventPause, EventReset, SimulationState};
+
+/// Environment logic called by `EnvHooksPlugin`, with exclusive access to the world
+pub trait EnvHooks<A, B>: Send + Sync + 'static {
+    /// Rebuild the environment for a new episode. `AIGymRng` is already reseeded
+    fn on_reset(&mut self, world: &mut World, reset: &EventReset);
+
+    /// Apply actions of a control interval, `None` for agents without one
+    fn on_action(&mut self, world: &mut World, actions: &[Option<A>]);
+
+    /// Environment state served to the trainer, collected after resets and on pauses
+    fn collect_state(&mut self, world: &mut World) -> B;
+
+    /// Rewards of agents earned since the previous pause, by agent index
+    fn compute_rewards(&mut self, world: &mut World) -> Vec<f32>;
+}
+
+/// Drives the environment through `EnvHooks` instead of event handlers
+pub struct EnvHooksPlugin<
+    H: EnvHooks<T, P>,
+    T: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::de::DeserializeOwned,
+    P: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::Serialize,
+> {
+    // Moved into the app when the plugin is built
+    hooks: Mutex<Option<H>>,
+    _phantom: std::marker::PhantomData<(T, P)>,
+}
+
+impl<
+        H: EnvHooks<T, P>,
+        T: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::de::DeserializeOwned,
+        P: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::Serialize,
+    > EnvHooksPlugin<H, T, P>
+{
+    pub fn new(hooks: H) -> Self {
+        Self {
+            hooks: Mutex::new(Some(hooks)),
+            _phantom: std::marker::PhantomData,
+        }
+    }
+}
+
+impl<
+        H: EnvHooks<T, P>,
+        T: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::de::DeserializeOwned,
+        P: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::Serialize,
+    > Plugin for EnvHooksPlugin<H, T, P>
+{
+    fn build(&self, app: &mut App) {
+        let hooks = self
+            .hooks
+            .lock()
+            .unwrap()
+            .take()
+            .expect("EnvHooksPlugin can be added only once");
+
+        app.insert_resource(RegisteredEnvHooks(hooks)).add_systems(
+            Update,
+            run_env_hooks::<H, T, P>.in_set(BevyRlSet::ComputeRewards),
+        );
+    }
+}
+
+#[derive(Resource)]
+struct RegisteredEnvHooks<H>(H);
+
+/// Call hooks for events of the frame: pauses first, as `control_switch` fires them before
+/// requests are taken, then resets and actions
+fn run_env_hooks<
+    H: EnvHooks<T, P>,
+    T: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::de::DeserializeOwned,
+    P: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::Serialize,
+>(
+    world: &mut World,
+    mut pause_events: Local<EventCursor<EventPause>>,
+    mut reset_events: Local<EventCursor<EventReset>>,
+    mut control_events: Local<EventCursor<EventControlTyped<T>>>,
+) {
+    let pauses = pause_events
+        .read(world.resource::<Events<EventPause>>())
+        .count();
+    let resets: Vec<EventReset> = reset_events
+        .read(world.resource::<Events<EventReset>>())
+        .map(|reset| EventReset {
+            seed: reset.seed,
+            options: reset.options.clone(),
+            metadata: reset.metadata.clone(),
+        })
+        .collect();
+    let controls: Vec<Vec<Option<T>>> = control_events
+        .read(world.resource::<Events<EventControlTyped<T>>>())
+        .map(|control| control.0.clone())
+        .collect();
+    if pauses == 0 && resets.is_empty() && controls.is_empty() {
+        return;
+    }
+
+    let ai_gym_state = world.resource::<state::AIGymState<T, P>>().clone();
+    world.resource_scope(|world, mut hooks: Mut<RegisteredEnvHooks<H>>| {
+        if pauses > 0 {
+            let rewards = hooks.0.compute_rewards(world);
+            let env_state = hooks.0.collect_state(world);
+            let mut ai_gym_state = ai_gym_state.lock().unwrap();
+            let agents = ai_gym_state.rewards.len();
+            for (agent_index, reward) in rewards.into_iter().enumerate().take(agents) {
+                ai_gym_state.add_reward(agent_index, reward);
+            }
+            ai_gym_state.set_env_state(env_state);
+        }
+
+        for reset in resets.iter() {
+            hooks.0.on_reset(world, reset);
+            let env_state = hooks.0.collect_state(world);
+            let mut ai_gym_state = ai_gym_state.lock().unwrap();
+            ai_gym_state.set_env_state(env_state);
+            ai_gym_state.reset();
+        }
+
+        for actions in controls.iter() {
+            hooks.0.on_action(world, actions);
+        }
+
+        if !resets.is_empty() || !controls.is_empty() {
+            world
+                .resource_mut::<NextState<SimulationState>>()
+                .set(SimulationState::Running);
+        }
+    });
+}
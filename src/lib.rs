@@ -1,44 +1,540 @@
 // #![feature(associated_type_bounds)]
 
-use std::{marker::PhantomData, thread};
+use std::{collections::HashMap, marker::PhantomData, thread};
 
 use bevy::{
     prelude::*,
-    render::{view::RenderLayers, RenderApp, RenderSet},
+    render::{
+        camera::{ManualTextureView, ManualTextureViewHandle, ManualTextureViews},
+        renderer::RenderDevice,
+        view::RenderLayers,
+        RenderApp, RenderSet,
+    },
+    time::{TimeSystem, TimeUpdateStrategy},
+    window::PrimaryWindow,
 };
+use rand::{rngs::StdRng, SeedableRng};
 
 mod api;
+#[cfg(feature = "avian")]
+pub mod avian;
+pub mod codegen;
+pub mod competition;
+pub mod conditions;
+pub mod curriculum;
+pub mod dataset;
+#[cfg(feature = "grpc")]
+pub mod grpc;
+pub mod gym;
+pub mod hooks;
+pub mod lidar;
+pub mod metrics;
+pub mod overlay;
+#[cfg(feature = "onnx")]
+pub mod policy;
+pub mod preprocess;
+#[cfg(feature = "rapier")]
+pub mod rapier;
 pub mod render;
+pub mod replay;
+pub mod security;
+pub mod set_state;
+#[cfg(feature = "shared-memory")]
+pub mod shm;
+pub mod snapshot;
+pub mod spaces;
+pub mod spawn;
+pub mod spectator;
 pub mod state;
+pub mod teleop;
+pub mod tensorboard;
+pub mod testing;
+pub mod timelapse;
 
+pub use conditions::*;
 use render::copy_from_gpu_to_ram;
 pub use state::*;
-use wgpu::{Extent3d, TextureDescriptor, TextureDimension, TextureFormat, TextureUsages};
+use wgpu::{
+    Extent3d, TextureDescriptor, TextureDimension, TextureFormat, TextureUsages,
+    TextureViewDescriptor, TextureViewDimension,
+};
+
+/// When the simulation is paused to wait for actions
+#[derive(Clone, Copy, Debug, PartialEq, serde::Serialize)]
+#[serde(tag = "mode", rename_all = "snake_case")]
+pub enum StepMode {
+    /// Pause every `AIGymSettings.pause_interval` seconds of wall-clock time
+    Interval,
+    /// Advance exactly `frames_per_step` frames in `SimulationState::Running` after each step,
+    /// then stay paused until the next one. Stepping doesn't depend on frame rate
+    Lockstep { frames_per_step: u32 },
+    /// Like `Lockstep` with `substeps` frames, but `Time` advances exactly `dt` seconds per
+    /// running frame and stands still while paused, `FixedUpdate` runs once per frame.
+    /// Step k is at the same simulated time in every run regardless of machine load
+    FixedTimestep { dt: f32, substeps: u32 },
+}
+
+/// Channels kept in visual observations. Grayscale matches classic DQN preprocessing
+/// and quarters the size of observations
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ColorMode {
+    #[default]
+    Rgba,
+    Rgb,
+    Grayscale,
+}
+
+impl ColorMode {
+    /// Convert a frame to the color mode
+    pub fn convert(&self, image: image::DynamicImage) -> image::DynamicImage {
+        match self {
+            ColorMode::Rgba => image,
+            ColorMode::Rgb => image::DynamicImage::ImageRgb8(image.into_rgb8()),
+            ColorMode::Grayscale => image::DynamicImage::ImageLuma8(image.into_luma8()),
+        }
+    }
+}
+
+/// Naming of fields of per-agent records in responses
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum FieldNaming {
+    #[default]
+    SnakeCase,
+    CamelCase,
+}
+
+/// Shape of per-agent records returned by `/step`, `/reset`, their vectorized and WebSocket
+/// variants, so that bevy_rl fits an existing trainer protocol without a translating proxy.
+/// Only top-level fields of records change, environment state and info are kept as is
+#[derive(Clone, Debug, Default)]
+pub struct ResponseFields {
+    pub naming: FieldNaming,
+    /// Renames applied after `naming`, by bevy_rl name, e.g. `"reward" -> "r"`
+    pub renames: HashMap<String, String>,
+    /// Fields left out, by bevy_rl name
+    pub exclude: Vec<String>,
+    /// Add `done` (terminated or truncated) of the gym API before 0.26
+    pub legacy_done: bool,
+}
+
+impl ResponseFields {
+    /// Reshape records of a serialized response, in lists or lists of lists
+    pub fn shape(&self, value: serde_json::Value) -> serde_json::Value {
+        match value {
+            serde_json::Value::Array(values) => {
+                values.into_iter().map(|value| self.shape(value)).collect()
+            }
+            serde_json::Value::Object(record) => {
+                serde_json::Value::Object(self.shape_record(record))
+            }
+            value => value,
+        }
+    }
+
+    fn shape_record(
+        &self,
+        mut record: serde_json::Map<String, serde_json::Value>,
+    ) -> serde_json::Map<String, serde_json::Value> {
+        if self.legacy_done {
+            let is_set = |field: &str| record.get(field).and_then(|value| value.as_bool());
+            let done = is_set("terminated")
+                .or(is_set("is_terminated"))
+                .unwrap_or(false)
+                || is_set("truncated")
+                    .or(is_set("is_truncated"))
+                    .unwrap_or(false);
+            record.insert("done".to_string(), serde_json::Value::Bool(done));
+        }
+
+        record
+            .into_iter()
+            .filter(|(name, _)| !self.exclude.contains(name))
+            .map(|(name, value)| {
+                let renamed = match self.renames.get(&name) {
+                    Some(renamed) => renamed.clone(),
+                    None if self.naming == FieldNaming::CamelCase => codegen::to_camel_case(&name),
+                    None => name,
+                };
+                (renamed, value)
+            })
+            .collect()
+    }
+}
 
 /// Plugin Settings
-#[derive(Clone, Resource, Default)]
+#[derive(Clone, Resource)]
 pub struct AIGymSettings {
+    // Name of the environment reported at `/info`
+    pub env_name: String,
+
     pub width: u32,
     pub height: u32,
     pub num_agents: u32,
     pub pause_interval: f32,
 
+    // How the simulation is paused for control, see `StepMode`
+    pub step_mode: StepMode,
+
+    // Pause virtual `Time` while waiting for actions and resume it with the next control,
+    // so physics and animations driven by it stop without environment glue. Off by default
+    pub pause_time: bool,
+
+    // Render all agents into layers of one texture array read back with a single copy.
+    // Cameras must target `ai_gym_state.render_target(i)`, there is no spectator window then
+    pub render_target_array: bool,
+
+    // Read visual observations back from GPU on every frame rather than only on pauses and resets,
+    // for continuous consumers like video streams
+    pub continuous_readback: bool,
+
+    // Frames which should be simulated per `pause_interval`, fewer is reported
+    // as low frame rate at `/health` and in step info. Zero disables the check
+    pub min_frames_per_step: u32,
+
+    // Stretch the control interval when frame rate is too low for `min_frames_per_step`,
+    // back to `pause_interval` as it recovers
+    pub stretch_pause_interval: bool,
+
+    // Discount per second of simulation time, step results then carry `discount_gamma ^ elapsed`
+    pub discount_gamma: Option<f32>,
+
+    // Apply each submitted action for this many control intervals before pausing again,
+    // rewards are summed over them. Zero and one mean no repeat
+    pub frame_skip: u32,
+
+    // Number of environment copies simulated side by side, each with `num_agents` agents.
+    // Agents of copy `env_id` take indices `env_id * num_agents..(env_id + 1) * num_agents`
+    pub num_envs: u32,
+
+    // Seed for `AIGymRng`
+    pub seed: u64,
+
+    // Truncate episodes after this number of control steps
+    pub max_episode_steps: Option<u32>,
+
     // Ignore rending buffer
     pub render_to_buffer: bool,
+
+    // Read back depth of agents' cameras into `depth_observations`. Cameras need
+    // `depth_texture_usages` with `COPY_SRC` and no MSAA
+    pub render_depth: bool,
+
+    // Reset the environment once all agents are done and return both terminal and
+    // initial observations in the step result
+    pub auto_reset: bool,
+
+    // Agents driven by local input (keyboard, gamepad) with `set_local_action`
+    pub local_agents: Vec<usize>,
+
+    // Scripted background agents (NPCs): rendered and simulated, but left out of actions,
+    // transitions and observations exchanged with the trainer over `/step` and `/reset`
+    pub npc_agents: Vec<usize>,
+
+    // Seconds to wait for trainer's actions after a pause, then continue with no actions
+    pub action_deadline: Option<f32>,
+
+    // Answer `400 Bad Request` to steps with actions which don't deserialize into the action
+    // type. Otherwise they're passed on as is in `EventControl`, and as `None` in
+    // `EventControlTyped` with a warning
+    pub reject_invalid_actions: bool,
+
+    // Space out frames rendered while the trainer is busy, at the measured rate
+    // it consumes steps, so workers sharing a machine with the learner leave it CPU and GPU
+    pub speed_governor: bool,
+
+    // Seconds without a frame after which `/healthz` reports the simulation loop as wedged
+    pub liveness_timeout: f32,
+
+    // Seconds API requests wait for the engine to process a step or reset before they fail
+    // with `504 Gateway Timeout`, instead of hanging if the simulation never gets to it.
+    // A request the engine hasn't taken yet is cancelled then, one in progress still completes.
+    // Waits forever if not set (default)
+    pub request_timeout: Option<f32>,
+
+    // Seconds to collect concurrent `/step` calls into one batch, applied as consecutive
+    // control intervals without waiting for requests in between
+    pub step_batch_window: Option<f32>,
+
+    // Start REST API server. When disabled environment is still controllable
+    // in-process with `gym::GymHandle` or with gRPC
+    pub enable_rest_api: bool,
+
+    // Ports tried in order for REST API on 127.0.0.1, the first free one is used
+    // and published as `ApiAddress` resource
+    pub api_ports: std::ops::Range<u16>,
+
+    // Sign responses with HMAC-SHA256 using this key
+    pub signing_key: Option<String>,
+
+    // Reject REST API requests without `Authorization: Bearer <api_token>` header
+    pub api_token: Option<String>,
+
+    // Origins of browser apps allowed to call REST API, e.g. "http://localhost:3000".
+    // "*" allows any origin, don't combine it with `api_token`: any website could then
+    // call the API with a token the browser holds
+    pub cors_origins: Vec<String>,
+
+    // Serve over TLS (HTTPS) with given certificate and key
+    #[cfg(feature = "tls")]
+    pub tls: Option<security::TlsSettings>,
+
+    // Serve over TLS and require clients to present a certificate signed by given CA,
+    // takes precedence over `tls`
+    #[cfg(feature = "tls")]
+    pub mutual_tls: Option<security::MutualTlsSettings>,
+
+    // Address of gRPC server, e.g. "127.0.0.1:50051". Server is not started if not set
+    #[cfg(feature = "grpc")]
+    pub grpc_address: Option<std::net::SocketAddr>,
+
+    // Write visual observations of every control step into a shared-memory region
+    // of this name, served at `/visual_observations/shm`. See `shm` for its layout
+    #[cfg(feature = "shared-memory")]
+    pub shared_memory: Option<String>,
+
+    // Competition mode: per-client budgets, hidden seeds and `/leaderboard`
+    pub competition: Option<competition::CompetitionSettings>,
+
+    // Format of agents' render targets, see `render::is_supported_format`.
+    // RGBA order is read back as is, BGRA is swizzled on CPU unless frames are downsampled on GPU.
+    // HDR formats are served as 16 bits per channel observations
+    pub texture_format: TextureFormat,
+
+    // Channels of visual observations served to the policy
+    pub observation_color: ColorMode,
+
+    // Resolution of visual observations served to the policy, rendered frames are
+    // downsampled to it. Zero means the same as render resolution
+    pub policy_width: u32,
+    pub policy_height: u32,
+
+    // Downsample agents' frames on GPU by this factor before reading them back, e.g. 0.1 for
+    // 840x840 targets observed at 84x84. One or more disables it, logging frames aren't downsampled
+    pub observation_scale: f32,
+
+    // Keep a full-resolution logging frame every N captures, zero disables logging frames
+    pub logging_interval: u32,
+
+    // Number of recent policy frames kept per agent for `/debug/contact_sheet`
+    pub frame_history: usize,
+
+    // Number of visual observations of the last control steps stacked per agent at `/stacked_observations`
+    pub frame_stack: u32,
+
+    // Observation pipeline, replaces `policy_width`/`policy_height`, `observation_color`
+    // and `frame_stack` when set, see `preprocess::Preprocess`
+    pub preprocessing: Vec<preprocess::Preprocess>,
+
+    // Naming and legacy fields of per-agent records in responses
+    pub response_fields: ResponseFields,
+
+    // Number of world snapshots kept for `/load_state`, the oldest is dropped first
+    pub max_snapshots: usize,
+
+    // Directory of policy checkpoints, `/policy/load` paths are relative to it and can't
+    // leave it. `/policy/load` is disabled if not set
+    pub policy_dir: Option<String>,
+}
+
+impl Default for AIGymSettings {
+    fn default() -> Self {
+        Self {
+            env_name: "bevy_rl".to_string(),
+            width: 0,
+            height: 0,
+            num_agents: 0,
+            pause_interval: 0.0,
+            step_mode: StepMode::Interval,
+            pause_time: false,
+            render_target_array: false,
+            continuous_readback: false,
+            min_frames_per_step: 0,
+            stretch_pause_interval: false,
+            frame_skip: 1,
+            discount_gamma: None,
+            num_envs: 1,
+            seed: 0,
+            max_episode_steps: None,
+            render_to_buffer: false,
+            render_depth: false,
+            auto_reset: false,
+            local_agents: Vec::new(),
+            npc_agents: Vec::new(),
+            action_deadline: None,
+            reject_invalid_actions: false,
+            speed_governor: false,
+            liveness_timeout: 10.0,
+            request_timeout: None,
+            step_batch_window: None,
+            enable_rest_api: true,
+            api_ports: 7878..7879,
+            signing_key: None,
+            api_token: None,
+            cors_origins: Vec::new(),
+            #[cfg(feature = "tls")]
+            tls: None,
+            #[cfg(feature = "tls")]
+            mutual_tls: None,
+            #[cfg(feature = "grpc")]
+            grpc_address: None,
+            #[cfg(feature = "shared-memory")]
+            shared_memory: None,
+            competition: None,
+            texture_format: TextureFormat::Rgba8UnormSrgb,
+            observation_color: ColorMode::Rgba,
+            policy_width: 0,
+            policy_height: 0,
+            observation_scale: 1.0,
+            logging_interval: 0,
+            frame_history: 0,
+            frame_stack: 0,
+            preprocessing: Vec::new(),
+            response_fields: ResponseFields::default(),
+            max_snapshots: 16,
+            policy_dir: None,
+        }
+    }
+}
+
+impl AIGymSettings {
+    /// Number of environment copies, at least one
+    pub fn env_count(&self) -> u32 {
+        self.num_envs.max(1)
+    }
+
+    /// Number of agents across all environment copies
+    pub fn total_agents(&self) -> u32 {
+        self.num_agents * self.env_count()
+    }
+
+    /// Indices of agents trained through the API, all but `npc_agents`
+    pub fn learning_agents(&self) -> Vec<usize> {
+        (0..self.total_agents() as usize)
+            .filter(|agent_index| !self.npc_agents.contains(agent_index))
+            .collect()
+    }
+
+    /// Indices of agents the trainer sends actions for: learning agents but `local_agents`,
+    /// which are driven in-process (human input, frozen policies, scripted bots)
+    pub fn api_agents(&self) -> Vec<usize> {
+        self.learning_agents()
+            .into_iter()
+            .filter(|agent_index| !self.local_agents.contains(agent_index))
+            .collect()
+    }
+
+    /// Resolution of frames read back from GPU, render resolution scaled by `observation_scale`
+    pub fn readback_size(&self) -> (u32, u32) {
+        if self.observation_scale <= 0.0 || self.observation_scale >= 1.0 {
+            return (self.width, self.height);
+        }
+        let scale = |size: u32| ((size as f32 * self.observation_scale).round() as u32).max(1);
+        (scale(self.width), scale(self.height))
+    }
+
+    /// Resolution of visual observations served to the policy
+    pub fn observation_size(&self) -> (u32, u32) {
+        let (width, height) = self.readback_size();
+        preprocess::output_size(&preprocess::pipeline(self), width, height)
+    }
 }
 
-/// This event is fired when user calls `reset` method of the REST API
+/// This event is fired when user calls `reset` method of the REST API.
+/// `AIGymRng` is already reseeded with `seed` if it's given
+#[derive(Event, Default)]
+pub struct EventReset {
+    pub seed: Option<u64>,
+    pub options: Option<serde_json::Value>,
+    /// Tags attached by the trainer, also kept in `AIGymStateInner.episode_metadata`
+    pub metadata: HashMap<String, serde_json::Value>,
+}
+
+/// This event is fired for every environment copy listed in `/vec/reset` request.
+/// Only agents of this copy should be respawned, `seed` is offset by `env_id`
 #[derive(Event)]
-pub struct EventReset;
+pub struct EventResetEnv {
+    pub env_id: usize,
+    pub seed: Option<u64>,
+    pub options: Option<serde_json::Value>,
+}
+
+/// This event is fired when user calls `reset_agents` method of the REST API.
+/// Only listed agents should be respawned, the rest of the episode continues
+#[derive(Event)]
+pub struct EventResetAgents(pub Vec<usize>);
 
 /// This event is fired when user calls `step` method of the REST API
 #[derive(Event)]
 pub struct EventControl(pub Vec<Option<String>>);
 
+/// This event is fired alongside `EventControl` with actions already deserialized into `A`.
+/// An agent's action is `None` if it wasn't sent or couldn't be parsed
+#[derive(Event)]
+pub struct EventControlTyped<A: 'static + Send + Sync>(pub Vec<Option<A>>);
+
+/// This event is fired on every frame of `/advance` after the first one, which fires
+/// `EventControl`. Physics plugins step once per `EventControl` and `EventAdvanceFrame`,
+/// so an advance of N frames runs N physics steps
+#[derive(Event)]
+pub struct EventAdvanceFrame;
+
+/// This event is fired when environment parameters are changed, e.g. by `curriculum::Curriculum`
+#[derive(Event)]
+pub struct EventConfigure(pub HashMap<String, f32>);
+
+/// This event is fired at an episode boundary when the trainer has set curriculum
+/// parameters with `PUT /curriculum`, before `EventReset`. They are kept in `CurriculumState`
+#[derive(Event)]
+pub struct EventCurriculumUpdate(pub serde_json::Value);
+
+/// This event is fired with the body of `POST /set_state` deserialized into `S`, when
+/// `set_state::SetStatePlugin<S, ..>` is added. Move agents (and update the environment state
+/// with `set_env_state`) to the given configuration, the episode continues from it
+#[derive(Event)]
+pub struct EventSetState<S: 'static + Send + Sync>(pub S);
+
+/// Address the REST API server is bound to, inserted at startup when the API is enabled
+#[derive(Resource, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ApiAddress(pub std::net::SocketAddr);
+
+/// Address the gRPC server is bound to, inserted at startup when `grpc_address` is set.
+/// Port 0 binds a free port
+#[cfg(feature = "grpc")]
+#[derive(Resource, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct GrpcAddress(pub std::net::SocketAddr);
+
+/// This event is fired at the start of the next episode after `/policy/load` was called.
+/// In-process policies acting for `local_agents` should load the checkpoint at `path` here
+/// if `slot` is theirs, `None` is the default slot
+#[derive(Event)]
+pub struct EventLoadPolicy {
+    pub path: String,
+    pub slot: Option<String>,
+}
+
 /// This event is fired when an internal timer would need to pause the simulation
 #[derive(Event)]
 pub struct EventPause;
 
+/// Sets bevy_rl systems belong to, in this order in `Update`. Order environment systems
+/// against them instead of guessing, e.g. apply actions `.after(BevyRlSet::ApplyActions)`
+/// and give rewards `.in_set(BevyRlSet::ComputeRewards)`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, SystemSet)]
+pub enum BevyRlSet {
+    /// Ending control intervals: `EventPause` is fired and the simulation pauses for control
+    Pause,
+    /// Taking API requests: steps fire `EventControl`, resets `EventReset`, local and scripted
+    /// agents' actions are substituted
+    ApplyActions,
+    /// `EnvHooksPlugin`, and environment systems setting rewards, terminations and the
+    /// environment state, e.g. on `EventPause`. Step results sent later in the frame include them
+    ComputeRewards,
+    /// Reading sensors in `Update` and sending step results with rewards and observations
+    /// in `PostUpdate`
+    CollectObservations,
+}
+
 /// States of the simulation
 #[derive(Debug, Clone, Eq, PartialEq, Hash, States, Default, SystemSet)]
 pub enum SimulationState {
@@ -48,19 +544,36 @@ pub enum SimulationState {
     PausedForControl,
 }
 
+/// First `ManualTextureViewHandle` of agents' layers with `AIGymSettings.render_target_array`
+pub const RENDER_TARGET_VIEW_BASE: u32 = 0x7262_0000;
+
 /// Timer to pause the simulation every `AIGymSettings.pause_interval` seconds
 #[derive(Resource)]
 pub struct SimulationPauseTimer(Timer);
 
+/// Frames simulated since the last pause in `StepMode::Lockstep` and `StepMode::FixedTimestep`
+#[derive(Resource, Default)]
+pub struct LockstepFrames(u32);
+
+/// State the simulation was in before `/pause` and whether virtual time was paused then,
+/// `None` if it isn't paused for debugging
+#[derive(Resource, Default)]
+pub struct DebugPause(Option<(SimulationState, bool)>);
+
+/// Random number generator seeded with `AIGymSettings.seed`.
+/// Use it in environment systems to keep episodes reproducible
+#[derive(Resource, Deref, DerefMut)]
+pub struct AIGymRng(pub StdRng);
+
 /// bevy_rl plugin
 #[derive(Default, Clone)]
 pub struct AIGymPlugin<
-    T: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe,
+    T: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::de::DeserializeOwned,
     P: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::Serialize,
 >(pub PhantomData<(T, P)>);
 
 impl<
-        T: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe,
+        T: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::de::DeserializeOwned,
         P: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::Serialize,
     > Plugin for AIGymPlugin<T, P>
 {
@@ -79,83 +592,290 @@ impl<
                 ai_gym_state.settings.pause_interval,
                 TimerMode::Repeating,
             )));
+            app.init_resource::<LockstepFrames>();
+            app.init_resource::<DebugPause>();
+            app.insert_resource(AIGymRng(StdRng::seed_from_u64(ai_gym_state.settings.seed)));
+
+            if let StepMode::FixedTimestep { dt, .. } = ai_gym_state.settings.step_mode {
+                let dt = std::time::Duration::from_secs_f32(dt);
+                app.insert_resource(Time::<Fixed>::from_duration(dt));
+                if let Some(mut virtual_time) = app.world_mut().get_resource_mut::<Time<Virtual>>()
+                {
+                    let max_delta = virtual_time.max_delta().max(dt);
+                    virtual_time.set_max_delta(max_delta);
+                }
+                app.insert_resource(TimeUpdateStrategy::ManualDuration(
+                    std::time::Duration::ZERO,
+                ))
+                .add_systems(First, drive_fixed_timestep::<T, P>.before(TimeSystem));
+            }
         }
 
         // Register events
         app.add_event::<EventReset>();
+        app.add_event::<EventResetEnv>();
+        app.add_event::<EventResetAgents>();
         app.add_event::<EventControl>();
+        app.add_event::<EventControlTyped<T>>();
+        app.add_event::<EventAdvanceFrame>();
         app.add_event::<EventPause>();
+        app.add_event::<EventConfigure>();
+        app.add_event::<EventLoadPolicy>();
+        app.add_event::<EventCurriculumUpdate>();
+        app.init_resource::<curriculum::CurriculumState>();
+        app.register_type::<snapshot::Snapshotted>();
 
         // Add system scheduling
         app.insert_state(SimulationState::Initializing)
+            .configure_sets(
+                Update,
+                (
+                    BevyRlSet::Pause,
+                    BevyRlSet::ApplyActions,
+                    BevyRlSet::ComputeRewards,
+                    BevyRlSet::CollectObservations,
+                )
+                    .chain(),
+            )
             .add_systems(
                 Update,
-                control_switch::<T, P>.in_set(SimulationState::Running),
+                control_switch::<T, P>
+                    .in_set(SimulationState::Running)
+                    .in_set(BevyRlSet::Pause),
             )
             .add_systems(
                 Update,
                 (
-                    process_control_request::<T, P>,
-                    process_reset_request::<T, P>,
+                    (
+                        process_control_request::<T, P>,
+                        process_advance_request::<T, P>.after(process_control_request::<T, P>),
+                        process_reset_request::<T, P>,
+                        process_reset_agents_request::<T, P>,
+                    )
+                        .run_if(not(is_debug_paused)),
+                    snapshot::process_snapshot_requests::<T, P>,
                 )
-                    .in_set(SimulationState::PausedForControl),
-            );
+                    .in_set(SimulationState::PausedForControl)
+                    .in_set(BevyRlSet::ApplyActions),
+            )
+            .add_systems(
+                Update,
+                process_debug_pause_request::<T, P>
+                    .in_set(BevyRlSet::Pause)
+                    .before(control_switch::<T, P>),
+            )
+            .add_systems(
+                Update,
+                curriculum::update_curriculum::<T, P>
+                    .run_if(resource_exists::<curriculum::Curriculum>)
+                    .in_set(BevyRlSet::ApplyActions)
+                    .before(process_reset_request::<T, P>),
+            )
+            .add_systems(
+                Update,
+                (
+                    spawn::reposition_agents_on_reset,
+                    spawn::despawn_episode_scoped_on_reset,
+                )
+                    .in_set(BevyRlSet::ApplyActions)
+                    .after(process_reset_request::<T, P>),
+            )
+            .add_systems(
+                Update,
+                lidar::update_lidar_sensors::<T, P>
+                    .run_if(any_with_component::<lidar::LidarSensor>.and(on_event::<EventPause>))
+                    .in_set(BevyRlSet::CollectObservations),
+            )
+            .add_systems(
+                PostUpdate,
+                send_step_result::<T, P>
+                    .run_if(in_state(SimulationState::PausedForControl))
+                    .in_set(BevyRlSet::CollectObservations),
+            )
+            .add_systems(OnEnter(SimulationState::Running), resume_time::<T, P>)
+            .add_systems(First, count_frame::<T, P>)
+            .add_systems(Last, govern_speed::<T, P>);
 
-        let render_app = app.get_sub_app_mut(RenderApp).unwrap();
+        // There is no render app in server builds without rendering plugins
+        let Some(render_app) = app.get_sub_app_mut(RenderApp) else {
+            return;
+        };
 
         render_app.add_systems(
             Update,
-            copy_from_gpu_to_ram::<T, P>.in_set(RenderSet::Render),
+            (
+                render::copy_depth_from_gpu_to_ram::<T, P>,
+                copy_from_gpu_to_ram::<T, P>,
+            )
+                .chain()
+                .in_set(RenderSet::Render),
         );
+        render_app.init_resource::<render::ReadbackBuffers>();
+
+        #[cfg(feature = "shared-memory")]
+        if let Some(name) = ai_gym_state.lock().unwrap().settings.shared_memory.clone() {
+            render_app
+                .insert_resource(shm::SharedObservations::new(&name))
+                .add_systems(
+                    Update,
+                    shm::write_shared_observations::<T, P>
+                        .after(copy_from_gpu_to_ram::<T, P>)
+                        .in_set(RenderSet::Render),
+                );
+        }
+
         render_app.insert_resource(ai_gym_state);
     }
 }
 
 /// Setup rendering
 pub(crate) fn setup<
-    T: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe,
+    T: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::de::DeserializeOwned,
     P: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::Serialize,
 >(
     mut commands: Commands,
-    mut images: ResMut<Assets<Image>>,
+    images: Option<ResMut<Assets<Image>>>,
+    render_device: Option<Res<RenderDevice>>,
+    manual_texture_views: Option<ResMut<ManualTextureViews>>,
+    primary_window: Query<(), With<PrimaryWindow>>,
     ai_gym_state: ResMut<state::AIGymState<T, P>>,
 ) {
     let ai_gym_state_locked = ai_gym_state.into_inner().clone();
     let mut ai_gym_state = ai_gym_state_locked.lock().unwrap();
     let ai_gym_settings = ai_gym_state.settings.clone();
 
-    let handler = api::router::<T, P>(api::GothamState {
+    let gotham_state = api::GothamState {
         inner: ai_gym_state_locked.clone(),
         settings: ai_gym_settings.clone(),
-    });
+        step_batcher: Default::default(),
+    };
+
+    #[cfg(feature = "grpc")]
+    if let Some(grpc_address) = ai_gym_settings.grpc_address {
+        // Bind failure stops the app here, like for REST API
+        let listener = grpc::bind_listener(grpc_address)
+            .and_then(|listener| Ok((listener.local_addr()?, listener)));
+        let (address, listener) = listener
+            .unwrap_or_else(|e| panic!("bevy_rl: can't bind gRPC server to {grpc_address}: {e}"));
+        info!("bevy_rl: gRPC server listens on {address}");
+        commands.insert_resource(GrpcAddress(address));
+        grpc::serve(listener, gotham_state.clone());
+    }
+
+    if ai_gym_settings.enable_rest_api {
+        let handler = api::router::<T, P>(gotham_state);
+
+        // Bind failure stops the app here rather than leave it running without API
+        let listener = api::bind_listener(ai_gym_settings.api_ports.clone())
+            .and_then(|listener| Ok((listener.local_addr()?, listener)));
+        let (address, listener) = listener.unwrap_or_else(|e| {
+            panic!(
+                "bevy_rl: can't bind REST API to any port of {:?}: {e}",
+                ai_gym_settings.api_ports
+            )
+        });
+        info!("bevy_rl: REST API listens on {address}");
+        commands.insert_resource(ApiAddress(address));
 
-    thread::spawn(move || gotham::start("127.0.0.1:7878", handler));
+        #[cfg(feature = "tls")]
+        {
+            let tls_config = match (&ai_gym_settings.mutual_tls, &ai_gym_settings.tls) {
+                (Some(tls_settings), _) => Some(security::mutual_tls_config(tls_settings)),
+                (None, Some(tls_settings)) => Some(security::tls_config(tls_settings)),
+                (None, None) => None,
+            };
+            let tls_config = tls_config.map(|tls_config| {
+                tls_config.unwrap_or_else(|e| panic!("Failed to configure TLS: {e}"))
+            });
+            api::serve(listener, handler, tls_config);
+        }
+        #[cfg(not(feature = "tls"))]
+        api::serve(listener, handler);
+    }
 
     if !ai_gym_settings.render_to_buffer {
         return;
     }
 
+    // Rendering plugins are missing, e.g. a server build with `MinimalPlugins`
+    let Some(mut images) = images else {
+        warn!("bevy_rl: render_to_buffer is set but there is no renderer, visual observations are disabled");
+        return;
+    };
+
+    assert!(
+        render::is_supported_format(ai_gym_settings.texture_format),
+        "bevy_rl: texture format {:?} is not supported",
+        ai_gym_settings.texture_format
+    );
+
     let size = Extent3d {
         width: ai_gym_settings.width,
         height: ai_gym_settings.height,
         ..default()
     };
 
-    for _ in 0..ai_gym_settings.num_agents {
+    if ai_gym_settings.render_target_array {
+        let (Some(render_device), Some(mut manual_texture_views)) =
+            (render_device, manual_texture_views)
+        else {
+            warn!("bevy_rl: render_target_array is set but there is no render device, visual observations are disabled");
+            return;
+        };
+
+        // One layer per agent, each camera renders into a view of its layer
+        let texture = render_device.create_texture(&TextureDescriptor {
+            label: Some("bevy_rl_render_targets"),
+            size: Extent3d {
+                depth_or_array_layers: ai_gym_settings.total_agents(),
+                ..size
+            },
+            dimension: TextureDimension::D2,
+            format: ai_gym_settings.texture_format,
+            mip_level_count: 1,
+            sample_count: 1,
+            usage: TextureUsages::COPY_SRC
+                | TextureUsages::TEXTURE_BINDING
+                | TextureUsages::RENDER_ATTACHMENT,
+            view_formats: &[],
+        });
+        for layer in 0..ai_gym_settings.total_agents() {
+            let texture_view = texture.create_view(&TextureViewDescriptor {
+                dimension: Some(TextureViewDimension::D2),
+                base_array_layer: layer,
+                array_layer_count: Some(1),
+                ..default()
+            });
+            let handle = ManualTextureViewHandle(RENDER_TARGET_VIEW_BASE + layer);
+            manual_texture_views.insert(
+                handle,
+                ManualTextureView {
+                    texture_view,
+                    size: UVec2::new(size.width, size.height),
+                    format: ai_gym_settings.texture_format,
+                },
+            );
+            ai_gym_state.render_target_views.push(handle);
+        }
+        ai_gym_state.render_target_array = Some(texture);
+        return;
+    }
+
+    for _ in 0..ai_gym_settings.total_agents() {
         // This is the texture that will be rendered to.
         let mut render_image = Image {
             texture_descriptor: TextureDescriptor {
                 label: None,
                 size,
                 dimension: TextureDimension::D2,
-                format: TextureFormat::Bgra8UnormSrgb,
+                format: ai_gym_settings.texture_format,
                 mip_level_count: 1,
                 sample_count: 1,
                 usage: TextureUsages::COPY_SRC
                     | TextureUsages::COPY_DST
                     | TextureUsages::TEXTURE_BINDING
                     | TextureUsages::RENDER_ATTACHMENT,
-                view_formats: &[TextureFormat::Bgra8UnormSrgb],
+                view_formats: &[],
             },
             ..default()
         };
@@ -165,6 +885,11 @@ pub(crate) fn setup<
             .push(images.add(render_image));
     }
 
+    // Render targets are offscreen, spectator view is only needed when there is a window to show it
+    if primary_window.is_empty() {
+        return;
+    }
+
     let second_pass_layer = RenderLayers::layer(1);
 
     commands
@@ -173,9 +898,9 @@ pub(crate) fn setup<
 
     // Show all camera views in tiled mode
     // let window = windows.get_primary_mut().unwrap();
-    let number_of_columns = (ai_gym_settings.num_agents as f32).sqrt().ceil() as u32;
+    let number_of_columns = (ai_gym_settings.total_agents() as f32).sqrt().ceil() as u32;
     let number_of_rows =
-        ((ai_gym_settings.num_agents as f32) / (number_of_columns as f32)).ceil() as u32;
+        ((ai_gym_settings.total_agents() as f32) / (number_of_columns as f32)).ceil() as u32;
     let mut frames: Vec<Handle<Image>> = Vec::new();
     for f in ai_gym_state.render_image_handles.iter() {
         frames.push(f.clone());
@@ -205,67 +930,561 @@ pub(crate) fn setup<
 }
 
 /// Pausing the external world each tick
+#[allow(clippy::too_many_arguments)]
 fn control_switch<
-    T: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe,
+    T: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::de::DeserializeOwned,
     P: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::Serialize,
 >(
+    current_state: Res<State<SimulationState>>,
     mut simulation_state: ResMut<NextState<SimulationState>>,
     time: Res<Time>,
+    mut virtual_time: ResMut<Time<Virtual>>,
     mut timer: ResMut<SimulationPauseTimer>,
+    mut lockstep_frames: ResMut<LockstepFrames>,
     ai_gym_state: ResMut<state::AIGymState<T, P>>,
     mut pause_event_writer: EventWriter<EventPause>,
+    mut control_event_writer: EventWriter<EventControl>,
+    mut typed_control_event_writer: EventWriter<EventControlTyped<T>>,
+    mut advance_frame_event_writer: EventWriter<EventAdvanceFrame>,
 ) {
-    let ai_gym_settings = ai_gym_state.lock().unwrap().settings.clone();
+    let step_mode = ai_gym_state.lock().unwrap().settings.step_mode;
+
+    // `/advance` runs its frames and pauses again without a step result
+    {
+        let mut ai_gym_state = ai_gym_state.lock().unwrap();
+        if ai_gym_state.advance_reply.is_some() {
+            if *current_state.get() != SimulationState::Running {
+                return;
+            }
+            ai_gym_state.advance_frames_remaining =
+                ai_gym_state.advance_frames_remaining.saturating_sub(1);
+            if ai_gym_state.advance_frames_remaining > 0 {
+                advance_frame_event_writer.send(EventAdvanceFrame);
+                return;
+            }
+
+            simulation_state.set(SimulationState::PausedForControl);
+            pause_event_writer.send(EventPause);
+            if ai_gym_state.settings.pause_time {
+                virtual_time.pause();
+            }
+            // The next step gets a full control interval
+            timer.0.reset();
+            lockstep_frames.0 = 0;
+            ai_gym_state.observation_requested = true;
+            ai_gym_state.paused_at = Some(std::time::Instant::now());
+            return;
+        }
+    }
+
     // This controls control frequency of the environment
-    if timer.0.tick(time.delta()).just_finished() {
-        // Set current state to control to disable simulation systems
-        simulation_state.set(SimulationState::PausedForControl);
+    let should_pause = match step_mode {
+        StepMode::Interval => {
+            if !time.delta().is_zero() {
+                ai_gym_state.lock().unwrap().step_frames += 1;
+            }
+            timer.0.tick(time.delta()).just_finished()
+        }
+        StepMode::Lockstep {
+            frames_per_step: frames,
+        }
+        | StepMode::FixedTimestep {
+            substeps: frames, ..
+        } => {
+            if *current_state.get() != SimulationState::Running {
+                return;
+            }
+            lockstep_frames.0 += 1;
+            if lockstep_frames.0 < frames.max(1) {
+                return;
+            }
+            lockstep_frames.0 = 0;
+            true
+        }
+    };
 
-        // Pause time in all environment
-        pause_event_writer.send(EventPause);
+    if !should_pause {
+        return;
+    }
+
+    // ai_gym_state is behind arc mutex, so we need to lock it
+    let mut ai_gym_state = ai_gym_state.lock().unwrap();
+
+    if step_mode == StepMode::Interval && ai_gym_state.settings.min_frames_per_step > 0 {
+        let was_low_frame_rate = ai_gym_state.frame_rate_health.low_frame_rate;
+        ai_gym_state.update_frame_rate_health();
+
+        let health = &ai_gym_state.frame_rate_health;
+        if health.low_frame_rate && !was_low_frame_rate {
+            warn!(
+                "bevy_rl: {} frames simulated per control interval, {} required",
+                health.frames_per_step, ai_gym_state.settings.min_frames_per_step
+            );
+        }
+        timer
+            .0
+            .set_duration(std::time::Duration::from_secs_f32(health.pause_interval));
+    }
+
+    // Repeat the last action instead of pausing while frame skip lasts.
+    // An agent terminated meanwhile ends the step right away, dead agents aren't simulated further
+    if ai_gym_state.frame_skip_remaining > 0 && ai_gym_state.is_terminated_during_step() {
+        ai_gym_state.frame_skip_remaining = 0;
+    }
+    if ai_gym_state.frame_skip_remaining > 0 {
+        ai_gym_state.frame_skip_remaining -= 1;
+        ai_gym_state.step_repeats += 1;
+        ai_gym_state.accumulate_skipped_rewards();
+        control_event_writer.send(EventControl(ai_gym_state.last_action_strings.clone()));
+        typed_control_event_writer.send(EventControlTyped(ai_gym_state.actions.clone()));
+        return;
+    }
+
+    // Set current state to control to disable simulation systems
+    simulation_state.set(SimulationState::PausedForControl);
+
+    // Pause time in all environment
+    pause_event_writer.send(EventPause);
+    if ai_gym_state.settings.pause_time {
+        virtual_time.pause();
+    }
+
+    // Step result is sent once the environment handled `EventPause` and set its state
+    ai_gym_state.restore_skipped_rewards();
+    if let Some(step_started_at) = ai_gym_state.step_started_at.take() {
+        ai_gym_state.step_elapsed = time.elapsed().saturating_sub(step_started_at).as_secs_f32();
+    }
+    ai_gym_state.step_result_pending = true;
+    ai_gym_state.observation_requested = true;
+    ai_gym_state.paused_at = Some(std::time::Instant::now());
+}
 
-        // ai_gym_state is behind arc mutex, so we need to lock it
+/// Advance time by `dt` in frames the simulation runs in `StepMode::FixedTimestep`, not at all
+/// in paused ones. State transitions apply after `First`, so a pending one decides
+fn drive_fixed_timestep<
+    T: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::de::DeserializeOwned,
+    P: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::Serialize,
+>(
+    current_state: Res<State<SimulationState>>,
+    next_state: Res<NextState<SimulationState>>,
+    ai_gym_state: Res<state::AIGymState<T, P>>,
+    mut time_update_strategy: ResMut<TimeUpdateStrategy>,
+) {
+    let step_mode = ai_gym_state.lock().unwrap().settings.step_mode;
+    let StepMode::FixedTimestep { dt, .. } = step_mode else {
+        return;
+    };
+    let is_running = match next_state.as_ref() {
+        NextState::Pending(next_state) => *next_state == SimulationState::Running,
+        NextState::Unchanged => *current_state.get() == SimulationState::Running,
+    };
+    let delta = if is_running {
+        std::time::Duration::from_secs_f32(dt)
+    } else {
+        std::time::Duration::ZERO
+    };
+    *time_update_strategy = TimeUpdateStrategy::ManualDuration(delta);
+}
+
+fn is_debug_paused(debug_pause: Res<DebugPause>) -> bool {
+    debug_pause.0.is_some()
+}
+
+/// Freeze the simulation on `/pause` regardless of the pause timer and put it back on `/resume`.
+/// Steps and resets wait meanwhile, the state and frames can be inspected
+fn process_debug_pause_request<
+    T: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::de::DeserializeOwned,
+    P: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::Serialize,
+>(
+    current_state: Res<State<SimulationState>>,
+    mut simulation_state: ResMut<NextState<SimulationState>>,
+    mut virtual_time: ResMut<Time<Virtual>>,
+    mut debug_pause: ResMut<DebugPause>,
+    ai_gym_state: Res<state::AIGymState<T, P>>,
+    mut pause_event_writer: EventWriter<EventPause>,
+) {
+    let mut ai_gym_state = ai_gym_state.lock().unwrap();
+    let Some(request) = ai_gym_state.debug_pause_requests.receive() else {
+        return;
+    };
+
+    let current_state = current_state.get().clone();
+    let new_state = match (request.request, debug_pause.0.take()) {
+        (true, None) => {
+            debug_pause.0 = Some((current_state.clone(), virtual_time.is_paused()));
+            virtual_time.pause();
+            if current_state != SimulationState::Running {
+                current_state
+            } else {
+                // Let the environment freeze and publish its state as on a control pause
+                simulation_state.set(SimulationState::PausedForControl);
+                pause_event_writer.send(EventPause);
+                ai_gym_state.observation_requested = true;
+                SimulationState::PausedForControl
+            }
+        }
+        (false, Some((previous_state, was_time_paused))) => {
+            if !was_time_paused {
+                virtual_time.unpause();
+            }
+            if previous_state != current_state {
+                simulation_state.set(previous_state.clone());
+            }
+            previous_state
+        }
+        // Already paused or running
+        (_, previous) => {
+            debug_pause.0 = previous;
+            current_state
+        }
+    };
+    ai_gym_state.debug_paused = debug_pause.0.is_some();
+    request.reply.send(new_state);
+}
+
+/// Count frames of the app for `/info` and record the last one for probes and timed out requests
+fn count_frame<
+    T: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::de::DeserializeOwned,
+    P: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::Serialize,
+>(
+    ai_gym_state: Res<state::AIGymState<T, P>>,
+    simulation_state: Option<Res<State<SimulationState>>>,
+) {
+    let mut ai_gym_state = ai_gym_state.lock().unwrap();
+    ai_gym_state.frames += 1;
+    ai_gym_state.last_frame_at = Some(std::time::Instant::now());
+    ai_gym_state.simulation_state =
+        simulation_state.map(|simulation_state| simulation_state.get().clone());
+    ai_gym_state.initialized |= ai_gym_state
+        .simulation_state
+        .as_ref()
+        .is_some_and(|simulation_state| *simulation_state != SimulationState::Initializing);
+}
+
+/// With `speed_governor`, sleep while the trainer is expected to be busy with the last step result
+fn govern_speed<
+    T: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::de::DeserializeOwned,
+    P: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::Serialize,
+>(
+    ai_gym_state: Res<state::AIGymState<T, P>>,
+) {
+    let sleep = {
         let ai_gym_state = ai_gym_state.lock().unwrap();
+        if !ai_gym_state.settings.speed_governor {
+            return;
+        }
+        ai_gym_state.governor_sleep()
+    };
 
-        // This will tell bevy_rl that environment is ready to receive actions
-        let results = (0..ai_gym_settings.num_agents).map(|_| true).collect();
-        ai_gym_state.send_step_result(results);
+    if let Some(sleep) = sleep {
+        thread::sleep(sleep);
     }
 }
 
+/// This will tell bevy_rl that environment is ready to receive actions.
+/// It runs in `PostUpdate` of the pause frame, after user systems in `Update` had a chance
+/// to handle `EventPause`
+fn send_step_result<
+    T: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::de::DeserializeOwned,
+    P: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::Serialize,
+>(
+    ai_gym_state: Res<state::AIGymState<T, P>>,
+) {
+    let mut ai_gym_state = ai_gym_state.lock().unwrap();
+
+    // Environment finished automatic reset, the next pause will carry its first observation
+    if ai_gym_state.auto_reset_transitions.is_some()
+        && !ai_gym_state.auto_reset_done
+        && ai_gym_state
+            .auto_reset_result
+            .as_ref()
+            .is_some_and(|result| result.try_recv().is_ok())
+    {
+        ai_gym_state.auto_reset_result = None;
+        ai_gym_state.auto_reset_done = true;
+        ai_gym_state.step_result_pending = false;
+        return;
+    }
+
+    // `/advance` is done once observations of its last frame are captured
+    if ai_gym_state.advance_reply.is_some()
+        && ai_gym_state.advance_frames_remaining == 0
+        && !ai_gym_state.is_observation_pending()
+    {
+        ai_gym_state.advance_reply.take().unwrap().send(true);
+        return;
+    }
+
+    if !ai_gym_state.step_result_pending || ai_gym_state.is_observation_pending() {
+        return;
+    }
+
+    if ai_gym_state.auto_reset_transitions.is_some() {
+        if !ai_gym_state.auto_reset_done {
+            return;
+        }
+
+        // Terminal transitions with observations of the new episode, like VecEnv does
+        let terminal_transitions = ai_gym_state.auto_reset_transitions.take().unwrap();
+        let initial_transitions = ai_gym_state.transitions();
+        let transitions = terminal_transitions
+            .into_iter()
+            .zip(initial_transitions)
+            .map(|(terminal, initial)| state::AgentTransition {
+                observation: initial.observation,
+                vector_observation: initial.vector_observation,
+                terminal_observation: terminal.observation,
+                ..terminal
+            })
+            .collect();
+
+        ai_gym_state.auto_reset_done = false;
+        ai_gym_state.step_result_pending = false;
+        ai_gym_state.send_transitions(transitions);
+        return;
+    }
+
+    if ai_gym_state.settings.auto_reset
+        && ai_gym_state.is_episode_done()
+        && ai_gym_state.reset_reply.is_none()
+        && ai_gym_state.reset_requests.is_empty()
+    {
+        // Reset goes the same way as the one requested by API, the result is sent once it's done
+        ai_gym_state.auto_reset_result = Some(
+            ai_gym_state
+                .reset_requests
+                .submit(state::ResetRequest::default()),
+        );
+        ai_gym_state.auto_reset_transitions = Some(ai_gym_state.transitions());
+        ai_gym_state.take_step_rewards();
+        return;
+    }
+
+    ai_gym_state.step_result_pending = false;
+    ai_gym_state.send_step_result();
+}
+
 /// This is called when user calls reset() in the REST api
 pub(crate) fn process_reset_request<
-    T: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe,
+    T: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::de::DeserializeOwned,
     P: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::Serialize,
 >(
     ai_gym_state: ResMut<state::AIGymState<T, P>>,
     mut reset_event_writer: EventWriter<EventReset>,
+    mut reset_env_event_writer: EventWriter<EventResetEnv>,
+    mut load_policy_event_writer: EventWriter<EventLoadPolicy>,
+    mut curriculum_event_writer: EventWriter<EventCurriculumUpdate>,
+    mut curriculum_state: ResMut<curriculum::CurriculumState>,
+    mut rng: ResMut<AIGymRng>,
     // mut simulation_state: ResMut<State<SimulationState>>,
 ) {
-    let ai_gym_state = ai_gym_state.lock().unwrap();
+    let mut ai_gym_state = ai_gym_state.lock().unwrap();
     if !ai_gym_state.is_reset_request() {
         return;
     }
 
-    ai_gym_state.receive_reset_request();
-    reset_event_writer.send(EventReset);
+    let request = ai_gym_state.receive_reset_request();
+    if let Some(seed) = request.seed {
+        rng.0 = StdRng::seed_from_u64(seed);
+    }
+
+    // Curriculum parameters of the trainer apply from the next episode of any copy
+    if let Some(parameters) = ai_gym_state.pending_curriculum.take() {
+        ai_gym_state.curriculum = Some(parameters.clone());
+        curriculum_state.payload = Some(parameters.clone());
+        curriculum_state.version += 1;
+        curriculum_event_writer.send(EventCurriculumUpdate(parameters));
+    }
+
+    // Reset of selected environment copies, the rest keep running
+    if let Some(env_ids) = request.env_ids {
+        ai_gym_state.pending_env_resets = env_ids.clone();
+        for env_id in env_ids {
+            reset_env_event_writer.send(EventResetEnv {
+                env_id,
+                seed: request.seed.map(|seed| seed.wrapping_add(env_id as u64)),
+                options: request.options.clone(),
+            });
+        }
+        return;
+    }
+
+    // Acting policy is swapped between episodes only
+    for (slot, path) in std::mem::take(&mut ai_gym_state.pending_policies) {
+        match &slot {
+            Some(slot) => {
+                ai_gym_state
+                    .policy_checkpoints
+                    .insert(slot.clone(), path.clone());
+            }
+            None => ai_gym_state.policy_checkpoint = Some(path.clone()),
+        }
+        load_policy_event_writer.send(EventLoadPolicy { path, slot });
+    }
+
+    ai_gym_state.observation_requested = true;
+    ai_gym_state.seed = request.seed;
+    ai_gym_state.reset_options = request.options.clone();
+    ai_gym_state.episode_metadata = request.metadata.clone();
+    reset_event_writer.send(EventReset {
+        seed: request.seed,
+        options: request.options,
+        metadata: request.metadata,
+    });
+}
+
+/// This is called when user calls reset_agents() in the REST api
+pub(crate) fn process_reset_agents_request<
+    T: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::de::DeserializeOwned,
+    P: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::Serialize,
+>(
+    ai_gym_state: ResMut<state::AIGymState<T, P>>,
+    mut reset_agents_event_writer: EventWriter<EventResetAgents>,
+) {
+    let mut ai_gym_state = ai_gym_state.lock().unwrap();
+    if !ai_gym_state.is_reset_agents_request() {
+        return;
+    }
+
+    let agent_indices = ai_gym_state.receive_reset_agents_request();
+    reset_agents_event_writer.send(EventResetAgents(agent_indices));
 }
 
 /// This is called when user calls step() in the REST api
 pub(crate) fn process_control_request<
-    T: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe,
+    T: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::de::DeserializeOwned,
     P: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::Serialize,
 >(
     ai_gym_state: ResMut<state::AIGymState<T, P>>,
+    time: Res<Time>,
+    mut virtual_time: ResMut<Time<Virtual>>,
     mut control_event_writer: EventWriter<EventControl>,
+    mut typed_control_event_writer: EventWriter<EventControlTyped<T>>,
 ) {
-    let ai_gym_state = ai_gym_state.lock().unwrap();
+    let mut ai_gym_state = ai_gym_state.lock().unwrap();
+    let ai_gym_settings = ai_gym_state.settings.clone();
+
+    // Steps wait until `/advance` is done
+    if ai_gym_state.advance_reply.is_some() {
+        return;
+    }
+
+    let is_deadline_missed = match (ai_gym_settings.action_deadline, ai_gym_state.paused_at) {
+        (Some(deadline), Some(paused_at)) => paused_at.elapsed().as_secs_f32() >= deadline,
+        _ => false,
+    };
+
+    let mut unparsed_actions = if ai_gym_state.is_next_action() {
+        ai_gym_state.receive_action_strings()
+    } else if is_deadline_missed {
+        // Trainer didn't send actions in time, keep the cadence with no actions for its agents
+        vec![None; ai_gym_settings.total_agents() as usize]
+    } else {
+        // Drop the system if users hasn't sent request this frame
+        return;
+    };
+    ai_gym_state.paused_at = None;
+    ai_gym_state.step_started_at = Some(time.elapsed());
+
+    // Locally controlled agents take the latest input instead of the trainer's actions
+    ai_gym_state.run_scripted_agents();
+    for &agent_index in ai_gym_settings.local_agents.iter() {
+        if agent_index < unparsed_actions.len() {
+            unparsed_actions[agent_index] = ai_gym_state.local_actions[agent_index].clone();
+        }
+    }
+
+    let actions: Vec<Option<T>> = unparsed_actions
+        .iter()
+        .enumerate()
+        .map(|(agent_index, unparsed_action)| {
+            unparsed_action
+                .as_ref()
+                .and_then(|a| match serde_json::from_str::<T>(a) {
+                    Ok(action) => Some(action),
+                    Err(e) => {
+                        warn!("bevy_rl: action of agent {agent_index} is ignored: {e}");
+                        None
+                    }
+                })
+        })
+        .collect();
+
+    ai_gym_state.actions = actions.clone();
+    ai_gym_state.begin_dataset_step();
+    ai_gym_state.log_action_entry(|| replay::ActionLogEntry::Step(unparsed_actions.clone()));
+    ai_gym_state.last_action_strings = unparsed_actions.clone();
+    ai_gym_state.frame_skip_remaining = ai_gym_settings.frame_skip.saturating_sub(1);
+    ai_gym_state.step_repeats = 1;
+    ai_gym_state.terminated_at_step_start = ai_gym_state.terminations.clone();
+    ai_gym_state.count_episode_step();
+    control_event_writer.send(EventControl(unparsed_actions));
+    typed_control_event_writer.send(EventControlTyped(actions));
+    if ai_gym_settings.pause_time {
+        virtual_time.unpause();
+    }
+}
 
-    // Drop the system if users hasn't sent request this frame
-    if !ai_gym_state.is_next_action() {
+/// This is called when user calls `advance` in the REST API: the environment runs for the given
+/// number of frames with the previous actions (or none) and pauses again, without a control step
+pub(crate) fn process_advance_request<
+    T: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::de::DeserializeOwned,
+    P: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::Serialize,
+>(
+    current_state: Res<State<SimulationState>>,
+    ai_gym_state: ResMut<state::AIGymState<T, P>>,
+    mut virtual_time: ResMut<Time<Virtual>>,
+    mut control_event_writer: EventWriter<EventControl>,
+    mut typed_control_event_writer: EventWriter<EventControlTyped<T>>,
+) {
+    let mut ai_gym_state = ai_gym_state.lock().unwrap();
+    // Only between control steps: paused, step result sent and no actions taken this frame
+    if *current_state.get() != SimulationState::PausedForControl
+        || ai_gym_state.advance_reply.is_some()
+        || ai_gym_state.step_result_pending
+        || ai_gym_state.paused_at.is_none()
+    {
+        return;
+    }
+    let Some(request) = ai_gym_state.advance_requests.receive() else {
+        return;
+    };
+    if request.request.frames == 0 {
+        request.reply.send(true);
         return;
     }
 
-    let unparsed_actions = ai_gym_state.receive_action_strings();
+    let (unparsed_actions, actions) =
+        if request.request.noop || ai_gym_state.last_action_strings.is_empty() {
+            let total_agents = ai_gym_state.settings.total_agents() as usize;
+            (vec![None; total_agents], vec![None; total_agents])
+        } else {
+            (
+                ai_gym_state.last_action_strings.clone(),
+                ai_gym_state.actions.clone(),
+            )
+        };
+
+    ai_gym_state.advance_frames_remaining = request.request.frames;
+    ai_gym_state.advance_reply = Some(request.reply);
+    ai_gym_state.paused_at = None;
     control_event_writer.send(EventControl(unparsed_actions));
+    typed_control_event_writer.send(EventControlTyped(actions));
+    if ai_gym_state.settings.pause_time {
+        virtual_time.unpause();
+    }
+}
+
+/// Resume time paused for control when the environment runs again without a control step,
+/// e.g. after a reset
+fn resume_time<
+    T: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::de::DeserializeOwned,
+    P: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::Serialize,
+>(
+    ai_gym_state: Res<state::AIGymState<T, P>>,
+    mut virtual_time: ResMut<Time<Virtual>>,
+) {
+    if ai_gym_state.lock().unwrap().settings.pause_time {
+        virtual_time.unpause();
+    }
 }
@@ -0,0 +1,83 @@
+//! Raycast (lidar) sensors.
+//! Attach `LidarSensor` to an agent entity and bevy_rl will cast a fan of rays against meshes
+//! on every pause for control, writing distances to `AIGymStateInner.lidar_observations`.
+//! It's a cheap vector observation for navigation tasks instead of pixels.
+
+use bevy::{
+    picking::mesh_picking::ray_cast::{MeshRayCast, RayCastSettings},
+    prelude::*,
+};
+
+use crate::state;
+
+/// Fan of `num_rays` rays spread over `fov` radians around the entity's forward direction
+/// in its local XZ plane. Rays not hitting anything within `max_range` report `max_range`
+#[derive(Component, Clone, Debug)]
+pub struct LidarSensor {
+    pub agent_index: usize,
+    pub num_rays: u32,
+    pub fov: f32,
+    pub max_range: f32,
+}
+
+impl LidarSensor {
+    /// Directions of rays in world space, left to right
+    pub fn directions(&self, transform: &GlobalTransform) -> Vec<Dir3> {
+        let rotation = transform.compute_transform().rotation;
+        (0..self.num_rays)
+            .map(|i| {
+                let t = if self.num_rays > 1 {
+                    i as f32 / (self.num_rays - 1) as f32
+                } else {
+                    0.5
+                };
+                let yaw = self.fov * (0.5 - t);
+                Dir3::new(rotation * Quat::from_rotation_y(yaw) * Vec3::NEG_Z)
+                    .unwrap_or(Dir3::NEG_Z)
+            })
+            .collect()
+    }
+}
+
+/// Cast rays of all lidar sensors when the simulation is paused for control
+pub(crate) fn update_lidar_sensors<
+    T: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::de::DeserializeOwned,
+    P: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::Serialize,
+>(
+    mut ray_cast: MeshRayCast,
+    sensors: Query<(Entity, &LidarSensor, &GlobalTransform)>,
+    children: Query<&Children>,
+    ai_gym_state: Res<state::AIGymState<T, P>>,
+) {
+    let mut readings = Vec::new();
+    for (entity, sensor, transform) in sensors.iter() {
+        // The agent's own meshes shouldn't block its rays
+        let own_entities: Vec<Entity> = std::iter::once(entity)
+            .chain(children.iter_descendants(entity))
+            .collect();
+        let filter = |hit_entity: Entity| !own_entities.contains(&hit_entity);
+        let settings = RayCastSettings::default().with_filter(&filter);
+
+        let distances = sensor
+            .directions(transform)
+            .into_iter()
+            .map(|direction| {
+                let ray = Ray3d::new(transform.translation(), direction);
+                ray_cast
+                    .cast_ray(ray, &settings)
+                    .first()
+                    .map(|(_, hit)| hit.distance.min(sensor.max_range))
+                    .unwrap_or(sensor.max_range)
+            })
+            .collect();
+
+        readings.push((sensor.agent_index, distances));
+    }
+
+    let mut ai_gym_state = ai_gym_state.lock().unwrap();
+    for (agent_index, distances) in readings {
+        if let Some(lidar_observation) = ai_gym_state.lidar_observations.get_mut(agent_index) {
+            *lidar_observation = distances;
+        }
+    }
+}
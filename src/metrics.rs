@@ -0,0 +1,216 @@
+//! Metrics of the environment in Prometheus text format, served at `/metrics`.
+//! Counters and histograms are kept in `AIGymStateInner.metrics` and rendered on request,
+//! so training infrastructure can alert on stalled or slow environments.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use futures::{FutureExt, TryFutureExt};
+use gotham::handler::HandlerFuture;
+use gotham::middleware::{Middleware, NewMiddleware};
+use gotham::state::{FromState, State};
+use hyper::{StatusCode, Uri};
+
+/// Buckets of durations in seconds, from 1 ms to 10 s
+const DURATION_BUCKETS: &[f64] = &[
+    0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// Buckets of episode returns, symmetric around zero
+const RETURN_BUCKETS: &[f64] = &[-1000.0, -100.0, -10.0, -1.0, 0.0, 1.0, 10.0, 100.0, 1000.0];
+
+/// Cumulative histogram with fixed upper bounds
+#[derive(Clone, Debug)]
+pub struct Histogram {
+    bounds: &'static [f64],
+    counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn new(bounds: &'static [f64]) -> Self {
+        Self {
+            bounds,
+            counts: vec![0; bounds.len()],
+            sum: 0.0,
+            count: 0,
+        }
+    }
+
+    pub fn observe(&mut self, value: f64) {
+        for (bound, count) in self.bounds.iter().zip(self.counts.iter_mut()) {
+            if value <= *bound {
+                *count += 1;
+            }
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+
+    /// `_bucket`, `_sum` and `_count` samples, `labels` are added to every sample
+    fn render(&self, out: &mut String, name: &str, labels: &str) {
+        let separator = if labels.is_empty() { "" } else { "," };
+        for (bound, count) in self.bounds.iter().zip(&self.counts) {
+            let _ = writeln!(
+                out,
+                "{name}_bucket{{{labels}{separator}le=\"{bound}\"}} {count}"
+            );
+        }
+        let _ = writeln!(
+            out,
+            "{name}_bucket{{{labels}{separator}le=\"+Inf\"}} {}",
+            self.count
+        );
+        let labels = if labels.is_empty() {
+            String::new()
+        } else {
+            format!("{{{labels}}}")
+        };
+        let _ = writeln!(out, "{name}_sum{labels} {}", self.sum);
+        let _ = writeln!(out, "{name}_count{labels} {}", self.count);
+    }
+}
+
+/// Counters and histograms of the environment
+#[derive(Clone, Debug)]
+pub struct Metrics {
+    /// Control steps answered to the trainer
+    pub steps: u64,
+    /// Finished episodes of all agents
+    pub episodes: u64,
+    /// Seconds from a step request until its transitions
+    pub step_latency: Histogram,
+    /// Seconds from copying a frame to the staging buffer until it's read back
+    pub readback: Histogram,
+    /// Returns of finished episodes
+    pub episode_return: Histogram,
+    /// Seconds to answer API requests, by route
+    pub request_duration: BTreeMap<String, Histogram>,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self {
+            steps: 0,
+            episodes: 0,
+            step_latency: Histogram::new(DURATION_BUCKETS),
+            readback: Histogram::new(DURATION_BUCKETS),
+            episode_return: Histogram::new(RETURN_BUCKETS),
+            request_duration: BTreeMap::new(),
+        }
+    }
+}
+
+impl Metrics {
+    pub(crate) fn observe_request(&mut self, path: &str, seconds: f64) {
+        self.request_duration
+            .entry(path.to_string())
+            .or_insert_with(|| Histogram::new(DURATION_BUCKETS))
+            .observe(seconds);
+    }
+
+    /// Metrics in Prometheus text exposition format, with gauges of the simulation loop
+    pub(crate) fn render(&self, frames: u64, seconds_since_frame: Option<f32>) -> String {
+        let mut out = String::new();
+
+        let counters = [
+            (
+                "bevy_rl_steps_total",
+                "Control steps answered to the trainer",
+                self.steps,
+            ),
+            (
+                "bevy_rl_episodes_total",
+                "Finished episodes of all agents",
+                self.episodes,
+            ),
+            ("bevy_rl_frames_total", "Frames run by the app", frames),
+        ];
+        for (name, help, value) in counters {
+            let _ = writeln!(
+                out,
+                "# HELP {name} {help}\n# TYPE {name} counter\n{name} {value}"
+            );
+        }
+
+        if let Some(seconds) = seconds_since_frame {
+            let name = "bevy_rl_seconds_since_frame";
+            let _ = writeln!(
+                out,
+                "# HELP {name} Seconds since the last frame of the app\n# TYPE {name} gauge\n{name} {seconds}"
+            );
+        }
+
+        let histograms = [
+            (
+                "bevy_rl_step_latency_seconds",
+                "Seconds from a step request until its transitions",
+                &self.step_latency,
+            ),
+            (
+                "bevy_rl_readback_seconds",
+                "Seconds from copying a frame until it's read back",
+                &self.readback,
+            ),
+            (
+                "bevy_rl_episode_return",
+                "Returns of finished episodes",
+                &self.episode_return,
+            ),
+        ];
+        for (name, help, histogram) in histograms {
+            let _ = writeln!(out, "# HELP {name} {help}\n# TYPE {name} histogram");
+            histogram.render(&mut out, name, "");
+        }
+
+        let name = "bevy_rl_http_request_duration_seconds";
+        let _ = writeln!(
+            out,
+            "# HELP {name} Seconds to answer API requests\n# TYPE {name} histogram"
+        );
+        for (path, histogram) in &self.request_duration {
+            histogram.render(&mut out, name, &format!("path=\"{path}\""));
+        }
+
+        out
+    }
+}
+
+/// Gotham middleware timing API requests by path. Unknown paths aren't recorded,
+/// so clients can't flood metrics with labels
+#[derive(Clone, NewMiddleware)]
+pub(crate) struct MetricsMiddleware {
+    metrics: Arc<Mutex<Metrics>>,
+}
+
+impl MetricsMiddleware {
+    pub(crate) fn new(metrics: Arc<Mutex<Metrics>>) -> Self {
+        Self { metrics }
+    }
+}
+
+impl Middleware for MetricsMiddleware {
+    fn call<Chain>(self, state: State, chain: Chain) -> Pin<Box<HandlerFuture>>
+    where
+        Chain: FnOnce(State) -> Pin<Box<HandlerFuture>> + Send + 'static,
+    {
+        let started_at = Instant::now();
+        let path = Uri::borrow_from(&state).path().to_string();
+
+        chain(state)
+            .map_ok(move |(state, response)| {
+                if response.status() != StatusCode::NOT_FOUND {
+                    self.metrics
+                        .lock()
+                        .unwrap()
+                        .observe_request(&path, started_at.elapsed().as_secs_f64());
+                }
+                (state, response)
+            })
+            .boxed()
+    }
+}
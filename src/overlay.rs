@@ -0,0 +1,102 @@
+//! Optional on-screen widget showing whether a trainer is connected, current `SimulationState`,
+//! control step rate and the last client address. Useful during demos and to debug
+//! whether anything is connected to the window at all.
+
+use std::marker::PhantomData;
+use std::time::Duration;
+
+use bevy::prelude::*;
+
+use crate::{state, EventControl, SimulationState};
+
+/// Trainer is considered connected if it made a request within this interval
+pub(crate) const CONNECTION_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Adds status overlay to the window
+#[derive(Default)]
+pub struct AIGymStatusOverlayPlugin<
+    T: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::de::DeserializeOwned,
+    P: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::Serialize,
+>(pub PhantomData<(T, P)>);
+
+impl<
+        T: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::de::DeserializeOwned,
+        P: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::Serialize,
+    > Plugin for AIGymStatusOverlayPlugin<T, P>
+{
+    fn build(&self, app: &mut App) {
+        app.add_systems(Startup, spawn_status_overlay)
+            .add_systems(Update, update_status_overlay::<T, P>);
+    }
+}
+
+/// Marks the overlay text
+#[derive(Component)]
+struct StatusOverlayText;
+
+/// Control steps counted over the last second
+#[derive(Default)]
+struct StepRate {
+    window: f32,
+    steps: u32,
+    rate: u32,
+}
+
+fn spawn_status_overlay(mut commands: Commands) {
+    commands.spawn((
+        Text::new(""),
+        TextFont {
+            font_size: 14.0,
+            ..default()
+        },
+        Node {
+            position_type: PositionType::Absolute,
+            top: Val::Px(5.0),
+            left: Val::Px(5.0),
+            ..default()
+        },
+        StatusOverlayText,
+    ));
+}
+
+fn update_status_overlay<
+    T: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::de::DeserializeOwned,
+    P: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::Serialize,
+>(
+    ai_gym_state: Res<state::AIGymState<T, P>>,
+    simulation_state: Res<State<SimulationState>>,
+    time: Res<Time<Real>>,
+    mut control_event_reader: EventReader<EventControl>,
+    mut step_rate: Local<StepRate>,
+    mut texts: Query<&mut Text, With<StatusOverlayText>>,
+) {
+    step_rate.steps += control_event_reader.read().count() as u32;
+    step_rate.window += time.delta_secs();
+    if step_rate.window >= 1.0 {
+        step_rate.rate = step_rate.steps;
+        step_rate.steps = 0;
+        step_rate.window = 0.0;
+    }
+
+    let (is_connected, last_client) = {
+        let ai_gym_state = ai_gym_state.lock().unwrap();
+        let is_connected = ai_gym_state
+            .last_request_at
+            .is_some_and(|t| t.elapsed() < CONNECTION_TIMEOUT);
+        (is_connected, ai_gym_state.last_client.clone())
+    };
+
+    for mut text in texts.iter_mut() {
+        text.0 = format!(
+            "trainer: {}\nstate: {:?}\nsteps/s: {}\nclient: {}",
+            if is_connected {
+                "connected"
+            } else {
+                "disconnected"
+            },
+            simulation_state.get(),
+            step_rate.rate,
+            last_client.as_deref().unwrap_or("-"),
+        );
+    }
+}
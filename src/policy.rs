@@ -0,0 +1,266 @@
+//! In-process policy inference with ONNX models (`onnx` feature).
+//! `PolicyRunnerPlugin` runs a trained policy for some agents inside the app, without an HTTP
+//! client, e.g. to ship trained opponents in a game. Each control step the agent's observation
+//! is fed to the model and the decoded action is passed to `set_local_action`, so the agents
+//! must be listed in `AIGymSettings.local_agents`. `/policy/load` swaps the model between episodes.
+
+use std::marker::PhantomData;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use bevy::prelude::*;
+use tract_onnx::prelude::*;
+
+use crate::{preprocess, state, BevyRlSet, EventLoadPolicy, SimulationState};
+
+/// Observation fed to the model, batch of one
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PolicyInput {
+    /// Stacked visual observations as `float32` `[1, frames * channels, height, width]`,
+    /// scaled to `[0, 1]` or normalized by the `Normalize` preprocessing step
+    Visual,
+    /// Vector observation set with `set_vector_observation` as `float32` `[1, size]`
+    Vector,
+}
+
+/// Turns the first output of the model into an action
+type ActionDecoder<T> = Arc<dyn Fn(&[f32]) -> T + Send + Sync>;
+
+/// Runs the ONNX model at `path` for `agents`
+pub struct PolicyRunnerPlugin<
+    T: 'static
+        + Send
+        + Sync
+        + Clone
+        + std::panic::RefUnwindSafe
+        + serde::de::DeserializeOwned
+        + serde::Serialize,
+    P: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::Serialize,
+> {
+    pub path: PathBuf,
+    pub agents: Vec<usize>,
+    pub input: PolicyInput,
+    /// Slot of `/policy/load` swapping the model, the default one if not set
+    pub slot: Option<String>,
+    decode_action: ActionDecoder<T>,
+    _phantom: PhantomData<P>,
+}
+
+impl<
+        T: 'static
+            + Send
+            + Sync
+            + Clone
+            + std::panic::RefUnwindSafe
+            + serde::de::DeserializeOwned
+            + serde::Serialize,
+        P: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::Serialize,
+    > PolicyRunnerPlugin<T, P>
+{
+    /// `decode_action` maps model output to an action, e.g. with `argmax` for discrete actions
+    pub fn new(
+        path: impl Into<PathBuf>,
+        agents: Vec<usize>,
+        input: PolicyInput,
+        decode_action: impl Fn(&[f32]) -> T + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            path: path.into(),
+            agents,
+            input,
+            slot: None,
+            decode_action: Arc::new(decode_action),
+            _phantom: PhantomData,
+        }
+    }
+
+    /// Swap the model with `/policy/load?slot=...`, e.g. to keep self-play opponents
+    /// from different checkpoints
+    pub fn with_slot(mut self, slot: impl Into<String>) -> Self {
+        self.slot = Some(slot.into());
+        self
+    }
+}
+
+impl<
+        T: 'static
+            + Send
+            + Sync
+            + Clone
+            + std::panic::RefUnwindSafe
+            + serde::de::DeserializeOwned
+            + serde::Serialize,
+        P: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::Serialize,
+    > Plugin for PolicyRunnerPlugin<T, P>
+{
+    fn build(&self, app: &mut App) {
+        app.insert_resource(PolicyRunner {
+            path: self.path.clone(),
+            agents: self.agents.clone(),
+            input: self.input,
+            slot: self.slot.clone(),
+            decode_action: self.decode_action.clone(),
+            model: None,
+            load_failed: false,
+            last_step: None,
+        })
+        .add_systems(
+            Update,
+            (
+                swap_policy::<T>,
+                run_policy::<T, P>.run_if(in_state(SimulationState::PausedForControl)),
+            )
+                .chain()
+                .before(BevyRlSet::ApplyActions),
+        );
+    }
+}
+
+/// Model is loaded on first use, when the input shape is known
+#[derive(Resource)]
+struct PolicyRunner<T> {
+    path: PathBuf,
+    agents: Vec<usize>,
+    input: PolicyInput,
+    slot: Option<String>,
+    decode_action: ActionDecoder<T>,
+    model: Option<TypedRunnableModel<TypedModel>>,
+    /// Model at `path` can't be loaded, agents get no actions until the next swap
+    load_failed: bool,
+    /// Control step and captured frame the actions were computed for
+    last_step: Option<(u64, u64)>,
+}
+
+/// Index of the largest value, e.g. of logits of a discrete action
+pub fn argmax(values: &[f32]) -> usize {
+    values
+        .iter()
+        .enumerate()
+        .max_by(|(_, a), (_, b)| a.total_cmp(b))
+        .map_or(0, |(index, _)| index)
+}
+
+fn load_model(path: &Path, shape: &[usize]) -> TractResult<TypedRunnableModel<TypedModel>> {
+    tract_onnx::onnx()
+        .model_for_path(path)?
+        .with_input_fact(0, f32::fact(shape).into())?
+        .into_optimized()?
+        .into_runnable()
+}
+
+/// Checkpoint loaded with `/policy/load` replaces the model at the start of the episode
+fn swap_policy<T: 'static + Send + Sync>(
+    mut events: EventReader<EventLoadPolicy>,
+    mut runner: ResMut<PolicyRunner<T>>,
+) {
+    if let Some(event) = events
+        .read()
+        .filter(|event| event.slot == runner.slot)
+        .last()
+    {
+        runner.path = PathBuf::from(&event.path);
+        runner.model = None;
+        runner.load_failed = false;
+        runner.last_step = None;
+    }
+}
+
+fn run_policy<
+    T: 'static
+        + Send
+        + Sync
+        + Clone
+        + std::panic::RefUnwindSafe
+        + serde::de::DeserializeOwned
+        + serde::Serialize,
+    P: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::Serialize,
+>(
+    mut runner: ResMut<PolicyRunner<T>>,
+    ai_gym_state: Res<state::AIGymState<T, P>>,
+) {
+    let mut ai_gym_state = ai_gym_state.lock().unwrap();
+    let step = (
+        ai_gym_state.metrics.lock().unwrap().steps,
+        ai_gym_state.frames_captured,
+    );
+    if runner.load_failed || runner.last_step == Some(step) {
+        return;
+    }
+    runner.last_step = Some(step);
+
+    let normalization = preprocess::normalization(&preprocess::pipeline(&ai_gym_state.settings));
+    for agent_index in runner.agents.clone() {
+        let observation = match runner.input {
+            PolicyInput::Visual => visual_input(
+                &ai_gym_state.stacked_observations(agent_index),
+                normalization,
+            ),
+            PolicyInput::Vector => ai_gym_state
+                .vector_observations
+                .get(agent_index)
+                .cloned()
+                .flatten()
+                .map(|values| (vec![1, values.len()], values)),
+        };
+        let Some((shape, values)) = observation else {
+            continue;
+        };
+
+        if runner.model.is_none() {
+            match load_model(&runner.path, &shape) {
+                Ok(model) => runner.model = Some(model),
+                Err(e) => {
+                    warn!("bevy_rl: can't load policy {}: {e}", runner.path.display());
+                    runner.load_failed = true;
+                    return;
+                }
+            }
+        }
+
+        let output = tract_ndarray::Array::from_shape_vec(shape, values)
+            .map_err(TractError::from)
+            .and_then(|input| {
+                runner
+                    .model
+                    .as_ref()
+                    .unwrap()
+                    .run(tvec!(Tensor::from(input).into()))
+            })
+            .and_then(|outputs| {
+                Ok(outputs[0]
+                    .to_array_view::<f32>()?
+                    .iter()
+                    .copied()
+                    .collect::<Vec<f32>>())
+            });
+        match output {
+            Ok(output) => {
+                let action = (runner.decode_action)(&output);
+                ai_gym_state.set_local_action(agent_index, action);
+            }
+            Err(e) => warn!("bevy_rl: policy failed for agent {agent_index}: {e}"),
+        }
+    }
+}
+
+/// `[1, frames * channels, height, width]` tensor of stacked frames
+fn visual_input(
+    frames: &[image::DynamicImage],
+    normalization: Option<(f32, f32)>,
+) -> Option<(Vec<usize>, Vec<f32>)> {
+    let first = frames.first()?;
+    let (width, height) = (first.width() as usize, first.height() as usize);
+    let channels = first.color().channel_count() as usize;
+    let (mean, std) = normalization.unwrap_or((0.0, 1.0));
+
+    let mut values = Vec::with_capacity(frames.len() * channels * height * width);
+    for frame in frames {
+        let pixels = frame.to_rgba32f();
+        for channel in 0..channels {
+            for pixel in pixels.pixels() {
+                values.push((pixel.0[channel] - mean) / std);
+            }
+        }
+    }
+    Some((vec![1, frames.len() * channels, height, width], values))
+}
@@ -0,0 +1,164 @@
+//! Observation preprocessing.
+//! What the policy sees is defined by one pipeline of steps: image steps (resize, color, crop)
+//! run on every captured frame, `Normalize` and `Stack` shape the tensor served at
+//! `/stacked_observations`. The pipeline and the resulting tensor are described at `/spec`.
+
+use serde::Serialize;
+use serde_json::json;
+
+use crate::{AIGymSettings, ColorMode};
+
+/// Step of the observation pipeline, applied in order
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum Preprocess {
+    /// Downsample the frame to given resolution
+    Resize { width: u32, height: u32 },
+    /// Keep only channels of the color mode
+    Color { mode: ColorMode },
+    /// Cut out a rectangle, in pixels of the frame at this point of the pipeline
+    Crop {
+        x: u32,
+        y: u32,
+        width: u32,
+        height: u32,
+    },
+    /// Serve tensors as `float32` values of `(value / max_value - mean) / std`
+    Normalize { mean: f32, std: f32 },
+    /// Stack observations of the last `frames` control steps
+    Stack { frames: u32 },
+}
+
+/// Pipeline in effect: `AIGymSettings.preprocessing` if set, otherwise the one described by
+/// `policy_width`/`policy_height`, `observation_color` and `frame_stack`
+pub fn pipeline(settings: &AIGymSettings) -> Vec<Preprocess> {
+    if !settings.preprocessing.is_empty() {
+        return settings.preprocessing.clone();
+    }
+
+    let mut pipeline = Vec::new();
+    if settings.policy_width > 0 && settings.policy_height > 0 {
+        pipeline.push(Preprocess::Resize {
+            width: settings.policy_width,
+            height: settings.policy_height,
+        });
+    }
+    if settings.observation_color != ColorMode::Rgba {
+        pipeline.push(Preprocess::Color {
+            mode: settings.observation_color,
+        });
+    }
+    if settings.frame_stack > 1 {
+        pipeline.push(Preprocess::Stack {
+            frames: settings.frame_stack,
+        });
+    }
+    pipeline
+}
+
+/// Run image steps of the pipeline on a captured frame
+pub fn apply(pipeline: &[Preprocess], mut frame: image::DynamicImage) -> image::DynamicImage {
+    for step in pipeline {
+        frame = match *step {
+            Preprocess::Resize { width, height } => {
+                if (frame.width(), frame.height()) == (width, height) {
+                    frame
+                } else {
+                    frame.resize_exact(width, height, image::imageops::FilterType::Triangle)
+                }
+            }
+            Preprocess::Color { mode } => mode.convert(frame),
+            Preprocess::Crop {
+                x,
+                y,
+                width,
+                height,
+            } => frame.crop_imm(x, y, width, height),
+            Preprocess::Normalize { .. } | Preprocess::Stack { .. } => frame,
+        };
+    }
+    frame
+}
+
+/// Resolution of frames coming out of the pipeline
+pub fn output_size(pipeline: &[Preprocess], width: u32, height: u32) -> (u32, u32) {
+    pipeline
+        .iter()
+        .fold((width, height), |(width, height), step| match *step {
+            Preprocess::Resize { width, height } => (width, height),
+            Preprocess::Crop {
+                x,
+                y,
+                width: crop_width,
+                height: crop_height,
+            } => (
+                crop_width.min(width.saturating_sub(x)),
+                crop_height.min(height.saturating_sub(y)),
+            ),
+            _ => (width, height),
+        })
+}
+
+/// Channels of frames coming out of the pipeline
+pub fn output_channels(pipeline: &[Preprocess]) -> u8 {
+    pipeline.iter().fold(4, |channels, step| match step {
+        Preprocess::Color {
+            mode: ColorMode::Rgba,
+        } => 4,
+        Preprocess::Color {
+            mode: ColorMode::Rgb,
+        } => 3,
+        Preprocess::Color {
+            mode: ColorMode::Grayscale,
+        } => 1,
+        _ => channels,
+    })
+}
+
+/// Number of stacked observations, at least one
+pub fn stack_frames(pipeline: &[Preprocess]) -> u32 {
+    pipeline
+        .iter()
+        .rev()
+        .find_map(|step| match step {
+            Preprocess::Stack { frames } => Some((*frames).max(1)),
+            _ => None,
+        })
+        .unwrap_or(1)
+}
+
+/// `(mean, std)` of the `Normalize` step if there is one
+pub fn normalization(pipeline: &[Preprocess]) -> Option<(f32, f32)> {
+    pipeline.iter().rev().find_map(|step| match step {
+        Preprocess::Normalize { mean, std } => Some((*mean, *std)),
+        _ => None,
+    })
+}
+
+/// Description of observations served to the policy, as returned by `/spec`
+pub fn spec(settings: &AIGymSettings) -> serde_json::Value {
+    let pipeline = pipeline(settings);
+    let (width, height) = settings.observation_size();
+    let dtype = if normalization(&pipeline).is_some() {
+        "float32"
+    } else if crate::render::is_float_format(settings.texture_format)
+        && !pipeline.iter().any(|step| {
+            // Rgb and grayscale conversions are 8-bit
+            matches!(step, Preprocess::Color { mode } if *mode != ColorMode::Rgba)
+        })
+    {
+        "uint16"
+    } else {
+        "uint8"
+    };
+
+    json!({
+        "num_agents": settings.num_agents,
+        "num_envs": settings.env_count(),
+        "observation": {
+            "pipeline": pipeline,
+            "shape": [stack_frames(&pipeline), height, width, output_channels(&pipeline)],
+            "dtype": dtype,
+        },
+    })
+}
@@ -0,0 +1,175 @@
+//! Physics stepping with bevy_rapier (`rapier` feature).
+//! `RapierStepPlugin` ties Rapier's pipeline to control steps instead of frames: it's inactive
+//! while the simulation is paused for control, and runs exactly one fixed `dt` step per control
+//! interval, that is `frame_skip` steps per control step. Physics then doesn't depend on frame
+//! rate or on how long the trainer takes to send actions.
+
+use bevy::prelude::*;
+use bevy_rapier3d::plugin::{PhysicsSet, RapierConfiguration, TimestepMode};
+
+use crate::{EventAdvanceFrame, EventControl, EventPause};
+
+/// Steps Rapier once per control interval
+pub struct RapierStepPlugin {
+    /// Simulated time of a physics step, usually the control interval
+    pub dt: f32,
+    /// Rapier substeps of a physics step
+    pub substeps: usize,
+}
+
+impl RapierStepPlugin {
+    pub fn new(dt: f32) -> Self {
+        Self { dt, substeps: 1 }
+    }
+
+    /// Split each physics step into substeps, e.g. for stiff joints
+    pub fn with_substeps(mut self, substeps: usize) -> Self {
+        self.substeps = substeps.max(1);
+        self
+    }
+}
+
+impl Plugin for RapierStepPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(TimestepMode::Fixed {
+            dt: self.dt,
+            substeps: self.substeps,
+        })
+        .init_resource::<PendingPhysicsSteps>()
+        .add_systems(
+            Update,
+            (count_physics_steps, pause_physics, start_physics_step).chain(),
+        )
+        .add_systems(PostUpdate, stop_physics.after(PhysicsSet::Writeback));
+    }
+}
+
+/// Control intervals whose physics step hasn't run yet
+#[derive(Resource, Default)]
+struct PendingPhysicsSteps(u32);
+
+/// One step per control interval, and per frame of `/advance`
+fn count_physics_steps(
+    mut control_events: EventReader<EventControl>,
+    mut advance_frame_events: EventReader<EventAdvanceFrame>,
+    mut pending_steps: ResMut<PendingPhysicsSteps>,
+) {
+    pending_steps.0 += control_events.read().count() as u32;
+    pending_steps.0 += advance_frame_events.read().count() as u32;
+}
+
+/// Nothing is stepped while paused for control, even if the environment resumes the pipeline
+fn pause_physics(
+    mut pause_events: EventReader<EventPause>,
+    mut rapier_configurations: Query<&mut RapierConfiguration>,
+) {
+    if pause_events.read().count() == 0 {
+        return;
+    }
+    for mut rapier_configuration in rapier_configurations.iter_mut() {
+        rapier_configuration.physics_pipeline_active = false;
+    }
+}
+
+/// Run one pending step in this frame, Rapier steps in `PostUpdate`
+fn start_physics_step(
+    mut pending_steps: ResMut<PendingPhysicsSteps>,
+    mut rapier_configurations: Query<&mut RapierConfiguration>,
+) {
+    if pending_steps.0 == 0 {
+        return;
+    }
+    pending_steps.0 -= 1;
+    for mut rapier_configuration in rapier_configurations.iter_mut() {
+        rapier_configuration.physics_pipeline_active = true;
+    }
+}
+
+fn stop_physics(mut rapier_configurations: Query<&mut RapierConfiguration>) {
+    for mut rapier_configuration in rapier_configurations.iter_mut() {
+        rapier_configuration.physics_pipeline_active = false;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::prelude::*;
+    use bevy_rapier3d::plugin::RapierConfiguration;
+
+    use super::*;
+    use crate::state::{AIGymState, AdvanceRequest};
+    use crate::testing::TestDriver;
+    use crate::{AIGymPlugin, AIGymSettings, SimulationState};
+
+    /// Frames in which Rapier's pipeline was active
+    #[derive(Resource, Default)]
+    struct PhysicsFrames(u32);
+
+    fn count_physics_frames(
+        rapier_configurations: Query<&RapierConfiguration>,
+        mut physics_frames: ResMut<PhysicsFrames>,
+    ) {
+        if rapier_configurations
+            .iter()
+            .any(|rapier_configuration| rapier_configuration.physics_pipeline_active)
+        {
+            physics_frames.0 += 1;
+        }
+    }
+
+    fn resume_on_control(
+        mut control_events: EventReader<EventControl>,
+        mut simulation_state: ResMut<NextState<SimulationState>>,
+    ) {
+        if control_events.read().count() > 0 {
+            simulation_state.set(SimulationState::Running);
+        }
+    }
+
+    #[test]
+    fn advance_steps_physics_every_frame() {
+        let mut app = App::new();
+        app.add_plugins(MinimalPlugins)
+            .add_plugins(WindowPlugin::default())
+            .add_plugins(AssetPlugin::default())
+            .add_plugins(ImagePlugin::default());
+        app.insert_resource(AIGymState::<String, ()>::new(AIGymSettings {
+            num_agents: 1,
+            render_to_buffer: false,
+            enable_rest_api: false,
+            pause_interval: 0.01,
+            ..default()
+        }))
+        .add_plugins(AIGymPlugin::<String, ()>::default())
+        .add_plugins(RapierStepPlugin::new(0.01))
+        .init_resource::<PhysicsFrames>()
+        .add_systems(Update, resume_on_control)
+        .add_systems(PostUpdate, count_physics_frames.before(stop_physics));
+        app.world_mut().spawn(RapierConfiguration::new(1.0));
+
+        let mut driver = TestDriver::<String, ()>::new(app);
+        driver.update();
+        driver.step_actions(vec![Some("UP".to_string())]);
+        assert_eq!(driver.app.world().resource::<PhysicsFrames>().0, 1);
+        driver.app.world_mut().resource_mut::<PhysicsFrames>().0 = 0;
+
+        let advance_result_rx = driver
+            .app
+            .world()
+            .resource::<AIGymState<String, ()>>()
+            .lock()
+            .unwrap()
+            .advance_requests
+            .submit(AdvanceRequest {
+                frames: 3,
+                noop: false,
+            });
+        let advanced = (0..20).any(|_| {
+            driver.update();
+            advance_result_rx.try_recv().is_ok()
+        });
+
+        assert!(advanced, "No advance result after 20 frames");
+        assert_eq!(driver.app.world().resource::<PhysicsFrames>().0, 3);
+    }
+}
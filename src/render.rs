@@ -1,135 +1,878 @@
 use bevy::{
     prelude::*,
     render::{
+        camera::{ExtractedCamera, NormalizedRenderTarget},
         render_asset::RenderAssets,
-        render_resource::{
-            Extent3d, TextureDescriptor, TextureDimension, TextureFormat, TextureUsages,
-        },
+        render_resource::{Extent3d, TextureFormat},
         renderer::{RenderDevice, RenderQueue},
         texture::GpuImage,
+        view::ViewDepthTexture,
     },
 };
 
+use std::collections::VecDeque;
+use std::sync::{Arc, OnceLock};
+
 use wgpu::ImageCopyBuffer;
 use wgpu::ImageDataLayout;
 
-use crate::state;
+use crate::{preprocess, state};
 
-fn texture_image_layout(desc: &TextureDescriptor<'_>) -> ImageDataLayout {
-    let size = desc.size;
+/// Rows of a texture copy must be aligned to 256 bytes
+fn padded_bytes_per_row(unpadded_bytes_per_row: u32) -> u32 {
+    let align = wgpu::COPY_BYTES_PER_ROW_ALIGNMENT;
+    unpadded_bytes_per_row.div_ceil(align) * align
+}
 
-    let width = size.width * desc.format.block_dimensions().0;
-    let height = size.width * desc.format.block_dimensions().1;
+/// Drop alignment padding at the end of every row of a texture copy
+fn strip_row_padding(
+    data: &[u8],
+    unpadded_bytes_per_row: u32,
+    padded_bytes_per_row: u32,
+) -> Vec<u8> {
+    data.chunks(padded_bytes_per_row as usize)
+        .flat_map(|row| &row[..unpadded_bytes_per_row as usize])
+        .copied()
+        .collect()
+}
 
-    ImageDataLayout {
-        bytes_per_row: if size.height > 1 { Some(width) } else { None },
-        rows_per_image: if size.depth_or_array_layers > 1 {
-            Some(height)
-        } else {
-            None
-        },
-        ..Default::default()
+/// Render target formats which can be read back into visual observations
+pub fn is_supported_format(format: TextureFormat) -> bool {
+    matches!(
+        format,
+        TextureFormat::Bgra8Unorm
+            | TextureFormat::Bgra8UnormSrgb
+            | TextureFormat::Rgba8Unorm
+            | TextureFormat::Rgba8UnormSrgb
+            | TextureFormat::Rgba16Float
+            | TextureFormat::Rgba32Float
+    )
+}
+
+/// Formats read back into 16 bits per channel observations
+pub fn is_float_format(format: TextureFormat) -> bool {
+    matches!(
+        format,
+        TextureFormat::Rgba16Float | TextureFormat::Rgba32Float
+    )
+}
+
+/// Same format with channels in RGBA order, the downsampling pass writes into it
+/// so that BGRA frames don't need swizzling on CPU
+fn rgba_order(format: TextureFormat) -> TextureFormat {
+    match format {
+        TextureFormat::Bgra8Unorm => TextureFormat::Rgba8Unorm,
+        TextureFormat::Bgra8UnormSrgb => TextureFormat::Rgba8UnormSrgb,
+        _ => format,
+    }
+}
+
+fn bytes_per_pixel(format: TextureFormat) -> u32 {
+    format.block_copy_size(None).unwrap_or(4)
+}
+
+/// Unpadded and padded bytes per row, and padded bytes per image of a readback of `size`
+fn readback_layout(format: TextureFormat, size: Extent3d) -> (u32, u32, u64) {
+    let unpadded_bytes_per_row = size.width * bytes_per_pixel(format);
+    let padded_bytes_per_row = padded_bytes_per_row(unpadded_bytes_per_row);
+    (
+        unpadded_bytes_per_row,
+        padded_bytes_per_row,
+        (padded_bytes_per_row * size.height) as u64,
+    )
+}
+
+/// Convert texture data read back from GPU to an image.
+/// 8-bit formats become RGBA8, float formats are clamped to RGBA16
+fn decode_texture(
+    format: TextureFormat,
+    width: u32,
+    height: u32,
+    data: Vec<u8>,
+) -> image::DynamicImage {
+    match format {
+        TextureFormat::Bgra8Unorm | TextureFormat::Bgra8UnormSrgb => {
+            let mut rgba_image: image::RgbaImage =
+                image::ImageBuffer::from_raw(width, height, data).unwrap();
+            // fixing bgra to rgba
+            convert_bgra_to_rgba(&mut rgba_image);
+            image::DynamicImage::ImageRgba8(rgba_image)
+        }
+        TextureFormat::Rgba16Float => {
+            let pixels: Vec<f32> = data
+                .chunks_exact(2)
+                .map(|bits| half::f16::from_ne_bytes([bits[0], bits[1]]).to_f32())
+                .collect();
+            let hdr_image: image::Rgba32FImage =
+                image::ImageBuffer::from_raw(width, height, pixels).unwrap();
+            image::DynamicImage::ImageRgba16(
+                image::DynamicImage::ImageRgba32F(hdr_image).into_rgba16(),
+            )
+        }
+        TextureFormat::Rgba32Float => {
+            let pixels: Vec<f32> = bytes_to_f32(&data);
+            let hdr_image: image::Rgba32FImage =
+                image::ImageBuffer::from_raw(width, height, pixels).unwrap();
+            image::DynamicImage::ImageRgba16(
+                image::DynamicImage::ImageRgba32F(hdr_image).into_rgba16(),
+            )
+        }
+        _ => image::DynamicImage::ImageRgba8(
+            image::ImageBuffer::from_raw(width, height, data).unwrap(),
+        ),
+    }
+}
+
+/// Reinterpret bytes as floats, stripped rows are not guaranteed to be aligned for a cast
+fn bytes_to_f32(data: &[u8]) -> Vec<f32> {
+    data.chunks_exact(4)
+        .map(|bytes| f32::from_ne_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+        .collect()
+}
+
+/// Staging buffers for color and depth readback
+const STAGING_BUFFERS: usize = 2;
+
+/// Box filter: every target pixel is the average of source texels it covers,
+/// `ratio` is source size over target size
+const DOWNSAMPLE_SHADER: &str = r#"
+@group(0) @binding(0) var source: texture_2d<f32>;
+@group(0) @binding(1) var<uniform> ratio: vec2<f32>;
+
+@vertex
+fn vertex(@builtin(vertex_index) index: u32) -> @builtin(position) vec4<f32> {
+    // Fullscreen triangle
+    let uv = vec2<f32>(f32((index << 1u) & 2u), f32(index & 2u));
+    return vec4<f32>(uv * 2.0 - 1.0, 0.0, 1.0);
+}
+
+@fragment
+fn fragment(@builtin(position) position: vec4<f32>) -> @location(0) vec4<f32> {
+    let source_size = vec2<i32>(textureDimensions(source));
+    let start = vec2<i32>(floor((position.xy - 0.5) * ratio));
+    let end = min(max(vec2<i32>(ceil((position.xy + 0.5) * ratio)), start + 1), source_size);
+
+    var sum = vec4<f32>(0.0);
+    for (var y = start.y; y < end.y; y++) {
+        for (var x = start.x; x < end.x; x++) {
+            sum += textureLoad(source, vec2<i32>(x, y), 0);
+        }
+    }
+    let count = vec2<f32>(end - start);
+    return sum / (count.x * count.y);
+}
+"#;
+
+/// Pass downsampling agents' frames for `AIGymSettings.observation_scale`. Each agent is drawn
+/// into its layer of a small texture array, which is read back instead of full-size targets
+struct Downsample {
+    pipeline: wgpu::RenderPipeline,
+    bind_group_layout: wgpu::BindGroupLayout,
+    ratio: wgpu::Buffer,
+    texture: wgpu::Texture,
+    layers: Vec<wgpu::TextureView>,
+}
+
+impl Downsample {
+    fn new(
+        device: &wgpu::Device,
+        render_queue: &RenderQueue,
+        format: TextureFormat,
+        source: Extent3d,
+        target: Extent3d,
+        layers: u32,
+    ) -> Self {
+        let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("bevy_rl_downsample"),
+            source: wgpu::ShaderSource::Wgsl(DOWNSAMPLE_SHADER.into()),
+        });
+
+        let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: None,
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        // Texels are loaded, not sampled, so float formats work as well
+                        sample_type: wgpu::TextureSampleType::Float { filterable: false },
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                        multisampled: false,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+            ],
+        });
+
+        let layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: None,
+            bind_group_layouts: &[&bind_group_layout],
+            push_constant_ranges: &[],
+        });
+
+        let pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some("bevy_rl_downsample"),
+            layout: Some(&layout),
+            vertex: wgpu::VertexState {
+                module: &shader,
+                entry_point: Some("vertex"),
+                compilation_options: default(),
+                buffers: &[],
+            },
+            primitive: default(),
+            depth_stencil: None,
+            multisample: default(),
+            fragment: Some(wgpu::FragmentState {
+                module: &shader,
+                entry_point: Some("fragment"),
+                compilation_options: default(),
+                targets: &[Some(wgpu::ColorTargetState {
+                    format,
+                    blend: None,
+                    write_mask: wgpu::ColorWrites::ALL,
+                })],
+            }),
+            multiview: None,
+            cache: None,
+        });
+
+        let ratio = device.create_buffer(&wgpu::BufferDescriptor {
+            label: None,
+            size: 8,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        render_queue.write_buffer(
+            &ratio,
+            0,
+            bytemuck::cast_slice(&[
+                source.width as f32 / target.width as f32,
+                source.height as f32 / target.height as f32,
+            ]),
+        );
+
+        let texture = device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("bevy_rl_downsampled_observations"),
+            size: Extent3d {
+                depth_or_array_layers: layers,
+                ..target
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format,
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::COPY_SRC,
+            view_formats: &[],
+        });
+        let layers = (0..layers)
+            .map(|layer| texture.create_view(&layer_view_descriptor(layer)))
+            .collect();
+
+        Self {
+            pipeline,
+            bind_group_layout,
+            ratio,
+            texture,
+            layers,
+        }
+    }
+
+    /// Record drawing of `source` into `layer`
+    fn draw(
+        &self,
+        device: &wgpu::Device,
+        encoder: &mut wgpu::CommandEncoder,
+        source: &wgpu::TextureView,
+        layer: usize,
+    ) {
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: None,
+            layout: &self.bind_group_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(source),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: self.ratio.as_entire_binding(),
+                },
+            ],
+        });
+
+        let mut pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some("bevy_rl_downsample"),
+            color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                view: &self.layers[layer],
+                resolve_target: None,
+                ops: wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(wgpu::Color::BLACK),
+                    store: wgpu::StoreOp::Store,
+                },
+            })],
+            depth_stencil_attachment: None,
+            timestamp_writes: None,
+            occlusion_query_set: None,
+        });
+        pass.set_pipeline(&self.pipeline);
+        pass.set_bind_group(0, &bind_group, &[]);
+        pass.draw(0..3, 0..1);
+    }
+}
+
+/// View of one layer of a texture array as a 2D texture
+fn layer_view_descriptor(layer: u32) -> wgpu::TextureViewDescriptor<'static> {
+    wgpu::TextureViewDescriptor {
+        dimension: Some(wgpu::TextureViewDimension::D2),
+        base_array_layer: layer,
+        array_layer_count: Some(1),
+        ..default()
+    }
+}
+
+/// Staging buffers of agents' color and depth readback. Frames of all agents are copied into
+/// one free buffer and mapped asynchronously, they're consumed on one of the next frames once
+/// mapping finished, so the render thread never waits for the GPU
+#[derive(Resource, Default)]
+pub(crate) struct ReadbackBuffers {
+    free: Vec<wgpu::Buffer>,
+    free_depth: Vec<wgpu::Buffer>,
+    in_flight: VecDeque<InFlightCopy>,
+    /// Depth copy of this frame, taken along by the color copy which runs right after
+    depth: Option<DepthCopy>,
+    downsample: Option<Downsample>,
+}
+
+/// Copy of a frame into a staging buffer, `mapping` is set by the map callback.
+/// Agents' images of `size` and `format` follow each other in the buffer in the order of `agents`
+struct InFlightCopy {
+    buffer: wgpu::Buffer,
+    mapping: Arc<OnceLock<Result<(), wgpu::BufferAsyncError>>>,
+    size: Extent3d,
+    format: TextureFormat,
+    agents: Vec<usize>,
+    is_logging_frame: bool,
+    /// The copy was requested for a control step, its observations are published once
+    /// it's consumed
+    is_step: bool,
+    depth: Option<DepthCopy>,
+    submitted_at: std::time::Instant,
+}
+
+impl InFlightCopy {
+    fn is_mapped(&self) -> bool {
+        self.mapping.get().is_some()
+            && self
+                .depth
+                .as_ref()
+                .map_or(true, |depth| depth.mapping.get().is_some())
     }
 }
 
+/// Copy of depth maps of a frame into a staging buffer, in the order of `agents`
+struct DepthCopy {
+    buffer: wgpu::Buffer,
+    mapping: Arc<OnceLock<Result<(), wgpu::BufferAsyncError>>>,
+    size: Extent3d,
+    agents: Vec<usize>,
+}
+
+impl DepthCopy {
+    /// Depth maps of `agents` from the mapped buffer, agents without a camera stay blank
+    fn read(&self, agents: usize) -> Vec<DepthImage> {
+        let (width, height) = (self.size.width, self.size.height);
+        let (unpadded_bytes_per_row, padded_bytes_per_row, agent_bytes) = depth_layout(self.size);
+
+        let mut depth_observations = vec![DepthImage::new(width, height); agents];
+        let data = self
+            .buffer
+            .slice(..agent_bytes * self.agents.len().max(1) as u64)
+            .get_mapped_range();
+        for (&agent_index, image) in self.agents.iter().zip(data.chunks(agent_bytes as usize)) {
+            let depth = bytes_to_f32(&strip_row_padding(
+                image,
+                unpadded_bytes_per_row,
+                padded_bytes_per_row,
+            ));
+            if let Some(depth_observation) = depth_observations.get_mut(agent_index) {
+                *depth_observation = DepthImage::from_raw(width, height, depth).unwrap();
+            }
+        }
+        drop(data);
+
+        depth_observations
+    }
+}
+
+/// Layout of a depth readback of `size`, depth is copied as 32-bit floats
+fn depth_layout(size: Extent3d) -> (u32, u32, u64) {
+    let unpadded_bytes_per_row = size.width * 4;
+    let padded_bytes_per_row = padded_bytes_per_row(unpadded_bytes_per_row);
+    (
+        unpadded_bytes_per_row,
+        padded_bytes_per_row,
+        (padded_bytes_per_row * size.height) as u64,
+    )
+}
+
 /// Copy a texture buffer from GPU to RAM and convert color space to RGBA.
 /// It makes possible to export render results via API.
+/// Observations lag one or more frames behind rendering, copies aren't waited for
 pub(crate) fn copy_from_gpu_to_ram<
-    T: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe,
+    T: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::de::DeserializeOwned,
     P: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::Serialize,
 >(
     gpu_images: Res<RenderAssets<GpuImage>>,
     render_device: Res<RenderDevice>,
     render_queue: Res<RenderQueue>,
     ai_gym_state: Res<state::AIGymState<T, P>>,
+    mut readback_buffers: ResMut<ReadbackBuffers>,
 ) {
     let mut ai_gym_state_locked = ai_gym_state.lock().unwrap();
     if !ai_gym_state_locked.settings.render_to_buffer {
         return;
     }
     let ai_gym_settings = ai_gym_state_locked.settings.clone();
+    let render_image_handles = ai_gym_state_locked.render_image_handles.clone();
+    let render_target_array = ai_gym_state_locked.render_target_array.clone();
+
+    // Frames between control steps aren't observed, only copies in flight are consumed then.
+    // If GPU is behind, the request waits for the next frame
+    let should_copy = (ai_gym_state_locked.observation_requested
+        || ai_gym_settings.continuous_readback)
+        && readback_buffers.in_flight.len() < STAGING_BUFFERS;
 
     let device = render_device.wgpu_device();
-    let size = Extent3d {
+    let full_size = Extent3d {
         width: ai_gym_settings.width,
         height: ai_gym_settings.height,
         ..default()
     };
+    let (width, height) = ai_gym_settings.readback_size();
 
-    ai_gym_state_locked.visual_observations = Vec::new();
-    for (_, gp) in ai_gym_state_locked
-        .render_image_handles
-        .clone()
-        .iter()
-        .enumerate()
-    {
-        let render_gpu_image = gpu_images.get(gp).unwrap();
-        let texture_width = size.width;
-        let texture_height = size.height;
+    // Agents keep their last observation until a newer copy is mapped
+    let pipeline = preprocess::pipeline(&ai_gym_settings);
+    let agents = ai_gym_state_locked.render_target_count();
+    if agents == 0 {
+        return;
+    }
+    if ai_gym_state_locked.visual_observations.len() != agents {
+        let blank = preprocess::apply(&pipeline, image::DynamicImage::new_rgba8(width, height));
+        ai_gym_state_locked.visual_observations = vec![blank; agents];
+    }
 
-        let destination = device.create_buffer(&wgpu::BufferDescriptor {
-            label: None,
-            size: (texture_width * texture_height * 4) as u64,
-            usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
-            mapped_at_creation: false,
-        });
+    // Full-resolution logging frames are kept only every `logging_interval` captures
+    let is_logging_frame = should_copy
+        && ai_gym_settings.logging_interval > 0
+        && ai_gym_state_locked.frames_captured % ai_gym_settings.logging_interval as u64 == 0;
 
-        let texture = render_gpu_image.texture.clone();
+    // Logging frames skip downsampling, so buffers fit full-size frames
+    let downsample =
+        should_copy && !is_logging_frame && (width, height) != (full_size.width, full_size.height);
+    let size = if downsample {
+        Extent3d {
+            width,
+            height,
+            ..default()
+        }
+    } else {
+        full_size
+    };
+    // Downsampled frames come in RGBA order whatever the render target format is
+    let format = ai_gym_settings.texture_format;
+    let copy_format = if downsample {
+        rgba_order(format)
+    } else {
+        format
+    };
+    let (unpadded_bytes_per_row, padded_bytes_per_row, agent_bytes) =
+        readback_layout(copy_format, size);
 
+    if should_copy {
+        ai_gym_state_locked.frames_captured += 1;
+
+        let destination = readback_buffers.free.pop().unwrap_or_else(|| {
+            device.create_buffer(&wgpu::BufferDescriptor {
+                label: None,
+                size: readback_layout(format, full_size).2 * agents as u64,
+                usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
+                mapped_at_creation: false,
+            })
+        });
+
+        // Copy this frame of all agents, with a texture array it's a single copy of all layers
         let mut encoder =
             render_device.create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
+        let mut copied = Vec::new();
+        let downsampled = if downsample {
+            let pass = readback_buffers.downsample.get_or_insert_with(|| {
+                Downsample::new(
+                    device,
+                    &render_queue,
+                    copy_format,
+                    full_size,
+                    size,
+                    agents as u32,
+                )
+            });
+            // Agents whose image isn't ready keep the previous content of their layer
+            match &render_target_array {
+                Some(texture) => {
+                    for layer in 0..agents {
+                        let view = texture.create_view(&layer_view_descriptor(layer as u32));
+                        pass.draw(device, &mut encoder, &view, layer);
+                    }
+                }
+                None => {
+                    for (index, handle) in render_image_handles.iter().enumerate() {
+                        if let Some(render_gpu_image) = gpu_images.get(handle) {
+                            pass.draw(device, &mut encoder, &render_gpu_image.texture_view, index);
+                        }
+                    }
+                }
+            }
+            Some(&pass.texture)
+        } else {
+            None
+        };
 
-        let texture_extent = Extent3d {
-            width: texture_width,
-            height: texture_height,
-            ..default()
+        match downsampled.or(render_target_array.as_deref()) {
+            Some(texture) => {
+                encoder.copy_texture_to_buffer(
+                    texture.as_image_copy(),
+                    ImageCopyBuffer {
+                        buffer: &destination,
+                        layout: ImageDataLayout {
+                            offset: 0,
+                            bytes_per_row: Some(padded_bytes_per_row),
+                            rows_per_image: Some(size.height),
+                        },
+                    },
+                    Extent3d {
+                        depth_or_array_layers: agents as u32,
+                        ..size
+                    },
+                );
+                copied.extend(0..agents);
+            }
+            None => {
+                for (index, handle) in render_image_handles.iter().enumerate() {
+                    let Some(render_gpu_image) = gpu_images.get(handle) else {
+                        continue;
+                    };
+
+                    encoder.copy_texture_to_buffer(
+                        render_gpu_image.texture.as_image_copy(),
+                        ImageCopyBuffer {
+                            buffer: &destination,
+                            layout: ImageDataLayout {
+                                offset: agent_bytes * copied.len() as u64,
+                                bytes_per_row: Some(padded_bytes_per_row),
+                                rows_per_image: None,
+                            },
+                        },
+                        size,
+                    );
+                    copied.push(index);
+                }
+            }
+        }
+        render_queue.submit([encoder.finish()]);
+
+        // Buffers can be mapped only after the copy is submitted
+        let mapping = Arc::new(OnceLock::new());
+        let mapping_ = mapping.clone();
+        destination
+            .slice(..agent_bytes * copied.len().max(1) as u64)
+            .map_async(wgpu::MapMode::Read, move |result| {
+                let _ = mapping_.set(result);
+            });
+
+        readback_buffers.in_flight.push_back(InFlightCopy {
+            buffer: destination,
+            mapping,
+            size,
+            format: copy_format,
+            agents: copied,
+            is_logging_frame,
+            is_step: ai_gym_state_locked.observation_requested,
+            // Depth of this frame was copied right before
+            depth: readback_buffers.depth.take(),
+            submitted_at: std::time::Instant::now(),
+        });
+        ai_gym_state_locked.observation_requested = false;
+        ai_gym_state_locked.observation_pending = true;
+    }
+
+    // Run callbacks of finished mappings without waiting for the rest
+    device.poll(wgpu::Maintain::Poll);
+
+    while readback_buffers
+        .in_flight
+        .front()
+        .is_some_and(InFlightCopy::is_mapped)
+    {
+        let copy = readback_buffers.in_flight.pop_front().unwrap();
+
+        // Device lost or out of memory: frames of the copy are lost, agents keep their previous
+        // observations. Staging buffers and the downsample pass are recreated on the next copy
+        if let Some(Err(e)) = copy.mapping.get() {
+            warn!("bevy_rl: readback of visual observations failed: {e}");
+            ai_gym_state_locked.readback_failed(e.to_string(), copy.is_step);
+            readback_buffers.free.clear();
+            readback_buffers.free_depth.clear();
+            readback_buffers.downsample = None;
+            continue;
+        }
+        if copy.is_step {
+            ai_gym_state_locked.readback_recovered();
+        }
+        ai_gym_state_locked
+            .metrics
+            .lock()
+            .unwrap()
+            .readback
+            .observe(copy.submitted_at.elapsed().as_secs_f64());
+
+        let (unpadded_bytes_per_row, padded_bytes_per_row, agent_bytes) =
+            readback_layout(copy.format, copy.size);
+
+        let data = copy
+            .buffer
+            .slice(..agent_bytes * copy.agents.len().max(1) as u64)
+            .get_mapped_range();
+        let images: Vec<Vec<u8>> = data
+            .chunks(agent_bytes as usize)
+            .take(copy.agents.len())
+            .map(|image| strip_row_padding(image, unpadded_bytes_per_row, padded_bytes_per_row))
+            .collect();
+        drop(data);
+        copy.buffer.unmap();
+        readback_buffers.free.push(copy.buffer);
+
+        for (&index, result) in copy.agents.iter().zip(images) {
+            let frame = decode_texture(copy.format, copy.size.width, copy.size.height, result);
+
+            // Full-size logging frames are downsampled for the policy on CPU
+            let observed = if (frame.width(), frame.height()) == (width, height) {
+                frame.clone()
+            } else {
+                frame.resize_exact(width, height, image::imageops::FilterType::Triangle)
+            };
+            let policy_image = preprocess::apply(&pipeline, observed);
+
+            if ai_gym_settings.frame_history > 0 {
+                let history = &mut ai_gym_state_locked.frame_history[index];
+                if history.len() == ai_gym_settings.frame_history {
+                    history.pop_front();
+                }
+                history.push_back(policy_image.clone());
+            }
+
+            ai_gym_state_locked.visual_observations[index] = policy_image;
+            if copy.is_logging_frame {
+                if ai_gym_state_locked.logging_observations.len() != agents {
+                    ai_gym_state_locked.logging_observations =
+                        vec![image::RgbaImage::new(full_size.width, full_size.height); agents];
+                }
+                ai_gym_state_locked.logging_observations[index] = frame.into_rgba8();
+            }
+        }
+
+        if let Some(depth) = copy.depth {
+            match depth.mapping.get() {
+                Some(Err(e)) => {
+                    // Depth maps stay blank for this frame
+                    warn!("bevy_rl: readback of depth failed: {e}");
+                    ai_gym_state_locked.readback_failed(e.to_string(), false);
+                    ai_gym_state_locked.depth_observations =
+                        vec![DepthImage::new(depth.size.width, depth.size.height); agents];
+                    readback_buffers.free_depth.clear();
+                }
+                _ => {
+                    ai_gym_state_locked.depth_observations = depth.read(agents);
+                    depth.buffer.unmap();
+                    readback_buffers.free_depth.push(depth.buffer);
+                }
+            }
+        }
+        if copy.is_step {
+            let depth = ai_gym_state_locked.depth_observations.clone();
+            ai_gym_state_locked.publish_observations(depth);
+        }
+    }
+
+    if readback_buffers.in_flight.is_empty() {
+        ai_gym_state_locked.observation_pending = false;
+    }
+}
+
+/// Depth map of an agent's camera, values as written by the camera (reverse-Z for bevy's
+/// perspective projection: 1.0 at the near plane, 0.0 at infinity)
+pub type DepthImage = image::ImageBuffer<image::Luma<f32>, Vec<f32>>;
+
+/// Copy depth textures of agents' cameras into a staging buffer, it's mapped and consumed
+/// together with the color copy of the same frame.
+/// Cameras are matched with agents by their render target image
+pub(crate) fn copy_depth_from_gpu_to_ram<
+    T: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::de::DeserializeOwned,
+    P: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::Serialize,
+>(
+    cameras: Query<(&ExtractedCamera, &ViewDepthTexture)>,
+    render_device: Res<RenderDevice>,
+    render_queue: Res<RenderQueue>,
+    ai_gym_state: Res<state::AIGymState<T, P>>,
+    mut readback_buffers: ResMut<ReadbackBuffers>,
+) {
+    let ai_gym_state_locked = ai_gym_state.lock().unwrap();
+    if !ai_gym_state_locked.settings.render_to_buffer || !ai_gym_state_locked.settings.render_depth
+    {
+        return;
+    }
+    // Depth is copied at the same frames as color, which runs right after
+    if (!ai_gym_state_locked.observation_requested
+        && !ai_gym_state_locked.settings.continuous_readback)
+        || readback_buffers.in_flight.len() >= STAGING_BUFFERS
+    {
+        return;
+    }
+    let agents = ai_gym_state_locked.render_target_count();
+    if agents == 0 {
+        return;
+    }
+    let ai_gym_settings = ai_gym_state_locked.settings.clone();
+    let render_image_handles = ai_gym_state_locked.render_image_handles.clone();
+    let render_target_views = ai_gym_state_locked.render_target_views.clone();
+    drop(ai_gym_state_locked);
+
+    let device = render_device.wgpu_device();
+    let size = Extent3d {
+        width: ai_gym_settings.width,
+        height: ai_gym_settings.height,
+        ..default()
+    };
+    let (_, padded_bytes_per_row, agent_bytes) = depth_layout(size);
+
+    let destination = readback_buffers.free_depth.pop().unwrap_or_else(|| {
+        device.create_buffer(&wgpu::BufferDescriptor {
+            label: None,
+            size: agent_bytes * agents as u64,
+            usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        })
+    });
+
+    let mut encoder =
+        render_device.create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
+    let mut copied = Vec::new();
+    for (camera, depth_texture) in cameras.iter() {
+        let agent_index = match &camera.target {
+            Some(NormalizedRenderTarget::Image(target)) => render_image_handles
+                .iter()
+                .position(|handle| handle.id() == target.handle.id()),
+            Some(NormalizedRenderTarget::TextureView(view)) => {
+                render_target_views.iter().position(|handle| handle == view)
+            }
+            _ => None,
         };
+        let Some(agent_index) = agent_index else {
+            continue;
+        };
+        if copied.len() == agents || copied.contains(&agent_index) {
+            continue;
+        }
 
         encoder.copy_texture_to_buffer(
-            texture.as_image_copy(),
+            wgpu::ImageCopyTexture {
+                texture: &depth_texture.texture,
+                mip_level: 0,
+                origin: wgpu::Origin3d::ZERO,
+                aspect: wgpu::TextureAspect::DepthOnly,
+            },
             ImageCopyBuffer {
                 buffer: &destination,
-                layout: texture_image_layout(&TextureDescriptor {
-                    label: None,
-                    size,
-                    dimension: TextureDimension::D2,
-                    format: TextureFormat::Bgra8UnormSrgb,
-                    mip_level_count: 1,
-                    sample_count: 1,
-                    usage: TextureUsages::TEXTURE_BINDING | TextureUsages::COPY_DST,
-                    view_formats: &[TextureFormat::Bgra8UnormSrgb],
-                }),
+                layout: ImageDataLayout {
+                    offset: agent_bytes * copied.len() as u64,
+                    bytes_per_row: Some(padded_bytes_per_row),
+                    rows_per_image: None,
+                },
             },
-            texture_extent,
+            size,
         );
+        copied.push(agent_index);
+    }
+    render_queue.submit([encoder.finish()]);
 
-        render_queue.submit([encoder.finish()]);
-        let buffer_slice = destination.slice(..);
-
-        buffer_slice.map_async(wgpu::MapMode::Read, move |result| {
-            let err = result.err();
-            if err.is_some() {
-                panic!("{}", err.unwrap().to_string());
-            }
+    // Buffers can be mapped only after the copy is submitted
+    let mapping = Arc::new(OnceLock::new());
+    let mapping_ = mapping.clone();
+    destination
+        .slice(..agent_bytes * copied.len().max(1) as u64)
+        .map_async(wgpu::MapMode::Read, move |result| {
+            let _ = mapping_.set(result);
         });
 
-        device.poll(wgpu::Maintain::Wait);
-
-        let data = buffer_slice.get_mapped_range();
-        let result: Vec<u8> = bytemuck::cast_slice(&data).to_vec();
-
-        drop(data);
-        let mut rgba_image: image::RgbaImage =
-            image::ImageBuffer::from_raw(texture_width, texture_height, result.clone()).unwrap();
+    readback_buffers.depth = Some(DepthCopy {
+        buffer: destination,
+        mapping,
+        size,
+        agents: copied,
+    });
+}
 
-        // fixing bgra to rgba
-        convert_bgra_to_rgba(&mut rgba_image);
+/// Render plugin running on wgpu's fallback adapter (lavapipe, llvmpipe or WARP), so that
+/// pixel observations can be captured in CI and containers without a GPU.
+/// Use it instead of the default one: `DefaultPlugins.set(render::software_render_plugin())`.
+/// The renderer is created right away, since Bevy doesn't ask for the fallback adapter itself
+#[cfg(feature = "software-rendering")]
+pub fn software_render_plugin() -> bevy::render::RenderPlugin {
+    use bevy::render::{
+        renderer::{initialize_renderer, RenderInstance, WgpuWrapper},
+        settings::{RenderCreation, WgpuSettings, WgpuSettingsPriority},
+    };
 
-        ai_gym_state_locked
-            .visual_observations
-            .push(rgba_image.clone());
+    let settings = WgpuSettings {
+        // Downlevel limits and no optional features, software adapters support little else
+        priority: WgpuSettingsPriority::Compatibility,
+        ..default()
+    };
+    let instance = wgpu::Instance::new(wgpu::InstanceDescriptor {
+        backends: settings.backends.unwrap_or(wgpu::Backends::all()),
+        flags: settings.instance_flags,
+        dx12_shader_compiler: settings.dx12_shader_compiler.clone(),
+        gles_minor_version: settings.gles3_minor_version,
+    });
+    let request_adapter_options = wgpu::RequestAdapterOptions {
+        power_preference: settings.power_preference,
+        force_fallback_adapter: true,
+        compatible_surface: None,
+    };
+    let (device, queue, adapter_info, adapter) = futures::executor::block_on(initialize_renderer(
+        &instance,
+        &settings,
+        &request_adapter_options,
+    ));
 
-        destination.unmap();
+    bevy::render::RenderPlugin {
+        render_creation: RenderCreation::manual(
+            device,
+            queue,
+            adapter_info,
+            adapter,
+            RenderInstance(Arc::new(WgpuWrapper::new(instance))),
+        ),
+        synchronous_pipeline_compilation: true,
+        ..default()
     }
 }
 
@@ -139,3 +882,56 @@ fn convert_bgra_to_rgba(image: &mut image::RgbaImage) {
         pixel.0.swap(0, 2);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn row_padding_is_stripped() {
+        for width in [100, 257] {
+            let height = 3;
+            let unpadded_bytes_per_row = width * 4;
+            let padded_bytes_per_row = padded_bytes_per_row(unpadded_bytes_per_row);
+            assert_eq!(padded_bytes_per_row % wgpu::COPY_BYTES_PER_ROW_ALIGNMENT, 0);
+            assert!(padded_bytes_per_row > unpadded_bytes_per_row);
+
+            let rows: Vec<Vec<u8>> = (0..height)
+                .map(|row| {
+                    (0..unpadded_bytes_per_row)
+                        .map(|i| (row * 31 + i) as u8)
+                        .collect()
+                })
+                .collect();
+            let padded: Vec<u8> = rows
+                .iter()
+                .flat_map(|row| {
+                    let padding =
+                        vec![0xaa; (padded_bytes_per_row - unpadded_bytes_per_row) as usize];
+                    row.iter().copied().chain(padding)
+                })
+                .collect();
+
+            let stripped = strip_row_padding(&padded, unpadded_bytes_per_row, padded_bytes_per_row);
+            assert_eq!(stripped, rows.concat());
+        }
+    }
+
+    #[test]
+    fn half_float_textures_are_clamped_to_rgba16() {
+        // 1.0, 0.0, -2.0, +inf, smallest subnormal, 65504.0, -inf, 1.0
+        let data: Vec<u8> = [
+            0x3c00u16, 0x0000, 0xc000, 0x7c00, 0x0001, 0x7bff, 0xfc00, 0x3c00,
+        ]
+        .iter()
+        .flat_map(|bits| bits.to_ne_bytes())
+        .collect();
+
+        let image = decode_texture(TextureFormat::Rgba16Float, 2, 1, data);
+        let image::DynamicImage::ImageRgba16(image) = image else {
+            panic!("half-float texture isn't decoded to RGBA16");
+        };
+        assert_eq!(image.get_pixel(0, 0).0, [65535, 0, 0, 65535]);
+        assert_eq!(image.get_pixel(1, 0).0, [0, 65535, 0, 65535]);
+    }
+}
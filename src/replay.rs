@@ -0,0 +1,216 @@
+//! Recording and replay of action sequences.
+//! `ActionLogPlugin` writes every reset and the actions of every control step to a JSON Lines
+//! log. `ReplayPlugin` feeds a log back through the same request queues the REST API uses, so
+//! actions reach `EventControl` at the original step boundaries and a run (a bug, a highlight
+//! episode) is reproduced without a trainer attached. Replays are deterministic as far as
+//! the environment is: seed resets and run physics in lockstep.
+
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::marker::PhantomData;
+use std::path::{Path, PathBuf};
+use std::thread;
+
+use bevy::prelude::*;
+use crossbeam_channel::{unbounded, Receiver};
+use serde::{Deserialize, Serialize};
+
+use crate::state::{self, ResetRequest};
+
+/// Line of an action log
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ActionLogEntry {
+    Reset(ResetRequest),
+    ResetAgents(Vec<usize>),
+    /// Serialized actions of all agents in a control step, `None` for skipped agents
+    Step(Vec<Option<String>>),
+}
+
+/// Writes resets and actions of every control step to `path`
+pub struct ActionLogPlugin<
+    T: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::de::DeserializeOwned,
+    P: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::Serialize,
+> {
+    pub path: PathBuf,
+    _phantom: PhantomData<(T, P)>,
+}
+
+impl<
+        T: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::de::DeserializeOwned,
+        P: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::Serialize,
+    > ActionLogPlugin<T, P>
+{
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            _phantom: PhantomData,
+        }
+    }
+}
+
+impl<
+        T: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::de::DeserializeOwned,
+        P: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::Serialize,
+    > Plugin for ActionLogPlugin<T, P>
+{
+    fn build(&self, app: &mut App) {
+        // Unbounded, a replay with missing steps is worse than a slow writer
+        let (entry_tx, entry_rx) = unbounded();
+        let path = self.path.clone();
+        thread::spawn(move || write_action_log(path, entry_rx));
+
+        app.add_systems(
+            Startup,
+            move |ai_gym_state: Res<state::AIGymState<T, P>>| {
+                ai_gym_state.lock().unwrap().action_log_tx = Some(entry_tx.clone());
+            },
+        );
+    }
+}
+
+fn write_action_log(path: PathBuf, entry_rx: Receiver<ActionLogEntry>) {
+    let file = path
+        .parent()
+        .map_or(Ok(()), std::fs::create_dir_all)
+        .and_then(|_| File::create(&path));
+    let mut writer = match file {
+        Ok(file) => BufWriter::new(file),
+        Err(e) => {
+            warn!("bevy_rl: can't write action log to {}: {e}", path.display());
+            return;
+        }
+    };
+
+    while let Ok(entry) = entry_rx.recv() {
+        // Flush once the queue is drained, so the log is complete if the app is killed
+        let result = std::iter::once(entry)
+            .chain(entry_rx.try_iter())
+            .try_for_each(|entry| {
+                serde_json::to_writer(&mut writer, &entry)?;
+                writer.write_all(b"\n")
+            })
+            .and_then(|_| writer.flush());
+        if let Err(e) = result {
+            warn!("bevy_rl: action log stopped: {e}");
+            return;
+        }
+    }
+}
+
+/// Replays the action log at `path` in place of a trainer
+pub struct ReplayPlugin<
+    T: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::de::DeserializeOwned,
+    P: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::Serialize,
+> {
+    pub path: PathBuf,
+    /// Exit the app once the log is replayed
+    pub exit_when_done: bool,
+    _phantom: PhantomData<(T, P)>,
+}
+
+impl<
+        T: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::de::DeserializeOwned,
+        P: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::Serialize,
+    > ReplayPlugin<T, P>
+{
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            exit_when_done: false,
+            _phantom: PhantomData,
+        }
+    }
+
+    /// Exit the app once the log is replayed, e.g. after rendering an episode
+    pub fn with_exit_when_done(mut self, exit_when_done: bool) -> Self {
+        self.exit_when_done = exit_when_done;
+        self
+    }
+}
+
+/// Replay thread is done
+#[derive(Resource)]
+struct ReplayDone(Receiver<()>);
+
+impl<
+        T: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::de::DeserializeOwned,
+        P: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::Serialize,
+    > Plugin for ReplayPlugin<T, P>
+{
+    fn build(&self, app: &mut App) {
+        let path = self.path.clone();
+        let (done_tx, done_rx) = crossbeam_channel::bounded(1);
+
+        app.add_systems(
+            Startup,
+            move |ai_gym_state: Res<state::AIGymState<T, P>>| {
+                let ai_gym_state = ai_gym_state.clone();
+                let path = path.clone();
+                let done_tx = done_tx.clone();
+                thread::spawn(move || {
+                    replay_action_log(&ai_gym_state, &path);
+                    let _ = done_tx.send(());
+                });
+            },
+        );
+
+        if self.exit_when_done {
+            app.insert_resource(ReplayDone(done_rx))
+                .add_systems(Update, exit_when_replayed);
+        }
+    }
+}
+
+fn exit_when_replayed(replay_done: Res<ReplayDone>, mut exit: EventWriter<AppExit>) {
+    if replay_done.0.try_recv().is_ok() {
+        exit.send(AppExit::Success);
+    }
+}
+
+/// Submit entries of the log one by one, waiting for each to be processed like a trainer would
+fn replay_action_log<
+    T: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::de::DeserializeOwned,
+    P: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::Serialize,
+>(
+    ai_gym_state: &state::AIGymState<T, P>,
+    path: &Path,
+) {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) => {
+            warn!("bevy_rl: can't read action log {}: {e}", path.display());
+            return;
+        }
+    };
+
+    for (line_index, line) in BufReader::new(file).lines().enumerate() {
+        let entry = line.map_err(|e| e.to_string()).and_then(|line| {
+            serde_json::from_str::<ActionLogEntry>(&line).map_err(|e| e.to_string())
+        });
+        let result = match entry {
+            Ok(ActionLogEntry::Reset(request)) => ai_gym_state.request_reset(request),
+            Ok(ActionLogEntry::ResetAgents(agent_indices)) => {
+                ai_gym_state.request_reset_agents(agent_indices)
+            }
+            Ok(ActionLogEntry::Step(actions)) => ai_gym_state.request_step(actions).map(|_| ()),
+            Err(e) => {
+                warn!(
+                    "bevy_rl: replay stopped at line {} of {}: {e}",
+                    line_index + 1,
+                    path.display()
+                );
+                return;
+            }
+        };
+        if result.is_err() {
+            warn!(
+                "bevy_rl: replay stopped at line {} of {}: engine has stopped",
+                line_index + 1,
+                path.display()
+            );
+            return;
+        }
+    }
+    info!("bevy_rl: replayed {}", path.display());
+}
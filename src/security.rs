@@ -0,0 +1,328 @@
+//! Response signing, token authentication and TLS for environments exposed over untrusted networks.
+//! With `AIGymSettings.signing_key` set, every response carries `X-Signature` header with
+//! hex-encoded HMAC-SHA256 of its body, so clients sharing the key can verify that the response
+//! comes from the genuine environment. `AIGymSettings.api_token` rejects requests without
+//! `Authorization: Bearer <token>` header. With `tls` feature, `AIGymSettings.tls` serves the
+//! API over HTTPS, `AIGymSettings.mutual_tls` makes the server accept only clients presenting
+//! a certificate signed by the given CA. `AIGymSettings.cors_origins` lets browser apps of
+//! given origins call the API.
+
+#[cfg(feature = "tls")]
+use std::fs::File;
+#[cfg(feature = "tls")]
+use std::io::BufReader;
+use std::pin::Pin;
+
+use futures::{FutureExt, TryFutureExt};
+use gotham::handler::HandlerFuture;
+use gotham::middleware::{Middleware, NewMiddleware};
+use gotham::state::{FromState, State};
+use hmac::{Hmac, Mac};
+use hyper::header::{
+    HeaderValue, ACCESS_CONTROL_ALLOW_HEADERS, ACCESS_CONTROL_ALLOW_METHODS,
+    ACCESS_CONTROL_ALLOW_ORIGIN, ACCESS_CONTROL_EXPOSE_HEADERS, ACCESS_CONTROL_MAX_AGE,
+    AUTHORIZATION, ORIGIN, VARY, WWW_AUTHENTICATE,
+};
+use hyper::{Body, HeaderMap, Method, Response, StatusCode, Uri};
+use sha2::Sha256;
+
+use crate::api::ApiError;
+
+/// Paths to PEM files of the server certificate chain and its PKCS8 private key
+#[cfg(feature = "tls")]
+#[derive(Clone, Debug)]
+pub struct TlsSettings {
+    pub cert_path: String,
+    pub key_path: String,
+}
+
+/// Paths to PEM files for TLS with client certificate verification
+#[cfg(feature = "tls")]
+#[derive(Clone, Debug)]
+pub struct MutualTlsSettings {
+    pub cert_path: String,
+    pub key_path: String,
+    pub client_ca_path: String,
+}
+
+/// Gotham middleware adding `X-Signature` header to responses. It's a no-op without a key
+#[derive(Clone, NewMiddleware)]
+pub(crate) struct SigningMiddleware {
+    key: Option<Vec<u8>>,
+}
+
+impl SigningMiddleware {
+    pub(crate) fn new(key: Option<String>) -> Self {
+        Self {
+            key: key.map(String::into_bytes),
+        }
+    }
+}
+
+impl Middleware for SigningMiddleware {
+    fn call<Chain>(self, state: State, chain: Chain) -> Pin<Box<HandlerFuture>>
+    where
+        Chain: FnOnce(State) -> Pin<Box<HandlerFuture>> + Send + 'static,
+    {
+        let Some(key) = self.key else {
+            return chain(state);
+        };
+
+        chain(state)
+            .and_then(move |(state, response)| async move {
+                // Upgraded connections and endless streams don't have a body to sign
+                let is_stream = response
+                    .headers()
+                    .get(hyper::header::CONTENT_TYPE)
+                    .is_some_and(|value| {
+                        value.as_bytes().starts_with(b"multipart/x-mixed-replace")
+                    });
+                if response.status() == StatusCode::SWITCHING_PROTOCOLS || is_stream {
+                    return Ok((state, response));
+                }
+
+                let (mut parts, body) = response.into_parts();
+                let bytes = match hyper::body::to_bytes(body).await {
+                    Ok(bytes) => bytes,
+                    Err(e) => return Err((state, e.into())),
+                };
+
+                let signature = sign(&key, &bytes);
+                parts
+                    .headers
+                    .insert("x-signature", HeaderValue::from_str(&signature).unwrap());
+
+                Ok((state, Response::from_parts(parts, Body::from(bytes))))
+            })
+            .boxed()
+    }
+}
+
+/// Paths served without the token: the dashboard page has no data and its requests send the
+/// token, health probes of orchestrators can't send one
+const UNAUTHENTICATED_PATHS: [&str; 3] = ["/", "/healthz", "/readyz"];
+
+/// Gotham middleware answering `401 Unauthorized` to requests without
+/// `Authorization: Bearer <token>` header. It's a no-op without a token
+#[derive(Clone, NewMiddleware)]
+pub(crate) struct TokenMiddleware {
+    token: Option<String>,
+}
+
+impl TokenMiddleware {
+    pub(crate) fn new(token: Option<String>) -> Self {
+        Self { token }
+    }
+}
+
+impl Middleware for TokenMiddleware {
+    fn call<Chain>(self, state: State, chain: Chain) -> Pin<Box<HandlerFuture>>
+    where
+        Chain: FnOnce(State) -> Pin<Box<HandlerFuture>> + Send + 'static,
+    {
+        let Some(token) = self.token else {
+            return chain(state);
+        };
+
+        if UNAUTHENTICATED_PATHS.contains(&Uri::borrow_from(&state).path()) {
+            return chain(state);
+        }
+
+        let is_authorized = HeaderMap::borrow_from(&state)
+            .get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .is_some_and(|given| constant_time_eq(given.as_bytes(), token.as_bytes()));
+        if is_authorized {
+            return chain(state);
+        }
+
+        let mut response =
+            ApiError::Unauthorized("Missing or invalid API token".to_string()).response(&state);
+        response
+            .headers_mut()
+            .insert(WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"));
+        futures::future::ok((state, response)).boxed()
+    }
+}
+
+/// Response headers browser apps may read, metadata of observations and the signature
+const EXPOSED_HEADERS: &str =
+    "x-observation-step, x-shape, x-dtype, x-num-agents, x-height, x-width, x-channels, x-signature";
+
+/// Gotham middleware allowing browser apps of `origins` to call the API: it answers
+/// preflight `OPTIONS` requests and adds `Access-Control-Allow-Origin` to responses.
+/// `"*"` allows any origin and is sent literally, so browsers don't attach credentials.
+/// It's a no-op without origins
+#[derive(Clone, NewMiddleware)]
+pub(crate) struct CorsMiddleware {
+    origins: Vec<String>,
+}
+
+impl CorsMiddleware {
+    pub(crate) fn new(origins: Vec<String>) -> Self {
+        Self { origins }
+    }
+}
+
+impl Middleware for CorsMiddleware {
+    fn call<Chain>(self, state: State, chain: Chain) -> Pin<Box<HandlerFuture>>
+    where
+        Chain: FnOnce(State) -> Pin<Box<HandlerFuture>> + Send + 'static,
+    {
+        let is_any_origin = self.origins.iter().any(|allowed| allowed == "*");
+        let origin = HeaderMap::borrow_from(&state)
+            .get(ORIGIN)
+            .filter(|origin| {
+                is_any_origin
+                    || self
+                        .origins
+                        .iter()
+                        .any(|allowed| origin.as_bytes() == allowed.as_bytes())
+            })
+            .map(|origin| {
+                if is_any_origin {
+                    HeaderValue::from_static("*")
+                } else {
+                    origin.clone()
+                }
+            });
+        let Some(origin) = origin else {
+            return chain(state);
+        };
+
+        // Preflight requests carry no credentials, they are answered before token check
+        if *Method::borrow_from(&state) == Method::OPTIONS {
+            let mut response = Response::builder()
+                .status(StatusCode::NO_CONTENT)
+                .header(ACCESS_CONTROL_ALLOW_METHODS, "GET, PUT, POST, OPTIONS")
+                .header(
+                    ACCESS_CONTROL_ALLOW_HEADERS,
+                    "Authorization, X-Client-Id, X-Client-Key, Accept, Content-Type",
+                )
+                .header(ACCESS_CONTROL_MAX_AGE, "600")
+                .body(Body::empty())
+                .unwrap();
+            allow_origin(&mut response, origin);
+            return futures::future::ok((state, response)).boxed();
+        }
+
+        chain(state)
+            .map_ok(move |(state, mut response)| {
+                allow_origin(&mut response, origin);
+                response.headers_mut().insert(
+                    ACCESS_CONTROL_EXPOSE_HEADERS,
+                    HeaderValue::from_static(EXPOSED_HEADERS),
+                );
+                (state, response)
+            })
+            .boxed()
+    }
+}
+
+fn allow_origin(response: &mut Response<Body>, origin: HeaderValue) {
+    let headers = response.headers_mut();
+    // Responses differ by origin only when it's echoed
+    if origin != "*" {
+        headers.append(VARY, HeaderValue::from_static("Origin"));
+    }
+    headers.insert(ACCESS_CONTROL_ALLOW_ORIGIN, origin);
+}
+
+/// Compare secrets in time independent of where they differ
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+/// Hex-encoded HMAC-SHA256 of the payload
+pub fn sign(key: &[u8], payload: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).unwrap();
+    mac.update(payload);
+    mac.finalize()
+        .into_bytes()
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+#[cfg(feature = "tls")]
+fn read_certificates(path: &str) -> std::io::Result<Vec<rustls::Certificate>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    Ok(rustls_pemfile::certs(&mut reader)?
+        .into_iter()
+        .map(rustls::Certificate)
+        .collect())
+}
+
+#[cfg(feature = "tls")]
+fn read_private_key(path: &str) -> std::io::Result<rustls::PrivateKey> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut keys = rustls_pemfile::pkcs8_private_keys(&mut reader)?;
+    if keys.is_empty() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("no PKCS8 private key found in {path}"),
+        ));
+    }
+    Ok(rustls::PrivateKey(keys.remove(0)))
+}
+
+/// Build TLS configuration for HTTPS without client authentication
+#[cfg(feature = "tls")]
+pub(crate) fn tls_config(
+    settings: &TlsSettings,
+) -> Result<rustls::ServerConfig, Box<dyn std::error::Error>> {
+    let config = rustls::ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(
+            read_certificates(&settings.cert_path)?,
+            read_private_key(&settings.key_path)?,
+        )?;
+
+    Ok(config)
+}
+
+/// Build TLS configuration requiring clients to authenticate with a certificate
+#[cfg(feature = "tls")]
+pub(crate) fn mutual_tls_config(
+    settings: &MutualTlsSettings,
+) -> Result<rustls::ServerConfig, Box<dyn std::error::Error>> {
+    let mut client_roots = rustls::RootCertStore::empty();
+    for certificate in read_certificates(&settings.client_ca_path)? {
+        client_roots.add(&certificate)?;
+    }
+
+    let config = rustls::ServerConfig::builder()
+        .with_safe_defaults()
+        .with_client_cert_verifier(rustls::server::AllowAnyAuthenticatedClient::new(
+            client_roots,
+        ))
+        .with_single_cert(
+            read_certificates(&settings.cert_path)?,
+            read_private_key(&settings.key_path)?,
+        )?;
+
+    Ok(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn secrets_are_compared_bytewise() {
+        assert!(constant_time_eq(b"token", b"token"));
+        assert!(!constant_time_eq(b"token", b"tokem"));
+        assert!(!constant_time_eq(b"token", b"token2"));
+        assert!(constant_time_eq(b"", b""));
+    }
+
+    #[test]
+    fn payloads_are_signed_with_hmac_sha256() {
+        assert_eq!(
+            sign(b"key", b"The quick brown fox jumps over the lazy dog"),
+            "f7bc83f430538424b13298e6aa6fb143ef4d59a14946175997479dbc2d1a3cd8"
+        );
+    }
+}
@@ -0,0 +1,102 @@
+//! Setting the environment to a given configuration mid-episode.
+//! `POST /set_state` takes a JSON body which `SetStatePlugin` deserializes into a user-defined
+//! type and delivers as `EventSetState`, e.g. to teleport agents to states sampled from a start
+//! state distribution for exploration research. The episode goes on from there.
+
+use std::marker::PhantomData;
+
+use bevy::prelude::*;
+
+use crate::state::{self, Reply};
+use crate::{BevyRlSet, EventSetState, SimulationState};
+
+/// Delivers bodies of `POST /set_state` as `EventSetState<S>`
+pub struct SetStatePlugin<
+    S: 'static + Send + Sync + serde::de::DeserializeOwned,
+    T: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::de::DeserializeOwned,
+    P: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::Serialize,
+> {
+    _phantom: PhantomData<(S, T, P)>,
+}
+
+impl<
+        S: 'static + Send + Sync + serde::de::DeserializeOwned,
+        T: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::de::DeserializeOwned,
+        P: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::Serialize,
+    > SetStatePlugin<S, T, P>
+{
+    pub fn new() -> Self {
+        Self {
+            _phantom: PhantomData,
+        }
+    }
+}
+
+impl<
+        S: 'static + Send + Sync + serde::de::DeserializeOwned,
+        T: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::de::DeserializeOwned,
+        P: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::Serialize,
+    > Default for SetStatePlugin<S, T, P>
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<
+        S: 'static + Send + Sync + serde::de::DeserializeOwned,
+        T: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::de::DeserializeOwned,
+        P: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::Serialize,
+    > Plugin for SetStatePlugin<S, T, P>
+{
+    fn build(&self, app: &mut App) {
+        app.add_event::<EventSetState<S>>()
+            .init_resource::<SetStateReply>()
+            .add_systems(Startup, |ai_gym_state: Res<state::AIGymState<T, P>>| {
+                ai_gym_state.lock().unwrap().set_state_enabled = true;
+            })
+            .add_systems(
+                Update,
+                receive_set_state_request::<S, T, P>
+                    .run_if(in_state(SimulationState::PausedForControl))
+                    .in_set(BevyRlSet::ApplyActions),
+            )
+            .add_systems(PostUpdate, send_set_state_result);
+    }
+}
+
+/// Reply to the request whose event is being handled this frame
+#[derive(Resource, Default)]
+struct SetStateReply(Option<Reply<Result<(), String>>>);
+
+fn receive_set_state_request<
+    S: 'static + Send + Sync + serde::de::DeserializeOwned,
+    T: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::de::DeserializeOwned,
+    P: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::Serialize,
+>(
+    ai_gym_state: Res<state::AIGymState<T, P>>,
+    mut set_state_event_writer: EventWriter<EventSetState<S>>,
+    mut set_state_reply: ResMut<SetStateReply>,
+) {
+    if set_state_reply.0.is_some() {
+        return;
+    }
+    let Some(request) = ai_gym_state.lock().unwrap().set_state_requests.receive() else {
+        return;
+    };
+
+    match serde_json::from_value::<S>(request.request) {
+        Ok(payload) => {
+            set_state_event_writer.send(EventSetState(payload));
+            set_state_reply.0 = Some(request.reply);
+        }
+        Err(e) => request.reply.send(Err(e.to_string())),
+    }
+}
+
+/// Reply once systems handling `EventSetState` in `Update` have run
+fn send_set_state_result(mut set_state_reply: ResMut<SetStateReply>) {
+    if let Some(reply) = set_state_reply.0.take() {
+        reply.send(Ok(()));
+    }
+}
@@ -0,0 +1,149 @@
+//! Shared-memory transport of visual observations for trainers on the same host.
+//! Every captured control step is written into a memory-mapped file and
+//! `/visual_observations/shm` returns only its path and step, frames are never serialized.
+//!
+//! Layout of the region, little endian:
+//!
+//! | Offset | Type  | Field                                                   |
+//! | ------ | ----- | ------------------------------------------------------- |
+//! | 0      | `u64` | sequence, odd while a step is being written             |
+//! | 8      | `u64` | step, same as `x-observation-step` of other endpoints   |
+//! | 16     | `u32` | number of agents                                        |
+//! | 20     | `u32` | height                                                  |
+//! | 24     | `u32` | width                                                   |
+//! | 28     | `u32` | channels                                                |
+//! | 32     | `u32` | bytes per channel, 1 (`uint8`) or 2 (`uint16`)          |
+//! | 64     |       | frames of learning agents, `[agents, height, width, channels]` |
+//!
+//! Readers copy the frames and check that the sequence is even and unchanged before and after.
+
+use std::{
+    fs::OpenOptions,
+    path::PathBuf,
+    sync::atomic::{fence, AtomicU64, Ordering},
+};
+
+use bevy::prelude::*;
+use memmap2::MmapMut;
+
+use crate::state;
+
+/// Bytes of the header before the frames
+pub const HEADER_SIZE: usize = 64;
+
+/// Path of the region called `name`: memory-backed `/dev/shm` on Linux, temp directory elsewhere
+pub fn region_path(name: &str) -> PathBuf {
+    if cfg!(target_os = "linux") {
+        PathBuf::from("/dev/shm").join(name)
+    } else {
+        std::env::temp_dir().join(name)
+    }
+}
+
+/// Region the render app writes observations to, removed when the app exits
+#[derive(Resource)]
+pub(crate) struct SharedObservations {
+    path: PathBuf,
+    region: Option<MmapMut>,
+    step: u64,
+}
+
+impl SharedObservations {
+    pub(crate) fn new(name: &str) -> Self {
+        Self {
+            path: region_path(name),
+            region: None,
+            step: 0,
+        }
+    }
+
+    /// Map the region with at least `size` bytes, growing the file if needed
+    fn map(&mut self, size: usize) -> std::io::Result<&mut MmapMut> {
+        if self
+            .region
+            .as_ref()
+            .map_or(true, |region| region.len() < size)
+        {
+            let file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(&self.path)?;
+            file.set_len(size as u64)?;
+            // Nothing else in the process maps this file
+            self.region = Some(unsafe { MmapMut::map_mut(&file)? });
+        }
+        Ok(self.region.as_mut().unwrap())
+    }
+}
+
+impl Drop for SharedObservations {
+    fn drop(&mut self) {
+        self.region = None;
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// Write visual observations of learning agents once a new step is captured
+pub(crate) fn write_shared_observations<
+    T: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::de::DeserializeOwned,
+    P: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::Serialize,
+>(
+    mut shared: ResMut<SharedObservations>,
+    ai_gym_state: Res<state::AIGymState<T, P>>,
+) {
+    let (observations, learning_agents) = {
+        let ai_gym_state = ai_gym_state.lock().unwrap();
+        (
+            ai_gym_state.observations.clone(),
+            ai_gym_state.settings.learning_agents(),
+        )
+    };
+    if observations.step == shared.step {
+        return;
+    }
+    shared.step = observations.step;
+
+    let frames: Vec<&image::DynamicImage> = learning_agents
+        .iter()
+        .filter_map(|&agent_index| observations.visual.get(agent_index))
+        .collect();
+    let Some(first) = frames.first() else {
+        return;
+    };
+    let color = first.color();
+    let frame_bytes = first.as_bytes().len();
+
+    let region = match shared.map(HEADER_SIZE + frame_bytes * frames.len()) {
+        Ok(region) => region,
+        Err(e) => {
+            warn!("bevy_rl: can't write observations to shared memory: {e}");
+            return;
+        }
+    };
+
+    // Region is page-aligned, so is the sequence at its start
+    let sequence = unsafe { &*(region.as_ptr() as *const AtomicU64) };
+    let started = sequence.load(Ordering::Relaxed) | 1;
+    sequence.store(started, Ordering::Relaxed);
+    fence(Ordering::Release);
+
+    region[8..16].copy_from_slice(&observations.step.to_le_bytes());
+    for (offset, value) in [
+        (16, frames.len() as u32),
+        (20, first.height()),
+        (24, first.width()),
+        (28, color.channel_count() as u32),
+        (32, (color.bytes_per_pixel() / color.channel_count()) as u32),
+    ] {
+        region[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+    }
+    for (agent, frame) in frames.iter().enumerate() {
+        let offset = HEADER_SIZE + agent * frame_bytes;
+        region[offset..offset + frame_bytes].copy_from_slice(frame.as_bytes());
+    }
+
+    sequence.store(started + 1, Ordering::Release);
+    ai_gym_state.lock().unwrap().shared_memory_step = observations.step;
+}
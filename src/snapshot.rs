@@ -0,0 +1,232 @@
+//! World snapshots for tree search and "reset to checkpoint" training.
+//! `/save_state` captures entities marked `Snapshotted` into a `DynamicScene`, together with the
+//! gym bookkeeping (rewards, terminations, episode statistics, observations), and keeps it in
+//! memory. `/load_state?id=` despawns marked entities and restores the snapshot in their place.
+//! Components are captured by reflection, so they must be registered with
+//! `app.register_type::<T>()` and `#[reflect(Component)]`.
+
+use std::collections::BTreeMap;
+
+use bevy::ecs::entity::EntityHashMap;
+use bevy::prelude::*;
+use bevy::reflect::TypeRegistry;
+
+use crate::state::{self, GymSnapshot};
+
+/// Attach to entities captured in snapshots. Mark whole hierarchies, children of a captured
+/// entity which aren't marked are lost on restore
+#[derive(Component, Reflect, Clone, Copy, Debug, Default)]
+#[reflect(Component)]
+pub struct Snapshotted;
+
+/// Request of the API thread
+pub(crate) enum SnapshotRequest {
+    Save,
+    Load(u64),
+}
+
+pub(crate) enum SnapshotError {
+    /// Snapshot was never saved or has been dropped for newer ones
+    NotFound(u64),
+    /// Scene can't be written to the world, e.g. a component type isn't registered
+    Scene(String),
+}
+
+/// Saved or loaded snapshot: id and number of entities
+pub(crate) type SnapshotResult = Result<(u64, usize), SnapshotError>;
+
+struct Snapshot<B> {
+    scene: DynamicScene,
+    gym: GymSnapshot<B>,
+}
+
+/// Snapshots kept in memory by id, the oldest is dropped once there are
+/// `AIGymSettings.max_snapshots` of them
+#[derive(Resource)]
+pub(crate) struct Snapshots<B> {
+    snapshots: BTreeMap<u64, Snapshot<B>>,
+    next_id: u64,
+}
+
+impl<B> Default for Snapshots<B> {
+    fn default() -> Self {
+        Self {
+            snapshots: BTreeMap::new(),
+            next_id: 0,
+        }
+    }
+}
+
+/// Save and load snapshots while the simulation is paused, so that they hold a consistent tick
+pub(crate) fn process_snapshot_requests<
+    T: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::de::DeserializeOwned,
+    P: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::Serialize,
+>(
+    world: &mut World,
+) {
+    let ai_gym_state = world.resource::<state::AIGymState<T, P>>().clone();
+    let Some(request) = ai_gym_state.lock().unwrap().snapshot_requests.receive() else {
+        return;
+    };
+
+    let result = match request.request {
+        SnapshotRequest::Save => save_snapshot(world, &ai_gym_state),
+        SnapshotRequest::Load(id) => load_snapshot(world, &ai_gym_state, id),
+    };
+    request.reply.send(result);
+}
+
+fn save_snapshot<
+    T: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::de::DeserializeOwned,
+    P: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::Serialize,
+>(
+    world: &mut World,
+    ai_gym_state: &state::AIGymState<T, P>,
+) -> SnapshotResult {
+    let entities: Vec<Entity> = world
+        .query_filtered::<Entity, With<Snapshotted>>()
+        .iter(world)
+        .collect();
+    let entity_count = entities.len();
+    let scene = DynamicSceneBuilder::from_world(world)
+        .extract_entities(entities.into_iter())
+        .build();
+
+    let (gym, max_snapshots) = {
+        let ai_gym_state = ai_gym_state.lock().unwrap();
+        (
+            ai_gym_state.gym_snapshot(world),
+            ai_gym_state.settings.max_snapshots.max(1),
+        )
+    };
+
+    let mut snapshots = world.get_resource_or_insert_with(Snapshots::<P>::default);
+    let id = snapshots.next_id;
+    snapshots.next_id += 1;
+    snapshots.snapshots.insert(id, Snapshot { scene, gym });
+    while snapshots.snapshots.len() > max_snapshots {
+        snapshots.snapshots.pop_first();
+    }
+
+    Ok((id, entity_count))
+}
+
+fn load_snapshot<
+    T: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::de::DeserializeOwned,
+    P: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::Serialize,
+>(
+    world: &mut World,
+    ai_gym_state: &state::AIGymState<T, P>,
+    id: u64,
+) -> SnapshotResult {
+    let has_snapshot = world
+        .get_resource::<Snapshots<P>>()
+        .is_some_and(|snapshots| snapshots.snapshots.contains_key(&id));
+    if !has_snapshot {
+        return Err(SnapshotError::NotFound(id));
+    }
+
+    world.resource_scope(|world, snapshots: Mut<Snapshots<P>>| {
+        let snapshot = &snapshots.snapshots[&id];
+        // The world is left as it is if the scene can't be written
+        check_scene_types(&snapshot.scene, &world.resource::<AppTypeRegistry>().read())
+            .map_err(SnapshotError::Scene)?;
+
+        let entities: Vec<Entity> = world
+            .query_filtered::<Entity, With<Snapshotted>>()
+            .iter(world)
+            .collect();
+        for entity in entities {
+            // Marked children are gone with their parents already
+            if let Ok(entity) = world.get_entity_mut(entity) {
+                entity.despawn_recursive();
+            }
+        }
+
+        let mut entity_map = EntityHashMap::default();
+        snapshot
+            .scene
+            .write_to_world(world, &mut entity_map)
+            .map_err(|e| SnapshotError::Scene(e.to_string()))?;
+
+        ai_gym_state
+            .lock()
+            .unwrap()
+            .restore_gym_snapshot(snapshot.gym.clone(), world);
+        Ok((id, entity_map.len()))
+    })
+}
+
+/// Check that `write_to_world` finds every component and resource of the scene in the registry
+fn check_scene_types(scene: &DynamicScene, type_registry: &TypeRegistry) -> Result<(), String> {
+    let components = scene
+        .entities
+        .iter()
+        .flat_map(|entity| entity.components.iter())
+        .map(|component| (component.as_ref(), false));
+    let resources = scene
+        .resources
+        .iter()
+        .map(|resource| (resource.as_ref(), true));
+
+    for (value, is_resource) in components.chain(resources) {
+        let type_path = value.reflect_type_path();
+        let registration = value
+            .get_represented_type_info()
+            .and_then(|type_info| type_registry.get(type_info.type_id()))
+            .ok_or_else(|| format!("type {type_path} isn't registered"))?;
+
+        let is_reflected = if is_resource {
+            registration.data::<ReflectResource>().is_some()
+        } else {
+            registration.data::<ReflectComponent>().is_some()
+        };
+        if !is_reflected {
+            let kind = if is_resource { "Resource" } else { "Component" };
+            return Err(format!(
+                "type {type_path} isn't registered with #[reflect({kind})]"
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::scene::DynamicEntity;
+
+    use super::*;
+
+    #[derive(Component, Reflect, Default)]
+    #[reflect(Component)]
+    struct Health(f32);
+
+    #[derive(Component, Reflect, Default)]
+    struct Unreflected;
+
+    fn scene_with(component: Box<dyn PartialReflect>) -> DynamicScene {
+        DynamicScene {
+            resources: Vec::new(),
+            entities: vec![DynamicEntity {
+                entity: Entity::from_raw(0),
+                components: vec![component],
+            }],
+        }
+    }
+
+    #[test]
+    fn scenes_with_unregistered_types_are_rejected() {
+        let mut type_registry = TypeRegistry::default();
+        let scene = scene_with(Box::new(Health(1.0)));
+        assert!(check_scene_types(&scene, &type_registry).is_err());
+
+        type_registry.register::<Health>();
+        assert!(check_scene_types(&scene, &type_registry).is_ok());
+
+        type_registry.register::<Unreflected>();
+        let error =
+            check_scene_types(&scene_with(Box::new(Unreflected)), &type_registry).unwrap_err();
+        assert!(error.contains("#[reflect(Component)]"));
+    }
+}
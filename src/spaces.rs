@@ -0,0 +1,72 @@
+//! Action and observation space metadata.
+//! Environments describe their spaces once and `/spaces` serves them, so gym wrappers
+//! construct `gym.spaces` objects instead of hard-coding them.
+
+use serde::Serialize;
+
+use crate::{preprocess, AIGymSettings};
+
+/// Space of actions or observations of an agent, mirrors `gym.spaces`
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum SpaceDescriptor {
+    /// Integers `0..n`
+    Discrete { n: u64 },
+    /// Values between `low` and `high` of given `shape` and numpy `dtype`.
+    /// A single bound applies to every element
+    Box {
+        low: Vec<f32>,
+        high: Vec<f32>,
+        shape: Vec<usize>,
+        dtype: String,
+    },
+    /// Vector of integers, element `i` in `0..nvec[i]`
+    MultiDiscrete { nvec: Vec<u64> },
+}
+
+impl SpaceDescriptor {
+    pub fn discrete(n: u64) -> Self {
+        Self::Discrete { n }
+    }
+
+    /// `float32` box with the same bounds for every element
+    pub fn bounded(low: f32, high: f32, shape: Vec<usize>) -> Self {
+        Self::Box {
+            low: vec![low],
+            high: vec![high],
+            shape,
+            dtype: "float32".to_string(),
+        }
+    }
+
+    pub fn multi_discrete(nvec: Vec<u64>) -> Self {
+        Self::MultiDiscrete { nvec }
+    }
+}
+
+/// Box of visual observations as served at `/stacked_observations`, used when the environment
+/// doesn't set its observation space. Normalized observations have no known bounds
+pub fn visual_observation_space(settings: &AIGymSettings) -> Option<SpaceDescriptor> {
+    if !settings.render_to_buffer {
+        return None;
+    }
+
+    let spec = preprocess::spec(settings);
+    let (high, dtype) = match spec["observation"]["dtype"].as_str() {
+        Some("uint8") => (u8::MAX as f32, "uint8"),
+        Some("uint16") => (u16::MAX as f32, "uint16"),
+        _ => return None,
+    };
+    let shape = spec["observation"]["shape"]
+        .as_array()?
+        .iter()
+        .filter_map(|dimension| dimension.as_u64().map(|dimension| dimension as usize))
+        .collect();
+
+    Some(SpaceDescriptor::Box {
+        low: vec![0.0],
+        high: vec![high],
+        shape,
+        dtype: dtype.to_string(),
+    })
+}
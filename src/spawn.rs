@@ -0,0 +1,183 @@
+//! Helpers to randomize agent spawn poses on `EventReset`.
+//! Attach `SpawnDistribution` to agent entities and bevy_rl will move them
+//! to a new pose sampled with `AIGymRng` every time the environment is reset.
+//! Entities marked `EpisodeScoped` (projectiles, particles, temporary spawns) are despawned instead.
+
+use bevy::prelude::*;
+use rand::distributions::{Distribution, WeightedIndex};
+use rand::Rng;
+
+use crate::{AIGymRng, EventReset};
+
+/// Region to sample spawn positions from
+#[derive(Clone, Debug)]
+pub enum SpawnRegion {
+    /// Uniformly inside an axis-aligned box
+    Aabb { min: Vec3, max: Vec3 },
+    /// Uniformly over the surface of a navigation mesh given as a list of triangles
+    NavMesh(Vec<[Vec3; 3]>),
+    /// Uniformly on a ring around `center` in XZ plane
+    Ring {
+        center: Vec3,
+        inner_radius: f32,
+        outer_radius: f32,
+    },
+}
+
+impl SpawnRegion {
+    /// Sample a position from the region
+    pub fn sample<R: Rng + ?Sized>(&self, rng: &mut R) -> Vec3 {
+        match self {
+            SpawnRegion::Aabb { min, max } => Vec3::new(
+                sample_range(rng, min.x, max.x),
+                sample_range(rng, min.y, max.y),
+                sample_range(rng, min.z, max.z),
+            ),
+            SpawnRegion::NavMesh(triangles) => {
+                let areas = triangles
+                    .iter()
+                    .map(|[a, b, c]| (*b - *a).cross(*c - *a).length() / 2.0);
+                let Ok(index) = WeightedIndex::new(areas) else {
+                    return Vec3::ZERO;
+                };
+
+                // Uniform sampling of a triangle with square root of the first coordinate
+                let [a, b, c] = triangles[index.sample(rng)];
+                let r1 = rng.gen::<f32>().sqrt();
+                let r2 = rng.gen::<f32>();
+                a * (1.0 - r1) + b * (r1 * (1.0 - r2)) + c * (r1 * r2)
+            }
+            SpawnRegion::Ring {
+                center,
+                inner_radius,
+                outer_radius,
+            } => {
+                // Radius is sampled so that points are uniform over the ring's area
+                let inner = inner_radius * inner_radius;
+                let outer = outer_radius * outer_radius;
+                let radius = sample_range(rng, inner, outer).sqrt();
+                let angle = rng.gen::<f32>() * std::f32::consts::TAU;
+                *center + Vec3::new(radius * angle.cos(), 0.0, radius * angle.sin())
+            }
+        }
+    }
+}
+
+/// Attach to an agent entity to reposition it on every `EventReset`
+#[derive(Component, Clone, Debug)]
+pub struct SpawnDistribution {
+    pub region: SpawnRegion,
+    /// Rotate the agent around Y axis by a random angle
+    pub random_yaw: bool,
+}
+
+impl SpawnDistribution {
+    /// Sample a pose from the distribution
+    pub fn sample<R: Rng + ?Sized>(&self, rng: &mut R) -> Transform {
+        let mut transform = Transform::from_translation(self.region.sample(rng));
+        if self.random_yaw {
+            transform.rotation = Quat::from_rotation_y(rng.gen::<f32>() * std::f32::consts::TAU);
+        }
+        transform
+    }
+}
+
+fn sample_range<R: Rng + ?Sized>(rng: &mut R, min: f32, max: f32) -> f32 {
+    if max > min {
+        rng.gen_range(min..max)
+    } else {
+        min
+    }
+}
+
+/// Move agents with `SpawnDistribution` to new poses when the environment is reset
+pub(crate) fn reposition_agents_on_reset(
+    mut reset_event_reader: EventReader<EventReset>,
+    mut rng: ResMut<AIGymRng>,
+    mut agents: Query<(Entity, &SpawnDistribution, &mut Transform)>,
+) {
+    if reset_event_reader.read().count() == 0 {
+        return;
+    }
+
+    // Sort agents so that the same seed always gives the same poses
+    let mut agents: Vec<_> = agents.iter_mut().collect();
+    agents.sort_by_key(|(entity, _, _)| *entity);
+
+    for (_, spawn_distribution, mut transform) in agents {
+        let pose = spawn_distribution.sample(&mut rng.0);
+        transform.translation = pose.translation;
+        if spawn_distribution.random_yaw {
+            transform.rotation = pose.rotation;
+        }
+    }
+}
+
+/// Attach to entities which live only until the end of the episode,
+/// they are despawned with their children on every `EventReset`
+#[derive(Component, Clone, Copy, Debug, Default)]
+pub struct EpisodeScoped;
+
+/// Despawn `EpisodeScoped` entities when the environment is reset.
+/// Entities spawned by reset handlers in the same frame are kept
+pub(crate) fn despawn_episode_scoped_on_reset(
+    mut commands: Commands,
+    mut reset_event_reader: EventReader<EventReset>,
+    entities: Query<Entity, With<EpisodeScoped>>,
+) {
+    if reset_event_reader.read().count() == 0 {
+        return;
+    }
+
+    for entity in entities.iter() {
+        commands.entity(entity).despawn_recursive();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    use super::*;
+
+    #[test]
+    fn samples_stay_inside_regions() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let aabb = SpawnRegion::Aabb {
+            min: Vec3::new(-1.0, 0.0, 2.0),
+            max: Vec3::new(1.0, 0.0, 3.0),
+        };
+        let ring = SpawnRegion::Ring {
+            center: Vec3::new(5.0, 1.0, 5.0),
+            inner_radius: 2.0,
+            outer_radius: 3.0,
+        };
+        let nav_mesh = SpawnRegion::NavMesh(vec![[Vec3::ZERO, Vec3::X, Vec3::Z]]);
+
+        for _ in 0..1000 {
+            let position = aabb.sample(&mut rng);
+            assert!((-1.0..=1.0).contains(&position.x));
+            assert_eq!(position.y, 0.0);
+            assert!((2.0..=3.0).contains(&position.z));
+
+            let position = ring.sample(&mut rng);
+            let offset = position - Vec3::new(5.0, 1.0, 5.0);
+            assert_eq!(offset.y, 0.0);
+            assert!((2.0 - 1e-4..=3.0 + 1e-4).contains(&offset.length()));
+
+            let position = nav_mesh.sample(&mut rng);
+            assert_eq!(position.y, 0.0);
+            assert!(position.x >= 0.0 && position.z >= 0.0);
+            assert!(position.x + position.z <= 1.0 + 1e-5);
+        }
+    }
+
+    #[test]
+    fn degenerate_nav_mesh_samples_origin() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let nav_mesh = SpawnRegion::NavMesh(vec![[Vec3::X, Vec3::X, Vec3::X]]);
+        assert_eq!(nav_mesh.sample(&mut rng), Vec3::ZERO);
+        assert_eq!(SpawnRegion::NavMesh(vec![]).sample(&mut rng), Vec3::ZERO);
+    }
+}
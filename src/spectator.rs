@@ -0,0 +1,171 @@
+//! Free-fly and orbit controls for a human spectator camera.
+//! The camera is drawn over the tiled agent views and sees the scene plus `SPECTATOR_LAYER`,
+//! so markers and gizmos put on that layer never show up in agents' observations.
+//! Controls run on real time and keep working while the simulation is paused for control.
+
+use bevy::{
+    input::mouse::{AccumulatedMouseMotion, AccumulatedMouseScroll},
+    prelude::*,
+    render::view::RenderLayers,
+};
+
+/// Render layer seen only by the spectator camera
+pub const SPECTATOR_LAYER: usize = 2;
+
+/// Pitch is kept short of straight up and down
+const MAX_PITCH: f32 = std::f32::consts::FRAC_PI_2 - 0.01;
+
+/// How the spectator camera moves
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SpectatorMode {
+    /// WASD to move, Q/E to go down/up, right mouse button to look around, wheel to change speed
+    FreeFly,
+    /// Right mouse button to rotate around `focus`, wheel to zoom, WASD to move the focus
+    Orbit { focus: Vec3 },
+}
+
+/// Adds a spectator camera toggled with `toggle_key`
+pub struct SpectatorCameraPlugin {
+    pub mode: SpectatorMode,
+    pub transform: Transform,
+    /// Units per second
+    pub speed: f32,
+    /// Radians per pixel of mouse motion
+    pub sensitivity: f32,
+    pub toggle_key: KeyCode,
+}
+
+impl Default for SpectatorCameraPlugin {
+    fn default() -> Self {
+        Self {
+            mode: SpectatorMode::FreeFly,
+            transform: Transform::from_xyz(0.0, 5.0, 10.0).looking_at(Vec3::ZERO, Vec3::Y),
+            speed: 5.0,
+            sensitivity: 0.003,
+            toggle_key: KeyCode::Tab,
+        }
+    }
+}
+
+impl Plugin for SpectatorCameraPlugin {
+    fn build(&self, app: &mut App) {
+        let (yaw, pitch, _) = self.transform.rotation.to_euler(EulerRot::YXZ);
+        let spectator_camera = SpectatorCamera {
+            mode: self.mode,
+            speed: self.speed,
+            sensitivity: self.sensitivity,
+            yaw,
+            pitch,
+        };
+        let transform = self.transform;
+
+        app.insert_resource(SpectatorToggleKey(self.toggle_key))
+            .add_systems(Startup, move |mut commands: Commands| {
+                commands.spawn((
+                    Camera3d::default(),
+                    Camera {
+                        // Over the tiled agent views, hidden until toggled
+                        order: 10,
+                        is_active: false,
+                        ..default()
+                    },
+                    transform,
+                    RenderLayers::from_layers(&[0, SPECTATOR_LAYER]),
+                    spectator_camera,
+                ));
+            })
+            .add_systems(
+                Update,
+                (toggle_spectator_camera, control_spectator_camera).chain(),
+            );
+    }
+}
+
+/// Spectator camera state, mode and speed can be changed at runtime
+#[derive(Component, Clone, Copy, Debug)]
+pub struct SpectatorCamera {
+    pub mode: SpectatorMode,
+    pub speed: f32,
+    pub sensitivity: f32,
+    yaw: f32,
+    pitch: f32,
+}
+
+#[derive(Resource)]
+struct SpectatorToggleKey(KeyCode);
+
+fn toggle_spectator_camera(
+    keys: Res<ButtonInput<KeyCode>>,
+    toggle_key: Res<SpectatorToggleKey>,
+    mut cameras: Query<&mut Camera, With<SpectatorCamera>>,
+) {
+    if !keys.just_pressed(toggle_key.0) {
+        return;
+    }
+
+    for mut camera in cameras.iter_mut() {
+        camera.is_active = !camera.is_active;
+    }
+}
+
+fn control_spectator_camera(
+    time: Res<Time<Real>>,
+    keys: Res<ButtonInput<KeyCode>>,
+    mouse_buttons: Res<ButtonInput<MouseButton>>,
+    mouse_motion: Res<AccumulatedMouseMotion>,
+    mouse_scroll: Res<AccumulatedMouseScroll>,
+    mut cameras: Query<(&Camera, &mut SpectatorCamera, &mut Transform)>,
+) {
+    for (camera, mut spectator, mut transform) in cameras.iter_mut() {
+        if !camera.is_active {
+            continue;
+        }
+
+        if mouse_buttons.pressed(MouseButton::Right) {
+            spectator.yaw -= mouse_motion.delta.x * spectator.sensitivity;
+            spectator.pitch = (spectator.pitch - mouse_motion.delta.y * spectator.sensitivity)
+                .clamp(-MAX_PITCH, MAX_PITCH);
+        }
+        let rotation = Quat::from_euler(EulerRot::YXZ, spectator.yaw, spectator.pitch, 0.0);
+
+        let mut direction = Vec3::ZERO;
+        for (key, axis) in [
+            (KeyCode::KeyW, Vec3::NEG_Z),
+            (KeyCode::KeyS, Vec3::Z),
+            (KeyCode::KeyA, Vec3::NEG_X),
+            (KeyCode::KeyD, Vec3::X),
+            (KeyCode::KeyQ, Vec3::NEG_Y),
+            (KeyCode::KeyE, Vec3::Y),
+        ] {
+            if keys.pressed(key) {
+                direction += axis;
+            }
+        }
+        let boost = if keys.pressed(KeyCode::ShiftLeft) {
+            4.0
+        } else {
+            1.0
+        };
+        let movement =
+            rotation * direction.normalize_or_zero() * spectator.speed * boost * time.delta_secs();
+
+        match spectator.mode {
+            SpectatorMode::FreeFly => {
+                if mouse_scroll.delta.y != 0.0 {
+                    spectator.speed =
+                        (spectator.speed * (1.0 + 0.1 * mouse_scroll.delta.y)).max(0.1);
+                }
+                transform.translation += movement;
+            }
+            SpectatorMode::Orbit { focus } => {
+                let focus = focus + movement;
+                let distance = (transform.translation.distance(focus)
+                    * (1.0 - 0.1 * mouse_scroll.delta.y))
+                    .max(0.1);
+                spectator.mode = SpectatorMode::Orbit { focus };
+                transform.translation = focus + rotation * Vec3::Z * distance;
+            }
+        }
+        transform.rotation = rotation;
+    }
+}
@@ -1,15 +1,430 @@
-use std::sync::{Arc, Mutex};
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    sync::{
+        atomic::{AtomicU8, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
 
-use bevy::prelude::*;
+use bevy::{
+    prelude::*,
+    render::{
+        camera::{ManualTextureViewHandle, RenderTarget},
+        render_resource::Texture,
+    },
+};
 use crossbeam_channel::*;
+use rand::rngs::StdRng;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
 
-use crate::AIGymSettings;
+use crate::{competition::Leaderboard, preprocess, AIGymRng, AIGymSettings};
+
+/// Transition of a single agent in Gymnasium terms: (observation, reward, terminated, truncated, info).
+/// It's captured at the moment the step result is sent, so all fields belong to the same tick
+#[derive(Clone, Serialize)]
+pub struct AgentTransition<B> {
+    pub observation: Option<B>,
+    pub reward: f32,
+    pub terminated: bool,
+    pub truncated: bool,
+    pub info: serde_json::Value,
+    /// Last observation of the episode when the environment was reset automatically,
+    /// `observation` holds the first observation of the new episode then
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub terminal_observation: Option<B>,
+    /// Simulation seconds between the step's actions and this transition, for semi-MDP discounting
+    pub elapsed: f32,
+    /// `discount_gamma ^ elapsed` if `AIGymSettings.discount_gamma` is set
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub discount: Option<f32>,
+    /// Reward and termination of every registered `Task`, by task name
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub tasks: BTreeMap<String, TaskOutcome>,
+    /// Agent's vector observation if one is set with `set_vector_observation`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub vector_observation: Option<Vec<f32>>,
+}
+
+/// Reward and termination of an agent according to one `Task`
+#[derive(Clone, Copy, Debug, Serialize)]
+pub struct TaskOutcome {
+    pub reward: f32,
+    pub terminated: bool,
+}
+
+/// Named reward and termination functions of an agent, evaluated over environment state
+/// at every step alongside the main ones. Several tasks over one rollout give separate
+/// reward channels for multi-task or auxiliary-objective training
+#[derive(Clone)]
+pub struct Task<B> {
+    pub name: String,
+    reward: Arc<dyn Fn(&B, usize) -> f32 + Send + Sync>,
+    terminated: Arc<dyn Fn(&B, usize) -> bool + Send + Sync>,
+}
+
+impl<B> Task<B> {
+    /// Functions take environment state and agent index
+    pub fn new(
+        name: impl Into<String>,
+        reward: impl Fn(&B, usize) -> f32 + Send + Sync + 'static,
+        terminated: impl Fn(&B, usize) -> bool + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            name: name.into(),
+            reward: Arc::new(reward),
+            terminated: Arc::new(terminated),
+        }
+    }
+
+    /// Evaluate the task for an agent
+    pub fn evaluate(&self, state: &B, agent_index: usize) -> TaskOutcome {
+        TaskOutcome {
+            reward: (self.reward)(state, agent_index),
+            terminated: (self.terminated)(state, agent_index),
+        }
+    }
+}
+
+/// Request of an API client, tagged with an id and answered on its own channel
+pub(crate) struct TaggedRequest<Q, R> {
+    pub(crate) request: Q,
+    pub(crate) reply: Reply<R>,
+    status: Arc<RequestStatus>,
+}
+
+/// Reply channel of one request
+pub(crate) struct Reply<R> {
+    id: u64,
+    tx: Sender<R>,
+}
+
+const REQUEST_QUEUED: u8 = 0;
+const REQUEST_TAKEN: u8 = 1;
+const REQUEST_CANCELLED: u8 = 2;
+
+/// Whether a request is still queued, taken by the engine or cancelled by its client
+struct RequestStatus {
+    state: AtomicU8,
+    // Requests of the queue which are neither taken nor cancelled
+    queued: Arc<AtomicUsize>,
+}
+
+impl RequestStatus {
+    /// Move a queued request to `state`, fails if it has been taken or cancelled already
+    fn leave_queue(&self, state: u8) -> bool {
+        let is_queued = self
+            .state
+            .compare_exchange(REQUEST_QUEUED, state, Ordering::AcqRel, Ordering::Acquire)
+            .is_ok();
+        if is_queued {
+            self.queued.fetch_sub(1, Ordering::AcqRel);
+        }
+        is_queued
+    }
+}
+
+/// Client side of a submitted request: the receiver of its result, which can cancel the request
+/// while the engine hasn't taken it
+pub(crate) struct PendingReply<R> {
+    rx: Receiver<R>,
+    status: Arc<RequestStatus>,
+}
+
+impl<R> PendingReply<R> {
+    /// Withdraw the request so that the engine never applies it. Returns `false` if the engine
+    /// has taken it already, the request completes then and its result is dropped
+    pub(crate) fn cancel(&self) -> bool {
+        self.status.leave_queue(REQUEST_CANCELLED)
+    }
+}
+
+impl<R> std::ops::Deref for PendingReply<R> {
+    type Target = Receiver<R>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.rx
+    }
+}
+
+impl<R> Reply<R> {
+    pub(crate) fn send(self, result: R) {
+        if self.tx.send(result).is_err() {
+            debug!("bevy_rl: client of request {} is gone", self.id);
+        }
+    }
+}
+
+/// Queue of requests from any number of concurrent clients (trainer, dashboard, debug tools).
+/// Every client waits on the reply channel of its own request, so results never get mixed up
+pub(crate) struct RequestQueue<Q, R> {
+    tx: Sender<TaggedRequest<Q, R>>,
+    rx: Receiver<TaggedRequest<Q, R>>,
+    next_id: u64,
+    queued: Arc<AtomicUsize>,
+}
+
+impl<Q, R> RequestQueue<Q, R> {
+    fn new() -> Self {
+        let (tx, rx) = unbounded();
+        Self {
+            tx,
+            rx,
+            next_id: 0,
+            queued: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// Enqueue a request, its result arrives at the returned receiver
+    pub(crate) fn submit(&mut self, request: Q) -> PendingReply<R> {
+        let (reply_tx, reply_rx) = bounded(1);
+        let status = Arc::new(RequestStatus {
+            state: AtomicU8::new(REQUEST_QUEUED),
+            queued: self.queued.clone(),
+        });
+        self.next_id += 1;
+        self.queued.fetch_add(1, Ordering::AcqRel);
+        self.tx
+            .send(TaggedRequest {
+                request,
+                reply: Reply {
+                    id: self.next_id,
+                    tx: reply_tx,
+                },
+                status: status.clone(),
+            })
+            .unwrap();
+        PendingReply {
+            rx: reply_rx,
+            status,
+        }
+    }
+
+    /// Take the oldest request, requests cancelled by their clients are dropped
+    pub(crate) fn receive(&self) -> Option<TaggedRequest<Q, R>> {
+        while let Ok(request) = self.rx.try_recv() {
+            if request.status.leave_queue(REQUEST_TAKEN) {
+                return Some(request);
+            }
+            debug!("bevy_rl: request {} was cancelled", request.reply.id);
+        }
+        None
+    }
+
+    /// Whether there are no requests to take, cancelled ones don't count
+    pub(crate) fn is_empty(&self) -> bool {
+        self.queued.load(Ordering::Acquire) == 0
+    }
+}
+
+/// `/advance` request: frames to run and whether to apply no actions instead of the previous ones
+pub(crate) struct AdvanceRequest {
+    pub(crate) frames: u32,
+    pub(crate) noop: bool,
+}
+
+/// Reset request parameters: seed for `AIGymRng` and arbitrary options for the environment
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct ResetRequest {
+    pub seed: Option<u64>,
+    pub options: Option<serde_json::Value>,
+    /// Environment copies to reset, all of them (the whole world) if not set
+    #[serde(default)]
+    pub env_ids: Option<Vec<usize>>,
+    /// Tags of the episode (experiment id, policy checkpoint) propagated to its artifacts
+    #[serde(default)]
+    pub metadata: HashMap<String, serde_json::Value>,
+}
+
+/// Per-agent episode statistics for learning curves, served at `/episodes`
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct EpisodeStats {
+    /// Number of finished episodes
+    pub episodes: u64,
+    /// Return and length of the current episode
+    pub episode_return: f32,
+    pub episode_length: u32,
+    /// Return and length of the last finished episode
+    pub last_return: Option<f32>,
+    pub last_length: Option<u32>,
+    /// Mean return and length over all finished episodes
+    pub mean_return: f32,
+    pub mean_length: f32,
+}
+
+impl EpisodeStats {
+    /// Close the current episode and return its return, episodes without steps are not counted
+    pub(crate) fn end_episode(&mut self) -> Option<f32> {
+        let finished = (self.episode_length > 0).then_some(self.episode_return);
+        if self.episode_length > 0 {
+            self.episodes += 1;
+            let n = self.episodes as f32;
+            self.mean_return += (self.episode_return - self.mean_return) / n;
+            self.mean_length += (self.episode_length as f32 - self.mean_length) / n;
+            self.last_return = Some(self.episode_return);
+            self.last_length = Some(self.episode_length);
+        }
+
+        self.episode_return = 0.0;
+        self.episode_length = 0;
+        finished
+    }
+}
+
+/// Frame rate of the simulation between control steps, served at `/health`
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct FrameRateHealth {
+    /// Frames simulated in the last control interval
+    pub frames_per_step: u32,
+    /// Frames per second of simulation time in the last control interval
+    pub frame_rate: f32,
+    /// Fewer frames than `AIGymSettings.min_frames_per_step` were simulated
+    pub low_frame_rate: bool,
+    /// Control interval in effect, differs from `AIGymSettings.pause_interval`
+    /// when it's stretched
+    pub pause_interval: f32,
+}
+
+/// GPU readback of observations, served at `/health`
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct ReadbackHealth {
+    /// Error of the last failed readback, cleared once observations of a step are read back again
+    pub error: Option<String>,
+    /// Readbacks failed since the start of the app
+    pub failures: u64,
+}
+
+/// Custom encoder of environment state, for state types which can't or shouldn't
+/// go through serde_json (protobuf, flatbuffers, domain-specific binary formats)
+#[derive(Clone)]
+pub struct StateCodec<B> {
+    pub content_type: mime::Mime,
+    encoder: Arc<dyn Fn(&B) -> Vec<u8> + Send + Sync>,
+}
+
+impl<B> StateCodec<B> {
+    pub fn new(
+        content_type: mime::Mime,
+        encoder: impl Fn(&B) -> Vec<u8> + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            content_type,
+            encoder: Arc::new(encoder),
+        }
+    }
+
+    pub fn encode(&self, state: &B) -> Vec<u8> {
+        (self.encoder)(state)
+    }
+}
+
+/// Bot acting for an agent inside the engine, registered with `add_scripted_agent`, e.g. a
+/// scripted opponent or a random baseline. Closures taking environment state implement it
+pub trait ScriptedAgent<A, B>: Send + Sync {
+    /// Action of the agent in the current control step
+    fn act(&mut self, state: &B) -> A;
+}
+
+impl<A, B, F: FnMut(&B) -> A + Send + Sync> ScriptedAgent<A, B> for F {
+    fn act(&mut self, state: &B) -> A {
+        self(state)
+    }
+}
+
+/// Scripted agent with actions serialized like the trainer's ones
+type SerializedScriptedAgent<B> = Box<dyn FnMut(&B) -> Option<String> + Send + Sync>;
+
+/// Environment-specific route served at `/custom/{name}`, registered with `add_endpoint`.
+/// Closures taking the locked state and query string parameters implement it
+pub trait CustomEndpoint<
+    A: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::de::DeserializeOwned,
+    B: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe,
+>: Send + Sync
+{
+    /// JSON body of the response, or the message of a `400 Bad Request`
+    fn respond(
+        &self,
+        state: &AIGymStateInner<A, B>,
+        params: &HashMap<String, String>,
+    ) -> Result<serde_json::Value, String>;
+}
+
+impl<
+        A: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::de::DeserializeOwned,
+        B: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe,
+        F: Fn(
+                &AIGymStateInner<A, B>,
+                &HashMap<String, String>,
+            ) -> Result<serde_json::Value, String>
+            + Send
+            + Sync,
+    > CustomEndpoint<A, B> for F
+{
+    fn respond(
+        &self,
+        state: &AIGymStateInner<A, B>,
+        params: &HashMap<String, String>,
+    ) -> Result<serde_json::Value, String> {
+        self(state, params)
+    }
+}
+
+/// Gym side of a world snapshot, see `crate::snapshot`
+#[derive(Clone)]
+pub(crate) struct GymSnapshot<B> {
+    rewards: Vec<f32>,
+    infos: Vec<serde_json::Value>,
+    terminations: Vec<bool>,
+    truncations: Vec<bool>,
+    episode_stats: Vec<EpisodeStats>,
+    episode_steps: u32,
+    env_episode_steps: Vec<u32>,
+    environment_state: Option<B>,
+    env_states: Vec<Option<B>>,
+    vector_observations: Vec<Option<Vec<f32>>>,
+    agent_observations: Vec<Option<B>>,
+    visual_observations: Vec<image::DynamicImage>,
+    frame_stacks: Vec<VecDeque<image::DynamicImage>>,
+    // World clock and randomness, so that replays from the snapshot are deterministic
+    rng: Option<StdRng>,
+    virtual_elapsed: Duration,
+}
+
+/// Visual observations captured for one control step. All endpoints and formats serve
+/// the same set until the next step is captured, so a response never mixes frames of two steps
+#[derive(Default)]
+pub struct StepObservations {
+    /// Number of the captured step, counted from the start of the app
+    pub step: u64,
+    pub visual: Vec<image::DynamicImage>,
+    /// Depth maps of the same frame, if `render_depth` is set
+    pub depth: Vec<crate::render::DepthImage>,
+    /// Readback of the step failed, there are no frames to serve
+    pub unavailable: bool,
+    encoded: Mutex<HashMap<String, (mime::Mime, Vec<u8>)>>,
+}
+
+impl StepObservations {
+    /// Observations encoded by `encode`, which runs once per step for each `key`
+    pub fn encoded(
+        &self,
+        key: &str,
+        encode: impl FnOnce(&Self) -> (mime::Mime, Vec<u8>),
+    ) -> (mime::Mime, Vec<u8>) {
+        let mut encoded = self.encoded.lock().unwrap();
+        encoded
+            .entry(key.to_string())
+            .or_insert_with(|| encode(self))
+            .clone()
+    }
+}
 
 /// `AIGymStateInner` handles synchronization between the engine thread and the API thread
-/// via set of channels. The engine thread will send messages to the API thread and wait for a response.
+/// via request queues. The API thread will send requests to the engine thread and wait for a response.
 ///
-/// (StepRequest, ResetRequest, StepResult, ResetResult) — these are the messages.  Requests are sent
-/// from API to the engine. Results are sent from engine to the API once the request is processed.
+/// (StepRequest, ResetRequest, StepResult, ResetResult) — these are the messages.  Requests are queued
+/// by API clients. Results are sent from engine to the client once its request is processed.
 ///
 /// Other fields are used to store the state of the environment,
 /// plugin settings and gym data tuple (S,A,R,T)
@@ -18,69 +433,289 @@ use crate::AIGymSettings;
 /// and used as resource in bevy systems and parallel-running REST API thread
 #[derive(Resource)]
 pub struct AIGymStateInner<
-    A: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe,
+    A: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::de::DeserializeOwned,
     B: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe,
 > {
     // Bevy image handle for the screen
     pub render_image_handles: Vec<Handle<Image>>,
+    // Views of layers of the texture array agents render into with `render_target_array`
+    pub render_target_views: Vec<ManualTextureViewHandle>,
+    pub(crate) render_target_array: Option<Texture>,
+
+    // Sync with engine thread. The engine works on one request of a kind at a time
+    // and keeps its reply until the result is ready
+    pub(crate) step_requests: RequestQueue<Vec<Vec<Option<String>>>, Vec<Vec<AgentTransition<B>>>>,
+    pub(crate) step_reply: Option<Reply<Vec<Vec<AgentTransition<B>>>>>,
+    pub(crate) step_result_pending: bool,
+
+    pub(crate) reset_requests: RequestQueue<ResetRequest, bool>,
+    pub(crate) reset_reply: Option<Reply<bool>>,
+
+    pub(crate) reset_agents_requests: RequestQueue<Vec<usize>, bool>,
+    pub(crate) reset_agents_reply: Option<Reply<bool>>,
+
+    pub(crate) snapshot_requests:
+        RequestQueue<crate::snapshot::SnapshotRequest, crate::snapshot::SnapshotResult>,
 
-    // Sync with engine thread.
-    pub(crate) step_request_tx: Sender<Vec<Option<String>>>,
-    pub(crate) step_request_rx: Receiver<Vec<Option<String>>>,
+    // Bodies of `POST /set_state`, handled if `SetStatePlugin` is added
+    pub(crate) set_state_requests: RequestQueue<serde_json::Value, Result<(), String>>,
+    pub(crate) set_state_enabled: bool,
 
-    pub(crate) reset_request_tx: Sender<bool>,
-    pub(crate) reset_request_rx: Receiver<bool>,
+    // `/pause` (true) and `/resume` (false), answered with the state the simulation goes to
+    pub(crate) debug_pause_requests: RequestQueue<bool, crate::SimulationState>,
+    pub(crate) debug_paused: bool,
 
-    pub(crate) step_result_tx: Sender<Vec<bool>>,
-    pub(crate) step_result_rx: Receiver<Vec<bool>>,
+    // Frames run without a control step, reply once the last one is captured
+    pub(crate) advance_requests: RequestQueue<AdvanceRequest, bool>,
+    pub(crate) advance_reply: Option<Reply<bool>>,
+    pub(crate) advance_frames_remaining: u32,
 
-    pub(crate) reset_result_tx: Sender<bool>,
-    pub(crate) reset_result_rx: Receiver<bool>,
+    // Batched steps: actions of the following control intervals, whether the next one
+    // should be applied right away and transitions of the intervals done so far
+    pub(crate) queued_action_strings: VecDeque<Vec<Option<String>>>,
+    pub(crate) queued_step_ready: bool,
+    pub(crate) batch_transitions: Vec<Vec<AgentTransition<B>>>,
+
+    // Terminal transitions of the episode being reset automatically,
+    // result of the reset request and whether the environment has finished the reset
+    pub(crate) auto_reset_transitions: Option<Vec<AgentTransition<B>>>,
+    pub(crate) auto_reset_result: Option<PendingReply<bool>>,
+    pub(crate) auto_reset_done: bool,
 
     pub(crate) environment_state: Option<B>,
 
+    // Encoder of environment state used instead of JSON if set
+    pub(crate) state_codec: Option<StateCodec<B>>,
+
+    // JSON schemas of action and state types for `/openapi.json`
+    pub(crate) action_schema: Option<serde_json::Value>,
+    pub(crate) state_schema: Option<serde_json::Value>,
+
+    // Spaces served at `/spaces`
+    pub(crate) action_space: Option<crate::spaces::SpaceDescriptor>,
+    pub(crate) observation_space: Option<crate::spaces::SpaceDescriptor>,
+
+    // Counters and histograms served at `/metrics`, shared with the API middleware timing requests
+    pub(crate) metrics: Arc<Mutex<crate::metrics::Metrics>>,
+    // Scalars for TensorBoard events, set by `TensorboardPlugin`
+    pub(crate) scalar_tx: Option<Sender<crate::tensorboard::Scalar>>,
+    // Offline RL dataset, recorded once `record_dataset` is called
+    pub(crate) dataset_recorder: Option<crate::dataset::DatasetRecorder<A>>,
+    // Resets and actions for replay, set by `ActionLogPlugin`
+    pub(crate) action_log_tx: Option<Sender<crate::replay::ActionLogEntry>>,
+
+    // Routes served at `/custom/{name}`, by name
+    pub(crate) custom_endpoints: HashMap<String, Arc<dyn CustomEndpoint<A, B>>>,
+    pub(crate) tasks: Vec<Task<B>>,
+
+    // Observations of environment copies set with `set_vec_env_state`
+    pub(crate) env_states: Vec<Option<B>>,
+
+    // Environment copies which haven't finished the requested reset yet
+    pub(crate) pending_env_resets: Vec<usize>,
+
+    // Latest serialized actions of locally controlled agents
+    pub(crate) local_actions: Vec<Option<String>>,
+    // Bots setting local actions of their agents every control step, by agent index
+    pub(crate) scripted_agents: BTreeMap<usize, SerializedScriptedAgent<B>>,
+    // Checkpoints requested with `/policy/load` by slot, swapped at the next reset
+    pub(crate) pending_policies: Vec<(Option<String>, String)>,
+    /// Checkpoint of the in-process policy, as loaded with `/policy/load`
+    pub policy_checkpoint: Option<String>,
+    /// Checkpoints of named policy slots, as loaded with `/policy/load?slot=`
+    pub policy_checkpoints: HashMap<String, String>,
+    // Curriculum parameters set with `PUT /curriculum`, applied at the next reset
+    pub(crate) pending_curriculum: Option<serde_json::Value>,
+    /// Curriculum parameters in effect, same as in `CurriculumState`
+    pub curriculum: Option<serde_json::Value>,
+
+    // Frame skip: intervals left to repeat `last_action_strings` and rewards of repeated intervals
+    pub(crate) frame_skip_remaining: u32,
+    pub(crate) last_action_strings: Vec<Option<String>>,
+    pub(crate) skipped_rewards: Vec<f32>,
+    // Intervals the step's actions were applied for and terminations when they were submitted,
+    // repeating stops early once an agent terminates
+    pub(crate) step_repeats: u32,
+    pub(crate) terminated_at_step_start: Vec<bool>,
+
+    // Simulation time when actions of the current step were applied and duration of the last step
+    pub(crate) step_started_at: Option<std::time::Duration>,
+    pub(crate) step_elapsed: f32,
+    pub(crate) step_frames: u32,
+    pub frame_rate_health: FrameRateHealth,
+    pub readback_health: ReadbackHealth,
+    pub(crate) observation_unavailable: bool,
+
+    // Time of the last pause which hasn't got actions yet
+    pub(crate) paused_at: Option<std::time::Instant>,
+
+    // Last API request, for status overlay
+    pub(crate) last_request_at: Option<std::time::Instant>,
+
+    // Real time the last step result was sent at and average time the trainer takes
+    // to send the next step after a result, for `speed_governor`
+    pub(crate) step_result_sent_at: Option<std::time::Instant>,
+    pub trainer_latency: Option<f32>,
+    pub(crate) last_client: Option<String>,
+
+    // Seed and options of the current episode
+    pub seed: Option<u64>,
+    pub reset_options: Option<serde_json::Value>,
+    pub episode_metadata: HashMap<String, serde_json::Value>,
+
+    // Competition mode bookkeeping
+    pub(crate) leaderboard: Leaderboard,
+
     // Settings
     pub settings: AIGymSettings,
 
+    // Environment parameters, e.g. set by curriculum
+    pub parameters: HashMap<String, f32>,
+
     // State
-    pub visual_observations: Vec<image::RgbaImage>,
+    pub visual_observations: Vec<image::DynamicImage>,
+    pub logging_observations: Vec<image::RgbaImage>,
+    pub depth_observations: Vec<crate::render::DepthImage>,
+    pub observations: Arc<StepObservations>,
+    // Last step written to the shared-memory region
+    #[cfg(feature = "shared-memory")]
+    pub(crate) shared_memory_step: u64,
+    pub lidar_observations: Vec<Vec<f32>>,
+    pub vector_observations: Vec<Option<Vec<f32>>>,
+    pub agent_observations: Vec<Option<B>>,
+    pub frames: u64,
+    // Real time of the last frame, state of the simulation in it and whether the environment
+    // has left `SimulationState::Initializing`, for `/healthz`, `/readyz` and timed out requests
+    pub(crate) last_frame_at: Option<std::time::Instant>,
+    pub(crate) simulation_state: Option<crate::SimulationState>,
+    pub(crate) initialized: bool,
+    pub(crate) frames_captured: u64,
+    pub(crate) observation_requested: bool,
+    pub(crate) observation_pending: bool,
+    pub(crate) frame_history: Vec<VecDeque<image::DynamicImage>>,
+    pub(crate) frame_stacks: Vec<VecDeque<image::DynamicImage>>,
     pub rewards: Vec<f32>,
+    pub infos: Vec<serde_json::Value>,
+    pub episode_stats: Vec<EpisodeStats>,
     pub actions: Vec<Option<A>>,
     pub terminations: Vec<bool>,
+    pub truncations: Vec<bool>,
+    pub episode_steps: u32,
+    pub env_episode_steps: Vec<u32>,
 }
 
 impl<
-        A: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe,
+        A: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::de::DeserializeOwned,
         B: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe,
     > AIGymStateInner<A, B>
 {
     pub fn new(settings: AIGymSettings) -> Self {
-        let (step_tx, step_rx) = bounded(1);
-        let (reset_tx, reset_rx) = bounded(1);
-        let (result_tx, result_rx) = bounded(1);
-        let (result_reset_tx, result_reset_rx) = bounded(1);
         Self {
-            // Channels
-            step_request_tx: step_tx,
-            step_request_rx: step_rx,
-            step_result_tx: result_tx,
-            step_result_rx: result_rx,
+            // Request queues
+            step_requests: RequestQueue::new(),
+            step_reply: None,
+            step_result_pending: false,
+            queued_action_strings: VecDeque::new(),
+            queued_step_ready: false,
+            batch_transitions: Vec::new(),
+            auto_reset_transitions: None,
+            auto_reset_result: None,
+            auto_reset_done: false,
+
+            reset_requests: RequestQueue::new(),
+            reset_reply: None,
+
+            reset_agents_requests: RequestQueue::new(),
+            reset_agents_reply: None,
 
-            reset_request_tx: reset_tx,
-            reset_request_rx: reset_rx,
-            reset_result_tx: result_reset_tx,
-            reset_result_rx: result_reset_rx,
+            snapshot_requests: RequestQueue::new(),
+            set_state_requests: RequestQueue::new(),
+            set_state_enabled: false,
+            debug_pause_requests: RequestQueue::new(),
+            debug_paused: false,
+            advance_requests: RequestQueue::new(),
+            advance_reply: None,
+            advance_frames_remaining: 0,
 
             environment_state: None,
+            state_codec: None,
+            action_schema: None,
+            state_schema: None,
+            action_space: None,
+            observation_space: None,
+            metrics: Arc::default(),
+            scalar_tx: None,
+            dataset_recorder: None,
+            action_log_tx: None,
+            custom_endpoints: HashMap::new(),
+            tasks: Vec::new(),
+            env_states: vec![None; settings.env_count() as usize],
+            pending_env_resets: Vec::new(),
+            local_actions: vec![None; settings.total_agents() as usize],
+            scripted_agents: BTreeMap::new(),
+            pending_policies: Vec::new(),
+            policy_checkpoint: None,
+            policy_checkpoints: HashMap::new(),
+            pending_curriculum: None,
+            curriculum: None,
+            frame_skip_remaining: 0,
+            last_action_strings: Vec::new(),
+            skipped_rewards: vec![0.0; settings.total_agents() as usize],
+            step_repeats: 0,
+            terminated_at_step_start: Vec::new(),
+            step_started_at: None,
+            step_elapsed: 0.0,
+            step_frames: 0,
+            frame_rate_health: FrameRateHealth {
+                pause_interval: settings.pause_interval,
+                ..Default::default()
+            },
+            readback_health: ReadbackHealth::default(),
+            observation_unavailable: false,
+            paused_at: None,
+            last_request_at: None,
+            step_result_sent_at: None,
+            trainer_latency: None,
+            last_client: None,
+            seed: None,
+            reset_options: None,
+            episode_metadata: HashMap::new(),
+            leaderboard: Leaderboard::default(),
 
             // Render Targets
             render_image_handles: Vec::new(),
+            render_target_views: Vec::new(),
+            render_target_array: None,
 
             // State
             visual_observations: Vec::new(),
-            rewards: vec![0.0; settings.num_agents as usize],
-            actions: vec![None; settings.num_agents as usize],
-            terminations: vec![false; settings.num_agents as usize],
+            logging_observations: Vec::new(),
+            depth_observations: Vec::new(),
+            observations: Arc::new(StepObservations::default()),
+            #[cfg(feature = "shared-memory")]
+            shared_memory_step: 0,
+            lidar_observations: vec![Vec::new(); settings.total_agents() as usize],
+            vector_observations: vec![None; settings.total_agents() as usize],
+            agent_observations: vec![None; settings.total_agents() as usize],
+            frames: 0,
+            last_frame_at: None,
+            simulation_state: None,
+            initialized: false,
+            frames_captured: 0,
+            observation_requested: true,
+            observation_pending: false,
+            frame_history: vec![VecDeque::new(); settings.total_agents() as usize],
+            frame_stacks: vec![VecDeque::new(); settings.total_agents() as usize],
+            rewards: vec![0.0; settings.total_agents() as usize],
+            infos: vec![json!({}); settings.total_agents() as usize],
+            episode_stats: vec![EpisodeStats::default(); settings.total_agents() as usize],
+            actions: vec![None; settings.total_agents() as usize],
+            terminations: vec![false; settings.total_agents() as usize],
+            truncations: vec![false; settings.total_agents() as usize],
+            episode_steps: 0,
+            env_episode_steps: vec![0; settings.env_count() as usize],
+
+            parameters: HashMap::new(),
 
             // Other
             settings,
@@ -90,37 +725,340 @@ impl<
     // Syncronization happens by sending messages to result-response channels
 
     /// Once the simulation step is done, send the results back to the API thread
-    pub fn send_step_result(&self, results: Vec<bool>) {
-        if self.step_result_tx.is_empty() {
-            self.step_result_tx.send(results).unwrap();
+    pub fn send_step_result(&mut self) {
+        let transitions = self.transitions();
+        self.take_step_rewards();
+        self.push_frame_stacks();
+        self.send_transitions(transitions);
+    }
+
+    /// Publish visual observations read back for the control step, with depth maps of their frame
+    pub(crate) fn publish_observations(&mut self, depth: Vec<crate::render::DepthImage>) {
+        self.observations = Arc::new(StepObservations {
+            step: self.observations.step + 1,
+            visual: self.visual_observations.clone(),
+            depth,
+            unavailable: false,
+            encoded: Mutex::default(),
+        });
+    }
+
+    /// Record a failed readback. If it was the copy of a control step, its observations
+    /// are published as unavailable and the step result tells so in info
+    pub(crate) fn readback_failed(&mut self, error: String, is_step: bool) {
+        self.readback_health.error = Some(error);
+        self.readback_health.failures += 1;
+        if is_step {
+            self.observation_unavailable = true;
+            self.observations = Arc::new(StepObservations {
+                step: self.observations.step + 1,
+                unavailable: true,
+                ..Default::default()
+            });
+        }
+    }
+
+    /// Observations of a control step were read back, readback works again after failures
+    pub(crate) fn readback_recovered(&mut self) {
+        self.readback_health.error = None;
+        self.observation_unavailable = false;
+    }
+
+    /// Add visual observations of the control step to agents' frame stacks
+    pub(crate) fn push_frame_stacks(&mut self) {
+        let frame_stack = preprocess::stack_frames(&preprocess::pipeline(&self.settings)) as usize;
+        if frame_stack <= 1 {
+            return;
+        }
+
+        for (stack, frame) in self.frame_stacks.iter_mut().zip(&self.visual_observations) {
+            if stack.len() == frame_stack {
+                stack.pop_front();
+            }
+            stack.push_back(frame.clone());
+        }
+    }
+
+    /// Last visual observations of the agent as stacked by the `Stack` step, oldest first.
+    /// Early in the episode the oldest available frame is repeated
+    pub fn stacked_observations(&self, agent_index: usize) -> Vec<image::DynamicImage> {
+        let frame_stack = preprocess::stack_frames(&preprocess::pipeline(&self.settings)) as usize;
+        let stack = &self.frame_stacks[agent_index];
+        let Some(oldest) = stack.front().or(self.visual_observations.get(agent_index)) else {
+            return Vec::new();
+        };
+
+        std::iter::repeat(oldest)
+            .take(frame_stack.saturating_sub(stack.len()))
+            .chain(stack.iter())
+            .take(frame_stack)
+            .cloned()
+            .collect()
+    }
+
+    /// Keep observations and actions of learning agents at the start of a step for the dataset
+    pub(crate) fn begin_dataset_step(&mut self) {
+        let Some(mut recorder) = self.dataset_recorder.take() else {
+            return;
+        };
+        for (agent_index, action) in self.actions.iter().enumerate() {
+            let Some(action) = action else {
+                continue;
+            };
+            if self.settings.npc_agents.contains(&agent_index) {
+                continue;
+            }
+            recorder.begin_step(
+                agent_index,
+                &self.stacked_observations(agent_index),
+                self.vector_observations[agent_index].clone(),
+                action,
+            );
+        }
+        self.dataset_recorder = Some(recorder);
+    }
+
+    /// Move rewards of the step into episode returns, the next step collects rewards from zero
+    pub(crate) fn take_step_rewards(&mut self) {
+        for i in 0..self.rewards.len() {
+            self.episode_stats[i].episode_return += self.rewards[i];
+            self.rewards[i] = 0.0;
+        }
+    }
+
+    /// Send transitions of a control interval, a batch of steps is answered once
+    /// all its intervals are done
+    pub(crate) fn send_transitions(&mut self, transitions: Vec<AgentTransition<B>>) {
+        self.metrics.lock().unwrap().steps += 1;
+        if let Some(recorder) = self.dataset_recorder.as_mut() {
+            for (agent_index, transition) in transitions.iter().enumerate() {
+                recorder.finish_step(
+                    agent_index,
+                    transition.reward,
+                    transition.terminated,
+                    transition.truncated,
+                );
+            }
+        }
+        self.batch_transitions.push(transitions);
+        if !self.queued_action_strings.is_empty() {
+            self.queued_step_ready = true;
+            return;
+        }
+
+        // Pauses nobody requested, like ones after a missed action deadline, aren't answered
+        let batch_transitions = std::mem::take(&mut self.batch_transitions);
+        if let Some(reply) = self.step_reply.take() {
+            reply.send(batch_transitions);
+            self.step_result_sent_at = Some(std::time::Instant::now());
+        }
+    }
+
+    /// Render target of the agent's camera
+    pub fn render_target(&self, agent_index: usize) -> RenderTarget {
+        match self.render_target_views.get(agent_index) {
+            Some(&view) => RenderTarget::TextureView(view),
+            None => RenderTarget::Image(self.render_image_handles[agent_index].clone()),
+        }
+    }
+
+    /// Number of agents' render targets
+    pub(crate) fn render_target_count(&self) -> usize {
+        self.render_image_handles
+            .len()
+            .max(self.render_target_views.len())
+    }
+
+    /// Visual observations of the current frame are being read back from GPU,
+    /// step results wait for them so that transitions and pixels belong to the same tick
+    pub(crate) fn is_observation_pending(&self) -> bool {
+        self.settings.render_to_buffer
+            && self.render_target_count() > 0
+            && (self.observation_requested || self.observation_pending)
+    }
+
+    /// Whether all agents but NPCs are terminated or truncated
+    pub fn is_episode_done(&self) -> bool {
+        (0..self.terminations.len())
+            .filter(|i| !self.settings.npc_agents.contains(i))
+            .all(|i| self.terminations[i] || self.truncations[i])
+    }
+
+    /// Snapshot of current (observation, reward, terminated, truncated, info) for every agent
+    pub fn transitions(&self) -> Vec<AgentTransition<B>> {
+        (0..self.rewards.len())
+            .map(|i| {
+                let env_state = self.env_states[self.env_id(i)]
+                    .as_ref()
+                    .or(self.environment_state.as_ref());
+                let tasks = env_state
+                    .map(|env_state| {
+                        self.tasks
+                            .iter()
+                            .map(|task| (task.name.clone(), task.evaluate(env_state, i)))
+                            .collect()
+                    })
+                    .unwrap_or_default();
+                // Agents without an observation of their own see the whole environment state
+                let observation = self.agent_observations[i].as_ref().or(env_state).cloned();
+
+                AgentTransition {
+                    observation,
+                    reward: self.rewards[i],
+                    terminated: self.terminations[i],
+                    truncated: self.truncations[i],
+                    info: self.step_info(i),
+                    terminal_observation: None,
+                    elapsed: self.step_elapsed,
+                    discount: self
+                        .settings
+                        .discount_gamma
+                        .map(|gamma| gamma.powf(self.step_elapsed)),
+                    tasks,
+                    vector_observation: self.vector_observations[i].clone(),
+                }
+            })
+            .collect()
+    }
+
+    /// Info of the agent, with intervals the action was repeated for when `frame_skip` is set
+    /// and frame rate of the step when `min_frames_per_step` is set
+    fn step_info(&self, agent_index: usize) -> serde_json::Value {
+        let mut info = self.infos[agent_index].clone();
+        if self.settings.frame_skip > 1 {
+            if let Some(info) = info.as_object_mut() {
+                info.insert("repeats".to_string(), json!(self.step_repeats));
+            }
+        }
+        if self.observation_unavailable {
+            if let Some(info) = info.as_object_mut() {
+                info.insert("observation_unavailable".to_string(), json!(true));
+            }
+        }
+        if self.settings.min_frames_per_step > 0 {
+            if let Some(info) = info.as_object_mut() {
+                let health = &self.frame_rate_health;
+                info.insert("frames".to_string(), json!(health.frames_per_step));
+                info.insert("low_frame_rate".to_string(), json!(health.low_frame_rate));
+            }
+        }
+        info
+    }
+
+    /// Measure frames simulated in the control interval which just ended and,
+    /// with `stretch_pause_interval`, choose the next interval to fit `min_frames_per_step`
+    pub(crate) fn update_frame_rate_health(&mut self) {
+        let frames = std::mem::take(&mut self.step_frames);
+        let min_frames = self.settings.min_frames_per_step;
+
+        let health = &mut self.frame_rate_health;
+        health.frames_per_step = frames;
+        health.frame_rate = frames as f32 / health.pause_interval;
+        health.low_frame_rate = frames < min_frames;
+
+        if self.settings.stretch_pause_interval {
+            health.pause_interval = (min_frames as f32 / health.frame_rate.max(f32::EPSILON))
+                .max(self.settings.pause_interval);
         }
     }
 
-    /// Once the simulation reset, send the results back to the API thread
-    pub fn send_reset_result(&self, result: bool) {
-        if self.reset_result_tx.is_empty() {
-            self.reset_result_tx.send(result).unwrap();
+    /// Once the simulation reset, send the results back to the client which requested the reset
+    pub fn send_reset_result(&mut self, result: bool) {
+        if let Some(reply) = self.reset_reply.take() {
+            reply.send(result);
+        }
+    }
+
+    /// Once the agents are reset, send the results back to the client which requested the reset
+    pub fn send_reset_agents_result(&mut self, result: bool) {
+        if let Some(reply) = self.reset_agents_reply.take() {
+            reply.send(result);
         }
     }
 
     /// Recieve serialized actions from the API thread
-    pub fn receive_action_strings(&self) -> Vec<Option<String>> {
-        self.step_request_rx.recv().unwrap()
+    pub fn receive_action_strings(&mut self) -> Vec<Option<String>> {
+        // Step result owed for the previous pause is now outdated, the API thread
+        // waits for the result of the upcoming control step instead
+        self.step_result_pending = false;
+
+        if self.queued_step_ready {
+            self.queued_step_ready = false;
+            return self.queued_action_strings.pop_front().unwrap();
+        }
+
+        // Transitions of a pause which nobody requested
+        self.batch_transitions.clear();
+
+        let Some(request) = self.step_requests.receive() else {
+            return Vec::new();
+        };
+        if let Some(sent_at) = self.step_result_sent_at.take() {
+            let latency = sent_at.elapsed().as_secs_f32();
+            self.trainer_latency = Some(match self.trainer_latency {
+                Some(average) => 0.9 * average + 0.1 * latency,
+                None => latency,
+            });
+        }
+        self.step_reply = Some(request.reply);
+        let mut batch: VecDeque<_> = request.request.into();
+        let actions = batch.pop_front().unwrap_or_default();
+        self.queued_action_strings = batch;
+        actions
     }
 
     /// Recieve reset request from the API thread
-    pub fn receive_reset_request(&self) {
-        self.reset_request_rx.recv().unwrap();
+    pub fn receive_reset_request(&mut self) -> ResetRequest {
+        let request = self.reset_requests.receive().unwrap();
+        self.reset_reply = Some(request.reply);
+        self.log_action_entry(|| crate::replay::ActionLogEntry::Reset(request.request.clone()));
+        request.request
+    }
+
+    /// Write an entry to the action log of `ActionLogPlugin` if there is one
+    pub(crate) fn log_action_entry(&self, entry: impl FnOnce() -> crate::replay::ActionLogEntry) {
+        if let Some(action_log_tx) = &self.action_log_tx {
+            let _ = action_log_tx.send(entry());
+        }
     }
 
-    /// Check whether the API thread has sent a step request
+    /// Check whether a step request is waiting. Requests of other clients wait
+    /// until the result of the current one is sent
     pub fn is_next_action(&self) -> bool {
-        !self.step_request_rx.is_empty()
+        self.queued_step_ready || (self.step_reply.is_none() && !self.step_requests.is_empty())
     }
 
-    /// Check whether the API thread has sent a reset request
+    /// Check whether a reset request is waiting and no reset is in progress
     pub fn is_reset_request(&self) -> bool {
-        !self.reset_request_rx.is_empty()
+        self.reset_reply.is_none() && !self.reset_requests.is_empty()
+    }
+
+    /// Recieve indices of agents to reset from the API thread
+    pub fn receive_reset_agents_request(&mut self) -> Vec<usize> {
+        let request = self.reset_agents_requests.receive().unwrap();
+        self.reset_agents_reply = Some(request.reply);
+        self.log_action_entry(|| {
+            crate::replay::ActionLogEntry::ResetAgents(request.request.clone())
+        });
+        request.request
+    }
+
+    /// Check whether a partial reset request is waiting and no partial reset is in progress
+    pub fn is_reset_agents_request(&self) -> bool {
+        self.reset_agents_reply.is_none() && !self.reset_agents_requests.is_empty()
+    }
+
+    /// set_vector_observation is used to pass the agent's observation as a vector of numbers.
+    /// It's returned inline in step and reset results, so clients don't need a `/state` round trip
+    pub fn set_vector_observation(&mut self, agent_index: usize, observation: Vec<f32>) {
+        self.vector_observations[agent_index] = Some(observation);
+    }
+
+    /// set_observation is used to pass the agent's own observation (its view of the world,
+    /// partial observability). It's returned as `observation` of the agent's transitions instead
+    /// of a copy of the whole environment state, which is sent for agents without one
+    pub fn set_observation(&mut self, agent_index: usize, observation: B) {
+        self.agent_observations[agent_index] = Some(observation);
     }
 
     /// set_reward is used to set the reward for the agent
@@ -128,25 +1066,420 @@ impl<
         self.rewards[agent_index] = score;
     }
 
+    /// add_reward is used to add to the reward of the agent collected since the last step
+    pub fn add_reward(&mut self, agent_index: usize, delta: f32) {
+        self.rewards[agent_index] += delta;
+    }
+
+    /// episode_return is the sum of the agent's rewards of finished steps of the episode
+    pub fn episode_return(&self, agent_index: usize) -> f32 {
+        self.episode_stats[agent_index].episode_return
+    }
+
+    /// set_info is used to attach a Gym-style info dict (distance to goal, collision flags, etc.)
+    /// to the agent's step and reset results. It's kept until set again or the agent is reset.
+    /// Info that can't be serialized is replaced by an empty dict with a warning
+    pub fn set_info<I: Serialize>(&mut self, agent_index: usize, info: I) {
+        self.infos[agent_index] = serde_json::to_value(info).unwrap_or_else(|e| {
+            warn!("bevy_rl: info of agent {agent_index} can't be serialized: {e}");
+            json!({})
+        });
+    }
+
     /// set_terminated is used to mark the agent as terminated
     pub fn set_terminated(&mut self, agent_index: usize, result: bool) {
         self.terminations[agent_index] = result;
     }
 
-    /// reset `bevy_rl` state history (terminated statuses and reward for agents)
+    /// set_truncated is used to mark the agent's episode as cut short by a time limit
+    pub fn set_truncated(&mut self, agent_index: usize, result: bool) {
+        self.truncations[agent_index] = result;
+    }
+
+    /// Count a control step of the episode and truncate it once `max_episode_steps` is reached.
+    /// Episode lengths grow for learning agents which aren't done yet
+    pub(crate) fn count_episode_step(&mut self) {
+        self.episode_steps += 1;
+        for steps in self.env_episode_steps.iter_mut() {
+            *steps += 1;
+        }
+        for agent_index in self.settings.learning_agents() {
+            if !self.terminations[agent_index] && !self.truncations[agent_index] {
+                self.episode_stats[agent_index].episode_length += 1;
+            }
+        }
+
+        let Some(max_episode_steps) = self.settings.max_episode_steps else {
+            return;
+        };
+        for env_id in 0..self.env_episode_steps.len() {
+            if self.env_episode_steps[env_id] >= max_episode_steps {
+                for i in self.env_agents(env_id) {
+                    self.set_truncated(i, true);
+                }
+            }
+        }
+    }
+
+    /// Whether an agent terminated since actions of the current step were submitted
+    pub(crate) fn is_terminated_during_step(&self) -> bool {
+        self.terminations
+            .iter()
+            .zip(self.terminated_at_step_start.iter())
+            .any(|(&terminated, &was_terminated)| terminated && !was_terminated)
+    }
+
+    /// Move rewards of a repeated control interval aside, the next interval starts from zero
+    pub(crate) fn accumulate_skipped_rewards(&mut self) {
+        for i in 0..self.rewards.len() {
+            self.skipped_rewards[i] += self.rewards[i];
+            self.rewards[i] = 0.0;
+        }
+    }
+
+    /// Add rewards of repeated control intervals to the reward of the step
+    pub(crate) fn restore_skipped_rewards(&mut self) {
+        for i in 0..self.rewards.len() {
+            self.rewards[i] += self.skipped_rewards[i];
+            self.skipped_rewards[i] = 0.0;
+        }
+    }
+
+    /// Index of the environment copy the agent belongs to
+    pub fn env_id(&self, agent_index: usize) -> usize {
+        agent_index / (self.settings.num_agents.max(1) as usize)
+    }
+
+    /// Indices of agents of the environment copy
+    pub fn env_agents(&self, env_id: usize) -> std::ops::Range<usize> {
+        let num_agents = self.settings.num_agents as usize;
+        env_id * num_agents..(env_id + 1) * num_agents
+    }
+
+    /// set_vec_env_state is used to set observation of a single environment copy,
+    /// it takes precedence over `set_env_state` for agents of this copy
+    pub fn set_vec_env_state(&mut self, env_id: usize, state: B) {
+        self.env_states[env_id] = Some(state);
+    }
+
+    /// reset state of agents of the environment copy after `EventResetEnv`.
+    /// The API is answered once all copies of the request are reset
+    pub fn reset_env(&mut self, env_id: usize) {
+        for i in self.env_agents(env_id) {
+            self.set_terminated(i, false);
+            self.set_truncated(i, false);
+            self.set_reward(i, 0.0);
+            self.end_episode(i);
+            self.infos[i] = json!({});
+        }
+        self.env_episode_steps[env_id] = 0;
+
+        self.pending_env_resets.retain(|&id| id != env_id);
+        if self.pending_env_resets.is_empty() {
+            self.send_reset_result(true);
+        }
+    }
+
+    /// Close the episode of an agent in its statistics, metrics and TensorBoard events
+    fn end_episode(&mut self, agent_index: usize) {
+        if let Some(episode_return) = self.episode_stats[agent_index].end_episode() {
+            {
+                let mut metrics = self.metrics.lock().unwrap();
+                metrics.episodes += 1;
+                metrics.episode_return.observe(episode_return as f64);
+            }
+
+            let episode_length = self.episode_stats[agent_index]
+                .last_length
+                .unwrap_or_default();
+            self.log_scalar(
+                &format!("episode_return/agent_{agent_index}"),
+                episode_return,
+            );
+            self.log_scalar(
+                &format!("episode_length/agent_{agent_index}"),
+                episode_length as f32,
+            );
+        }
+    }
+
+    /// record_dataset starts recording steps of learning agents for offline RL, `encode_action`
+    /// turns an action into the numbers of its `actions` row. Recorded steps are kept in memory
+    /// until `take_dataset`
+    pub fn record_dataset(
+        &mut self,
+        encode_action: impl Fn(&A) -> Vec<f32> + Send + Sync + 'static,
+    ) {
+        self.dataset_recorder = Some(crate::dataset::DatasetRecorder::new(encode_action));
+    }
+
+    /// take_dataset returns steps recorded so far, recording goes on with an empty dataset
+    pub fn take_dataset(&mut self) -> Option<crate::dataset::Dataset> {
+        self.dataset_recorder
+            .as_mut()
+            .map(|recorder| std::mem::take(&mut recorder.dataset))
+    }
+
+    /// log_scalar records `value` under `tag` at the current control step in TensorBoard events
+    /// of `TensorboardPlugin`, e.g. distance to goal. Without the plugin it does nothing
+    pub fn log_scalar(&self, tag: &str, value: f32) {
+        if let Some(scalar_tx) = &self.scalar_tx {
+            let step = self.metrics.lock().unwrap().steps;
+            crate::tensorboard::send_scalar(scalar_tx, tag.to_string(), value, step);
+        }
+    }
+
+    /// Episode bookkeeping, observations, `AIGymRng` and virtual time saved with a world snapshot
+    pub(crate) fn gym_snapshot(&self, world: &World) -> GymSnapshot<B> {
+        GymSnapshot {
+            rewards: self.rewards.clone(),
+            infos: self.infos.clone(),
+            terminations: self.terminations.clone(),
+            truncations: self.truncations.clone(),
+            episode_stats: self.episode_stats.clone(),
+            episode_steps: self.episode_steps,
+            env_episode_steps: self.env_episode_steps.clone(),
+            environment_state: self.environment_state.clone(),
+            env_states: self.env_states.clone(),
+            vector_observations: self.vector_observations.clone(),
+            agent_observations: self.agent_observations.clone(),
+            visual_observations: self.visual_observations.clone(),
+            frame_stacks: self.frame_stacks.clone(),
+            rng: world.get_resource::<AIGymRng>().map(|rng| rng.0.clone()),
+            virtual_elapsed: world
+                .get_resource::<Time<Virtual>>()
+                .map_or(Duration::ZERO, |time| time.elapsed()),
+        }
+    }
+
+    /// Restore what `gym_snapshot` saved. Actions repeated for frame skip are dropped,
+    /// the next step starts from the restored state
+    pub(crate) fn restore_gym_snapshot(&mut self, snapshot: GymSnapshot<B>, world: &mut World) {
+        self.rewards = snapshot.rewards;
+        self.infos = snapshot.infos;
+        self.terminations = snapshot.terminations;
+        self.truncations = snapshot.truncations;
+        self.episode_stats = snapshot.episode_stats;
+        self.episode_steps = snapshot.episode_steps;
+        self.env_episode_steps = snapshot.env_episode_steps;
+        self.environment_state = snapshot.environment_state;
+        self.env_states = snapshot.env_states;
+        self.vector_observations = snapshot.vector_observations;
+        self.agent_observations = snapshot.agent_observations;
+        self.visual_observations = snapshot.visual_observations;
+        self.frame_stacks = snapshot.frame_stacks;
+
+        if let (Some(rng), Some(mut ai_gym_rng)) =
+            (snapshot.rng, world.get_resource_mut::<AIGymRng>())
+        {
+            ai_gym_rng.0 = rng;
+        }
+        // Elapsed time can't be set back, the clock is replaced keeping its settings and pause.
+        // The new delta only lasts until time is updated in the next frame
+        if let Some(mut time) = world.get_resource_mut::<Time<Virtual>>() {
+            let mut restored = Time::new_with(*time.context());
+            restored.advance_to(snapshot.virtual_elapsed);
+            *time = restored;
+        }
+
+        self.pending_env_resets.clear();
+        self.frame_skip_remaining = 0;
+        for reward in self.skipped_rewards.iter_mut() {
+            *reward = 0.0;
+        }
+    }
+
+    /// reset `bevy_rl` state history (terminated and truncated statuses and reward for agents)
     pub fn reset(&mut self) {
         for i in 0..self.terminations.len() {
             self.set_terminated(i, false);
+            self.set_truncated(i, false);
             self.set_reward(i, 0.0);
+            self.end_episode(i);
+            self.infos[i] = json!({});
+        }
+        self.episode_steps = 0;
+        for steps in self.env_episode_steps.iter_mut() {
+            *steps = 0;
+        }
+        self.pending_env_resets.clear();
+        for stack in self.frame_stacks.iter_mut() {
+            stack.clear();
+        }
+        self.frame_skip_remaining = 0;
+        for reward in self.skipped_rewards.iter_mut() {
+            *reward = 0.0;
         }
 
         self.send_reset_result(true);
     }
 
+    /// set_parameter is used to set an environment parameter
+    pub fn set_parameter(&mut self, name: &str, value: f32) {
+        self.parameters.insert(name.to_string(), value);
+    }
+
+    /// parameter returns an environment parameter if it's set
+    pub fn parameter(&self, name: &str) -> Option<f32> {
+        self.parameters.get(name).copied()
+    }
+
+    /// set_local_action is used to pass input of a locally controlled agent (see `AIGymSettings.local_agents`).
+    /// The latest action is applied on every control step. Actions of unknown agents and ones
+    /// that can't be serialized are ignored with a warning
+    pub fn set_local_action(&mut self, agent_index: usize, action: A)
+    where
+        A: serde::Serialize,
+    {
+        let Some(local_action) = self.local_actions.get_mut(agent_index) else {
+            warn!("bevy_rl: local action of unknown agent {agent_index} is ignored");
+            return;
+        };
+        match serde_json::to_string(&action) {
+            Ok(action) => *local_action = Some(action),
+            Err(e) => warn!("bevy_rl: local action of agent {agent_index} is ignored: {e}"),
+        }
+    }
+
+    /// add_scripted_agent lets `agent` act for the agent at `agent_index` inside the engine. It's
+    /// given the agent's environment state every control step and its action is applied like
+    /// one passed with `set_local_action`, so the agent must be listed in `local_agents`.
+    /// Agents that aren't are ignored with a warning: the trainer keeps driving them and
+    /// the bot's actions would never be applied
+    pub fn add_scripted_agent(
+        &mut self,
+        agent_index: usize,
+        mut agent: impl ScriptedAgent<A, B> + 'static,
+    ) where
+        A: serde::Serialize,
+    {
+        if !self.settings.local_agents.contains(&agent_index) {
+            warn!("bevy_rl: scripted agent {agent_index} isn't in `local_agents` and is ignored");
+            return;
+        }
+        self.scripted_agents.insert(
+            agent_index,
+            Box::new(move |state| serde_json::to_string(&agent.act(state)).ok()),
+        );
+    }
+
+    /// Let scripted agents act on the environment state of the control step
+    pub(crate) fn run_scripted_agents(&mut self) {
+        let mut scripted_agents = std::mem::take(&mut self.scripted_agents);
+        for (&agent_index, agent) in scripted_agents.iter_mut() {
+            let env_id = self.env_id(agent_index);
+            let state = self.env_states[env_id]
+                .as_ref()
+                .or(self.environment_state.as_ref());
+            if let Some(state) = state {
+                self.local_actions[agent_index] = agent(state);
+            }
+        }
+        self.scripted_agents = scripted_agents;
+    }
+
+    /// Time to sleep this frame with `speed_governor` while the trainer is busy: half of the time
+    /// left until its next step is expected, at most 50 ms. Frames nobody reads are spaced out,
+    /// while a trainer coming early waits little
+    pub(crate) fn governor_sleep(&self) -> Option<std::time::Duration> {
+        let latency = self.trainer_latency?;
+        let sent_at = self.step_result_sent_at?;
+        if !self.step_requests.is_empty() {
+            return None;
+        }
+
+        let remaining = latency - sent_at.elapsed().as_secs_f32();
+        (remaining > 0.0).then(|| std::time::Duration::from_secs_f32((remaining / 2.0).min(0.05)))
+    }
+
+    /// Remember when and from where the last API request came
+    pub(crate) fn record_api_request(&mut self, client: &str) {
+        self.last_request_at = Some(std::time::Instant::now());
+        self.last_client = Some(client.to_string());
+    }
+
+    /// reset state of given agents (terminated and truncated statuses and reward) after partial reset
+    pub fn reset_agents(&mut self, agent_indices: &[usize]) {
+        for &i in agent_indices {
+            self.set_terminated(i, false);
+            self.set_truncated(i, false);
+            self.set_reward(i, 0.0);
+            self.end_episode(i);
+            self.infos[i] = json!({});
+        }
+
+        self.send_reset_agents_result(true);
+    }
+
+    /// set_state_codec is used to encode environment state with a custom encoder instead of JSON
+    pub fn set_state_codec(&mut self, codec: StateCodec<B>) {
+        self.state_codec = Some(codec);
+    }
+
+    /// add_task registers a reward and termination definition reported in `tasks` of transitions.
+    /// A task with the same name is replaced
+    pub fn add_task(&mut self, task: Task<B>) {
+        self.tasks.retain(|registered| registered.name != task.name);
+        self.tasks.push(task);
+    }
+
     /// set_env_state is used to synchrinize simulation state with bevy_rl for REST API
     pub fn set_env_state(&mut self, state: B) {
         self.environment_state = Some(state);
     }
+
+    /// Seconds since the last frame of the app, `None` before the first one
+    pub(crate) fn seconds_since_frame(&self) -> Option<f32> {
+        self.last_frame_at
+            .map(|last_frame_at| last_frame_at.elapsed().as_secs_f32())
+    }
+
+    /// The simulation loop ran a frame within `AIGymSettings.liveness_timeout`
+    pub(crate) fn is_responsive(&self) -> bool {
+        self.seconds_since_frame()
+            .is_some_and(|seconds| seconds <= self.settings.liveness_timeout)
+    }
+
+    /// Render targets of all agents are allocated, or aren't needed without `render_to_buffer`
+    pub(crate) fn has_render_targets(&self) -> bool {
+        !self.settings.render_to_buffer
+            || self.render_target_array.is_some()
+            || self.render_image_handles.len() == self.settings.total_agents() as usize
+    }
+
+    /// env_state is the environment state last set with `set_env_state`
+    pub fn env_state(&self) -> Option<&B> {
+        self.environment_state.as_ref()
+    }
+
+    /// set_action_schema is used to describe the action type with a JSON schema in `/openapi.json`
+    pub fn set_action_schema(&mut self, schema: serde_json::Value) {
+        self.action_schema = Some(schema);
+    }
+
+    /// set_state_schema is used to describe the state type with a JSON schema in `/openapi.json`
+    pub fn set_state_schema(&mut self, schema: serde_json::Value) {
+        self.state_schema = Some(schema);
+    }
+
+    /// set_action_space is used to describe actions of an agent at `/spaces`
+    pub fn set_action_space(&mut self, space: crate::spaces::SpaceDescriptor) {
+        self.action_space = Some(space);
+    }
+
+    /// set_observation_space is used to describe observations of an agent at `/spaces`.
+    /// If it's not set, the box of visual observations is served
+    pub fn set_observation_space(&mut self, space: crate::spaces::SpaceDescriptor) {
+        self.observation_space = Some(space);
+    }
+
+    /// add_endpoint serves `endpoint` at `/custom/{name}` for environment-specific debugging.
+    /// It runs on the API thread with the state locked, so it should return quickly.
+    /// An endpoint with the same name is replaced
+    pub fn add_endpoint(&mut self, name: &str, endpoint: impl CustomEndpoint<A, B> + 'static) {
+        self.custom_endpoints
+            .insert(name.to_string(), Arc::new(endpoint));
+    }
 }
 
 /// `AIGymStateInner` is never used directly, instead it's wrapped
@@ -154,16 +1487,155 @@ impl<
 /// To use AIGymState you would need to lock it with `AIGymState::lock()`
 #[derive(Resource, Deref, DerefMut, Clone)]
 pub struct AIGymState<
-    A: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe,
+    A: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::de::DeserializeOwned,
     B: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe,
 >(pub Arc<Mutex<AIGymStateInner<A, B>>>);
 
 impl<
-        A: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe,
+        A: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::de::DeserializeOwned,
         B: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe,
     > AIGymState<A, B>
 {
     pub fn new(settings: AIGymSettings) -> Self {
         Self(Arc::new(Mutex::new(AIGymStateInner::new(settings))))
     }
+
+    /// Send serialized actions to the engine and block until transitions of the next control step.
+    /// Must not be called from the engine thread, fails once the engine has stopped
+    pub fn request_step(
+        &self,
+        actions: Vec<Option<String>>,
+    ) -> Result<Vec<AgentTransition<B>>, RecvError> {
+        let mut batch_transitions = self.request_step_batch(vec![actions])?;
+        batch_transitions.pop().ok_or(RecvError)
+    }
+
+    /// Send actions of several consecutive control intervals to the engine and block until
+    /// transitions of all of them. The engine applies them in a row without waiting for requests.
+    /// Must not be called from the engine thread, fails once the engine has stopped
+    pub fn request_step_batch(
+        &self,
+        batch: Vec<Vec<Option<String>>>,
+    ) -> Result<Vec<Vec<AgentTransition<B>>>, RecvError> {
+        self.submit_step_batch(batch).recv()
+    }
+
+    /// Ask the engine to reset the environment and block until it's done.
+    /// Must not be called from the engine thread, fails once the engine has stopped
+    pub fn request_reset(&self, request: ResetRequest) -> Result<(), RecvError> {
+        self.submit_reset(request).recv().map(|_| ())
+    }
+
+    /// Ask the engine to reset given agents and block until it's done.
+    /// Must not be called from the engine thread, fails once the engine has stopped
+    pub fn request_reset_agents(&self, agent_indices: Vec<usize>) -> Result<(), RecvError> {
+        self.submit_reset_agents(agent_indices).recv().map(|_| ())
+    }
+
+    /// Queue a batch of actions, transitions arrive at the returned receiver
+    pub(crate) fn submit_step_batch(
+        &self,
+        batch: Vec<Vec<Option<String>>>,
+    ) -> PendingReply<Vec<Vec<AgentTransition<B>>>> {
+        self.lock().unwrap().step_requests.submit(batch)
+    }
+
+    /// Queue a reset, its result arrives at the returned receiver
+    pub(crate) fn submit_reset(&self, request: ResetRequest) -> PendingReply<bool> {
+        self.lock().unwrap().reset_requests.submit(request)
+    }
+
+    /// Queue a reset of given agents, its result arrives at the returned receiver
+    pub(crate) fn submit_reset_agents(&self, agent_indices: Vec<usize>) -> PendingReply<bool> {
+        self.lock()
+            .unwrap()
+            .reset_agents_requests
+            .submit(agent_indices)
+    }
+
+    /// Queue saving or loading a world snapshot, its result arrives at the returned receiver
+    pub(crate) fn submit_snapshot(
+        &self,
+        request: crate::snapshot::SnapshotRequest,
+    ) -> PendingReply<crate::snapshot::SnapshotResult> {
+        self.lock().unwrap().snapshot_requests.submit(request)
+    }
+
+    /// Queue pausing (`true`) or resuming the simulation for debugging, the state it goes to
+    /// arrives at the returned receiver
+    pub(crate) fn submit_debug_pause(&self, pause: bool) -> PendingReply<crate::SimulationState> {
+        self.lock().unwrap().debug_pause_requests.submit(pause)
+    }
+
+    /// Queue running frames without a control step, the result arrives at the returned receiver
+    pub(crate) fn submit_advance(&self, request: AdvanceRequest) -> PendingReply<bool> {
+        self.lock().unwrap().advance_requests.submit(request)
+    }
+
+    /// Queue a payload for `EventSetState`, the result arrives at the returned receiver
+    /// once the event has been handled
+    pub(crate) fn submit_set_state(
+        &self,
+        payload: serde_json::Value,
+    ) -> PendingReply<Result<(), String>> {
+        self.lock().unwrap().set_state_requests.submit(payload)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finished_episodes_update_running_means() {
+        let mut stats = EpisodeStats::default();
+        assert_eq!(stats.end_episode(), None);
+        assert_eq!(stats.episodes, 0);
+
+        stats.episode_return = 1.0;
+        stats.episode_length = 10;
+        assert_eq!(stats.end_episode(), Some(1.0));
+
+        stats.episode_return = 3.0;
+        stats.episode_length = 20;
+        assert_eq!(stats.end_episode(), Some(3.0));
+
+        assert_eq!(stats.episodes, 2);
+        assert_eq!(stats.mean_return, 2.0);
+        assert_eq!(stats.mean_length, 15.0);
+        assert_eq!(stats.last_return, Some(3.0));
+        assert_eq!(stats.last_length, Some(20));
+        assert_eq!(stats.episode_return, 0.0);
+        assert_eq!(stats.episode_length, 0);
+    }
+
+    #[test]
+    fn episode_steps_count_running_learning_agents() {
+        let mut state = AIGymStateInner::<(), ()>::new(AIGymSettings {
+            num_agents: 3,
+            npc_agents: vec![2],
+            ..Default::default()
+        });
+        state.count_episode_step();
+        state.set_terminated(0, true);
+        state.count_episode_step();
+
+        assert_eq!(state.episode_stats[0].episode_length, 1);
+        assert_eq!(state.episode_stats[1].episode_length, 2);
+        assert_eq!(state.episode_stats[2].episode_length, 0);
+    }
+
+    #[test]
+    fn transitions_prefer_agent_observations() {
+        let mut state = AIGymStateInner::<(), u32>::new(AIGymSettings {
+            num_agents: 2,
+            ..Default::default()
+        });
+        state.set_env_state(10);
+        state.set_observation(1, 1);
+
+        let transitions = state.transitions();
+        assert_eq!(transitions[0].observation, Some(10));
+        assert_eq!(transitions[1].observation, Some(1));
+    }
 }
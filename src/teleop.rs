@@ -0,0 +1,262 @@
+//! Utilities to map raw keyboard and gamepad input to agent actions.
+//! Together with `AIGymSettings.local_agents` and `set_local_action` they let a human drive an agent
+//! with exactly the same action encoding a trained policy uses, so recorded demonstrations
+//! are directly consumable by the policy interface. `TeleopPlugin` wires it up: input goes through
+//! the usual step pipeline and steps are recorded as an offline RL dataset of demonstrations.
+
+use std::marker::PhantomData;
+use std::sync::Arc;
+use std::thread;
+
+use bevy::input::gamepad::{Gamepad, GamepadAxis};
+use bevy::prelude::*;
+
+use crate::state::{AIGymState, ResetRequest};
+use crate::BevyRlSet;
+
+/// Zero out values within the deadzone and rescale the rest to keep the full `[-1, 1]` range
+pub fn apply_deadzone(value: f32, deadzone: f32) -> f32 {
+    let magnitude = value.abs();
+    if magnitude <= deadzone || deadzone >= 1.0 {
+        return 0.0;
+    }
+    value.signum() * ((magnitude - deadzone) / (1.0 - deadzone)).min(1.0)
+}
+
+/// Discretization of an analog axis in `[-1, 1]` into equal-width bins
+#[derive(Clone, Debug)]
+pub struct AxisBins {
+    pub bins: u32,
+    pub deadzone: f32,
+}
+
+impl AxisBins {
+    /// Bin index of the axis value. With odd number of bins values in the deadzone map to the central bin
+    pub fn discretize(&self, value: f32) -> u32 {
+        if self.bins <= 1 {
+            return 0;
+        }
+        let value = apply_deadzone(value, self.deadzone);
+        let normalized = (value.clamp(-1.0, 1.0) + 1.0) / 2.0;
+        ((normalized * self.bins as f32) as u32).min(self.bins - 1)
+    }
+
+    /// Center value of the bin, inverse of `discretize`
+    pub fn value(&self, bin: u32) -> f32 {
+        if self.bins <= 1 {
+            return 0.0;
+        }
+        (bin.min(self.bins - 1) as f32 + 0.5) / self.bins as f32 * 2.0 - 1.0
+    }
+}
+
+/// Mapping of keys to discrete actions. The first pressed binding wins
+#[derive(Clone, Debug)]
+pub struct KeyActionMap<A: Clone> {
+    pub bindings: Vec<(KeyCode, A)>,
+    pub default: A,
+}
+
+impl<A: Clone> KeyActionMap<A> {
+    pub fn action(&self, keys: &ButtonInput<KeyCode>) -> A {
+        self.bindings
+            .iter()
+            .find(|(key, _)| keys.pressed(*key))
+            .map(|(_, action)| action.clone())
+            .unwrap_or_else(|| self.default.clone())
+    }
+}
+
+/// Read gamepad axes with the deadzone applied
+pub fn gamepad_axes(gamepad: &Gamepad, axes: &[GamepadAxis], deadzone: f32) -> Vec<f32> {
+    axes.iter()
+        .map(|axis| apply_deadzone(gamepad.get(*axis).unwrap_or(0.0), deadzone))
+        .collect()
+}
+
+/// Read gamepad axes discretized into bins, e.g. for multi-discrete action spaces
+pub fn gamepad_axes_discrete(gamepad: &Gamepad, axes: &[GamepadAxis], bins: &AxisBins) -> Vec<u32> {
+    axes.iter()
+        .map(|axis| bins.discretize(gamepad.get(*axis).unwrap_or(0.0)))
+        .collect()
+}
+
+/// Maps keyboard and the first connected gamepad to an action
+type InputMap<T> = Arc<dyn Fn(&ButtonInput<KeyCode>, Option<&Gamepad>) -> T + Send + Sync>;
+
+/// Human teleoperation of an agent. Every frame input is mapped to an action with `map` and passed
+/// to `set_local_action`, so the agent must be listed in `AIGymSettings.local_agents`
+pub struct TeleopPlugin<
+    T: 'static
+        + Send
+        + Sync
+        + Clone
+        + std::panic::RefUnwindSafe
+        + serde::de::DeserializeOwned
+        + serde::Serialize,
+    P: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::Serialize,
+> {
+    pub agent_index: usize,
+    map: InputMap<T>,
+    /// Step and reset the environment without a trainer, e.g. to collect demonstrations
+    pub autostep: bool,
+    /// Record steps as a dataset with `record_dataset`, see `with_demonstrations`
+    encode_action: Option<Arc<dyn Fn(&T) -> Vec<f32> + Send + Sync>>,
+    _phantom: PhantomData<P>,
+}
+
+impl<
+        T: 'static
+            + Send
+            + Sync
+            + Clone
+            + std::panic::RefUnwindSafe
+            + serde::de::DeserializeOwned
+            + serde::Serialize,
+        P: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::Serialize,
+    > TeleopPlugin<T, P>
+{
+    /// Drive the agent with input mapped by `map`, stepping the environment without a trainer
+    pub fn new(
+        agent_index: usize,
+        map: impl Fn(&ButtonInput<KeyCode>, Option<&Gamepad>) -> T + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            agent_index,
+            map: Arc::new(map),
+            autostep: true,
+            encode_action: None,
+            _phantom: PhantomData,
+        }
+    }
+
+    /// Leave stepping to a trainer controlling the other agents, e.g. in human-vs-agent games
+    pub fn with_autostep(mut self, autostep: bool) -> Self {
+        self.autostep = autostep;
+        self
+    }
+
+    /// Record demonstrations: steps of the environment are kept with `record_dataset`, to be taken
+    /// with `take_dataset` and exported for imitation learning
+    pub fn with_demonstrations(
+        mut self,
+        encode_action: impl Fn(&T) -> Vec<f32> + Send + Sync + 'static,
+    ) -> Self {
+        self.encode_action = Some(Arc::new(encode_action));
+        self
+    }
+}
+
+/// Agent driven by the human and its input mapping
+#[derive(Resource)]
+struct Teleop<T> {
+    agent_index: usize,
+    map: InputMap<T>,
+}
+
+impl<
+        T: 'static
+            + Send
+            + Sync
+            + Clone
+            + std::panic::RefUnwindSafe
+            + serde::de::DeserializeOwned
+            + serde::Serialize,
+        P: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::Serialize,
+    > Plugin for TeleopPlugin<T, P>
+{
+    fn build(&self, app: &mut App) {
+        let agent_index = self.agent_index;
+        let autostep = self.autostep;
+        let encode_action = self.encode_action.clone();
+
+        app.insert_resource(Teleop {
+            agent_index,
+            map: self.map.clone(),
+        })
+        .add_systems(
+            Startup,
+            move |ai_gym_state: Res<AIGymState<T, P>>| {
+                {
+                    let mut ai_gym_state = ai_gym_state.lock().unwrap();
+                    if !ai_gym_state.settings.local_agents.contains(&agent_index) {
+                        warn!(
+                            "bevy_rl: teleoperated agent {agent_index} isn't in `local_agents`, input is ignored"
+                        );
+                    }
+                    if let Some(encode_action) = encode_action.clone() {
+                        ai_gym_state.record_dataset(move |action: &T| encode_action(action));
+                    }
+                }
+
+                if autostep {
+                    let ai_gym_state = ai_gym_state.clone();
+                    thread::spawn(move || autostep_environment(ai_gym_state));
+                }
+            },
+        )
+        .add_systems(
+            Update,
+            capture_teleop_input::<T, P>.before(BevyRlSet::ApplyActions),
+        );
+    }
+}
+
+fn capture_teleop_input<
+    T: 'static
+        + Send
+        + Sync
+        + Clone
+        + std::panic::RefUnwindSafe
+        + serde::de::DeserializeOwned
+        + serde::Serialize,
+    P: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::Serialize,
+>(
+    teleop: Res<Teleop<T>>,
+    keys: Res<ButtonInput<KeyCode>>,
+    gamepads: Query<&Gamepad>,
+    ai_gym_state: Res<AIGymState<T, P>>,
+) {
+    let action = (teleop.map)(&keys, gamepads.iter().next());
+    ai_gym_state
+        .lock()
+        .unwrap()
+        .set_local_action(teleop.agent_index, action);
+}
+
+/// Act as a trainer sending no actions, so that local agents' input drives steps.
+/// The environment is reset at start and when the episode is done
+fn autostep_environment<
+    T: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::de::DeserializeOwned,
+    P: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::Serialize,
+>(
+    ai_gym_state: AIGymState<T, P>,
+) {
+    let agents = ai_gym_state.lock().unwrap().settings.total_agents() as usize;
+    // Requests fail once the app exits
+    if ai_gym_state
+        .submit_reset(ResetRequest::default())
+        .recv()
+        .is_err()
+    {
+        return;
+    }
+    loop {
+        if ai_gym_state
+            .submit_step_batch(vec![vec![None; agents]])
+            .recv()
+            .is_err()
+        {
+            return;
+        }
+        let is_episode_done = ai_gym_state.lock().unwrap().is_episode_done();
+        if is_episode_done
+            && ai_gym_state
+                .submit_reset(ResetRequest::default())
+                .recv()
+                .is_err()
+        {
+            return;
+        }
+    }
+}
@@ -0,0 +1,201 @@
+//! TensorBoard event files of the environment.
+//! Episode returns and lengths of every agent, and scalars recorded with
+//! `ai_gym_state.log_scalar(tag, value)`, are written to `log_dir` so environment-side metrics
+//! show up next to trainer metrics. Events are encoded by hand (TFRecord framing around
+//! `tensorflow.Event` protobufs), no TensorFlow dependency is needed.
+
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::marker::PhantomData;
+use std::path::PathBuf;
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use bevy::prelude::*;
+use crossbeam_channel::{bounded, Receiver, Sender};
+
+use crate::state;
+
+/// Scalars waiting for the writer thread, newer ones are dropped when it's behind
+const QUEUE_SIZE: usize = 4096;
+
+/// Scalar value at a control step
+#[derive(Clone, Debug)]
+pub struct Scalar {
+    pub tag: String,
+    pub value: f32,
+    /// Control steps answered since the start of the app
+    pub step: u64,
+    /// Seconds since the Unix epoch
+    pub wall_time: f64,
+}
+
+/// Writes scalars of the environment as TensorBoard events to `log_dir`,
+/// pass the trainer's run directory to see them in the same run
+pub struct TensorboardPlugin<
+    T: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::de::DeserializeOwned,
+    P: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::Serialize,
+> {
+    pub log_dir: PathBuf,
+    _phantom: PhantomData<(T, P)>,
+}
+
+impl<
+        T: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::de::DeserializeOwned,
+        P: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::Serialize,
+    > TensorboardPlugin<T, P>
+{
+    pub fn new(log_dir: impl Into<PathBuf>) -> Self {
+        Self {
+            log_dir: log_dir.into(),
+            _phantom: PhantomData,
+        }
+    }
+}
+
+impl<
+        T: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::de::DeserializeOwned,
+        P: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::Serialize,
+    > Plugin for TensorboardPlugin<T, P>
+{
+    fn build(&self, app: &mut App) {
+        let (scalar_tx, scalar_rx) = bounded(QUEUE_SIZE);
+        let log_dir = self.log_dir.clone();
+        thread::spawn(move || write_events(log_dir, scalar_rx));
+
+        app.add_systems(
+            Startup,
+            move |ai_gym_state: Res<state::AIGymState<T, P>>| {
+                ai_gym_state.lock().unwrap().scalar_tx = Some(scalar_tx.clone());
+            },
+        );
+    }
+}
+
+pub(crate) fn send_scalar(scalar_tx: &Sender<Scalar>, tag: String, value: f32, step: u64) {
+    let wall_time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs_f64();
+    let _ = scalar_tx.try_send(Scalar {
+        tag,
+        value,
+        step,
+        wall_time,
+    });
+}
+
+fn write_events(log_dir: PathBuf, scalar_rx: Receiver<Scalar>) {
+    let wall_time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let host = std::env::var("HOSTNAME").unwrap_or_else(|_| "bevy_rl".to_string());
+    let path = log_dir.join(format!(
+        "events.out.tfevents.{}.{host}",
+        wall_time.as_secs()
+    ));
+
+    let file = std::fs::create_dir_all(&log_dir).and_then(|_| File::create(&path));
+    let mut writer = match file {
+        Ok(file) => BufWriter::new(file),
+        Err(e) => {
+            warn!(
+                "bevy_rl: can't write TensorBoard events to {}: {e}",
+                path.display()
+            );
+            return;
+        }
+    };
+
+    // TensorBoard expects the file version in the first event
+    let mut event = Vec::new();
+    encode_fixed64(&mut event, 1, wall_time.as_secs_f64().to_bits());
+    encode_bytes(&mut event, 3, b"brain.Event:2");
+    if let Err(e) = write_record(&mut writer, &event).and_then(|_| writer.flush()) {
+        warn!("bevy_rl: TensorBoard events stopped: {e}");
+        return;
+    }
+
+    while let Ok(scalar) = scalar_rx.recv() {
+        // Flush once the queue is drained, so events show up while training runs
+        let result = std::iter::once(scalar)
+            .chain(scalar_rx.try_iter())
+            .try_for_each(|scalar| write_record(&mut writer, &encode_scalar_event(&scalar)))
+            .and_then(|_| writer.flush());
+        if let Err(e) = result {
+            warn!("bevy_rl: TensorBoard events stopped: {e}");
+            return;
+        }
+    }
+}
+
+/// `Event { wall_time, step, summary: Summary { value: [Value { tag, simple_value }] } }`
+fn encode_scalar_event(scalar: &Scalar) -> Vec<u8> {
+    let mut value = Vec::new();
+    encode_bytes(&mut value, 1, scalar.tag.as_bytes());
+    encode_key(&mut value, 2, 5);
+    value.extend_from_slice(&scalar.value.to_le_bytes());
+
+    let mut summary = Vec::new();
+    encode_bytes(&mut summary, 1, &value);
+
+    let mut event = Vec::new();
+    encode_fixed64(&mut event, 1, scalar.wall_time.to_bits());
+    encode_key(&mut event, 2, 0);
+    encode_varint(&mut event, scalar.step);
+    encode_bytes(&mut event, 5, &summary);
+    event
+}
+
+fn encode_varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn encode_key(out: &mut Vec<u8>, field: u64, wire_type: u64) {
+    encode_varint(out, field << 3 | wire_type);
+}
+
+fn encode_fixed64(out: &mut Vec<u8>, field: u64, value: u64) {
+    encode_key(out, field, 1);
+    out.extend_from_slice(&value.to_le_bytes());
+}
+
+fn encode_bytes(out: &mut Vec<u8>, field: u64, bytes: &[u8]) {
+    encode_key(out, field, 2);
+    encode_varint(out, bytes.len() as u64);
+    out.extend_from_slice(bytes);
+}
+
+/// TFRecord: length, CRC of length, data, CRC of data
+fn write_record(writer: &mut impl Write, data: &[u8]) -> std::io::Result<()> {
+    let length = (data.len() as u64).to_le_bytes();
+    writer.write_all(&length)?;
+    writer.write_all(&masked_crc32c(&length).to_le_bytes())?;
+    writer.write_all(data)?;
+    writer.write_all(&masked_crc32c(data).to_le_bytes())
+}
+
+fn masked_crc32c(data: &[u8]) -> u32 {
+    let crc = crc32c(data);
+    crc.rotate_right(15).wrapping_add(0xa282_ead8)
+}
+
+/// CRC-32C (Castagnoli), bitwise since records are small
+fn crc32c(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0x82f6_3b78
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
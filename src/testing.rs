@@ -0,0 +1,364 @@
+//! Deterministic test driver.
+//! `TestDriver` owns the `App` and advances it frame by frame, passing requests through the same
+//! channels as REST API does. Tests don't need a running server, threads or sleeps: every call
+//! returns as soon as the engine answers, and fails after a bounded number of frames if it doesn't.
+//! `TestDriver::get` sends requests through the REST API router instead, with Gotham's in-memory
+//! transport, to test routes, query strings, status codes and middlewares as well.
+//! `RewardFixture` unit-tests reward and termination logic on a bare `World`, without an app.
+
+use std::sync::MutexGuard;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use bevy::{ecs::system::RunSystemOnce, prelude::*, time::TimeUpdateStrategy};
+use serde::Serialize;
+
+use crate::api;
+use crate::state::{AIGymState, AIGymStateInner, AgentTransition, ResetRequest};
+use crate::{AIGymSettings, EventControl, EventControlTyped};
+
+/// Wall-clock limit of an API request in `TestDriver::get`
+const API_REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Drives an `App` with `AIGymPlugin` in the test thread
+pub struct TestDriver<
+    A: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::de::DeserializeOwned,
+    B: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe,
+> {
+    pub app: App,
+    ai_gym_state: AIGymState<A, B>,
+    max_frames: usize,
+    api_server: Option<gotham::test::TestServer>,
+}
+
+impl<
+        A: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::de::DeserializeOwned,
+        B: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe,
+    > TestDriver<A, B>
+{
+    /// Take a configured app. Every frame advances time by exactly `pause_interval`,
+    /// so the simulation pauses for control on every frame in `StepMode::Interval`.
+    /// `StepMode::FixedTimestep` drives time by its own `dt` instead
+    pub fn new(mut app: App) -> Self {
+        let ai_gym_state = app
+            .world()
+            .get_resource::<AIGymState<A, B>>()
+            .expect("AIGymState resource is missing")
+            .clone();
+
+        let pause_interval = ai_gym_state.lock().unwrap().settings.pause_interval;
+        app.insert_resource(TimeUpdateStrategy::ManualDuration(Duration::from_secs_f32(
+            pause_interval,
+        )));
+        app.update();
+
+        Self {
+            app,
+            ai_gym_state,
+            max_frames: 100,
+            api_server: None,
+        }
+    }
+
+    /// Fail a request if the engine doesn't answer in this many frames
+    pub fn with_max_frames(mut self, max_frames: usize) -> Self {
+        self.max_frames = max_frames;
+        self
+    }
+
+    /// Advance the app by one frame
+    pub fn update(&mut self) {
+        self.app.update();
+    }
+
+    /// Send serialized actions and advance frames until transitions of the control step are ready
+    pub fn step(&mut self, actions: Vec<Option<String>>) -> Vec<AgentTransition<B>> {
+        let step_result_rx = self
+            .ai_gym_state
+            .lock()
+            .unwrap()
+            .step_requests
+            .submit(vec![actions]);
+
+        for _ in 0..self.max_frames {
+            self.app.update();
+            if let Ok(mut batch_transitions) = step_result_rx.try_recv() {
+                return batch_transitions.pop().unwrap();
+            }
+        }
+
+        panic!("No step result after {} frames", self.max_frames);
+    }
+
+    /// Same as `step` with typed actions
+    pub fn step_actions(&mut self, actions: Vec<Option<A>>) -> Vec<AgentTransition<B>>
+    where
+        A: Serialize,
+    {
+        let actions = actions
+            .iter()
+            .map(|action| {
+                action
+                    .as_ref()
+                    .map(|action| serde_json::to_string(action).unwrap())
+            })
+            .collect();
+
+        self.step(actions)
+    }
+
+    /// Reset the environment and advance frames until it's done
+    pub fn reset(&mut self, request: ResetRequest) -> Vec<AgentTransition<B>> {
+        let reset_result_rx = self
+            .ai_gym_state
+            .lock()
+            .unwrap()
+            .reset_requests
+            .submit(request);
+
+        for _ in 0..self.max_frames {
+            self.app.update();
+            if reset_result_rx.try_recv().is_ok() {
+                return self.ai_gym_state.lock().unwrap().transitions();
+            }
+        }
+
+        panic!("No reset result after {} frames", self.max_frames);
+    }
+
+    /// Reset given agents and advance frames until it's done
+    pub fn reset_agents(&mut self, agent_indices: Vec<usize>) -> Vec<AgentTransition<B>> {
+        let reset_agents_result_rx = self
+            .ai_gym_state
+            .lock()
+            .unwrap()
+            .reset_agents_requests
+            .submit(agent_indices);
+
+        for _ in 0..self.max_frames {
+            self.app.update();
+            if reset_agents_result_rx.try_recv().is_ok() {
+                return self.ai_gym_state.lock().unwrap().transitions();
+            }
+        }
+
+        panic!("No reset_agents result after {} frames", self.max_frames);
+    }
+
+    /// Send a GET request to the REST API router, e.g. `/step?payload=...`, and advance frames
+    /// until it's answered. Returns the status and body of the response.
+    /// The request can't be told apart from frames the engine spends idle, so it's bounded by
+    /// wall-clock time rather than `max_frames`
+    pub fn get(&mut self, path: &str) -> (hyper::StatusCode, Vec<u8>)
+    where
+        B: serde::Serialize,
+    {
+        let server = self
+            .api_server
+            .get_or_insert_with(|| {
+                let settings = self.ai_gym_state.lock().unwrap().settings.clone();
+                let router = api::router(api::GothamState {
+                    inner: self.ai_gym_state.clone(),
+                    settings,
+                    step_batcher: Default::default(),
+                });
+                gotham::test::TestServer::new(router).expect("Can't start the test server")
+            })
+            .clone();
+
+        // The handler blocks until the engine answers, so the app is driven from this thread
+        let uri = format!("http://localhost{path}");
+        let response = thread::spawn(move || {
+            let response = server
+                .client()
+                .get(uri)
+                .perform()
+                .expect("API request failed");
+            let status = response.status();
+            (
+                status,
+                response.read_body().expect("Can't read response body"),
+            )
+        });
+
+        let started_at = Instant::now();
+        while !response.is_finished() {
+            assert!(
+                started_at.elapsed() < API_REQUEST_TIMEOUT,
+                "No response to {path} in {API_REQUEST_TIMEOUT:?}"
+            );
+            self.app.update();
+            thread::yield_now();
+        }
+
+        response.join().expect("API request panicked")
+    }
+
+    /// Current environment state, as served at `/state`
+    pub fn state(&self) -> Option<B> {
+        self.ai_gym_state.lock().unwrap().environment_state.clone()
+    }
+}
+
+/// Bare `World` with `AIGymState` to unit-test reward and termination logic without booting
+/// a render app. Spawn the entities reward systems read into `world`, feed synthetic actions
+/// and environment states, run the systems and check rewards, terminations and task outcomes
+pub struct RewardFixture<
+    A: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::de::DeserializeOwned,
+    B: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe,
+> {
+    pub world: World,
+    ai_gym_state: AIGymState<A, B>,
+}
+
+impl<
+        A: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::de::DeserializeOwned,
+        B: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe,
+    > RewardFixture<A, B>
+{
+    pub fn new(settings: AIGymSettings) -> Self {
+        let ai_gym_state = AIGymState::<A, B>::new(settings);
+
+        let mut world = World::new();
+        world.insert_resource(ai_gym_state.clone());
+        world.init_resource::<Events<EventControl>>();
+        world.init_resource::<Events<EventControlTyped<A>>>();
+
+        Self {
+            world,
+            ai_gym_state,
+        }
+    }
+
+    /// State the systems under test see, e.g. to register tasks
+    pub fn state(&self) -> MutexGuard<'_, AIGymStateInner<A, B>> {
+        self.ai_gym_state.lock().unwrap()
+    }
+
+    /// Set environment state the tasks are evaluated over
+    pub fn set_env_state(&mut self, env_state: B) {
+        self.state().set_env_state(env_state);
+    }
+
+    /// Send actions of a control step as `EventControl` and `EventControlTyped`
+    pub fn control(&mut self, actions: Vec<Option<A>>)
+    where
+        A: Serialize,
+    {
+        let action_strings = actions
+            .iter()
+            .map(|action| {
+                action
+                    .as_ref()
+                    .map(|action| serde_json::to_string(action).unwrap())
+            })
+            .collect();
+
+        self.state().actions = actions.clone();
+        self.world.send_event(EventControl(action_strings));
+        self.world.send_event(EventControlTyped(actions));
+    }
+
+    /// Run a reward or termination system once
+    pub fn run<M>(&mut self, system: impl IntoSystem<(), (), M>) {
+        self.world
+            .run_system_once(system)
+            .expect("System under test can't run on the fixture world");
+    }
+
+    /// Transitions of all agents as a step would return them, with task outcomes
+    pub fn transitions(&self) -> Vec<AgentTransition<B>> {
+        self.state().transitions()
+    }
+
+    #[track_caller]
+    pub fn assert_reward(&self, agent_index: usize, expected: f32) {
+        let reward = self.state().rewards[agent_index];
+        assert!(
+            (reward - expected).abs() <= 1e-6,
+            "reward of agent {agent_index} is {reward}, expected {expected}"
+        );
+    }
+
+    #[track_caller]
+    pub fn assert_terminated(&self, agent_index: usize, expected: bool) {
+        let terminated = self.state().terminations[agent_index];
+        assert_eq!(
+            terminated, expected,
+            "terminated of agent {agent_index} is {terminated}, expected {expected}"
+        );
+    }
+
+    /// Check reward and termination of a registered task for the current environment state
+    #[track_caller]
+    pub fn assert_task(&self, task: &str, agent_index: usize, reward: f32, terminated: bool) {
+        let transitions = self.transitions();
+        let outcome = transitions[agent_index]
+            .tasks
+            .get(task)
+            .unwrap_or_else(|| panic!("task {task} isn't registered or there's no env state"));
+        assert!(
+            (outcome.reward - reward).abs() <= 1e-6 && outcome.terminated == terminated,
+            "task {task} of agent {agent_index} gives {outcome:?}, expected reward {reward}, terminated {terminated}"
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::Task;
+
+    #[derive(Component)]
+    struct Player(usize);
+
+    /// Reward moving forward, terminate at the goal
+    fn compute_rewards(
+        mut control_events: EventReader<EventControlTyped<String>>,
+        players: Query<(&Player, &Transform)>,
+        ai_gym_state: Res<AIGymState<String, f32>>,
+    ) {
+        let mut ai_gym_state = ai_gym_state.lock().unwrap();
+        for control in control_events.read() {
+            for (agent_index, action) in control.0.iter().enumerate() {
+                if action.as_deref() == Some("forward") {
+                    ai_gym_state.add_reward(agent_index, 1.0);
+                }
+            }
+        }
+        for (player, transform) in players.iter() {
+            ai_gym_state.set_terminated(player.0, transform.translation.z >= 10.0);
+        }
+    }
+
+    #[test]
+    fn reward_fixture_runs_reward_systems() {
+        let mut fixture = RewardFixture::<String, f32>::new(AIGymSettings {
+            num_agents: 2,
+            ..default()
+        });
+        fixture
+            .world
+            .spawn((Player(0), Transform::from_xyz(0.0, 0.0, 10.0)));
+        fixture
+            .world
+            .spawn((Player(1), Transform::from_xyz(0.0, 0.0, 0.0)));
+
+        fixture.control(vec![Some("forward".to_string()), None]);
+        fixture.run(compute_rewards);
+        fixture.assert_reward(0, 1.0);
+        fixture.assert_reward(1, 0.0);
+        fixture.assert_terminated(0, true);
+        fixture.assert_terminated(1, false);
+
+        fixture.state().add_task(Task::new(
+            "reach_goal",
+            |distance: &f32, _| if *distance <= 0.0 { 1.0 } else { 0.0 },
+            |distance: &f32, _| *distance <= 0.0,
+        ));
+        fixture.set_env_state(0.0);
+        fixture.assert_task("reach_goal", 0, 1.0, true);
+        fixture.set_env_state(5.0);
+        fixture.assert_task("reach_goal", 1, 0.0, false);
+    }
+}
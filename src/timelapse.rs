@@ -0,0 +1,150 @@
+//! Timelapse of the whole training run.
+//! Every `interval` of real time the tiled view of all agents (the same grid the spectator
+//! window shows) is captured and piped to `ffmpeg`, independently of episodes, to see how
+//! behavior changes over a long run. Without `ffmpeg` on `PATH` frames are written as
+//! numbered PNG files next to the output instead.
+
+use std::io::Write;
+use std::marker::PhantomData;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::Duration;
+
+use bevy::prelude::*;
+use crossbeam_channel::{bounded, Receiver, Sender};
+
+use crate::{api, state};
+
+/// Records a timelapse into `output`. Prefer a container which survives an abrupt exit
+/// of the process, like `.mkv`
+pub struct TimelapsePlugin<
+    T: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::de::DeserializeOwned,
+    P: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::Serialize,
+> {
+    pub output: PathBuf,
+    /// Real time between captured frames
+    pub interval: Duration,
+    /// Frame rate of the video
+    pub fps: u32,
+    _phantom: PhantomData<(T, P)>,
+}
+
+impl<
+        T: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::de::DeserializeOwned,
+        P: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::Serialize,
+    > TimelapsePlugin<T, P>
+{
+    /// One frame per second played at 30 fps
+    pub fn new(output: impl Into<PathBuf>) -> Self {
+        Self {
+            output: output.into(),
+            interval: Duration::from_secs(1),
+            fps: 30,
+            _phantom: PhantomData,
+        }
+    }
+}
+
+impl<
+        T: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::de::DeserializeOwned,
+        P: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::Serialize,
+    > Plugin for TimelapsePlugin<T, P>
+{
+    fn build(&self, app: &mut App) {
+        let (frame_tx, frame_rx) = bounded(8);
+        let output = self.output.clone();
+        let fps = self.fps;
+        thread::spawn(move || write_timelapse(output, fps, frame_rx));
+
+        app.insert_resource(TimelapseRecorder {
+            frame_tx,
+            timer: Timer::new(self.interval, TimerMode::Repeating),
+        })
+        .add_systems(Update, capture_timelapse_frame::<T, P>);
+    }
+}
+
+/// PNG-encoded frames are sent to the writer thread
+#[derive(Resource)]
+struct TimelapseRecorder {
+    frame_tx: Sender<Vec<u8>>,
+    timer: Timer,
+}
+
+fn capture_timelapse_frame<
+    T: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::de::DeserializeOwned,
+    P: 'static + Send + Sync + Clone + std::panic::RefUnwindSafe + serde::Serialize,
+>(
+    time: Res<Time<Real>>,
+    mut recorder: ResMut<TimelapseRecorder>,
+    ai_gym_state: Res<state::AIGymState<T, P>>,
+) {
+    if !recorder.timer.tick(time.delta()).just_finished() {
+        return;
+    }
+
+    let (frames, (width, height)) = {
+        let ai_gym_state = ai_gym_state.lock().unwrap();
+        (
+            ai_gym_state.visual_observations.clone(),
+            ai_gym_state.settings.observation_size(),
+        )
+    };
+    if frames.is_empty() {
+        return;
+    }
+
+    // Writer is behind, drop the frame rather than stall the simulation
+    let _ = recorder
+        .frame_tx
+        .try_send(api::tile_grid(&frames, width, height));
+}
+
+fn write_timelapse(output: PathBuf, fps: u32, frame_rx: Receiver<Vec<u8>>) {
+    let ffmpeg = Command::new("ffmpeg")
+        .args(["-y", "-loglevel", "error", "-f", "image2pipe"])
+        .args(["-framerate", &fps.to_string(), "-i", "-"])
+        // yuv420p needs even dimensions
+        .args([
+            "-vf",
+            "pad=ceil(iw/2)*2:ceil(ih/2)*2",
+            "-pix_fmt",
+            "yuv420p",
+        ])
+        .arg(&output)
+        .stdin(Stdio::piped())
+        .spawn();
+
+    match ffmpeg {
+        Ok(mut ffmpeg) => {
+            let mut stdin = ffmpeg.stdin.take().unwrap();
+            for frame in frame_rx.iter() {
+                if let Err(e) = stdin.write_all(&frame) {
+                    warn!("bevy_rl: timelapse stopped, ffmpeg failed: {e}");
+                    break;
+                }
+            }
+            drop(stdin);
+            let _ = ffmpeg.wait();
+        }
+        Err(e) => {
+            let directory = output.with_extension("");
+            warn!(
+                "bevy_rl: can't start ffmpeg ({e}), timelapse frames are written to {}",
+                directory.display()
+            );
+            if let Err(e) = std::fs::create_dir_all(&directory) {
+                warn!("bevy_rl: timelapse stopped: {e}");
+                return;
+            }
+            for (frame_index, frame) in frame_rx.iter().enumerate() {
+                let path = directory.join(format!("frame_{frame_index:06}.png"));
+                if let Err(e) = std::fs::write(path, frame) {
+                    warn!("bevy_rl: timelapse stopped: {e}");
+                    return;
+                }
+            }
+        }
+    }
+}
@@ -1,6 +1,6 @@
 use bevy::prelude::*;
 use bevy_rl::*;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 
 #[derive(Default, Clone, Serialize, Debug)]
 pub struct Agent {
@@ -8,7 +8,7 @@ pub struct Agent {
     health: f32,
 }
 
-#[derive(Default, Deref, DerefMut, Clone)]
+#[derive(Default, Deref, DerefMut, Clone, Serialize, Deserialize)]
 pub struct Actions(String);
 
 // Observation space
@@ -53,7 +53,51 @@ fn bevy_rl_control_request(
     }
 }
 
-fn start_bevy_app() {
+fn bevy_rl_reset_request(
+    mut reset_event_reader: EventReader<EventReset>,
+    mut simulation_state: ResMut<NextState<SimulationState>>,
+    ai_gym_state: Res<AIGymState<Actions, EnvironmentState>>,
+    mut env_state: ResMut<EnvironmentState>,
+) {
+    for _ in reset_event_reader.read() {
+        for agent in env_state.agents.iter_mut() {
+            *agent = Agent::default();
+        }
+
+        let mut ai_gym_state = ai_gym_state.lock().unwrap();
+        ai_gym_state.set_env_state(env_state.clone());
+        ai_gym_state.send_reset_result(true);
+        simulation_state.set(SimulationState::Running);
+    }
+}
+
+/// Percent-encode a query string value
+fn encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                (byte as char).to_string()
+            }
+            _ => format!("%{byte:02X}"),
+        })
+        .collect()
+}
+
+/// Typed actions of control steps, as the environment receives them
+#[derive(Default, Resource)]
+struct TypedActions(Vec<Vec<Option<Actions>>>);
+
+fn record_typed_actions(
+    mut control_event_reader: EventReader<EventControlTyped<Actions>>,
+    mut typed_actions: ResMut<TypedActions>,
+) {
+    for control in control_event_reader.read() {
+        typed_actions.0.push(control.0.clone());
+    }
+}
+
+fn build_bevy_app(local_agents: Vec<usize>) -> App {
     let num_agents = 5;
     let initial_state = EnvironmentState {
         agents: vec![Agent::default(); num_agents],
@@ -67,11 +111,13 @@ fn start_bevy_app() {
     app.add_plugins(AssetPlugin::default());
     app.add_plugins(ImagePlugin::default());
 
-    // Setup bevy_rl
+    // Setup bevy_rl, requests are passed by the test driver
     let ai_gym_state = AIGymState::<Actions, EnvironmentState>::new(AIGymSettings {
         num_agents: num_agents as u32,
         render_to_buffer: false,
-        pause_interval: 0.0001,
+        pause_interval: 0.01,
+        enable_rest_api: false,
+        local_agents,
         ..default()
     });
     app.insert_resource(ai_gym_state)
@@ -83,85 +129,257 @@ fn start_bevy_app() {
     // bevy_rl events
     app.add_systems(Update, bevy_rl_pause_request);
     app.add_systems(Update, bevy_rl_control_request);
+    app.add_systems(Update, bevy_rl_reset_request);
 
-    // Run for 1M frames
-    for _ in 0..1000000 {
-        // sleep for 1/60 of a second
-        std::thread::sleep(std::time::Duration::from_millis(16));
-        app.update();
-    }
+    app
 }
 
 #[test]
-/// This test would start a basic bevy_rl app and test the 3 scenarios:
-/// 1. Test `state` endpoint with environment original state
-/// 2. Test `step` endpoint with 5 actions for each agent
-/// 3. Test `state` endpoint with environment state after actions taken to make sure
+/// This test would drive a basic bevy_rl app frame by frame and test the 3 scenarios:
+/// 1. Test environment original state
+/// 2. Test `step` with 5 actions for each agent
+/// 3. Test environment state after actions taken to make sure
 /// it matches the expected state
 fn test_api_state_step() {
-    // Start bevy app in a separate thread
-    std::thread::spawn(|| {
-        start_bevy_app();
-    });
+    let mut driver = testing::TestDriver::<Actions, EnvironmentState>::new(build_bevy_app(vec![]));
 
-    // let bevy app start REST API
-    std::thread::sleep(std::time::Duration::from_millis(500));
-
-    // Test `state` endpoint
-    let response = reqwest::blocking::get("http://localhost:7878/state")
-        .unwrap()
-        .text()
-        .unwrap();
+    // Test original state, environment sets it on the first pause
+    driver.update();
+    let response = serde_json::to_string(&driver.state()).unwrap();
 
     let expected_response = r#"{"agents":[{"health":0.0,"location":[0.0,0.0]},{"health":0.0,"location":[0.0,0.0]},{"health":0.0,"location":[0.0,0.0]},{"health":0.0,"location":[0.0,0.0]},{"health":0.0,"location":[0.0,0.0]}]}"#;
     assert_eq!(response, expected_response);
 
-    // Test `step` endpoint
-    #[derive(Serialize)]
-    struct RESTAPIAction {
-        action: String,
+    // Test `step`
+    // bevy_rl passes each action as a serialized string, your environment will need to
+    // deserialize the action string to the correct type
+    let actions = ["DOWN", "UP", "LEFT", "RIGHT", "IDLE"]
+        .iter()
+        .map(|action| Some(action.to_string()))
+        .collect();
+    let transitions = driver.step(actions);
+
+    // Each agent gets (observation, reward, terminated, truncated, info) tuple
+    let transitions: Vec<serde_json::Value> =
+        serde_json::from_str(&serde_json::to_string(&transitions).unwrap()).unwrap();
+    assert_eq!(transitions.len(), 5);
+    for transition in transitions.iter() {
+        assert_eq!(transition["reward"], 0.0);
+        assert_eq!(transition["terminated"], false);
+        assert_eq!(transition["truncated"], false);
+        assert!(transition["observation"]["agents"].is_array());
+        assert!(transition["info"].is_object());
     }
 
-    // bevy_rl expects each action to be in format: {"action": string:serialized_action}
-    // bevy_rl will deserialize it's internal AgentAction and your environment will need to
-    // deserialize the action string to the correct type
+    let response = serde_json::to_string(&driver.state()).unwrap();
 
-    let actions: [RESTAPIAction; 5] = [
-        RESTAPIAction {
-            action: "DOWN".to_string(),
-        },
-        RESTAPIAction {
-            action: "UP".to_string(),
-        },
-        RESTAPIAction {
-            action: "LEFT".to_string(),
-        },
-        RESTAPIAction {
-            action: "RIGHT".to_string(),
-        },
-        RESTAPIAction {
-            action: "IDLE".to_string(),
-        },
-    ];
+    let expected_response = r#"{"agents":[{"health":0.0,"location":[0.0,-1.0]},{"health":0.0,"location":[0.0,1.0]},{"health":0.0,"location":[-1.0,0.0]},{"health":0.0,"location":[1.0,0.0]},{"health":0.0,"location":[0.0,0.0]}]}"#;
 
-    let actions_json = serde_json::to_string(&actions).unwrap();
-    let response =
-        reqwest::blocking::get(format!("http://localhost:7878/step?payload={actions_json}"))
-            .unwrap()
-            .text()
-            .unwrap();
+    assert_eq!(response, expected_response);
+}
 
-    std::thread::sleep(std::time::Duration::from_millis(1000));
+#[test]
+/// Same cycle through the REST API router: requests pass the query string extractors,
+/// handlers and middlewares, and responses are checked for status and JSON body
+fn test_api_router_step_reset() {
+    let mut driver = testing::TestDriver::<Actions, EnvironmentState>::new(build_bevy_app(vec![]));
+    driver.update();
+
+    let payload = r#"[{"action":"DOWN"},{"action":"UP"},{"action":"LEFT"},{"action":"RIGHT"},{"action":"IDLE"}]"#;
+    let (status, body) = driver.get(&format!("/step?payload={}", encode(payload)));
+    assert_eq!(status, 200);
+    let transitions: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    let transitions = transitions.as_array().unwrap();
+    assert_eq!(transitions.len(), 5);
+    for transition in transitions.iter() {
+        assert_eq!(transition["reward"], 0.0);
+        assert_eq!(transition["terminated"], false);
+        assert_eq!(transition["truncated"], false);
+    }
+    assert_eq!(
+        transitions[0]["observation"]["agents"][0]["location"][1],
+        -1.0
+    );
 
-    let expected_response = r#"[{"is_terminated":false,"reward":0.0},{"is_terminated":false,"reward":0.0},{"is_terminated":false,"reward":0.0},{"is_terminated":false,"reward":0.0},{"is_terminated":false,"reward":0.0}]"#;
-    assert!(response == expected_response);
+    // Actions of every agent are required
+    let (status, body) = driver.get(&format!("/step?payload={}", encode(r#"[{"action":"UP"}]"#)));
+    assert_eq!(status, 400);
+    let error: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert!(error["error"].is_string());
 
-    let response = reqwest::blocking::get("http://localhost:7878/state")
+    let (status, body) = driver.get("/reset?seed=7");
+    assert_eq!(status, 200);
+    let agent_states: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    let agent_states = agent_states.as_array().unwrap();
+    assert_eq!(agent_states.len(), 5);
+    for agent_state in agent_states.iter() {
+        assert_eq!(agent_state["reward"], 0.0);
+        assert_eq!(agent_state["is_terminated"], false);
+        assert_eq!(agent_state["is_truncated"], false);
+    }
+
+    let (status, body) = driver.get("/state");
+    assert_eq!(status, 200);
+    let env_state: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(
+        env_state["agents"][0]["location"],
+        serde_json::json!([0.0, 0.0])
+    );
+}
+
+#[test]
+/// With `reject_invalid_actions` steps with actions which aren't JSON of the action type fail
+fn test_api_router_rejects_invalid_actions() {
+    let mut driver = testing::TestDriver::<Actions, EnvironmentState>::new(build_bevy_app(vec![]));
+    driver
+        .app
+        .world()
+        .resource::<AIGymState<Actions, EnvironmentState>>()
+        .lock()
         .unwrap()
-        .text()
-        .unwrap();
+        .settings
+        .reject_invalid_actions = true;
+    driver.update();
 
-    let expected_response = r#"{"agents":[{"health":0.0,"location":[0.0,-1.0]},{"health":0.0,"location":[0.0,1.0]},{"health":0.0,"location":[-1.0,0.0]},{"health":0.0,"location":[1.0,0.0]},{"health":0.0,"location":[0.0,0.0]}]}"#;
+    let payload =
+        r#"[{"action":"\"UP\""},{"action":"UP"},{"action":null},{"action":null},{"action":null}]"#;
+    let (status, body) = driver.get(&format!("/step?payload={}", encode(payload)));
+    assert_eq!(status, 400);
+    let error: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert!(error["error"]
+        .as_str()
+        .unwrap()
+        .starts_with("Invalid action of agent 1"));
+}
+
+#[test]
+/// Scripted agents act for local agents inside the engine, their actions replace the trainer's
+fn test_scripted_agent_action() {
+    let mut app = build_bevy_app(vec![4]);
+    app.init_resource::<TypedActions>()
+        .add_systems(Update, record_typed_actions);
+    let mut driver = testing::TestDriver::<Actions, EnvironmentState>::new(app);
+    driver.update();
+
+    driver
+        .app
+        .world()
+        .resource::<AIGymState<Actions, EnvironmentState>>()
+        .lock()
+        .unwrap()
+        .add_scripted_agent(4, |_: &EnvironmentState| Actions("UP".to_string()));
+
+    let actions = vec![None, None, None, None, Some(r#""DOWN""#.to_string())];
+    driver.step(actions);
+
+    let typed_actions = driver.app.world().resource::<TypedActions>();
+    let actions = typed_actions.0.last().expect("No control step");
+    assert_eq!(actions.len(), 5);
+    assert!(actions[..4].iter().all(Option::is_none));
+    assert_eq!(
+        actions[4].as_ref().map(|action| action.as_str()),
+        Some("UP")
+    );
+}
+
+#[test]
+/// Scripted agents that aren't local are ignored, the trainer keeps driving them
+fn test_scripted_agent_must_be_local() {
+    let mut app = build_bevy_app(vec![]);
+    app.init_resource::<TypedActions>()
+        .add_systems(Update, record_typed_actions);
+    let mut driver = testing::TestDriver::<Actions, EnvironmentState>::new(app);
+    driver.update();
+
+    driver
+        .app
+        .world()
+        .resource::<AIGymState<Actions, EnvironmentState>>()
+        .lock()
+        .unwrap()
+        .add_scripted_agent(1, |_: &EnvironmentState| Actions("UP".to_string()));
+
+    let actions = vec![None, Some(r#""DOWN""#.to_string()), None, None, None];
+    driver.step(actions);
+
+    let typed_actions = driver.app.world().resource::<TypedActions>();
+    let actions = typed_actions.0.last().expect("No control step");
+    assert_eq!(
+        actions[1].as_ref().map(|action| action.as_str()),
+        Some("DOWN")
+    );
+}
+
+/// Hooks of the test environment: agent 0 terminates on its first action, every agent earns
+/// a reward on pauses
+struct TerminatingHooks;
+
+impl hooks::EnvHooks<Actions, EnvironmentState> for TerminatingHooks {
+    fn on_reset(&mut self, world: &mut World, _reset: &EventReset) {
+        for agent in world.resource_mut::<EnvironmentState>().agents.iter_mut() {
+            *agent = Agent::default();
+        }
+    }
 
-    assert!(response == expected_response);
+    fn on_action(&mut self, world: &mut World, _actions: &[Option<Actions>]) {
+        world
+            .resource::<AIGymState<Actions, EnvironmentState>>()
+            .lock()
+            .unwrap()
+            .set_terminated(0, true);
+    }
+
+    fn collect_state(&mut self, world: &mut World) -> EnvironmentState {
+        world.resource::<EnvironmentState>().clone()
+    }
+
+    fn compute_rewards(&mut self, world: &mut World) -> Vec<f32> {
+        vec![1.0; world.resource::<EnvironmentState>().agents.len()]
+    }
+}
+
+#[test]
+/// Resets through `EnvHooksPlugin` clear terminations and close episodes
+fn test_env_hooks_reset() {
+    let num_agents = 5;
+    let mut app = App::new();
+    app.add_plugins(MinimalPlugins);
+    app.add_plugins(WindowPlugin::default());
+    app.add_plugins(AssetPlugin::default());
+    app.add_plugins(ImagePlugin::default());
+    app.insert_resource(AIGymState::<Actions, EnvironmentState>::new(
+        AIGymSettings {
+            num_agents: num_agents as u32,
+            render_to_buffer: false,
+            pause_interval: 0.01,
+            enable_rest_api: false,
+            ..default()
+        },
+    ))
+    .add_plugins(AIGymPlugin::<Actions, EnvironmentState>::default())
+    .insert_resource(EnvironmentState {
+        agents: vec![Agent::default(); num_agents],
+    })
+    .add_plugins(hooks::EnvHooksPlugin::<_, Actions, EnvironmentState>::new(
+        TerminatingHooks,
+    ));
+
+    let mut driver = testing::TestDriver::<Actions, EnvironmentState>::new(app);
+    driver.update();
+
+    let transitions = driver.step_actions(vec![Some(Actions("UP".to_string())); num_agents]);
+    assert!(transitions[0].terminated);
+
+    let transitions = driver.reset(state::ResetRequest::default());
+    assert!(transitions.iter().all(|transition| !transition.terminated));
+
+    let ai_gym_state = driver
+        .app
+        .world()
+        .resource::<AIGymState<Actions, EnvironmentState>>()
+        .lock()
+        .unwrap();
+    assert_eq!(ai_gym_state.episode_stats[0].episodes, 1);
+    assert!(ai_gym_state.episode_stats[0].last_return.is_some());
+    assert_eq!(ai_gym_state.episode_stats[0].episode_length, 0);
 }